@@ -0,0 +1,168 @@
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+use crate::config::{AlertConfig, Config, WebhookConfig};
+use crate::logger::log_warning;
+use crate::miner::MinerManager;
+use crate::pool::PoolManager;
+
+static ALERTER: OnceLock<Alerter> = OnceLock::new();
+
+/// Minimum shares before a miner's reject rate is judged.
+const MIN_SHARES_FOR_REJECT_RATE: i64 = 20;
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+/// Expired cooldowns are pruned once this many are tracked.
+const MAX_TRACKED_ALERTS: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertEvent {
+    MinerConnected,
+    MinerDisconnected,
+    PoolUnhealthy,
+    HighRejectRate,
+    NoShares,
+    BlockCandidate,
+}
+
+struct Alerter {
+    client: reqwest::Client,
+    webhooks: Vec<WebhookConfig>,
+    cooldown: Duration,
+    /// Last send per (event, subject), so a flapping pool or miner isn't reported every check
+    last_sent: DashMap<(AlertEvent, String), Instant>,
+}
+
+/// Enables webhook delivery; without it `notify` is a no-op.
+pub fn init(webhooks: Vec<WebhookConfig>, config: &AlertConfig) {
+    if webhooks.is_empty() {
+        return;
+    }
+    let _ = ALERTER.set(Alerter {
+        client: reqwest::Client::new(),
+        webhooks,
+        cooldown: Duration::from_secs(config.cooldown_secs),
+        last_sent: DashMap::new(),
+    });
+}
+
+/// Posts `event` about `subject` to every webhook subscribed to it, unless the
+/// same event for the same subject was sent within the cooldown.
+pub fn notify(event: AlertEvent, subject: &str, message: &str, fields: serde_json::Value) {
+    let Some(alerter) = ALERTER.get() else {
+        return;
+    };
+
+    let key = (event, subject.to_string());
+    if let Some(last) = alerter.last_sent.get(&key) {
+        if last.elapsed() < alerter.cooldown {
+            return;
+        }
+    }
+    alerter.last_sent.insert(key, Instant::now());
+    if alerter.last_sent.len() > MAX_TRACKED_ALERTS {
+        alerter.last_sent.retain(|_, sent| sent.elapsed() < alerter.cooldown);
+    }
+
+    // `content` and `text` let Discord and Slack incoming webhooks render the message as-is
+    let mut payload = serde_json::json!({
+        "event": event,
+        "subject": subject,
+        "message": message,
+        "content": message,
+        "text": message,
+        "timestamp": chrono::Utc::now().to_rfc3339(),
+    });
+    if let (Some(payload), serde_json::Value::Object(fields)) = (payload.as_object_mut(), fields) {
+        payload.extend(fields);
+    }
+    let body = payload.to_string();
+
+    for webhook in &alerter.webhooks {
+        if !webhook.events.is_empty() && !webhook.events.contains(&event) {
+            continue;
+        }
+        let request = alerter.client.post(&webhook.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body.clone());
+        let url = webhook.url.clone();
+        tokio::spawn(async move {
+            match request.send().await {
+                Ok(response) if !response.status().is_success() => {
+                    log_warning(&format!("Webhook {} returned {}", url, response.status()));
+                }
+                Err(e) => log_warning(&format!("Webhook {} failed: {}", url, e)),
+                Ok(_) => {}
+            }
+        });
+    }
+}
+
+/// Periodically checks pool health and per-miner share rates, raising alerts on thresholds.
+pub async fn monitor(pool_manager: Arc<PoolManager>, miner_manager: Arc<MinerManager>, config: Config) {
+    if ALERTER.get().is_none() {
+        return;
+    }
+
+    let no_shares_window = Duration::from_secs(config.alerts.no_shares_minutes * 60);
+    // Accepted-share count per miner and when it last changed
+    let mut progress: HashMap<String, (i64, Instant)> = HashMap::new();
+    let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + CHECK_INTERVAL, CHECK_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        for (key, pool_config) in &config.pools {
+            let pool = pool_manager.get_or_create(&pool_config.name);
+            let pool = pool.read().await;
+            if !pool.is_healthy() {
+                notify(AlertEvent::PoolUnhealthy, key,
+                    &format!("Pool {} ({}:{}) is not responding", pool_config.name, pool_config.host, pool_config.port),
+                    serde_json::json!({
+                        "pool": pool_config.name,
+                        "last_ping_time": pool.last_ping_time.to_rfc3339(),
+                    }));
+            }
+        }
+
+        let entries = miner_manager.get_all_entries();
+        progress.retain(|key, _| entries.iter().any(|(k, _)| k == key));
+
+        for (key, miner_arc) in entries {
+            let miner = miner_arc.read().await;
+            if miner.wallet.is_empty() {
+                continue;
+            }
+            let accepted = miner.shares_accepted.load(Ordering::Relaxed);
+            let total = accepted + miner.shares_rejected.load(Ordering::Relaxed);
+            let reject_rate = 100.0 - miner.efficiency_percent();
+            if total >= MIN_SHARES_FOR_REJECT_RATE && reject_rate > config.alerts.reject_rate_percent {
+                notify(AlertEvent::HighRejectRate, &key,
+                    &format!("Miner {} rejects {:.1}% of shares on {}", miner.name, reject_rate, miner.pool_name),
+                    serde_json::json!({
+                        "wallet": miner.wallet,
+                        "miner": miner.name,
+                        "pool": miner.pool_name,
+                        "reject_rate_percent": reject_rate,
+                    }));
+            }
+
+            let entry = progress.entry(key.clone()).or_insert((accepted, Instant::now()));
+            if entry.0 != accepted {
+                *entry = (accepted, Instant::now());
+            } else if entry.1.elapsed() >= no_shares_window {
+                notify(AlertEvent::NoShares, &key,
+                    &format!("Miner {} has no accepted shares for {} minutes", miner.name,
+                        config.alerts.no_shares_minutes),
+                    serde_json::json!({
+                        "wallet": miner.wallet,
+                        "miner": miner.name,
+                        "pool": miner.pool_name,
+                    }));
+            }
+        }
+    }
+}
@@ -1,60 +1,195 @@
 use axum::{
-    extract::{Path, Query, State, WebSocketUpgrade},
-    response::{IntoResponse, Json},
-    routing::get,
+    body::Body,
+    extract::{rejection::JsonRejection, Path, Query, Request, State, WebSocketUpgrade},
+    http::{header, HeaderValue, Method, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Json, Response},
+    routing::{get, post},
     Router,
 };
+use futures::{SinkExt, StreamExt};
 use serde::{Deserialize, Serialize};
+use sqlx::Row;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use tokio::sync::RwLock;
-use tower_http::cors::CorsLayer;
+use tokio_util::sync::CancellationToken;
+use tower_http::cors::{AllowOrigin, CorsLayer};
 
 use crate::{
-    database::Database,
-    miner::{MinerManager, MinerInfo},
-    pool::PoolManager,
+    banlist::{BanList, IpRange},
+    limiter::ConnectionLimiter,
+    config::{Config, ReloadError, SharedConfig},
+    database::{Database, MinerRecord},
+    miner::{serialize_hashrate, MinerManager, MinerInfo, MinerSnapshot},
+    pool::{PoolManager, PoolMetrics, ShareVariance, ACCEPT_TIME_BUCKETS_MS},
+    tunnel::{TunnelManager, TunnelMetrics},
     metrics::SystemMetrics,
 };
 
 pub type AppState = Arc<ApiState>;
 
+/// Shares included in `/api/i/:wallet` under `recent_shares`
+const RECENT_SHARES_LIMIT: usize = 20;
+/// Default and maximum rows returned by `/api/pools/:name/miners`
+const POOL_MINERS_LIMIT: usize = 1000;
+/// Maximum rows returned by `/api/sessions`
+const SESSIONS_LIMIT: usize = 1000;
+/// Bucket edges for `/api/hashrate/distribution` when the query gives none
+const DEFAULT_DISTRIBUTION_EDGES: [f64; 2] = [10e12, 50e12];
+
 pub struct ApiState {
     pub miner_manager: Arc<MinerManager>,
     pub pool_manager: Arc<PoolManager>,
+    pub tunnel_manager: Arc<TunnelManager>,
     pub system_metrics: Arc<RwLock<SystemMetrics>>,
     pub database: Option<Arc<Database>>,
+    pub config: Arc<SharedConfig>,
+    pub ban_list: Arc<BanList>,
+    pub limiter: Arc<ConnectionLimiter>,
+    /// Set while tunnels refuse new miners ahead of a restart
+    pub draining: Arc<AtomicBool>,
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn start_api_server(
-    port: u16,
+    config: Arc<SharedConfig>,
     miner_manager: Arc<MinerManager>,
     pool_manager: Arc<PoolManager>,
+    tunnel_manager: Arc<TunnelManager>,
     system_metrics: Arc<RwLock<SystemMetrics>>,
     database: Option<Arc<Database>>,
+    ban_list: Arc<BanList>,
+    limiter: Arc<ConnectionLimiter>,
+    draining: Arc<AtomicBool>,
+    shutdown: CancellationToken,
+    bound: tokio::sync::oneshot::Sender<()>,
 ) -> anyhow::Result<()> {
+    let (addr, protect_metrics, cors) = {
+        let config = config.get();
+        (config.api_addr()?, config.api_key_metrics, cors_layer(&config.allowed_origins))
+    };
     let state = Arc::new(ApiState {
         miner_manager,
         pool_manager,
+        tunnel_manager,
         system_metrics,
         database,
+        config,
+        ban_list,
+        limiter,
+        draining,
     });
 
-    let app = Router::new()
+    let app = router(state, protect_metrics, cors);
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    let _ = bound.send(());
+
+    // Stop accepting on shutdown but let in-flight requests finish
+    axum::serve(listener, app)
+        .with_graceful_shutdown(async move { shutdown.cancelled().await })
+        .await?;
+
+    Ok(())
+}
+
+fn router(state: AppState, protect_metrics: bool, cors: CorsLayer) -> Router {
+    let api_routes = Router::new()
         .route("/api/metrics", get(handle_metrics))
+        .route("/api/summary", get(handle_summary))
         .route("/api/i/:wallet", get(handle_miner_info))
+        .route("/api/miners/:key/disconnect", post(handle_disconnect_miner))
+        .route("/api/miners/:key/reset", post(handle_reset_miner))
+        .route("/api/leaderboard", get(handle_leaderboard))
+        .route("/api/drain", post(handle_drain))
+        .route("/api/undrain", post(handle_undrain))
+        .route("/api/pools", get(handle_pools))
+        .route("/api/pools/:name", get(handle_pool_detail))
+        .route("/api/pools/:name/miners", get(handle_pool_miners))
+        .route("/api/pools/:name/variance", get(handle_pool_variance))
+        .route("/api/tunnels", get(handle_tunnels))
+        .route("/api/groups", get(handle_groups))
+        .route("/api/hashrate/history", get(handle_hashrate_history))
+        .route("/api/hashrate/distribution", get(handle_hashrate_distribution))
+        .route("/api/sessions", get(handle_sessions))
         .route("/api/network/stats", get(handle_network_stats))
         .route("/api/shares/stats", get(handle_shares_stats))
-        .route("/metrics", get(handle_prometheus_metrics))
         .route("/api/logs/stream", get(handle_websocket))
-        .layer(CorsLayer::permissive())
-        .with_state(state);
+        .route("/api/export/miners.csv", get(handle_export_miners))
+        .route("/api/export/shares.csv", get(handle_export_shares))
+        .route("/api/ban", get(handle_ban_list))
+        .route("/api/ban/:ip", post(handle_ban).delete(handle_unban))
+        .route("/api/config", get(handle_get_config).put(handle_put_config))
+        .route_layer(middleware::from_fn_with_state(Arc::clone(&state), require_api_key));
 
-    let addr = format!("0.0.0.0:{}", port);
-    let listener = tokio::net::TcpListener::bind(&addr).await?;
+    let mut public_routes = Router::new()
+        .route("/metrics", get(handle_prometheus_metrics))
+        .route("/health", get(handle_health));
+    if protect_metrics {
+        public_routes = public_routes
+            .route_layer(middleware::from_fn_with_state(Arc::clone(&state), require_api_key));
+    }
 
-    axum::serve(listener, app).await?;
+    api_routes
+        .merge(public_routes)
+        .layer(cors)
+        .with_state(state)
+}
 
-    Ok(())
+/// Without configured origins any site a miner operator visits could read wallets
+/// and IPs through their browser, so this stays permissive only for compatibility.
+fn cors_layer(allowed_origins: &[String]) -> CorsLayer {
+    if allowed_origins.is_empty() {
+        return CorsLayer::permissive();
+    }
+
+    // Origins were checked by `Config::validate`
+    let origins: Vec<HeaderValue> = allowed_origins.iter()
+        .filter_map(|origin| HeaderValue::from_str(origin).ok())
+        .collect();
+    CorsLayer::new()
+        .allow_origin(AllowOrigin::list(origins))
+        .allow_methods([Method::GET, Method::POST, Method::PUT, Method::DELETE])
+        .allow_headers([header::AUTHORIZATION, header::CONTENT_TYPE])
+}
+
+async fn require_api_key(
+    State(state): State<AppState>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let Some(expected) = state.config.get().api_key.clone().filter(|k| !k.is_empty()) else {
+        return next.run(req).await;
+    };
+    let expected = expected.as_str();
+
+    let bearer = req.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(str::trim);
+
+    let authorized = bearer.is_some_and(|key| keys_match(key, expected))
+        || query_key(req.uri()).is_some_and(|key| keys_match(&key, expected));
+    if authorized {
+        next.run(req).await
+    } else {
+        ApiError::Unauthorized.into_response()
+    }
+}
+
+/// The percent-decoded `?key=` parameter.
+fn query_key(uri: &axum::http::Uri) -> Option<String> {
+    let Query(mut params) = Query::<std::collections::HashMap<String, String>>::try_from_uri(uri).ok()?;
+    params.remove("key")
+}
+
+/// Compares every byte instead of stopping at the first mismatch, so response timing
+/// doesn't reveal how much of the key was right.
+fn keys_match(given: &str, expected: &str) -> bool {
+    let (given, expected) = (given.as_bytes(), expected.as_bytes());
+    given.len() == expected.len()
+        && given.iter().zip(expected).fold(0u8, |diff, (a, b)| diff | (a ^ b)) == 0
 }
 
 #[derive(Serialize)]
@@ -62,6 +197,7 @@ struct MetricsResponse {
     system: SystemInfo,
     database: DatabaseInfo,
     network: NetworkInfo,
+    network_interfaces: NetworkInterfacesInfo,
     miners: MinersInfo,
     pools: serde_json::Value,
 }
@@ -71,12 +207,16 @@ struct SystemInfo {
     cpu_model: String,
     cpu_cores: usize,
     cpu_usage_percent: String,
+    cpu_per_core_percent: Vec<f32>,
     ram_total_bytes: u64,
     ram_used_bytes: u64,
     ram_usage_percent: String,
     disk_total_bytes: u64,
     disk_used_bytes: u64,
     disk_usage_percent: String,
+    process_cpu_percent: String,
+    process_memory_bytes: u64,
+    process_threads: usize,
     os: String,
     public_ip: String,
     uptime_seconds: u64,
@@ -102,30 +242,113 @@ struct NetworkInfo {
     packets_received: i64,
 }
 
+/// Host NIC traffic, as opposed to the miner bytes the proxy counts in `NetworkInfo`
+#[derive(Serialize)]
+struct NetworkInterfacesInfo {
+    interfaces: Vec<String>,
+    rx_bytes: u64,
+    tx_bytes: u64,
+    rx_bytes_per_sec: f64,
+    tx_bytes_per_sec: f64,
+}
+
 #[derive(Serialize)]
 struct MinersInfo {
     active_count: usize,
+    /// Miners matching the filters, before `limit`/`offset` are applied
+    total: usize,
+    /// Connections closed for not authorizing within `unauthorized_grace_secs`
+    reaped_total: u64,
     list: Vec<MinerData>,
 }
 
 #[derive(Serialize)]
 struct MinerData {
-    wallet: String,
+    /// Connection key, as used by `/api/miners/:key/disconnect`
+    key: String,
+    /// Names this endpoint used before sharing `MinerSnapshot`, kept for existing clients
     name: String,
-    ip: String,
     pool: String,
+    #[serde(flatten)]
+    miner: MinerSnapshot,
+}
+
+/// Fleet-wide totals, cheap enough to poll every second.
+#[derive(Serialize, Default)]
+struct Summary {
+    active_miners: usize,
+    #[serde(serialize_with = "serialize_hashrate")]
+    total_hashrate: f64,
     shares_accepted: i64,
     shares_rejected: i64,
-    current_hashrate: String,
-    average_hashrate: String,
-    difficulty: f64,
-    uptime_seconds: i64,
+    acceptance_rate: f64,
+    healthy_pools: usize,
+    unhealthy_pools: usize,
+    bytes_download: i64,
+    bytes_upload: i64,
+    download_bytes_per_sec: f64,
+    upload_bytes_per_sec: f64,
+}
+
+/// Sums the cached miner snapshots and the pools in one pass.
+async fn summarize(state: &ApiState) -> Summary {
+    let mut summary = Summary::default();
+    for (_, miner) in state.miner_manager.cached_snapshots().iter() {
+        summary.active_miners += 1;
+        summary.total_hashrate += miner.current_hashrate;
+        summary.shares_accepted += miner.shares_accepted;
+        summary.shares_rejected += miner.shares_rejected;
+        summary.bytes_download += miner.bytes_download;
+        summary.bytes_upload += miner.bytes_upload;
+        summary.download_bytes_per_sec += miner.download_rate;
+        summary.upload_bytes_per_sec += miner.upload_rate;
+    }
+
+    let shares = summary.shares_accepted + summary.shares_rejected;
+    if shares > 0 {
+        summary.acceptance_rate = summary.shares_accepted as f64 / shares as f64 * 100.0;
+    }
+
+    for pool_arc in state.pool_manager.get_all_pools().await {
+        if pool_arc.read().await.is_healthy() {
+            summary.healthy_pools += 1;
+        } else {
+            summary.unhealthy_pools += 1;
+        }
+    }
+
+    summary
+}
+
+async fn handle_summary(State(state): State<AppState>) -> impl IntoResponse {
+    Json(summarize(&state).await)
+}
+
+#[derive(Deserialize)]
+struct MinerListQuery {
+    limit: Option<usize>,
+    offset: Option<usize>,
+    pool: Option<String>,
+    wallet: Option<String>,
+    /// A `worker_groups` label
+    group: Option<String>,
+    /// `hashrate` or `uptime`, both descending
+    sort: Option<String>,
 }
 
-async fn handle_metrics(State(state): State<AppState>) -> impl IntoResponse {
+async fn handle_metrics(
+    Query(params): Query<MinerListQuery>,
+    State(state): State<AppState>,
+) -> Result<Response, ApiError> {
+    let sort_by_hashrate = match params.sort.as_deref() {
+        None | Some("uptime") => false,
+        Some("hashrate") => true,
+        Some(other) => return Err(ApiError::InvalidSort(other.to_string())),
+    };
+
     let metrics = state.system_metrics.read().await;
     let pools = state.pool_manager.get_all_pools().await;
-    let miners = state.miner_manager.get_all_miners().await;
+    let miners = state.miner_manager.cached_snapshots();
 
     let mut pools_data = serde_json::Map::new();
     for pool_arc in pools {
@@ -133,60 +356,79 @@ async fn handle_metrics(State(state): State<AppState>) -> impl IntoResponse {
         let pool_info = serde_json::json!({
             "current_ping_ms": pool.current_ping,
             "average_ping_ms": pool.average_ping,
+            "ping_p50_ms": pool.ping_percentile(50.0),
+            "ping_p95_ms": pool.ping_percentile(95.0),
+            "ping_p99_ms": pool.ping_percentile(99.0),
             "avg_accept_time_ms": pool.avg_accept_time,
             "shares_accepted": pool.shares_accepted,
             "shares_rejected": pool.shares_rejected,
+            "shares_lost": pool.shares_lost,
+            "active_connections": pool.active_connections.load(std::sync::atomic::Ordering::Relaxed),
             "last_ping_time": pool.last_ping_time.to_rfc3339(),
         });
         pools_data.insert(pool.name.clone(), pool_info);
     }
 
+    let mut total_download = 0i64;
+    let mut total_upload = 0i64;
+    let mut total_sent = 0i64;
+    let mut total_received = 0i64;
+
+    let hide_unauthorized = state.config.get().hide_unauthorized_miners;
     let mut miners_list = Vec::new();
-    for miner_arc in miners {
-        let miner = miner_arc.read().await;
-        let uptime = (chrono::Utc::now() - miner.connected_at).num_seconds();
+    for (key, miner) in miners.iter() {
+        total_download += miner.bytes_download;
+        total_upload += miner.bytes_upload;
+        total_sent += miner.packets_sent;
+        total_received += miner.packets_received;
+
+        if (hide_unauthorized && miner.wallet.is_empty())
+            || params.pool.as_ref().is_some_and(|pool| *pool != miner.pool_name)
+            || params.wallet.as_ref().is_some_and(|wallet| *wallet != miner.wallet)
+            || params.group.as_ref().is_some_and(|group| Some(group) != miner.group.as_ref()) {
+            continue;
+        }
 
         miners_list.push(MinerData {
-            wallet: miner.wallet.clone(),
+            key: key.clone(),
             name: miner.name.clone(),
-            ip: miner.ip.clone(),
             pool: miner.pool_name.clone(),
-            shares_accepted: miner.shares_accepted.load(std::sync::atomic::Ordering::Relaxed),
-            shares_rejected: miner.shares_rejected.load(std::sync::atomic::Ordering::Relaxed),
-            current_hashrate: MinerInfo::format_hashrate(miner.current_hashrate),
-            average_hashrate: MinerInfo::format_hashrate(miner.average_hashrate),
-            difficulty: miner.difficulty,
-            uptime_seconds: uptime,
+            miner: miner.clone(),
         });
     }
 
-    let data_db_size = get_file_size("./data.db");
-    let system_db_size = get_file_size("./system.db");
+    if sort_by_hashrate {
+        miners_list.sort_by(|a, b| b.miner.current_hashrate.total_cmp(&a.miner.current_hashrate));
+    } else if params.sort.is_some() {
+        miners_list.sort_by_key(|data| std::cmp::Reverse(data.miner.uptime_seconds));
+    }
 
-    let mut total_download = 0i64;
-    let mut total_upload = 0i64;
-    let mut total_sent = 0i64;
-    let mut total_received = 0i64;
+    let total = miners_list.len();
+    let miners_list: Vec<MinerData> = miners_list.into_iter()
+        .skip(params.offset.unwrap_or(0))
+        .take(params.limit.unwrap_or(usize::MAX))
+        .collect();
 
-    for miner_arc in state.miner_manager.get_all_miners().await {
-        let miner = miner_arc.read().await;
-        total_download += miner.bytes_download.load(std::sync::atomic::Ordering::Relaxed);
-        total_upload += miner.bytes_upload.load(std::sync::atomic::Ordering::Relaxed);
-        total_sent += miner.packets_sent.load(std::sync::atomic::Ordering::Relaxed);
-        total_received += miner.packets_received.load(std::sync::atomic::Ordering::Relaxed);
-    }
+    let (data_db_size, system_db_size) = match &state.database {
+        Some(db) => (get_file_size(db.data_db_path()), get_file_size(db.system_db_path())),
+        None => (0, 0),
+    };
 
     let response = MetricsResponse {
         system: SystemInfo {
             cpu_model: metrics.cpu_model.clone(),
             cpu_cores: metrics.cpu_cores,
             cpu_usage_percent: format!("{:.2}%", metrics.cpu_usage),
+            cpu_per_core_percent: metrics.cpu_per_core.clone(),
             ram_total_bytes: metrics.ram_total,
             ram_used_bytes: metrics.ram_used,
             ram_usage_percent: format!("{:.2}%", (metrics.ram_used as f64 / metrics.ram_total as f64) * 100.0),
             disk_total_bytes: metrics.disk_total,
             disk_used_bytes: metrics.disk_used,
             disk_usage_percent: format!("{:.2}%", (metrics.disk_used as f64 / metrics.disk_total as f64) * 100.0),
+            process_cpu_percent: format!("{:.2}%", metrics.process_cpu_percent),
+            process_memory_bytes: metrics.process_memory_bytes,
+            process_threads: metrics.process_threads,
             os: metrics.os.clone(),
             public_ip: metrics.public_ip.clone(),
             uptime_seconds: metrics.uptime.as_secs(),
@@ -207,86 +449,521 @@ async fn handle_metrics(State(state): State<AppState>) -> impl IntoResponse {
             packets_sent: total_sent,
             packets_received: total_received,
         },
+        network_interfaces: NetworkInterfacesInfo {
+            interfaces: metrics.net_interfaces.clone(),
+            rx_bytes: metrics.net_rx_bytes,
+            tx_bytes: metrics.net_tx_bytes,
+            rx_bytes_per_sec: metrics.net_rx_rate,
+            tx_bytes_per_sec: metrics.net_tx_rate,
+        },
         miners: MinersInfo {
             active_count: metrics.active_miners,
+            total,
+            reaped_total: state.miner_manager.reaped_total(),
             list: miners_list,
         },
         pools: serde_json::Value::Object(pools_data),
     };
 
-    Json(response)
+    Ok(Json(response).into_response())
+}
+
+#[derive(Deserialize)]
+struct LeaderboardQuery {
+    /// `hashrate` (default) or `shares`
+    by: Option<String>,
+    limit: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct LeaderboardEntry {
+    rank: usize,
+    wallet: String,
+    name: String,
+    pool: String,
+    value: f64,
+    uptime_seconds: i64,
+}
+
+async fn handle_leaderboard(
+    Query(params): Query<LeaderboardQuery>,
+    State(state): State<AppState>,
+) -> Result<Response, ApiError> {
+    let by = params.by.unwrap_or_else(|| "hashrate".to_string());
+    if by != "hashrate" && by != "shares" {
+        return Err(ApiError::InvalidRanking(by));
+    }
+
+    let now = chrono::Utc::now();
+    let mut entries = Vec::new();
+    for miner_arc in state.miner_manager.get_all_miners().await {
+        let miner = miner_arc.read().await;
+        if miner.wallet.is_empty() {
+            continue;
+        }
+        let value = if by == "shares" {
+            miner.shares_accepted.load(std::sync::atomic::Ordering::Relaxed) as f64
+        } else {
+            miner.current_hashrate
+        };
+        entries.push(LeaderboardEntry {
+            rank: 0,
+            wallet: miner.wallet.clone(),
+            name: miner.name.clone(),
+            pool: miner.pool_name.clone(),
+            value,
+            uptime_seconds: (now - miner.connected_at).num_seconds(),
+        });
+    }
+
+    // Highest value first; longer-connected miners win ties
+    entries.sort_by(|a, b| b.value.total_cmp(&a.value).then(b.uptime_seconds.cmp(&a.uptime_seconds)));
+    entries.truncate(params.limit.unwrap_or(10));
+    for (i, entry) in entries.iter_mut().enumerate() {
+        entry.rank = i + 1;
+    }
+
+    Ok(Json(serde_json::json!({
+        "by": by,
+        "miners": entries,
+    })).into_response())
 }
 
 async fn handle_miner_info(
     Path(wallet): Path<String>,
     State(state): State<AppState>,
-) -> impl IntoResponse {
+) -> Result<Response, ApiError> {
     let mut active_miner = None;
+    let mut worker_count = 0;
+    let mut total_current_hashrate = 0.0;
+    let mut total_average_hashrate = 0.0;
     let miners = state.miner_manager.get_all_miners().await;
 
+    // Same prefix match as the stored workers below, so the totals cover the same rigs
     for miner_arc in miners {
         let miner = miner_arc.read().await;
-        if miner.wallet.starts_with(&wallet) {
-            let uptime = (chrono::Utc::now() - miner.connected_at).num_seconds();
-            active_miner = Some(serde_json::json!({
-                "wallet": miner.wallet,
-                "miner_name": miner.name,
-                "ip": miner.ip,
-                "pool_name": miner.pool_name,
-                "shares_accepted": miner.shares_accepted.load(std::sync::atomic::Ordering::Relaxed),
-                "shares_rejected": miner.shares_rejected.load(std::sync::atomic::Ordering::Relaxed),
-                "bytes_download": miner.bytes_download.load(std::sync::atomic::Ordering::Relaxed),
-                "bytes_upload": miner.bytes_upload.load(std::sync::atomic::Ordering::Relaxed),
-                "packets_sent": miner.packets_sent.load(std::sync::atomic::Ordering::Relaxed),
-                "packets_received": miner.packets_received.load(std::sync::atomic::Ordering::Relaxed),
-                "current_hashrate": MinerInfo::format_hashrate(miner.current_hashrate),
-                "average_hashrate": MinerInfo::format_hashrate(miner.average_hashrate),
-                "difficulty": miner.difficulty,
-                "uptime_seconds": uptime,
-                "connected_at": miner.connected_at.to_rfc3339(),
-                "last_seen": miner.last_seen.to_rfc3339(),
-                "status": "online",
-            }));
-            break;
+        if !miner.wallet.starts_with(&wallet) {
+            continue;
+        }
+        worker_count += 1;
+        total_current_hashrate += miner.current_hashrate;
+        total_average_hashrate += miner.average_hashrate;
+        if active_miner.is_none() {
+            let mut snapshot = serde_json::to_value(miner.snapshot()).unwrap_or_default();
+            snapshot["status"] = "online".into();
+            active_miner = Some(snapshot);
         }
     }
 
-    let historical_data = if let Some(ref db) = state.database {
-        match db.get_miner_by_wallet(&wallet).await {
-            Ok(records) => {
-                records.iter().map(|r| {
-                    serde_json::json!({
-                        "wallet": r.wallet,
-                        "miner_name": r.miner_name,
-                        "ip": r.ip,
-                        "pool_name": r.pool_name,
-                        "shares_accepted": r.shares_accepted,
-                        "shares_rejected": r.shares_rejected,
-                        "bytes_download": r.bytes_download,
-                        "bytes_upload": r.bytes_upload,
-                        "packets_sent": r.packets_sent,
-                        "packets_received": r.packets_received,
-                        "current_hashrate": MinerInfo::format_hashrate(r.current_hashrate),
-                        "average_hashrate": MinerInfo::format_hashrate(r.average_hashrate),
-                        "connected_at": r.connected_at,
-                        "last_seen": r.last_seen,
-                    })
-                }).collect::<Vec<_>>()
-            }
-            Err(_) => Vec::new(),
-        }
-    } else {
-        Vec::new()
+    let historical_data = match &state.database {
+        Some(db) => db.get_miner_by_wallet(&wallet).await?.iter().map(miner_record_json).collect::<Vec<_>>(),
+        None => Vec::new(),
+    };
+
+    let summary = match &state.database {
+        Some(db) => db.get_wallet_summary(&wallet).await?.map(|summary| serde_json::json!({
+            "first_seen": summary.first_seen,
+            "last_seen": summary.last_seen,
+            "shares_accepted": summary.shares_accepted,
+            "shares_rejected": summary.shares_rejected,
+            "difficulty_accepted_sum": summary.difficulty_accepted_sum,
+            "difficulty_rejected_sum": summary.difficulty_rejected_sum,
+            "bytes_download": summary.bytes_download,
+            "bytes_upload": summary.bytes_upload,
+            "workers": summary.workers,
+        })),
+        None => None,
+    };
+
+    let recent_shares = match &state.database {
+        Some(db) => db.get_shares_by_wallet(&wallet, RECENT_SHARES_LIMIT).await?
+            .iter()
+            .map(|share| serde_json::json!({
+                "miner_name": share.miner_name,
+                "pool_name": share.pool_name,
+                "job_id": share.job_id,
+                "accepted": share.accepted,
+                "difficulty": share.difficulty,
+                "submitted_at": share.submitted_at.to_rfc3339(),
+            }))
+            .collect::<Vec<_>>(),
+        None => Vec::new(),
     };
 
+    let sessions = state.miner_manager.session_stats(&wallet).map(|(total, reconnects)| serde_json::json!({
+        "total": total,
+        "reconnects_last_hour": reconnects,
+    }));
+
     let response = serde_json::json!({
         "wallet": wallet,
         "active_miner": active_miner,
+        "worker_count": worker_count,
+        "total_current_hashrate": MinerInfo::format_hashrate(total_current_hashrate),
+        "total_average_hashrate": MinerInfo::format_hashrate(total_average_hashrate),
+        "summary": summary,
+        "sessions": sessions,
         "historical_data": historical_data,
         "total_miners": historical_data.len(),
+        "recent_shares": recent_shares,
     });
 
-    Json(response)
+    Ok(Json(response).into_response())
+}
+
+#[derive(Serialize)]
+struct TunnelData {
+    name: String,
+    active_connections: i64,
+    connections_total: i64,
+    shares_accepted: i64,
+    shares_rejected: i64,
+    bytes_download: i64,
+    bytes_upload: i64,
+    /// Closed connections by reason, e.g. `pool_closed`
+    disconnects: std::collections::BTreeMap<&'static str, i64>,
+}
+
+impl From<&TunnelMetrics> for TunnelData {
+    fn from(tunnel: &TunnelMetrics) -> Self {
+        use std::sync::atomic::Ordering;
+
+        Self {
+            name: tunnel.name.clone(),
+            active_connections: tunnel.active_connections.load(Ordering::Relaxed),
+            connections_total: tunnel.connections_total.load(Ordering::Relaxed),
+            shares_accepted: tunnel.shares_accepted.load(Ordering::Relaxed),
+            shares_rejected: tunnel.shares_rejected.load(Ordering::Relaxed),
+            bytes_download: tunnel.bytes_download.load(Ordering::Relaxed),
+            bytes_upload: tunnel.bytes_upload.load(Ordering::Relaxed),
+            disconnects: tunnel.disconnect_counts(),
+        }
+    }
+}
+
+async fn handle_tunnels(State(state): State<AppState>) -> impl IntoResponse {
+    let tunnels: Vec<TunnelData> = state.tunnel_manager.get_all_tunnels().iter()
+        .map(|tunnel| TunnelData::from(&**tunnel))
+        .collect();
+    Json(tunnels)
+}
+
+#[derive(Serialize, Default)]
+struct GroupData {
+    group: String,
+    workers: usize,
+    #[serde(serialize_with = "serialize_hashrate")]
+    current_hashrate: f64,
+    #[serde(serialize_with = "serialize_hashrate")]
+    average_hashrate: f64,
+    shares_accepted: i64,
+    shares_rejected: i64,
+}
+
+/// Active miners rolled up by their `worker_groups` label.
+async fn handle_groups(State(state): State<AppState>) -> impl IntoResponse {
+    let mut groups: std::collections::BTreeMap<String, GroupData> = std::collections::BTreeMap::new();
+    let mut ungrouped = 0;
+    for (_, miner) in state.miner_manager.cached_snapshots().iter() {
+        let Some(group) = &miner.group else {
+            ungrouped += 1;
+            continue;
+        };
+        let data = groups.entry(group.clone()).or_insert_with(|| GroupData { group: group.clone(), ..Default::default() });
+        data.workers += 1;
+        data.current_hashrate += miner.current_hashrate;
+        data.average_hashrate += miner.average_hashrate;
+        data.shares_accepted += miner.shares_accepted;
+        data.shares_rejected += miner.shares_rejected;
+    }
+
+    Json(serde_json::json!({
+        "count": groups.len(),
+        "groups": groups.into_values().collect::<Vec<_>>(),
+        "ungrouped_workers": ungrouped,
+    }))
+}
+
+fn miner_record_json(r: &MinerRecord) -> serde_json::Value {
+    serde_json::json!({
+        "wallet": r.wallet,
+        "miner_name": r.miner_name,
+        "ip": r.ip,
+        "pool_name": r.pool_name,
+        "user_agent": r.user_agent,
+        "shares_accepted": r.shares_accepted,
+        "shares_rejected": r.shares_rejected,
+        "difficulty_accepted_sum": r.difficulty_accepted_sum,
+        "difficulty_rejected_sum": r.difficulty_rejected_sum,
+        "bytes_download": r.bytes_download,
+        "bytes_upload": r.bytes_upload,
+        "packets_sent": r.packets_sent,
+        "packets_received": r.packets_received,
+        "current_hashrate": MinerInfo::format_hashrate(r.current_hashrate),
+        "average_hashrate": MinerInfo::format_hashrate(r.average_hashrate),
+        "connected_at": r.connected_at,
+        "last_seen": r.last_seen,
+        "handshake_secs": r.handshake_secs,
+        "time_to_first_share_secs": r.time_to_first_share_secs,
+    })
+}
+
+#[derive(Serialize)]
+struct PoolData {
+    name: String,
+    healthy: bool,
+    current_ping_ms: f64,
+    average_ping_ms: f64,
+    ping_p50_ms: f64,
+    ping_p95_ms: f64,
+    ping_p99_ms: f64,
+    avg_accept_time_ms: f64,
+    shares_accepted: i64,
+    shares_rejected: i64,
+    shares_lost: i64,
+    acceptance_rate: f64,
+    active_connections: i64,
+    blocks_found: i64,
+    jobs_per_minute: usize,
+    jobs_total: i64,
+    clean_jobs_total: i64,
+    recent_job_ids: Vec<String>,
+    last_ping_time: String,
+}
+
+impl From<&PoolMetrics> for PoolData {
+    fn from(pool: &PoolMetrics) -> Self {
+        Self {
+            name: pool.name.clone(),
+            healthy: pool.is_healthy(),
+            current_ping_ms: pool.current_ping,
+            average_ping_ms: pool.average_ping,
+            ping_p50_ms: pool.ping_percentile(50.0),
+            ping_p95_ms: pool.ping_percentile(95.0),
+            ping_p99_ms: pool.ping_percentile(99.0),
+            avg_accept_time_ms: pool.avg_accept_time,
+            shares_accepted: pool.shares_accepted,
+            shares_rejected: pool.shares_rejected,
+            shares_lost: pool.shares_lost,
+            acceptance_rate: pool.acceptance_rate(),
+            active_connections: pool.active_connections.load(std::sync::atomic::Ordering::Relaxed),
+            blocks_found: pool.blocks_found,
+            jobs_per_minute: pool.jobs_per_minute(),
+            jobs_total: pool.jobs_total,
+            clean_jobs_total: pool.clean_jobs_total,
+            recent_job_ids: pool.recent_job_ids.iter().cloned().collect(),
+            last_ping_time: pool.last_ping_time.to_rfc3339(),
+        }
+    }
+}
+
+async fn handle_pools(State(state): State<AppState>) -> impl IntoResponse {
+    let mut pools = Vec::new();
+    for pool_arc in state.pool_manager.get_all_pools().await {
+        pools.push(PoolData::from(&*pool_arc.read().await));
+    }
+    pools.sort_by(|a, b| a.name.cmp(&b.name));
+
+    Json(serde_json::json!({
+        "count": pools.len(),
+        "pools": pools,
+    }))
+}
+
+async fn handle_pool_detail(
+    Path(name): Path<String>,
+    State(state): State<AppState>,
+) -> Result<Response, ApiError> {
+    for pool_arc in state.pool_manager.get_all_pools().await {
+        let pool = pool_arc.read().await;
+        if pool.name == name {
+            return Ok(Json(PoolData::from(&*pool)).into_response());
+        }
+    }
+
+    Err(ApiError::PoolNotFound(name))
+}
+
+#[derive(Deserialize)]
+struct PoolMinersQuery {
+    limit: Option<usize>,
+}
+
+async fn handle_pool_miners(
+    Path(name): Path<String>,
+    Query(params): Query<PoolMinersQuery>,
+    State(state): State<AppState>,
+) -> Result<Response, ApiError> {
+    let db = state.database.as_ref().ok_or(ApiError::DatabaseDisabled)?;
+    let limit = params.limit.unwrap_or(POOL_MINERS_LIMIT).min(POOL_MINERS_LIMIT);
+
+    let records = db.get_miners_by_pool(&name, limit).await?;
+    Ok(Json(serde_json::json!({
+        "pool": name,
+        "count": records.len(),
+        "miners": records.iter().map(miner_record_json).collect::<Vec<_>>(),
+    })).into_response())
+}
+
+#[derive(Deserialize)]
+struct PoolVarianceQuery {
+    hours: Option<u32>,
+}
+
+/// Share interval statistics and block luck of one pool, from the stored shares.
+async fn handle_pool_variance(
+    Path(name): Path<String>,
+    Query(params): Query<PoolVarianceQuery>,
+    State(state): State<AppState>,
+) -> Result<Response, ApiError> {
+    let pool_config = state.config.get().pools.get(&name).cloned().ok_or_else(|| ApiError::PoolNotFound(name.clone()))?;
+    let db = state.database.as_ref().ok_or(ApiError::DatabaseDisabled)?;
+    let hours = params.hours.unwrap_or(24);
+
+    // Shares are recorded under the pool's display name, not its config key
+    let shares = db.get_share_intervals(&pool_config.name, hours).await?;
+    let variance = ShareVariance::from_shares(&shares, pool_config.network_difficulty);
+    let mut response = serde_json::to_value(variance).unwrap_or_default();
+    response["pool"] = name.into();
+    response["hours"] = hours.into();
+
+    Ok(Json(response).into_response())
+}
+
+#[derive(Deserialize)]
+struct HashrateHistoryQuery {
+    wallet: String,
+    hours: Option<u32>,
+}
+
+async fn handle_hashrate_history(
+    Query(params): Query<HashrateHistoryQuery>,
+    State(state): State<AppState>,
+) -> Result<Response, ApiError> {
+    let db = state.database.as_ref().ok_or(ApiError::DatabaseDisabled)?;
+    let hours = params.hours.unwrap_or(24);
+
+    let history = db.get_hashrate_history(&params.wallet, hours).await?;
+    let points: Vec<_> = history.iter().map(|h| serde_json::json!({
+        "miner_name": h.miner_name,
+        "pool_name": h.pool_name,
+        "current_hashrate": h.current_hashrate,
+        "average_hashrate": h.average_hashrate,
+        "timestamp": h.timestamp.to_rfc3339(),
+    })).collect();
+
+    Ok(Json(serde_json::json!({
+        "wallet": params.wallet,
+        "hours": hours,
+        "data_points": points.len(),
+        "history": points,
+    })).into_response())
+}
+
+#[derive(Deserialize)]
+struct DistributionQuery {
+    /// Comma-separated bucket edges, e.g. `10T,50T`
+    buckets: Option<String>,
+}
+
+#[derive(Serialize)]
+struct DistributionBucket {
+    label: String,
+    /// Inclusive lower edge in H/s
+    min: f64,
+    /// Exclusive upper edge in H/s; `null` for the last bucket
+    max: Option<f64>,
+    miners: usize,
+}
+
+/// Authorized miners counted per `current_hashrate` bucket.
+async fn handle_hashrate_distribution(
+    Query(params): Query<DistributionQuery>,
+    State(state): State<AppState>,
+) -> Result<Response, ApiError> {
+    let mut edges = match &params.buckets {
+        None => DEFAULT_DISTRIBUTION_EDGES.to_vec(),
+        Some(text) => text.split(',')
+            .map(MinerInfo::parse_hashrate)
+            .collect::<Option<Vec<f64>>>()
+            .ok_or_else(|| ApiError::InvalidBuckets(text.clone()))?,
+    };
+    edges.sort_by(f64::total_cmp);
+    edges.dedup();
+    edges.retain(|edge| *edge > 0.0);
+
+    let mut buckets: Vec<DistributionBucket> = Vec::with_capacity(edges.len() + 1);
+    let mut min = 0.0;
+    for max in edges.iter().copied().map(Some).chain(std::iter::once(None)) {
+        let label = match max {
+            Some(max) => format!("{} - {}", MinerInfo::format_hashrate(min), MinerInfo::format_hashrate(max)),
+            None => format!("> {}", MinerInfo::format_hashrate(min)),
+        };
+        buckets.push(DistributionBucket { label, min, max, miners: 0 });
+        min = max.unwrap_or(min);
+    }
+
+    let mut hashrates = Vec::new();
+    for miner_arc in state.miner_manager.get_all_miners().await {
+        let miner = miner_arc.read().await;
+        if miner.wallet.is_empty() {
+            continue;
+        }
+        hashrates.push(miner.current_hashrate);
+        let index = edges.partition_point(|edge| *edge <= miner.current_hashrate);
+        buckets[index].miners += 1;
+    }
+
+    hashrates.sort_by(f64::total_cmp);
+    let total: f64 = hashrates.iter().sum();
+    let middle = hashrates.len() / 2;
+    let median = match hashrates.len() {
+        0 => 0.0,
+        n if n % 2 == 0 => (hashrates[middle - 1] + hashrates[middle]) / 2.0,
+        _ => hashrates[middle],
+    };
+
+    Ok(Json(serde_json::json!({
+        "miners": hashrates.len(),
+        "total_hashrate": MinerInfo::format_hashrate(total),
+        "median_hashrate": MinerInfo::format_hashrate(median),
+        "buckets": buckets,
+    })).into_response())
+}
+
+#[derive(Deserialize)]
+struct SessionsQuery {
+    wallet: Option<String>,
+    hours: Option<u32>,
+}
+
+async fn handle_sessions(
+    Query(params): Query<SessionsQuery>,
+    State(state): State<AppState>,
+) -> Result<Response, ApiError> {
+    let db = state.database.as_ref().ok_or(ApiError::DatabaseDisabled)?;
+    let wallet = params.wallet.filter(|w| !w.is_empty());
+    let hours = params.hours.unwrap_or(24);
+
+    let sessions = db.get_sessions(wallet.as_deref(), hours, SESSIONS_LIMIT).await?;
+    let sessions: Vec<_> = sessions.iter().map(|s| serde_json::json!({
+        "wallet": s.wallet,
+        "miner_name": s.miner_name,
+        "ip": s.ip,
+        "pool_name": s.pool_name,
+        "tunnel_name": s.tunnel_name,
+        "connected_at": s.connected_at.to_rfc3339(),
+        "disconnected_at": s.disconnected_at.to_rfc3339(),
+        "duration_secs": s.duration_secs(),
+        "reason": s.reason,
+    })).collect();
+
+    Ok(Json(serde_json::json!({
+        "wallet": wallet,
+        "hours": hours,
+        "count": sessions.len(),
+        "sessions": sessions,
+    })).into_response())
 }
 
 #[derive(Deserialize)]
@@ -337,7 +1014,7 @@ async fn handle_shares_stats(
 async fn handle_prometheus_metrics(State(state): State<AppState>) -> impl IntoResponse {
     let metrics = state.system_metrics.read().await;
     let pools = state.pool_manager.get_all_pools().await;
-    let miners = state.miner_manager.get_all_miners().await;
+    let miners = state.miner_manager.cached_snapshots();
 
     let mut output = String::new();
 
@@ -349,10 +1026,77 @@ async fn handle_prometheus_metrics(State(state): State<AppState>) -> impl IntoRe
     output.push_str("# TYPE mining_tunnel_active_miners gauge\n");
     output.push_str(&format!("mining_tunnel_active_miners {}\n\n", metrics.active_miners));
 
+    let summary = summarize(&state).await;
+
+    output.push_str("# HELP mining_tunnel_hashrate Summed current hashrate of active miners in H/s\n");
+    output.push_str("# TYPE mining_tunnel_hashrate gauge\n");
+    output.push_str(&format!("mining_tunnel_hashrate {:.2}\n\n", summary.total_hashrate));
+
+    output.push_str("# HELP mining_tunnel_shares Shares submitted by active miners this session\n");
+    output.push_str("# TYPE mining_tunnel_shares gauge\n");
+    output.push_str(&format!("mining_tunnel_shares{{status=\"accepted\"}} {}\n", summary.shares_accepted));
+    output.push_str(&format!("mining_tunnel_shares{{status=\"rejected\"}} {}\n\n", summary.shares_rejected));
+
+    output.push_str("# HELP mining_tunnel_acceptance_rate_percent Accepted share percentage across active miners\n");
+    output.push_str("# TYPE mining_tunnel_acceptance_rate_percent gauge\n");
+    output.push_str(&format!("mining_tunnel_acceptance_rate_percent {:.2}\n\n", summary.acceptance_rate));
+
+    output.push_str("# HELP mining_tunnel_pools Configured pools by health\n");
+    output.push_str("# TYPE mining_tunnel_pools gauge\n");
+    output.push_str(&format!("mining_tunnel_pools{{state=\"healthy\"}} {}\n", summary.healthy_pools));
+    output.push_str(&format!("mining_tunnel_pools{{state=\"unhealthy\"}} {}\n\n", summary.unhealthy_pools));
+
+    // One series per configured pool, by display name like the pool metrics; pools removed
+    // by a reload keep their metrics but drop out here
+    let (ping_interval_secs, mut configured) = {
+        let config = state.config.get();
+        (config.ping_interval_secs, config.pools.values().map(|pool| pool.name.clone()).collect::<Vec<_>>())
+    };
+    configured.sort();
+    let mut last_pings = String::new();
+    output.push_str("# HELP mining_tunnel_pool_up Whether a ping to the pool succeeded within the last two ping intervals\n");
+    output.push_str("# TYPE mining_tunnel_pool_up gauge\n");
+    for name in &configured {
+        let (up, last_ping) = match state.pool_manager.get(name) {
+            Some(pool_arc) => {
+                let pool = pool_arc.read().await;
+                (pool.is_up(ping_interval_secs), pool.last_successful_ping())
+            }
+            // Not pinged yet
+            None => (false, None),
+        };
+        output.push_str(&format!("mining_tunnel_pool_up{{pool=\"{}\"}} {}\n", name, u8::from(up)));
+        last_pings.push_str(&format!("mining_tunnel_pool_last_ping_timestamp_seconds{{pool=\"{}\"}} {}\n",
+            name, last_ping.map_or(0, |t| t.timestamp())));
+    }
+    output.push('\n');
+    output.push_str("# HELP mining_tunnel_pool_last_ping_timestamp_seconds Unix time of the last successful ping, 0 if none\n");
+    output.push_str("# TYPE mining_tunnel_pool_last_ping_timestamp_seconds gauge\n");
+    output.push_str(&last_pings);
+    output.push('\n');
+
+    output.push_str("# HELP mining_tunnel_bytes_per_second Bandwidth relayed for active miners\n");
+    output.push_str("# TYPE mining_tunnel_bytes_per_second gauge\n");
+    output.push_str(&format!("mining_tunnel_bytes_per_second{{direction=\"download\"}} {:.2}\n",
+        summary.download_bytes_per_sec));
+    output.push_str(&format!("mining_tunnel_bytes_per_second{{direction=\"upload\"}} {:.2}\n\n",
+        summary.upload_bytes_per_sec));
+
+    output.push_str("# HELP mining_tunnel_miners_reaped_total Connections closed for never sending mining.authorize\n");
+    output.push_str("# TYPE mining_tunnel_miners_reaped_total counter\n");
+    output.push_str(&format!("mining_tunnel_miners_reaped_total {}\n\n", state.miner_manager.reaped_total()));
+
     output.push_str("# HELP mining_tunnel_cpu_usage_percent CPU usage percentage\n");
     output.push_str("# TYPE mining_tunnel_cpu_usage_percent gauge\n");
     output.push_str(&format!("mining_tunnel_cpu_usage_percent {:.2}\n\n", metrics.cpu_usage));
 
+    output.push_str("# HELP mining_tunnel_cpu_core_usage_percent CPU usage percentage per core\n");
+    output.push_str("# TYPE mining_tunnel_cpu_core_usage_percent gauge\n");
+    for (core, usage) in metrics.cpu_per_core.iter().enumerate() {
+        output.push_str(&format!("mining_tunnel_cpu_core_usage_percent{{core=\"{}\"}} {:.2}\n", core, usage));
+    }
+    output.push('\n');
+
     output.push_str("# HELP mining_tunnel_cpu_cores Number of CPU cores\n");
     output.push_str("# TYPE mining_tunnel_cpu_cores gauge\n");
     output.push_str(&format!("mining_tunnel_cpu_cores {}\n\n", metrics.cpu_cores));
@@ -362,32 +1106,451 @@ async fn handle_prometheus_metrics(State(state): State<AppState>) -> impl IntoRe
     output.push_str(&format!("mining_tunnel_ram_bytes{{type=\"total\"}} {}\n", metrics.ram_total));
     output.push_str(&format!("mining_tunnel_ram_bytes{{type=\"used\"}} {}\n\n", metrics.ram_used));
 
+    output.push_str("# HELP mining_tunnel_process_cpu_percent CPU used by the tunnel process, 100 per full core\n");
+    output.push_str("# TYPE mining_tunnel_process_cpu_percent gauge\n");
+    output.push_str(&format!("mining_tunnel_process_cpu_percent {:.2}\n\n", metrics.process_cpu_percent));
+
+    output.push_str("# HELP mining_tunnel_process_memory_bytes Resident memory of the tunnel process\n");
+    output.push_str("# TYPE mining_tunnel_process_memory_bytes gauge\n");
+    output.push_str(&format!("mining_tunnel_process_memory_bytes {}\n\n", metrics.process_memory_bytes));
+
+    output.push_str("# HELP mining_tunnel_process_threads Threads in the tunnel process\n");
+    output.push_str("# TYPE mining_tunnel_process_threads gauge\n");
+    output.push_str(&format!("mining_tunnel_process_threads {}\n\n", metrics.process_threads));
+
+    output.push_str("# HELP mining_tunnel_host_net_bytes Bytes through the host network interfaces\n");
+    output.push_str("# TYPE mining_tunnel_host_net_bytes counter\n");
+    output.push_str(&format!("mining_tunnel_host_net_bytes{{direction=\"rx\"}} {}\n", metrics.net_rx_bytes));
+    output.push_str(&format!("mining_tunnel_host_net_bytes{{direction=\"tx\"}} {}\n\n", metrics.net_tx_bytes));
+
+    output.push_str("# HELP mining_tunnel_host_net_bytes_per_second Host network interface throughput\n");
+    output.push_str("# TYPE mining_tunnel_host_net_bytes_per_second gauge\n");
+    output.push_str(&format!("mining_tunnel_host_net_bytes_per_second{{direction=\"rx\"}} {:.2}\n", metrics.net_rx_rate));
+    output.push_str(&format!("mining_tunnel_host_net_bytes_per_second{{direction=\"tx\"}} {:.2}\n\n", metrics.net_tx_rate));
+
+    output.push_str("# HELP mining_tunnel_connections Open miner connections across all tunnels\n");
+    output.push_str("# TYPE mining_tunnel_connections gauge\n");
+    output.push_str(&format!("mining_tunnel_connections {}\n\n", state.limiter.active_connections()));
+    if let Some(max) = state.limiter.max_connections() {
+        output.push_str("# HELP mining_tunnel_connections_max Configured max_connections\n");
+        output.push_str("# TYPE mining_tunnel_connections_max gauge\n");
+        output.push_str(&format!("mining_tunnel_connections_max {}\n\n", max));
+    }
+
+    output.push_str("# HELP mining_tunnel_banned_connections_total Connections rejected by the ban list\n");
+    output.push_str("# TYPE mining_tunnel_banned_connections_total counter\n");
+    output.push_str(&format!("mining_tunnel_banned_connections_total {}\n\n", state.ban_list.rejected_count()));
+
+    if let Some(db) = &state.database {
+        output.push_str("# HELP mining_tunnel_db_size_bytes SQLite database file size in bytes\n");
+        output.push_str("# TYPE mining_tunnel_db_size_bytes gauge\n");
+        output.push_str(&format!("mining_tunnel_db_size_bytes{{db=\"data\"}} {}\n",
+            get_file_size(db.data_db_path())));
+        output.push_str(&format!("mining_tunnel_db_size_bytes{{db=\"system\"}} {}\n\n",
+            get_file_size(db.system_db_path())));
+
+        if let Ok(counts) = db.row_counts().await {
+            output.push_str("# HELP mining_tunnel_db_rows_total Rows per database table\n");
+            output.push_str("# TYPE mining_tunnel_db_rows_total gauge\n");
+            output.push_str(&format!("mining_tunnel_db_rows_total{{table=\"miners\"}} {}\n", counts.miners));
+            output.push_str(&format!("mining_tunnel_db_rows_total{{table=\"shares\"}} {}\n\n", counts.shares));
+        }
+    }
+
+    output.push_str("# HELP mining_tunnel_share_accept_time_ms Time from share submit to pool accept\n");
+    output.push_str("# TYPE mining_tunnel_share_accept_time_ms histogram\n");
+    for pool_arc in &pools {
+        let pool = pool_arc.read().await;
+        let histogram = &pool.accept_time_histogram;
+        for (bound, count) in ACCEPT_TIME_BUCKETS_MS.iter().zip(histogram.buckets) {
+            output.push_str(&format!("mining_tunnel_share_accept_time_ms_bucket{{pool=\"{}\",le=\"{}\"}} {}\n",
+                pool.name, bound, count));
+        }
+        output.push_str(&format!("mining_tunnel_share_accept_time_ms_bucket{{pool=\"{}\",le=\"+Inf\"}} {}\n",
+            pool.name, histogram.count));
+        output.push_str(&format!("mining_tunnel_share_accept_time_ms_sum{{pool=\"{}\"}} {:.2}\n",
+            pool.name, histogram.sum));
+        output.push_str(&format!("mining_tunnel_share_accept_time_ms_count{{pool=\"{}\"}} {}\n",
+            pool.name, histogram.count));
+    }
+    output.push('\n');
+
     for pool_arc in pools {
         let pool = pool_arc.read().await;
         output.push_str(&format!("mining_tunnel_pool_ping_ms{{pool=\"{}\",type=\"current\"}} {:.2}\n",
             pool.name, pool.current_ping));
-        output.push_str(&format!("mining_tunnel_pool_ping_ms{{pool=\"{}\",type=\"average\"}} {:.2}\n\n",
+        output.push_str(&format!("mining_tunnel_pool_ping_ms{{pool=\"{}\",type=\"average\"}} {:.2}\n",
             pool.name, pool.average_ping));
+        for (quantile, percentile) in [("0.5", 50.0), ("0.95", 95.0), ("0.99", 99.0)] {
+            output.push_str(&format!("mining_tunnel_pool_ping_ms{{pool=\"{}\",quantile=\"{}\"}} {:.2}\n",
+                pool.name, quantile, pool.ping_percentile(percentile)));
+        }
+        output.push('\n');
 
         output.push_str(&format!("mining_tunnel_pool_shares_total{{pool=\"{}\",status=\"accepted\"}} {}\n",
             pool.name, pool.shares_accepted));
-        output.push_str(&format!("mining_tunnel_pool_shares_total{{pool=\"{}\",status=\"rejected\"}} {}\n\n",
+        output.push_str(&format!("mining_tunnel_pool_shares_total{{pool=\"{}\",status=\"rejected\"}} {}\n",
             pool.name, pool.shares_rejected));
+        output.push_str(&format!("mining_tunnel_pool_shares_total{{pool=\"{}\",status=\"lost\"}} {}\n\n",
+            pool.name, pool.shares_lost));
+
+        output.push_str(&format!("mining_tunnel_pool_connections{{pool=\"{}\"}} {}\n\n",
+            pool.name, pool.active_connections.load(std::sync::atomic::Ordering::Relaxed)));
+
+        output.push_str(&format!("mining_tunnel_pool_blocks_found_total{{pool=\"{}\"}} {}\n\n",
+            pool.name, pool.blocks_found));
+
+        output.push_str(&format!("mining_tunnel_pool_jobs_per_minute{{pool=\"{}\"}} {}\n",
+            pool.name, pool.jobs_per_minute()));
+        output.push_str(&format!("mining_tunnel_pool_jobs_total{{pool=\"{}\",clean=\"true\"}} {}\n",
+            pool.name, pool.clean_jobs_total));
+        output.push_str(&format!("mining_tunnel_pool_jobs_total{{pool=\"{}\",clean=\"false\"}} {}\n\n",
+            pool.name, pool.jobs_total - pool.clean_jobs_total));
     }
 
-    for miner_arc in miners {
-        let miner = miner_arc.read().await;
+    for tunnel in state.tunnel_manager.get_all_tunnels() {
+        let tunnel = TunnelData::from(&*tunnel);
+        output.push_str(&format!("mining_tunnel_tunnel_connections{{tunnel=\"{}\"}} {}\n",
+            tunnel.name, tunnel.active_connections));
+        output.push_str(&format!("mining_tunnel_tunnel_connections_total{{tunnel=\"{}\"}} {}\n",
+            tunnel.name, tunnel.connections_total));
+        output.push_str(&format!("mining_tunnel_tunnel_shares_total{{tunnel=\"{}\",status=\"accepted\"}} {}\n",
+            tunnel.name, tunnel.shares_accepted));
+        output.push_str(&format!("mining_tunnel_tunnel_shares_total{{tunnel=\"{}\",status=\"rejected\"}} {}\n",
+            tunnel.name, tunnel.shares_rejected));
+        output.push_str(&format!("mining_tunnel_tunnel_bytes_total{{tunnel=\"{}\",direction=\"download\"}} {}\n",
+            tunnel.name, tunnel.bytes_download));
+        output.push_str(&format!("mining_tunnel_tunnel_bytes_total{{tunnel=\"{}\",direction=\"upload\"}} {}\n",
+            tunnel.name, tunnel.bytes_upload));
+        for (reason, count) in &tunnel.disconnects {
+            output.push_str(&format!("mining_tunnel_tunnel_disconnects_total{{tunnel=\"{}\",reason=\"{}\"}} {}\n",
+                tunnel.name, reason, count));
+        }
+        output.push('\n');
+    }
+
+    let mut miner_output = String::new();
+    let mut total_download = 0i64;
+    let mut total_upload = 0i64;
+    let mut total_sent = 0i64;
+    let mut total_received = 0i64;
+
+    for (_, miner) in miners.iter() {
+        let download = miner.bytes_download;
+        let upload = miner.bytes_upload;
+        total_download += download;
+        total_upload += upload;
+        total_sent += miner.packets_sent;
+        total_received += miner.packets_received;
+
         if !miner.wallet.is_empty() {
-            output.push_str(&format!("mining_tunnel_miner_hashrate{{wallet=\"{}\",miner=\"{}\",type=\"current\"}} {:.2}\n",
+            miner_output.push_str(&format!("mining_tunnel_miner_hashrate{{wallet=\"{}\",miner=\"{}\",type=\"current\"}} {:.2}\n",
                 miner.wallet, miner.name, miner.current_hashrate));
-            output.push_str(&format!("mining_tunnel_miner_hashrate{{wallet=\"{}\",miner=\"{}\",type=\"average\"}} {:.2}\n",
+            miner_output.push_str(&format!("mining_tunnel_miner_hashrate{{wallet=\"{}\",miner=\"{}\",type=\"average\"}} {:.2}\n",
                 miner.wallet, miner.name, miner.average_hashrate));
+            miner_output.push_str(&format!("mining_tunnel_miner_difficulty_sum{{wallet=\"{}\",miner=\"{}\",status=\"accepted\"}} {}\n",
+                miner.wallet, miner.name, miner.difficulty_accepted_sum));
+            miner_output.push_str(&format!("mining_tunnel_miner_difficulty_sum{{wallet=\"{}\",miner=\"{}\",status=\"rejected\"}} {}\n",
+                miner.wallet, miner.name, miner.difficulty_rejected_sum));
+            miner_output.push_str(&format!("mining_tunnel_miner_shares_stale_total{{wallet=\"{}\",miner=\"{}\"}} {}\n",
+                miner.wallet, miner.name, miner.shares_stale));
+            miner_output.push_str(&format!("mining_tunnel_miner_shares_duplicate_total{{wallet=\"{}\",miner=\"{}\"}} {}\n",
+                miner.wallet, miner.name, miner.shares_duplicate));
+            miner_output.push_str(&format!("mining_tunnel_miner_shares_lost_total{{wallet=\"{}\",miner=\"{}\"}} {}\n",
+                miner.wallet, miner.name, miner.shares_lost));
+            miner_output.push_str(&format!("mining_tunnel_miner_efficiency_percent{{wallet=\"{}\",miner=\"{}\"}} {:.2}\n",
+                miner.wallet, miner.name, miner.efficiency_percent));
+            miner_output.push_str(&format!("mining_tunnel_miner_bytes_total{{wallet=\"{}\",miner=\"{}\",direction=\"download\"}} {}\n",
+                miner.wallet, miner.name, download));
+            miner_output.push_str(&format!("mining_tunnel_miner_bytes_total{{wallet=\"{}\",miner=\"{}\",direction=\"upload\"}} {}\n",
+                miner.wallet, miner.name, upload));
+            miner_output.push_str(&format!("mining_tunnel_miner_bytes_per_second{{wallet=\"{}\",miner=\"{}\",direction=\"download\"}} {:.2}\n",
+                miner.wallet, miner.name, miner.download_rate));
+            miner_output.push_str(&format!("mining_tunnel_miner_bytes_per_second{{wallet=\"{}\",miner=\"{}\",direction=\"upload\"}} {:.2}\n",
+                miner.wallet, miner.name, miner.upload_rate));
         }
     }
 
+    output.push_str("# HELP mining_tunnel_bytes_total Bytes relayed for active miners\n");
+    output.push_str("# TYPE mining_tunnel_bytes_total counter\n");
+    output.push_str(&format!("mining_tunnel_bytes_total{{direction=\"download\"}} {}\n", total_download));
+    output.push_str(&format!("mining_tunnel_bytes_total{{direction=\"upload\"}} {}\n\n", total_upload));
+
+    output.push_str("# HELP mining_tunnel_packets_total Stratum messages relayed for active miners\n");
+    output.push_str("# TYPE mining_tunnel_packets_total counter\n");
+    output.push_str(&format!("mining_tunnel_packets_total{{direction=\"sent\"}} {}\n", total_sent));
+    output.push_str(&format!("mining_tunnel_packets_total{{direction=\"received\"}} {}\n\n", total_received));
+
+    output.push_str(&miner_output);
+
     output
 }
 
+#[derive(Deserialize)]
+struct ExportSharesQuery {
+    wallet: Option<String>,
+    hours: Option<u32>,
+}
+
+async fn handle_export_miners(State(state): State<AppState>) -> Result<Response, ApiError> {
+    let db = state.database.clone().ok_or(ApiError::DatabaseDisabled)?;
+
+    let header = "wallet,miner_name,ip,pool_name,shares_accepted,shares_rejected,bytes_download,bytes_upload,\
+        packets_sent,packets_received,current_hashrate,average_hashrate,connected_at,last_seen\n";
+
+    Ok(csv_response("miners.csv", header, db, |db| db.stream_miners(), |row| {
+        format!("{},{},{},{},{},{},{},{},{},{},{},{},{},{}\n",
+            csv_text(row, "wallet"), csv_text(row, "miner_name"), csv_text(row, "ip"), csv_text(row, "pool_name"),
+            row.get::<i64, _>("shares_accepted"), row.get::<i64, _>("shares_rejected"),
+            row.get::<i64, _>("bytes_download"), row.get::<i64, _>("bytes_upload"),
+            row.get::<i64, _>("packets_sent"), row.get::<i64, _>("packets_received"),
+            row.get::<f64, _>("current_hashrate"), row.get::<f64, _>("average_hashrate"),
+            csv_text(row, "connected_at"), csv_text(row, "last_seen"))
+    }))
+}
+
+async fn handle_export_shares(
+    Query(params): Query<ExportSharesQuery>,
+    State(state): State<AppState>,
+) -> Result<Response, ApiError> {
+    let db = state.database.clone().ok_or(ApiError::DatabaseDisabled)?;
+
+    let wallet = params.wallet.filter(|w| !w.is_empty());
+    let since = params.hours.map(|h| chrono::Utc::now() - chrono::Duration::hours(h as i64));
+    let header = "wallet,miner_name,ip,pool_name,job_id,accepted,difficulty,submitted_at\n";
+
+    Ok(csv_response("shares.csv", header, db, move |db| db.stream_shares(wallet, since), |row| {
+        format!("{},{},{},{},{},{},{},{}\n",
+            csv_text(row, "wallet"), csv_text(row, "miner_name"), csv_text(row, "ip"), csv_text(row, "pool_name"),
+            csv_text(row, "job_id"), row.get::<i64, _>("accepted"), row.get::<f64, _>("difficulty"),
+            csv_text(row, "submitted_at"))
+    }))
+}
+
+/// Streams a database query to the client as a CSV download, one row at a time.
+fn csv_response<Q, F>(filename: &str, header: &'static str, db: Arc<Database>, query: Q, format_row: F) -> Response
+where
+    Q: for<'a> FnOnce(&'a Database) -> futures::stream::BoxStream<'a, Result<sqlx::sqlite::SqliteRow, sqlx::Error>>
+        + Send + 'static,
+    F: Fn(&sqlx::sqlite::SqliteRow) -> String + Send + 'static,
+{
+    let (mut tx, rx) = futures::channel::mpsc::channel::<Result<String, std::io::Error>>(64);
+
+    tokio::spawn(async move {
+        if tx.send(Ok(header.to_string())).await.is_err() {
+            return;
+        }
+
+        let mut rows = query(&db);
+        while let Some(row) = rows.next().await {
+            let line = row.map(|r| format_row(&r)).map_err(std::io::Error::other);
+            let failed = line.is_err();
+            if tx.send(line).await.is_err() || failed {
+                break;
+            }
+        }
+    });
+
+    (
+        [
+            (header::CONTENT_TYPE, "text/csv; charset=utf-8".to_string()),
+            (header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", filename)),
+        ],
+        Body::from_stream(rx),
+    ).into_response()
+}
+
+/// Reads a nullable text column and quotes it for CSV when needed.
+fn csv_text(row: &sqlx::sqlite::SqliteRow, column: &str) -> String {
+    let value: Option<String> = row.try_get(column).ok().flatten();
+    let value = value.unwrap_or_default();
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value
+    }
+}
+
+/// Handler failures, returned as `{"error": {"code": ..., "message": ...}}`.
+#[derive(Debug, thiserror::Error)]
+pub enum ApiError {
+    #[error("missing or invalid API key")]
+    Unauthorized,
+    #[error("unknown sort '{0}'")]
+    InvalidSort(String),
+    #[error("unknown ranking '{0}'")]
+    InvalidRanking(String),
+    #[error("{0}")]
+    InvalidIp(String),
+    #[error("invalid bucket edges '{0}'")]
+    InvalidBuckets(String),
+    #[error("no pool named {0}")]
+    PoolNotFound(String),
+    #[error("no active miner {0}")]
+    MinerNotFound(String),
+    #[error("{0} is not banned")]
+    NotBanned(String),
+    #[error("database logging is disabled")]
+    DatabaseDisabled,
+    #[error("{0}")]
+    InvalidConfig(String),
+    #[error("{0}")]
+    RestartRequired(String),
+    #[error("config updates need api_key to be set")]
+    ConfigUpdatesDisabled,
+    #[error("database error: {0:#}")]
+    Database(#[from] anyhow::Error),
+}
+
+impl ApiError {
+    fn status(&self) -> StatusCode {
+        match self {
+            ApiError::Unauthorized => StatusCode::UNAUTHORIZED,
+            ApiError::InvalidSort(_) | ApiError::InvalidRanking(_) | ApiError::InvalidIp(_)
+            | ApiError::InvalidBuckets(_) | ApiError::InvalidConfig(_) => StatusCode::BAD_REQUEST,
+            ApiError::ConfigUpdatesDisabled => StatusCode::FORBIDDEN,
+            ApiError::RestartRequired(_) => StatusCode::CONFLICT,
+            ApiError::PoolNotFound(_) | ApiError::MinerNotFound(_) | ApiError::NotBanned(_) => StatusCode::NOT_FOUND,
+            ApiError::DatabaseDisabled => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn code(&self) -> &'static str {
+        match self {
+            ApiError::Unauthorized => "unauthorized",
+            ApiError::InvalidSort(_) => "invalid_sort",
+            ApiError::InvalidRanking(_) => "invalid_ranking",
+            ApiError::InvalidIp(_) => "invalid_ip",
+            ApiError::InvalidBuckets(_) => "invalid_buckets",
+            ApiError::PoolNotFound(_) => "pool_not_found",
+            ApiError::MinerNotFound(_) => "miner_not_found",
+            ApiError::NotBanned(_) => "not_banned",
+            ApiError::DatabaseDisabled => "database_disabled",
+            ApiError::InvalidConfig(_) => "invalid_config",
+            ApiError::RestartRequired(_) => "restart_required",
+            ApiError::ConfigUpdatesDisabled => "config_updates_disabled",
+            ApiError::Database(_) => "database_error",
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        if let ApiError::Database(e) = &self {
+            crate::logger::log_error(&format!("API database error: {:#}", e));
+        }
+        let body = serde_json::json!({
+            "error": { "code": self.code(), "message": self.to_string() },
+        });
+        (self.status(), Json(body)).into_response()
+    }
+}
+
+async fn handle_disconnect_miner(
+    Path(key): Path<String>,
+    State(state): State<AppState>,
+) -> Result<Response, ApiError> {
+    if state.miner_manager.disconnect_miner(&key) {
+        Ok(Json(serde_json::json!({ "disconnected": key })).into_response())
+    } else {
+        Err(ApiError::MinerNotFound(key))
+    }
+}
+
+async fn handle_reset_miner(
+    Path(key): Path<String>,
+    State(state): State<AppState>,
+) -> Result<Response, ApiError> {
+    let miner_arc = state.miner_manager.get_miner(&key).ok_or_else(|| ApiError::MinerNotFound(key.clone()))?;
+
+    miner_arc.write().await.reset_stats().await;
+    Ok(Json(serde_json::json!({ "reset": key })).into_response())
+}
+
+async fn handle_ban_list(State(state): State<AppState>) -> impl IntoResponse {
+    Json(serde_json::json!({
+        "banned": state.ban_list.list(),
+        "rejected_connections": state.ban_list.rejected_count(),
+    }))
+}
+
+async fn handle_ban(
+    Path(ip): Path<String>,
+    State(state): State<AppState>,
+) -> Result<Response, ApiError> {
+    let range = ip.parse::<IpRange>().map_err(|e| ApiError::InvalidIp(e.to_string()))?;
+    let added = state.ban_list.ban(range);
+    Ok(Json(serde_json::json!({ "banned": range.to_string(), "added": added })).into_response())
+}
+
+async fn handle_unban(
+    Path(ip): Path<String>,
+    State(state): State<AppState>,
+) -> Result<Response, ApiError> {
+    let range = ip.parse::<IpRange>().map_err(|e| ApiError::InvalidIp(e.to_string()))?;
+    if !state.ban_list.unban(&range) {
+        return Err(ApiError::NotBanned(range.to_string()));
+    }
+    Ok(Json(serde_json::json!({ "unbanned": range.to_string() })).into_response())
+}
+
+async fn handle_health(State(state): State<AppState>) -> impl IntoResponse {
+    let metrics = state.system_metrics.read().await;
+    let draining = state.draining.load(Ordering::Relaxed);
+
+    // 503 while draining so load balancers stop sending new miners here
+    let status = if draining { StatusCode::SERVICE_UNAVAILABLE } else { StatusCode::OK };
+    (status, Json(serde_json::json!({
+        "status": if draining { "draining" } else { "ok" },
+        "draining": draining,
+        "uptime_seconds": metrics.uptime.as_secs(),
+        "active_miners": metrics.active_miners,
+        "connections": {
+            "active": state.limiter.active_connections(),
+            "max": state.limiter.max_connections(),
+        },
+    })))
+}
+
+async fn handle_get_config(State(state): State<AppState>) -> impl IntoResponse {
+    Json(state.config.get().redacted())
+}
+
+/// Replaces the running config the same way SIGHUP does. Without an `api_key` the API is
+/// open to anyone who can reach it, so updates are refused.
+async fn handle_put_config(
+    State(state): State<AppState>,
+    body: Result<Json<Config>, JsonRejection>,
+) -> Result<Response, ApiError> {
+    if state.config.get().api_key.as_deref().is_none_or(str::is_empty) {
+        return Err(ApiError::ConfigUpdatesDisabled);
+    }
+    let Json(config) = body.map_err(|e| ApiError::InvalidConfig(e.body_text()))?;
+
+    let pending = state.config.reload(config).map_err(|e| match e {
+        ReloadError::Invalid(_) => ApiError::InvalidConfig(e.to_string()),
+        ReloadError::RestartRequired(_) => ApiError::RestartRequired(e.to_string()),
+    })?;
+    crate::logger::log_info("Config replaced through the API");
+    if !pending.is_empty() {
+        crate::logger::log_warning(&format!("Restart to apply config changes to {}", pending.join(", ")));
+    }
+    Ok(Json(serde_json::json!({ "applied": true, "restart_required": pending })).into_response())
+}
+
+async fn handle_drain(State(state): State<AppState>) -> impl IntoResponse {
+    state.draining.store(true, Ordering::Relaxed);
+    crate::logger::log_info("Draining: new miner connections will be refused");
+    Json(serde_json::json!({ "draining": true }))
+}
+
+async fn handle_undrain(State(state): State<AppState>) -> impl IntoResponse {
+    state.draining.store(false, Ordering::Relaxed);
+    crate::logger::log_info("Drain cancelled: accepting new miner connections");
+    Json(serde_json::json!({ "draining": false }))
+}
+
 async fn handle_websocket(
     ws: WebSocketUpgrade,
     State(_state): State<AppState>,
@@ -400,3 +1563,165 @@ async fn handle_websocket(
 fn get_file_size(path: &str) -> u64 {
     std::fs::metadata(path).map(|m| m.len()).unwrap_or(0)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_state() -> AppState {
+        test_state_with(None)
+    }
+
+    fn test_state_with(database: Option<Arc<Database>>) -> AppState {
+        Arc::new(ApiState {
+            miner_manager: Arc::new(MinerManager::new()),
+            pool_manager: Arc::new(PoolManager::new()),
+            tunnel_manager: Arc::new(TunnelManager::new()),
+            system_metrics: Arc::new(RwLock::new(SystemMetrics::new())),
+            database,
+            config: Arc::new(SharedConfig::new(Config::default(), |_| {})),
+            ban_list: Arc::new(BanList::new(&[]).unwrap()),
+            limiter: Arc::new(ConnectionLimiter::new(None, None, None)),
+            draining: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    /// Serves the API for `state` on a local port and returns its base URL.
+    async fn serve(state: AppState) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, router(state, false, cors_layer(&[]))).await });
+        format!("http://{}", addr)
+    }
+
+    async fn send(request: reqwest::RequestBuilder) -> (StatusCode, serde_json::Value) {
+        let response = request.send().await.unwrap();
+        let status = StatusCode::from_u16(response.status().as_u16()).unwrap();
+        (status, serde_json::from_str(&response.text().await.unwrap()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn metrics_keep_the_original_miner_fields() {
+        let state = test_state();
+        let mut miner = MinerInfo::new("10.0.0.1".to_string(), "1000".to_string(), "Example Pool".to_string());
+        miner.name = "wallet.rig1".to_string();
+        state.miner_manager.add_miner("10.0.0.1:1000".to_string(), miner);
+        state.miner_manager.refresh_snapshots();
+        let base = serve(Arc::clone(&state)).await;
+
+        let (status, body) = send(reqwest::Client::new().get(format!("{}/api/metrics", base))).await;
+        assert_eq!(status, StatusCode::OK);
+        let miner = &body["miners"]["list"][0];
+        assert_eq!(miner["key"], "10.0.0.1:1000");
+        assert_eq!(miner["name"], "wallet.rig1");
+        assert_eq!(miner["miner_name"], "wallet.rig1");
+        assert_eq!(miner["pool"], "Example Pool");
+        assert_eq!(miner["pool_name"], "Example Pool");
+    }
+
+    #[tokio::test]
+    async fn pool_variance_reads_shares_by_display_name() {
+        let dir = std::env::temp_dir().join(format!("tunnel-rust-api-variance-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let db = Database::new(dir.join("data.db").to_str().unwrap(), dir.join("system.db").to_str().unwrap(),
+            std::time::Duration::from_secs(5)).await.unwrap();
+
+        // The default config's pool1 is displayed as "Example Pool"
+        let now = chrono::Utc::now();
+        let shares = (0..3).map(|i| crate::database::ShareRecord {
+            wallet: "wallet".to_string(),
+            miner_name: "wallet.rig1".to_string(),
+            ip: "10.0.0.1".to_string(),
+            pool_name: "Example Pool".to_string(),
+            job_id: i.to_string(),
+            accepted: true,
+            difficulty: 1000.0,
+            submitted_at: now - chrono::Duration::seconds(30 - i * 10),
+        }).collect();
+        db.save_shares_batch(shares).await.unwrap();
+        let base = serve(test_state_with(Some(Arc::new(db)))).await;
+
+        let (status, body) = send(reqwest::Client::new().get(format!("{}/api/pools/pool1/variance", base))).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["pool"], "pool1");
+        assert_eq!(body["shares"], 3);
+        assert_eq!(body["mean_interval_secs"], 10.0);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn pool_up_covers_each_configured_pool_once() {
+        let state = test_state();
+        state.pool_manager.get_or_create("Example Pool").write().await.add_ping_sample(20.0);
+        // Left behind by a pool a reload removed
+        state.pool_manager.get_or_create("Removed Pool").write().await.add_ping_sample(20.0);
+        let base = serve(Arc::clone(&state)).await;
+
+        let body = reqwest::get(format!("{}/metrics", base)).await.unwrap().text().await.unwrap();
+        let pool_up: Vec<&str> = body.lines().filter(|line| line.starts_with("mining_tunnel_pool_up{")).collect();
+        assert_eq!(pool_up, ["mining_tunnel_pool_up{pool=\"Example Pool\"} 1"]);
+    }
+
+    #[tokio::test]
+    async fn disconnect_route_kicks_connected_miners() {
+        let state = test_state();
+        let token = state.miner_manager.add_miner("10.0.0.1:1000".to_string(),
+            MinerInfo::new("10.0.0.1".to_string(), "1000".to_string(), "pool".to_string()));
+        let base = serve(Arc::clone(&state)).await;
+        let client = reqwest::Client::new();
+
+        let (status, body) = send(client.post(format!("{}/api/miners/10.0.0.1:1000/disconnect", base))).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, serde_json::json!({ "disconnected": "10.0.0.1:1000" }));
+        assert!(token.is_cancelled());
+
+        let (status, body) = send(client.post(format!("{}/api/miners/10.0.0.1:1001/disconnect", base))).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["error"]["code"], "miner_not_found");
+    }
+
+    #[tokio::test]
+    async fn ban_routes_add_list_and_remove_ranges() {
+        let state = test_state();
+        let base = serve(Arc::clone(&state)).await;
+        let client = reqwest::Client::new();
+
+        let (status, body) = send(client.post(format!("{}/api/ban/10.0.0.0%2F8", base))).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, serde_json::json!({ "banned": "10.0.0.0/8", "added": true }));
+        assert_eq!(send(client.post(format!("{}/api/ban/10.0.0.0%2F8", base))).await.1["added"], false);
+        assert!(state.ban_list.check("10.1.2.3".parse().unwrap()));
+
+        let (status, body) = send(client.post(format!("{}/api/ban/10.0.0.0%2F40", base))).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"]["code"], "invalid_ip");
+
+        let (_, body) = send(client.get(format!("{}/api/ban", base))).await;
+        assert_eq!(body, serde_json::json!({ "banned": ["10.0.0.0/8"], "rejected_connections": 1 }));
+
+        let (status, body) = send(client.delete(format!("{}/api/ban/10.0.0.0%2F8", base))).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["unbanned"], "10.0.0.0/8");
+        assert!(!state.ban_list.check("10.1.2.3".parse().unwrap()));
+        let (status, body) = send(client.delete(format!("{}/api/ban/10.0.0.0%2F8", base))).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["error"]["code"], "not_banned");
+    }
+
+    #[test]
+    fn keys_match_only_on_equal_keys() {
+        assert!(keys_match("s3cret", "s3cret"));
+        assert!(!keys_match("s3creT", "s3cret"));
+        assert!(!keys_match("s3cre", "s3cret"));
+        assert!(!keys_match("", "s3cret"));
+    }
+
+    #[test]
+    fn query_key_is_percent_decoded() {
+        let uri: axum::http::Uri = "/api/metrics?a=1&key=a%2Bb%26c".parse().unwrap();
+        assert_eq!(query_key(&uri).as_deref(), Some("a+b&c"));
+        assert_eq!(query_key(&"/api/metrics?a=1".parse().unwrap()), None);
+    }
+}
@@ -0,0 +1,123 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use anyhow::{bail, Result};
+use crate::config::{BalanceStrategy, Config, PoolConfig};
+use crate::pool::{PoolConnection, PoolManager};
+
+/// Picks the upstream pool for each new miner connection on a tunnel.
+pub struct Balancer {
+    strategy: BalanceStrategy,
+    pools: Vec<(PoolConfig, u32)>,
+    next: AtomicUsize,
+}
+
+impl Balancer {
+    /// Resolves a tunnel's `pool` setting, which names either a pool or a pool group.
+    pub fn for_tunnel(target: &str, config: &Config) -> Result<Self> {
+        if let Some(pool) = config.pools.get(target) {
+            return Ok(Self {
+                strategy: BalanceStrategy::RoundRobin,
+                pools: vec![(pool.clone(), 1)],
+                next: AtomicUsize::new(0),
+            });
+        }
+
+        let Some(group) = config.pool_groups.get(target) else {
+            bail!("Pool {} not found", target);
+        };
+
+        let mut pools = Vec::new();
+        for member in &group.pools {
+            let pool = config.pools.get(&member.pool)
+                .ok_or_else(|| anyhow::anyhow!("Pool {} in group {} not found", member.pool, target))?;
+            pools.push((pool.clone(), member.weight));
+        }
+        if pools.is_empty() {
+            bail!("Pool group {} has no pools", target);
+        }
+        if group.strategy == BalanceStrategy::Weighted && pools.iter().all(|(_, weight)| *weight == 0) {
+            bail!("Pool group {} has no pool with a non-zero weight", target);
+        }
+
+        Ok(Self {
+            strategy: group.strategy,
+            pools,
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    /// Picks a pool and counts the new connection against it right away, so the next
+    /// least-connections pick sees it; dropping the guard (e.g. when the connect fails)
+    /// releases the slot.
+    pub fn pick(&self, pool_manager: &PoolManager) -> (PoolConfig, PoolConnection) {
+        let index = match self.strategy {
+            BalanceStrategy::RoundRobin => self.next.fetch_add(1, Ordering::Relaxed) % self.pools.len(),
+            BalanceStrategy::Weighted => {
+                let total: u32 = self.pools.iter().map(|(_, weight)| weight).sum();
+                let mut slot = (self.next.fetch_add(1, Ordering::Relaxed) % total as usize) as u32;
+                self.pools.iter()
+                    .position(|(_, weight)| {
+                        if slot < *weight {
+                            return true;
+                        }
+                        slot -= weight;
+                        false
+                    })
+                    .unwrap_or(0)
+            }
+            BalanceStrategy::LeastConnections => self.pools.iter()
+                .enumerate()
+                .min_by_key(|(_, (pool, _))| pool_manager.active_connections(&pool.name))
+                .map(|(i, _)| i)
+                .unwrap_or(0),
+        };
+
+        let pool = self.pools[index].0.clone();
+        let connection = pool_manager.track_connection(&pool.name);
+        (pool, connection)
+    }
+
+    /// Human-readable upstream list for startup logs.
+    pub fn describe(&self) -> String {
+        if let [(pool, _)] = self.pools.as_slice() {
+            return format!("{}:{} ({})", pool.host, pool.port, pool.name);
+        }
+
+        let names: Vec<&str> = self.pools.iter().map(|(pool, _)| pool.name.as_str()).collect();
+        format!("{} ({:?})", names.join(", "), self.strategy)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{PoolGroupConfig, PoolGroupMember};
+
+    fn least_connections_config() -> Config {
+        let mut config = Config::default();
+        let pool = config.pools["pool1"].clone();
+        config.pools.insert("pool2".to_string(), PoolConfig { name: "Second Pool".to_string(), ..pool });
+        config.pool_groups.insert("both".to_string(), PoolGroupConfig {
+            strategy: BalanceStrategy::LeastConnections,
+            pools: ["pool1", "pool2"].iter()
+                .map(|pool| PoolGroupMember { pool: pool.to_string(), weight: 1 })
+                .collect(),
+        });
+        config
+    }
+
+    #[test]
+    fn least_connections_reserves_the_picked_pool() {
+        let balancer = Balancer::for_tunnel("both", &least_connections_config()).unwrap();
+        let pool_manager = PoolManager::new();
+
+        let (first, first_slot) = balancer.pick(&pool_manager);
+        let (second, _second_slot) = balancer.pick(&pool_manager);
+        assert_ne!(first.name, second.name);
+        assert_eq!(pool_manager.active_connections(&first.name), 1);
+
+        // A failed connect drops its slot, so the pool is picked again
+        drop(first_slot);
+        assert_eq!(pool_manager.active_connections(&first.name), 0);
+        assert_eq!(balancer.pick(&pool_manager).0.name, first.name);
+    }
+}
@@ -0,0 +1,176 @@
+use dashmap::DashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// A single address or CIDR range, e.g. `1.2.3.4` or `10.0.0.0/8`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpRange {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl IpRange {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = match (self.network, ip) {
+            (IpAddr::V4(_), IpAddr::V6(v6)) => match v6.to_ipv4_mapped() {
+                Some(v4) => IpAddr::V4(v4),
+                None => return false,
+            },
+            _ => ip,
+        };
+
+        match (self.network, ip) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(addr)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+
+    fn max_prefix(addr: &IpAddr) -> u8 {
+        if addr.is_ipv4() { 32 } else { 128 }
+    }
+}
+
+impl FromStr for IpRange {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+
+        let network: IpAddr = addr.parse()
+            .map_err(|_| anyhow::anyhow!("invalid IP address '{}'", addr))?;
+        let max = Self::max_prefix(&network);
+        let prefix = match prefix {
+            Some(p) => p.parse::<u8>().ok().filter(|p| *p <= max)
+                .ok_or_else(|| anyhow::anyhow!("invalid prefix length '{}' for {}", p, addr))?,
+            None => max,
+        };
+
+        Ok(Self { network, prefix })
+    }
+}
+
+impl fmt::Display for IpRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.prefix == Self::max_prefix(&self.network) {
+            write!(f, "{}", self.network)
+        } else {
+            write!(f, "{}/{}", self.network, self.prefix)
+        }
+    }
+}
+
+/// Banned addresses shared between the tunnels and the API.
+pub struct BanList {
+    ranges: DashMap<String, IpRange>,
+    rejected: AtomicU64,
+}
+
+impl BanList {
+    pub fn new(entries: &[String]) -> anyhow::Result<Self> {
+        let ranges = DashMap::new();
+        for entry in entries {
+            let range: IpRange = entry.parse()?;
+            ranges.insert(range.to_string(), range);
+        }
+
+        Ok(Self {
+            ranges,
+            rejected: AtomicU64::new(0),
+        })
+    }
+
+    /// Returns true if `ip` is banned, counting it as a rejected connection.
+    pub fn check(&self, ip: IpAddr) -> bool {
+        let banned = self.ranges.iter().any(|entry| entry.value().contains(ip));
+        if banned {
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+        }
+        banned
+    }
+
+    pub fn ban(&self, range: IpRange) -> bool {
+        self.ranges.insert(range.to_string(), range).is_none()
+    }
+
+    pub fn unban(&self, range: &IpRange) -> bool {
+        self.ranges.remove(&range.to_string()).is_some()
+    }
+
+    pub fn list(&self) -> Vec<String> {
+        let mut entries: Vec<String> = self.ranges.iter().map(|entry| entry.key().clone()).collect();
+        entries.sort();
+        entries
+    }
+
+    pub fn rejected_count(&self) -> u64 {
+        self.rejected.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_and_displays_ranges() {
+        assert_eq!("1.2.3.4".parse::<IpRange>().unwrap().to_string(), "1.2.3.4");
+        assert_eq!("1.2.3.4/32".parse::<IpRange>().unwrap().to_string(), "1.2.3.4");
+        assert_eq!(" 10.0.0.0/8 ".parse::<IpRange>().unwrap().to_string(), "10.0.0.0/8");
+        assert_eq!("2001:db8::/32".parse::<IpRange>().unwrap().to_string(), "2001:db8::/32");
+        assert!("10.0.0.0/33".parse::<IpRange>().is_err());
+        assert!("2001:db8::/129".parse::<IpRange>().is_err());
+        assert!("10.0.0.0/x".parse::<IpRange>().is_err());
+        assert!("not-an-ip".parse::<IpRange>().is_err());
+    }
+
+    #[test]
+    fn ranges_match_their_addresses() {
+        let range: IpRange = "10.0.0.0/8".parse().unwrap();
+        assert!(range.contains("10.255.0.1".parse().unwrap()));
+        assert!(!range.contains("11.0.0.1".parse().unwrap()));
+        // IPv4 clients accepted on a dual-stack socket arrive IPv4-mapped
+        assert!(range.contains("::ffff:10.1.2.3".parse().unwrap()));
+        assert!(!range.contains("2001:db8::1".parse().unwrap()));
+
+        let any: IpRange = "0.0.0.0/0".parse().unwrap();
+        assert!(any.contains("203.0.113.7".parse().unwrap()));
+
+        let v6: IpRange = "2001:db8::/32".parse().unwrap();
+        assert!(v6.contains("2001:db8:ffff::1".parse().unwrap()));
+        assert!(!v6.contains("2001:db9::1".parse().unwrap()));
+        assert!(!v6.contains("10.0.0.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn ban_list_checks_and_counts() {
+        let bans = BanList::new(&["192.168.1.0/24".to_string()]).unwrap();
+        assert!(bans.check("192.168.1.50".parse().unwrap()));
+        assert!(!bans.check("192.168.2.50".parse().unwrap()));
+        assert_eq!(bans.rejected_count(), 1);
+
+        let single: IpRange = "203.0.113.7".parse().unwrap();
+        assert!(bans.ban(single));
+        assert!(!bans.ban(single));
+        assert_eq!(bans.list(), vec!["192.168.1.0/24", "203.0.113.7"]);
+        assert!(bans.check("203.0.113.7".parse().unwrap()));
+
+        assert!(bans.unban(&single));
+        assert!(!bans.unban(&single));
+        assert!(!bans.check("203.0.113.7".parse().unwrap()));
+
+        assert!(BanList::new(&["192.168.1.0/99".to_string()]).is_err());
+    }
+}
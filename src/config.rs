@@ -1,14 +1,150 @@
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{RwLock, RwLockReadGuard};
 use tokio::fs;
+use tokio::io::AsyncReadExt;
 use colored::Colorize;
+use crate::alert::AlertEvent;
+
+/// Pool latency probe timeout when a pool doesn't set `ping_timeout_secs`
+const DEFAULT_PING_TIMEOUT_SECS: u64 = 5;
+/// How long fetching a config from a URL may take
+const CONFIG_FETCH_TIMEOUT_SECS: u64 = 30;
+/// Pool host written to a freshly created default config; it never resolves to a real pool.
+pub const PLACEHOLDER_POOL_HOST: &str = "pool.example.com";
+/// Stands in for secrets in configs returned by the API; sent back, it keeps the current value.
+pub const REDACTED: &str = "[redacted]";
+/// Top-level settings read on every use, so a reload applies them without a restart
+const LIVE_SETTINGS: [&str; 3] = ["api_key", "hide_unauthorized_miners", "worker_groups"];
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
     pub pools: HashMap<String, PoolConfig>,
+    /// Named sets of pools a tunnel can balance miners across
+    #[serde(default)]
+    pub pool_groups: HashMap<String, PoolGroupConfig>,
     pub tunnels: HashMap<String, TunnelConfig>,
     pub api_port: u16,
+    /// IP the API server listens on; `127.0.0.1` keeps it reachable only from this host
+    #[serde(default = "default_api_bind")]
+    pub api_bind: String,
     pub database: DatabaseConfig,
+    /// When set, `/api/*` routes require `Authorization: Bearer <key>` or `?key=<key>`
+    #[serde(default)]
+    pub api_key: Option<String>,
+    /// Also require the API key on `/metrics` and `/health`
+    #[serde(default)]
+    pub api_key_metrics: bool,
+    /// Browser origins allowed to call the API, e.g. `https://dashboard.example.com`;
+    /// any origin is allowed when empty
+    #[serde(default)]
+    pub allowed_origins: Vec<String>,
+    /// Maximum simultaneous miner connections across all tunnels (unlimited when unset)
+    #[serde(default)]
+    pub max_connections: Option<usize>,
+    /// Maximum simultaneous connections from a single IP (unlimited when unset)
+    #[serde(default)]
+    pub max_conns_per_ip: Option<u32>,
+    /// Maximum new connections per minute from a single IP (unlimited when unset)
+    #[serde(default)]
+    pub conn_rate_per_min: Option<u32>,
+    /// IPs or CIDR ranges refused before the pool handshake
+    #[serde(default)]
+    pub banned_ips: Vec<String>,
+    /// Stratum methods dropped instead of forwarded when a miner sends them
+    #[serde(default)]
+    pub blocked_client_methods: Vec<String>,
+    /// Stratum methods dropped instead of forwarded when a pool sends them, e.g. `client.reconnect`
+    #[serde(default)]
+    pub blocked_pool_methods: Vec<String>,
+    /// Service returning this host's public IP as plain text
+    #[serde(default = "default_public_ip_url")]
+    pub public_ip_url: String,
+    #[serde(default = "default_public_ip_refresh_secs")]
+    pub public_ip_refresh_secs: u64,
+    /// Regexes deriving a group label (e.g. a rig) from worker names, first match wins.
+    /// The `group` capture is the label: `\.(?P<group>[^.]+)-gpu`
+    #[serde(default)]
+    pub worker_groups: Vec<crate::grouping::WorkerPattern>,
+    /// Host network interfaces counted in the NIC throughput metrics, e.g. `eth0`;
+    /// all interfaces (including loopback and bridges) when empty
+    #[serde(default)]
+    pub network_interfaces: Vec<String>,
+    /// How often active miners' hashrates are written to `hashrate_history`
+    #[serde(default = "default_hashrate_snapshot_secs")]
+    pub hashrate_snapshot_secs: u64,
+    /// Shares counted towards a miner's current hashrate, in seconds
+    #[serde(default = "default_hashrate_window_secs")]
+    pub hashrate_window_secs: u64,
+    /// Weight of each new reading in the average hashrate, in (0, 1]; higher follows changes faster
+    #[serde(default = "default_hashrate_ema_alpha")]
+    pub hashrate_ema_alpha: f64,
+    /// How often every pool's latency is probed
+    #[serde(default = "default_ping_interval_secs")]
+    pub ping_interval_secs: u64,
+    /// How often active miners' stats are saved, bounding what a crash can lose
+    #[serde(default = "default_miner_checkpoint_secs")]
+    pub miner_checkpoint_secs: u64,
+    /// Consecutive restarts of a failed tunnel before its port is left closed (0 never restarts)
+    #[serde(default = "default_tunnel_max_restarts")]
+    pub tunnel_max_restarts: u32,
+    /// Connections that haven't sent `mining.authorize` this long after connecting are closed; 0 (the
+    /// default) keeps them
+    #[serde(default)]
+    pub unauthorized_grace_secs: u64,
+    /// A `mining.submit` the pool hasn't answered this long after it was sent counts as lost; 0 never does
+    #[serde(default = "default_submit_timeout_secs")]
+    pub submit_timeout_secs: u64,
+    /// Leave miners without a wallet out of `/api/metrics`
+    #[serde(default)]
+    pub hide_unauthorized_miners: bool,
+    #[serde(default)]
+    pub retention: RetentionConfig,
+    /// Also write logs (without colors) to this file, rotated daily
+    #[serde(default)]
+    pub log_file: Option<String>,
+    /// Number of rotated log files to keep
+    #[serde(default = "default_log_retention")]
+    pub log_retention: usize,
+    /// Emit logs as one JSON object per line
+    #[serde(default)]
+    pub json_logs: bool,
+    #[serde(default)]
+    pub webhooks: Vec<WebhookConfig>,
+    #[serde(default)]
+    pub alerts: AlertConfig,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebhookConfig {
+    pub url: String,
+    /// Events to deliver; all events when empty
+    #[serde(default)]
+    pub events: Vec<AlertEvent>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AlertConfig {
+    /// Alert when a miner's rejected shares exceed this percentage
+    #[serde(default = "default_reject_rate_percent")]
+    pub reject_rate_percent: f64,
+    /// Alert when an authorized miner has no accepted share for this long
+    #[serde(default = "default_no_shares_minutes")]
+    pub no_shares_minutes: u64,
+    /// Minimum time between repeats of the same alert for the same pool or miner
+    #[serde(default = "default_alert_cooldown_secs")]
+    pub cooldown_secs: u64,
+}
+
+impl Default for AlertConfig {
+    fn default() -> Self {
+        Self {
+            reject_rate_percent: default_reject_rate_percent(),
+            no_shares_minutes: default_no_shares_minutes(),
+            cooldown_secs: default_alert_cooldown_secs(),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -16,13 +152,317 @@ pub struct PoolConfig {
     pub host: String,
     pub port: u16,
     pub name: String,
+    /// Current network difficulty; an accepted share at or above it is reported as a possible block
+    #[serde(default)]
+    pub network_difficulty: Option<f64>,
+    /// Scales this pool's difficulty into standard units for hashrate estimates only;
+    /// miners still receive the pool's value, and a tunnel's own difficulty is used as-is
+    #[serde(default = "default_difficulty_multiplier")]
+    pub difficulty_multiplier: f64,
+    /// How long a latency probe may take before it counts as failed (5 seconds when unset)
+    #[serde(default)]
+    pub ping_timeout_secs: Option<u64>,
+    /// Connect to the pool over TLS (`stratum+ssl`)
+    #[serde(default)]
+    pub tls: bool,
+    /// Server name sent and verified during the TLS handshake, when it differs from `host`
+    #[serde(default)]
+    pub tls_sni: Option<String>,
+    /// Accept any certificate, e.g. a pool's self-signed one
+    #[serde(default)]
+    pub tls_insecure: bool,
+}
+
+impl PoolConfig {
+    pub fn ping_timeout(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.ping_timeout_secs.unwrap_or(DEFAULT_PING_TIMEOUT_SECS))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PoolGroupConfig {
+    pub pools: Vec<PoolGroupMember>,
+    #[serde(default)]
+    pub strategy: BalanceStrategy,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PoolGroupMember {
+    pub pool: String,
+    /// Relative share of new miners under the `weighted` strategy
+    #[serde(default = "default_pool_weight")]
+    pub weight: u32,
+}
+
+/// How a pool group picks the upstream for each new miner connection.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BalanceStrategy {
+    #[default]
+    RoundRobin,
+    Weighted,
+    LeastConnections,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TunnelConfig {
     pub ip: String,
     pub port: u16,
+    /// Name of an entry in `pools` or `pool_groups`
     pub pool: String,
+    /// Proxy-side variable difficulty; disabled when unset
+    #[serde(default)]
+    pub vardiff: Option<VardiffConfig>,
+    /// Difficulty sent to miners right after they subscribe, until the pool sets its own;
+    /// unset relays only what the pool sends
+    #[serde(default)]
+    pub initial_difficulty: Option<f64>,
+    /// Relay `client.reconnect` from the pool untouched instead of following it upstream ourselves
+    #[serde(default)]
+    pub reconnect_passthrough: bool,
+    /// Hosts (`host` or `host:port`) besides the pool's own that a `client.reconnect` may send us to
+    #[serde(default)]
+    pub reconnect_allowed_hosts: Vec<String>,
+    /// Expect a HAProxy PROXY protocol (v1 or v2) header carrying the real miner address
+    #[serde(default)]
+    pub proxy_protocol: bool,
+    /// Load balancer addresses or CIDR ranges whose PROXY headers are believed; other peers are
+    /// treated as direct connections. Empty trusts every peer.
+    #[serde(default)]
+    pub proxy_protocol_trusted: Vec<String>,
+    /// Longest Stratum line accepted from either side before the connection is closed
+    #[serde(default = "default_max_line_bytes")]
+    pub max_line_bytes: usize,
+    /// Read buffer per socket; Stratum lines are short, so larger buffers mostly cost memory per miner
+    #[serde(default = "default_read_buffer_bytes")]
+    pub read_buffer_bytes: usize,
+    /// Disable Nagle's algorithm on the miner and pool sockets so small frames such as
+    /// share submits go out at once, at the cost of more (smaller) packets
+    #[serde(default = "default_tcp_nodelay")]
+    pub tcp_nodelay: bool,
+    /// Cap on each direction of a miner connection, to catch floods; unlimited when unset
+    #[serde(default)]
+    pub max_bytes_per_sec: Option<u64>,
+    /// Disconnect miners that send nothing for this many seconds (0 disables)
+    #[serde(default = "default_idle_timeout_secs")]
+    pub idle_timeout_secs: u64,
+    /// Give up on reaching the pool after this many seconds
+    #[serde(default = "default_connect_timeout_secs")]
+    pub connect_timeout_secs: u64,
+    /// Connections the OS queues for this tunnel before they are accepted; capped by
+    /// `net.core.somaxconn` on Linux
+    #[serde(default = "default_listen_backlog")]
+    pub listen_backlog: u32,
+    /// Close miners whose `mining.authorize` the pool refuses instead of leaving them idle
+    #[serde(default)]
+    pub disconnect_unauthorized: bool,
+    /// Send miners turned away by a connection limit a Stratum error saying why before closing
+    #[serde(default)]
+    pub refuse_with_stratum_error: bool,
+    /// Answer resubmitted shares with a duplicate error instead of relaying them to the pool
+    #[serde(default)]
+    pub drop_duplicate_shares: bool,
+    /// Log every raw Stratum line in both directions (rate limited); also `--trace-protocol`
+    #[serde(default)]
+    pub trace_protocol: bool,
+    /// Also write each miner's traced lines to its own file in this directory
+    #[serde(default)]
+    pub trace_dir: Option<String>,
+}
+
+impl TunnelConfig {
+    /// Parses `ip` and `port` into a listen address; IPv6 literals may be bare or bracketed.
+    pub fn bind_addr(&self) -> anyhow::Result<SocketAddr> {
+        let ip = self.ip.trim().trim_start_matches('[').trim_end_matches(']');
+        let ip: IpAddr = ip.parse()
+            .map_err(|_| anyhow::anyhow!("invalid listen IP '{}' (expected an IPv4 or IPv6 address)", self.ip))?;
+        Ok(SocketAddr::new(ip, self.port))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VardiffConfig {
+    /// Desired seconds between shares for each miner
+    pub target_share_secs: f64,
+    pub min_difficulty: f64,
+    pub max_difficulty: f64,
+    #[serde(default = "default_retarget_secs")]
+    pub retarget_secs: u64,
+    /// Changes smaller than this percentage are not sent to the miner
+    #[serde(default = "default_variance_percent")]
+    pub variance_percent: f64,
+}
+
+impl VardiffConfig {
+    /// Settings `vardiff::retarget` can't work with, since clamping to them would panic.
+    fn problems(&self) -> Vec<&'static str> {
+        let mut problems = Vec::new();
+        if !(self.min_difficulty > 0.0 && self.min_difficulty <= self.max_difficulty && self.max_difficulty.is_finite()) {
+            problems.push("need 0 < min_difficulty <= max_difficulty");
+        }
+        if !(self.target_share_secs > 0.0 && self.target_share_secs.is_finite()) {
+            problems.push("target_share_secs must be positive");
+        }
+        if self.retarget_secs == 0 {
+            problems.push("retarget_secs must be nonzero");
+        }
+        problems
+    }
+}
+
+fn default_pool_weight() -> u32 {
+    1
+}
+
+fn default_api_bind() -> String {
+    "0.0.0.0".to_string()
+}
+
+fn default_public_ip_url() -> String {
+    "https://api.ipify.org?format=text".to_string()
+}
+
+fn default_public_ip_refresh_secs() -> u64 {
+    900
+}
+
+fn default_ping_interval_secs() -> u64 {
+    30
+}
+
+fn default_hashrate_snapshot_secs() -> u64 {
+    300
+}
+
+fn default_hashrate_window_secs() -> u64 {
+    600
+}
+
+fn default_hashrate_ema_alpha() -> f64 {
+    0.1
+}
+
+fn default_miner_checkpoint_secs() -> u64 {
+    300
+}
+
+fn default_tunnel_max_restarts() -> u32 {
+    5
+}
+
+fn default_submit_timeout_secs() -> u64 {
+    30
+}
+
+fn default_hashrate_days() -> u32 {
+    30
+}
+
+fn default_shares_days() -> u32 {
+    365
+}
+
+fn default_traffic_days() -> u32 {
+    180
+}
+
+fn default_sessions_days() -> u32 {
+    90
+}
+
+fn default_reject_rate_percent() -> f64 {
+    10.0
+}
+
+fn default_no_shares_minutes() -> u64 {
+    15
+}
+
+fn default_alert_cooldown_secs() -> u64 {
+    300
+}
+
+fn default_log_retention() -> usize {
+    7
+}
+
+fn default_difficulty_multiplier() -> f64 {
+    1.0
+}
+
+fn default_max_line_bytes() -> usize {
+    64 * 1024
+}
+
+fn default_read_buffer_bytes() -> usize {
+    8 * 1024
+}
+
+fn default_tcp_nodelay() -> bool {
+    true
+}
+
+fn default_idle_timeout_secs() -> u64 {
+    600
+}
+
+fn default_connect_timeout_secs() -> u64 {
+    10
+}
+
+fn default_listen_backlog() -> u32 {
+    1024
+}
+
+fn default_data_path() -> String {
+    "./data.db".to_string()
+}
+
+fn default_system_path() -> String {
+    "./system.db".to_string()
+}
+
+fn default_busy_timeout_ms() -> u64 {
+    5000
+}
+
+fn default_wal_checkpoint_secs() -> u64 {
+    300
+}
+
+fn default_retarget_secs() -> u64 {
+    60
+}
+
+fn default_variance_percent() -> f64 {
+    30.0
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RetentionConfig {
+    #[serde(default = "default_hashrate_days")]
+    pub hashrate_days: u32,
+    #[serde(default = "default_shares_days")]
+    pub shares_days: u32,
+    #[serde(default = "default_traffic_days")]
+    pub traffic_days: u32,
+    #[serde(default = "default_sessions_days")]
+    pub sessions_days: u32,
+    /// Run VACUUM on both databases every this many days; it locks them while running (never when unset)
+    #[serde(default)]
+    pub vacuum_days: Option<u32>,
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        Self {
+            hashrate_days: default_hashrate_days(),
+            shares_days: default_shares_days(),
+            traffic_days: default_traffic_days(),
+            sessions_days: default_sessions_days(),
+            vacuum_days: None,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -32,30 +472,426 @@ pub struct DatabaseConfig {
     pub user: String,
     pub password: String,
     pub dbname: String,
+    /// How long SQLite waits on a locked database before failing a write
+    #[serde(default = "default_busy_timeout_ms")]
+    pub busy_timeout_ms: u64,
+    /// SQLite file holding the `miners` table
+    #[serde(default = "default_data_path")]
+    pub data_path: String,
+    /// SQLite file holding shares, traffic and hashrate history
+    #[serde(default = "default_system_path")]
+    pub system_path: String,
+    /// Checkpoint and truncate the WAL files every this many seconds (0 leaves it to SQLite)
+    #[serde(default = "default_wal_checkpoint_secs")]
+    pub wal_checkpoint_secs: u64,
 }
 
 impl Config {
-    pub async fn load_or_create(path: &str) -> anyhow::Result<Self> {
-        if tokio::fs::metadata(path).await.is_ok() {
-            let content = fs::read_to_string(path).await?;
-            Ok(serde_yaml::from_str(&content)?)
-        } else {
+    /// Loads the config from `source`: a file path, `-` for YAML on stdin, or an
+    /// `http(s)://` URL. Only a missing file is replaced by a newly written default.
+    pub async fn load_or_create(source: &str) -> anyhow::Result<Self> {
+        let is_file = source != "-" && !source.starts_with("http://") && !source.starts_with("https://");
+        if is_file && tokio::fs::metadata(source).await.is_err() {
             let config = Self::default();
-            let yaml = serde_yaml::to_string(&config)?;
-            fs::write(path, yaml).await?;
-            println!("{}", "Created default config.yml".bright_yellow());
-            Ok(config)
+            fs::write(source, ConfigFormat::from_path(source)?.serialize(&config)?).await?;
+            crate::logger::console(&format!("Created default {}", source).bright_yellow().to_string());
+            return Ok(config);
+        }
+        Self::load(source).await
+    }
+
+    /// Loads the config from `source` like `load_or_create`, but fails on a missing file.
+    pub async fn load(source: &str) -> anyhow::Result<Self> {
+        let (content, format) = if source == "-" {
+            let mut content = String::new();
+            tokio::io::stdin().read_to_string(&mut content).await?;
+            (content, ConfigFormat::Yaml)
+        } else if source.starts_with("http://") || source.starts_with("https://") {
+            (fetch_config(source).await?, ConfigFormat::from_url(source))
+        } else {
+            let format = ConfigFormat::from_path(source)?;
+            let content = fs::read_to_string(source).await
+                .map_err(|e| anyhow::anyhow!("cannot read {}: {}", source, e))?;
+            (content, format)
+        };
+
+        let mut config = format.parse(&content)?;
+        config.expand_env_vars()?;
+        if let Err(problems) = config.validate() {
+            for problem in &problems {
+                eprintln!("{}", format!("config: {}", problem).red());
+            }
+            anyhow::bail!("{} has {} problem(s)", source, problems.len());
+        }
+        Ok(config)
+    }
+}
+
+async fn fetch_config(url: &str) -> anyhow::Result<String> {
+    let client = reqwest::Client::builder()
+        .timeout(std::time::Duration::from_secs(CONFIG_FETCH_TIMEOUT_SECS))
+        .build()?;
+    let response = client.get(url).send().await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| anyhow::anyhow!("failed to fetch config from {}: {}", url, e))?;
+    Ok(response.text().await?)
+}
+
+/// Config file syntax, chosen by file extension.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ConfigFormat {
+    Yaml,
+    Toml,
+    Json,
+}
+
+impl ConfigFormat {
+    fn from_path(path: &str) -> anyhow::Result<Self> {
+        let extension = std::path::Path::new(path)
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(str::to_ascii_lowercase);
+        match extension.as_deref() {
+            Some("yml") | Some("yaml") => Ok(Self::Yaml),
+            Some("toml") => Ok(Self::Toml),
+            Some("json") => Ok(Self::Json),
+            _ => anyhow::bail!("{}: unsupported config extension (expected .yml, .yaml, .toml or .json)", path),
         }
     }
+
+    /// Like `from_path` on the URL's path, falling back to YAML.
+    fn from_url(url: &str) -> Self {
+        let path = url.split(['?', '#']).next().unwrap_or(url);
+        Self::from_path(path).unwrap_or(Self::Yaml)
+    }
+
+    fn parse(self, content: &str) -> anyhow::Result<Config> {
+        Ok(match self {
+            Self::Yaml => serde_yaml::from_str(content)?,
+            Self::Toml => toml::from_str(content)?,
+            Self::Json => serde_json::from_str(content)?,
+        })
+    }
+
+    fn serialize(self, config: &Config) -> anyhow::Result<String> {
+        Ok(match self {
+            Self::Yaml => serde_yaml::to_string(config)?,
+            Self::Toml => toml::to_string_pretty(config)?,
+            Self::Json => serde_json::to_string_pretty(config)?,
+        })
+    }
+}
+
+impl Config {
+    /// Names of pools still pointing at the default config's placeholder host, sorted.
+    pub fn placeholder_pools(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.pools.iter()
+            .filter(|(_, pool)| pool.host.eq_ignore_ascii_case(PLACEHOLDER_POOL_HOST))
+            .map(|(name, _)| name.as_str())
+            .collect();
+        names.sort();
+        names
+    }
+
+    /// Whether every pool behind `target` (a pool or pool group name) is a placeholder,
+    /// so a tunnel to it could never reach a pool.
+    pub fn targets_only_placeholders(&self, target: &str) -> bool {
+        let placeholders = self.placeholder_pools();
+        match self.pool_groups.get(target) {
+            Some(group) => group.pools.iter().all(|member| placeholders.contains(&member.pool.as_str())),
+            None => placeholders.contains(&target),
+        }
+    }
+
+    /// Replaces `${VAR}` references in secret-bearing fields with values from the environment.
+    pub fn expand_env_vars(&mut self) -> anyhow::Result<()> {
+        self.database.password = expand_env(&self.database.password, "database.password")?;
+        self.database.user = expand_env(&self.database.user, "database.user")?;
+        if let Some(api_key) = &self.api_key {
+            self.api_key = Some(expand_env(api_key, "api_key")?);
+        }
+        for (i, webhook) in self.webhooks.iter_mut().enumerate() {
+            webhook.url = expand_env(&webhook.url, &format!("webhooks[{}].url", i))?;
+        }
+        Ok(())
+    }
+
+    /// A copy with the fields `expand_env_vars` covers replaced by `REDACTED`.
+    pub fn redacted(&self) -> Config {
+        let mut config = self.clone();
+        config.database.password = REDACTED.to_string();
+        config.database.user = REDACTED.to_string();
+        if config.api_key.is_some() {
+            config.api_key = Some(REDACTED.to_string());
+        }
+        for webhook in &mut config.webhooks {
+            webhook.url = REDACTED.to_string();
+        }
+        config
+    }
+
+    /// Puts back the secrets of `current` wherever this config still holds `REDACTED`.
+    /// Webhooks are matched by position.
+    fn restore_redacted(&mut self, current: &Config) {
+        if self.database.password == REDACTED {
+            self.database.password = current.database.password.clone();
+        }
+        if self.database.user == REDACTED {
+            self.database.user = current.database.user.clone();
+        }
+        if self.api_key.as_deref() == Some(REDACTED) {
+            self.api_key = current.api_key.clone();
+        }
+        for (webhook, old) in self.webhooks.iter_mut().zip(&current.webhooks) {
+            if webhook.url == REDACTED {
+                webhook.url = old.url.clone();
+            }
+        }
+    }
+
+    /// Takes the `LIVE_SETTINGS` from `new`.
+    fn apply_live_settings(&mut self, new: Config) {
+        self.api_key = new.api_key;
+        self.hide_unauthorized_miners = new.hide_unauthorized_miners;
+        crate::grouping::set(&new.worker_groups);
+        self.worker_groups = new.worker_groups;
+    }
+
+    /// Top-level settings whose value differs from `other`, sorted.
+    fn changed_settings(&self, other: &Config) -> Vec<String> {
+        let (Ok(serde_json::Value::Object(ours)), Ok(serde_json::Value::Object(theirs))) =
+            (serde_json::to_value(self), serde_json::to_value(other)) else {
+            return Vec::new();
+        };
+        ours.iter()
+            .filter(|(key, value)| theirs.get(*key) != Some(*value))
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+
+    /// Parses `api_bind` and `api_port` into the API listen address.
+    pub fn api_addr(&self) -> anyhow::Result<SocketAddr> {
+        let ip = self.api_bind.trim().trim_start_matches('[').trim_end_matches(']');
+        let ip: IpAddr = ip.parse()
+            .map_err(|_| anyhow::anyhow!("invalid listen IP '{}' (expected an IPv4 or IPv6 address)", self.api_bind))?;
+        Ok(SocketAddr::new(ip, self.api_port))
+    }
+
+    /// Checks references between sections and listen addresses, returning every problem found.
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let mut problems = Vec::new();
+
+        let mut pool_names: Vec<&String> = self.pools.keys().collect();
+        pool_names.sort();
+        // Pool metrics, shares and the API all key pools by display name
+        let mut display_names: HashMap<&str, &String> = HashMap::new();
+        for name in pool_names {
+            let pool = &self.pools[name];
+            if let Some(other) = display_names.insert(&pool.name, name) {
+                problems.push(format!("pool {}: name '{}' is already used by pool {}", name, pool.name, other));
+            }
+            if pool.host.trim().is_empty() {
+                problems.push(format!("pool {}: host is empty", name));
+            }
+            if pool.port == 0 {
+                problems.push(format!("pool {}: port must be nonzero", name));
+            }
+            if !(pool.difficulty_multiplier.is_finite() && pool.difficulty_multiplier > 0.0) {
+                problems.push(format!("pool {}: difficulty_multiplier must be positive", name));
+            }
+            if pool.ping_timeout_secs == Some(0) {
+                problems.push(format!("pool {}: ping_timeout_secs must be nonzero", name));
+            }
+        }
+
+        let mut group_names: Vec<&String> = self.pool_groups.keys().collect();
+        group_names.sort();
+        for name in group_names {
+            let group = &self.pool_groups[name];
+            if group.pools.is_empty() {
+                problems.push(format!("pool group {}: has no pools", name));
+            }
+            for member in &group.pools {
+                if !self.pools.contains_key(&member.pool) {
+                    problems.push(format!("pool group {}: pool '{}' is not defined in pools", name, member.pool));
+                }
+            }
+        }
+
+        let mut tunnel_names: Vec<&String> = self.tunnels.keys().collect();
+        tunnel_names.sort();
+        let mut bound: HashMap<SocketAddr, &String> = HashMap::new();
+        for name in tunnel_names {
+            let tunnel = &self.tunnels[name];
+            if !self.pools.contains_key(&tunnel.pool) && !self.pool_groups.contains_key(&tunnel.pool) {
+                problems.push(format!("tunnel {}: pool '{}' is not defined in pools or pool_groups", name, tunnel.pool));
+            }
+            if tunnel.port == 0 {
+                problems.push(format!("tunnel {}: port must be nonzero", name));
+            }
+            if tunnel.read_buffer_bytes == 0 {
+                problems.push(format!("tunnel {}: read_buffer_bytes must be nonzero", name));
+            }
+            if tunnel.listen_backlog == 0 {
+                problems.push(format!("tunnel {}: listen_backlog must be nonzero", name));
+            }
+            if tunnel.max_bytes_per_sec == Some(0) {
+                problems.push(format!("tunnel {}: max_bytes_per_sec must be nonzero", name));
+            }
+            if tunnel.initial_difficulty.is_some_and(|d| !(d > 0.0 && d.is_finite())) {
+                problems.push(format!("tunnel {}: initial_difficulty must be positive", name));
+            }
+            if let Some(vardiff) = &tunnel.vardiff {
+                for problem in vardiff.problems() {
+                    problems.push(format!("tunnel {}: vardiff: {}", name, problem));
+                }
+            }
+            for range in &tunnel.proxy_protocol_trusted {
+                if let Err(e) = range.parse::<crate::banlist::IpRange>() {
+                    problems.push(format!("tunnel {}: proxy_protocol_trusted: {}", name, e));
+                }
+            }
+            match tunnel.bind_addr() {
+                Ok(addr) => {
+                    if let Some(other) = bound.insert(addr, name) {
+                        problems.push(format!("tunnel {}: {} is already used by tunnel {}", name, addr, other));
+                    }
+                }
+                Err(e) => problems.push(format!("tunnel {}: {}", name, e)),
+            }
+        }
+
+        if let Err(e) = self.api_addr() {
+            problems.push(format!("api_bind: {}", e));
+        }
+
+        if self.ping_interval_secs == 0 {
+            problems.push("ping_interval_secs must be nonzero".to_string());
+        }
+
+        if self.hashrate_window_secs == 0 {
+            problems.push("hashrate_window_secs must be nonzero".to_string());
+        }
+        if !(self.hashrate_ema_alpha > 0.0 && self.hashrate_ema_alpha <= 1.0) {
+            problems.push(format!("hashrate_ema_alpha must be in (0, 1], got {}", self.hashrate_ema_alpha));
+        }
+
+        for origin in &self.allowed_origins {
+            if axum::http::HeaderValue::from_str(origin).is_err() {
+                problems.push(format!("allowed_origins: '{}' is not a valid origin", origin));
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems)
+        }
+    }
+}
+
+/// Expands every `${VAR}` in `value`; `field` names the setting in error messages.
+fn expand_env(value: &str, field: &str) -> anyhow::Result<String> {
+    let mut expanded = String::with_capacity(value.len());
+    let mut rest = value;
+
+    while let Some(start) = rest.find("${") {
+        expanded.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let end = after.find('}')
+            .ok_or_else(|| anyhow::anyhow!("{}: unterminated '${{' in value", field))?;
+        let name = &after[..end];
+        let var = std::env::var(name)
+            .map_err(|_| anyhow::anyhow!("{}: environment variable {} is not set", field, name))?;
+        expanded.push_str(&var);
+        rest = &after[end + 1..];
+    }
+    expanded.push_str(rest);
+
+    Ok(expanded)
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ReloadError {
+    #[error("invalid config: {}", .0.join("; "))]
+    Invalid(Vec<String>),
+    #[error("{0}; restart to apply this change")]
+    RestartRequired(String),
+}
+
+/// The running config. SIGHUP and `PUT /api/config` replace its `LIVE_SETTINGS`; the rest
+/// stays as started until a restart.
+pub struct SharedConfig {
+    current: RwLock<Config>,
+    /// Command-line overrides, applied again to every reloaded config
+    overrides: Box<dyn Fn(&mut Config) + Send + Sync>,
+}
+
+impl SharedConfig {
+    pub fn new(config: Config, overrides: impl Fn(&mut Config) + Send + Sync + 'static) -> Self {
+        Self {
+            current: RwLock::new(config),
+            overrides: Box::new(overrides),
+        }
+    }
+
+    /// The running config; don't hold the guard across an `.await`.
+    pub fn get(&self) -> RwLockReadGuard<'_, Config> {
+        self.current.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Validates `new` and applies its live settings. Changes to listen addresses are
+    /// refused, since sockets are only bound at startup. Returns the other changed
+    /// settings, which are only picked up after a restart.
+    pub fn reload(&self, mut new: Config) -> Result<Vec<String>, ReloadError> {
+        let mut current = self.current.write().unwrap_or_else(|e| e.into_inner());
+
+        new.restore_redacted(&current);
+        new.expand_env_vars().map_err(|e| ReloadError::Invalid(vec![e.to_string()]))?;
+        (self.overrides)(&mut new);
+        new.validate().map_err(ReloadError::Invalid)?;
+
+        if new.api_addr().ok() != current.api_addr().ok() {
+            return Err(ReloadError::RestartRequired(format!("the API would move to {}:{}",
+                new.api_bind, new.api_port)));
+        }
+        let mut names: Vec<&String> = current.tunnels.keys().chain(new.tunnels.keys()).collect();
+        names.sort();
+        names.dedup();
+        for name in names {
+            let old_addr = current.tunnels.get(name).and_then(|t| t.bind_addr().ok());
+            let new_addr = new.tunnels.get(name).and_then(|t| t.bind_addr().ok());
+            if old_addr != new_addr {
+                let change = match (old_addr, new_addr) {
+                    (Some(old), Some(addr)) => format!("tunnel {} would move from {} to {}", name, old, addr),
+                    (None, _) => format!("tunnel {} would be added", name),
+                    (_, None) => format!("tunnel {} would be removed", name),
+                };
+                return Err(ReloadError::RestartRequired(change));
+            }
+        }
+
+        let pending = new.changed_settings(&current).into_iter()
+            .filter(|setting| !LIVE_SETTINGS.contains(&setting.as_str()))
+            .collect();
+        current.apply_live_settings(new);
+        Ok(pending)
+    }
 }
 
 impl Default for Config {
     fn default() -> Self {
         let mut pools = HashMap::new();
         pools.insert("pool1".to_string(), PoolConfig {
-            host: "pool.example.com".to_string(),
+            host: PLACEHOLDER_POOL_HOST.to_string(),
             port: 4444,
             name: "Example Pool".to_string(),
+            network_difficulty: None,
+            difficulty_multiplier: default_difficulty_multiplier(),
+            ping_timeout_secs: None,
+            tls: false,
+            tls_sni: None,
+            tls_insecure: false,
         });
 
         let mut tunnels = HashMap::new();
@@ -63,19 +899,338 @@ impl Default for Config {
             ip: "0.0.0.0".to_string(),
             port: 3333,
             pool: "pool1".to_string(),
+            vardiff: None,
+            initial_difficulty: None,
+            reconnect_passthrough: false,
+            reconnect_allowed_hosts: Vec::new(),
+            proxy_protocol: false,
+            proxy_protocol_trusted: Vec::new(),
+            max_line_bytes: default_max_line_bytes(),
+            read_buffer_bytes: default_read_buffer_bytes(),
+            tcp_nodelay: default_tcp_nodelay(),
+            max_bytes_per_sec: None,
+            idle_timeout_secs: default_idle_timeout_secs(),
+            connect_timeout_secs: default_connect_timeout_secs(),
+            listen_backlog: default_listen_backlog(),
+            disconnect_unauthorized: false,
+            refuse_with_stratum_error: false,
+            drop_duplicate_shares: false,
+            trace_protocol: false,
+            trace_dir: None,
         });
 
         Self {
             pools,
+            pool_groups: HashMap::new(),
             tunnels,
             api_port: 8080,
+            api_bind: default_api_bind(),
             database: DatabaseConfig {
                 host: "localhost".to_string(),
                 port: 3306,
                 user: "root".to_string(),
                 password: "password".to_string(),
                 dbname: "mining_tunnel".to_string(),
+                busy_timeout_ms: default_busy_timeout_ms(),
+                data_path: default_data_path(),
+                system_path: default_system_path(),
+                wal_checkpoint_secs: default_wal_checkpoint_secs(),
             },
+            api_key: None,
+            api_key_metrics: false,
+            allowed_origins: Vec::new(),
+            max_connections: None,
+            max_conns_per_ip: None,
+            conn_rate_per_min: None,
+            banned_ips: Vec::new(),
+            blocked_client_methods: Vec::new(),
+            blocked_pool_methods: Vec::new(),
+            public_ip_url: default_public_ip_url(),
+            public_ip_refresh_secs: default_public_ip_refresh_secs(),
+            worker_groups: Vec::new(),
+            network_interfaces: Vec::new(),
+            hashrate_snapshot_secs: default_hashrate_snapshot_secs(),
+            hashrate_window_secs: default_hashrate_window_secs(),
+            hashrate_ema_alpha: default_hashrate_ema_alpha(),
+            ping_interval_secs: default_ping_interval_secs(),
+            miner_checkpoint_secs: default_miner_checkpoint_secs(),
+            tunnel_max_restarts: default_tunnel_max_restarts(),
+            unauthorized_grace_secs: 0,
+            submit_timeout_secs: default_submit_timeout_secs(),
+            hide_unauthorized_miners: false,
+            retention: RetentionConfig::default(),
+            log_file: None,
+            log_retention: default_log_retention(),
+            json_logs: false,
+            webhooks: Vec::new(),
+            alerts: AlertConfig::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn problems(config: &Config) -> Vec<String> {
+        config.validate().err().unwrap_or_default()
+    }
+
+    #[test]
+    fn default_config_is_valid() {
+        assert!(Config::default().validate().is_ok());
+    }
+
+    #[test]
+    fn detects_untouched_placeholder_pool() {
+        let mut config = Config::default();
+        assert_eq!(config.placeholder_pools(), ["pool1"]);
+        assert!(config.targets_only_placeholders("pool1"));
+
+        let mut real = config.pools["pool1"].clone();
+        real.host = "stratum.real-pool.net".to_string();
+        config.pools.insert("pool2".to_string(), real);
+        config.pool_groups.insert("mixed".to_string(), PoolGroupConfig {
+            pools: ["pool1", "pool2"].iter()
+                .map(|pool| PoolGroupMember { pool: pool.to_string(), weight: 1 })
+                .collect(),
+            strategy: BalanceStrategy::default(),
+        });
+        assert!(!config.targets_only_placeholders("pool2"));
+        assert!(!config.targets_only_placeholders("mixed"));
+    }
+
+    #[test]
+    fn reload_keeps_redacted_secrets_and_refuses_rebinding() {
+        let config = Config {
+            api_key: Some("secret".to_string()),
+            api_bind: "127.0.0.1".to_string(),
+            ..Config::default()
+        };
+        let shared = SharedConfig::new(config, |config: &mut Config| config.api_bind = "127.0.0.1".to_string());
+
+        let mut update = shared.get().redacted();
+        update.hide_unauthorized_miners = !update.hide_unauthorized_miners;
+        update.ping_interval_secs += 1;
+        assert_eq!(shared.reload(update).unwrap(), ["ping_interval_secs"]);
+        assert_eq!(shared.get().api_key.as_deref(), Some("secret"));
+        assert_eq!(shared.get().api_bind, "127.0.0.1");
+        // Applied live, while the rest keeps running as started
+        assert_ne!(shared.get().hide_unauthorized_miners, Config::default().hide_unauthorized_miners);
+        assert_eq!(shared.get().ping_interval_secs, Config::default().ping_interval_secs);
+
+        let mut moved = shared.get().clone();
+        moved.tunnels.get_mut("tunnel1").unwrap().port += 1;
+        assert!(matches!(shared.reload(moved), Err(ReloadError::RestartRequired(_))));
+
+        let invalid = Config { ping_interval_secs: 0, ..shared.get().clone() };
+        assert!(matches!(shared.reload(invalid), Err(ReloadError::Invalid(_))));
+        assert_eq!(shared.get().tunnels["tunnel1"].port, 3333);
+    }
+
+    #[test]
+    fn reload_applies_every_live_setting_and_nothing_else() {
+        let shared = SharedConfig::new(Config::default(), |_| {});
+        let mut update = Config {
+            api_key: Some("new".to_string()),
+            hide_unauthorized_miners: !Config::default().hide_unauthorized_miners,
+            worker_groups: vec![r"\.(?P<group>[^.]+)$".parse().unwrap()],
+            ..Config::default()
+        };
+        update.blocked_client_methods.push("mining.extranonce.subscribe".to_string());
+
+        assert_eq!(shared.reload(update.clone()).unwrap(), ["blocked_client_methods"]);
+        assert_eq!(shared.get().changed_settings(&update), ["blocked_client_methods"]);
+        crate::grouping::set(&[]);
+    }
+
+    #[tokio::test]
+    async fn load_does_not_create_a_missing_file() {
+        let path = std::env::temp_dir().join(format!("tunnel-rust-missing-{}.yml", std::process::id()));
+        let path = path.to_str().unwrap();
+        assert!(Config::load(path).await.is_err());
+        assert!(tokio::fs::metadata(path).await.is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_tunnel_pool() {
+        let mut config = Config::default();
+        config.tunnels.get_mut("tunnel1").unwrap().pool = "missing".to_string();
+        let problems = problems(&config);
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].contains("pool 'missing' is not defined"));
+    }
+
+    #[test]
+    fn rejects_duplicate_listen_address() {
+        let mut config = Config::default();
+        let tunnel = config.tunnels["tunnel1"].clone();
+        config.tunnels.insert("tunnel2".to_string(), tunnel);
+        let problems = problems(&config);
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].contains("already used by tunnel tunnel1"));
+    }
+
+    #[test]
+    fn rejects_zero_ports() {
+        let mut config = Config::default();
+        config.tunnels.get_mut("tunnel1").unwrap().port = 0;
+        config.pools.get_mut("pool1").unwrap().port = 0;
+        let problems = problems(&config);
+        assert_eq!(problems.len(), 2);
+        assert!(problems.iter().any(|p| p.starts_with("tunnel tunnel1: port must be nonzero")));
+        assert!(problems.iter().any(|p| p.starts_with("pool pool1: port must be nonzero")));
+    }
+
+    #[test]
+    fn rejects_empty_pool_host() {
+        let mut config = Config::default();
+        config.pools.get_mut("pool1").unwrap().host = " ".to_string();
+        assert_eq!(problems(&config), vec!["pool pool1: host is empty".to_string()]);
+    }
+
+    #[test]
+    fn url_format_follows_the_path_extension() {
+        assert_eq!(ConfigFormat::from_url("https://cfg.example.com/tunnel.toml?v=2"), ConfigFormat::Toml);
+        assert_eq!(ConfigFormat::from_url("https://cfg.example.com/tunnel.json#x"), ConfigFormat::Json);
+        assert_eq!(ConfigFormat::from_url("https://cfg.example.com/tunnel"), ConfigFormat::Yaml);
+    }
+
+    #[test]
+    fn rejects_invalid_api_bind() {
+        let config = Config { api_bind: "localhost".to_string(), ..Config::default() };
+        let problems = problems(&config);
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].starts_with("api_bind: invalid listen IP"));
+
+        let config = Config { api_bind: "[::1]".to_string(), ..Config::default() };
+        assert_eq!(config.api_addr().unwrap(), "[::1]:8080".parse().unwrap());
+    }
+
+    #[test]
+    fn rejects_zero_ping_settings() {
+        let mut config = Config { ping_interval_secs: 0, ..Config::default() };
+        config.pools.get_mut("pool1").unwrap().ping_timeout_secs = Some(0);
+        assert_eq!(problems(&config), vec![
+            "pool pool1: ping_timeout_secs must be nonzero".to_string(),
+            "ping_interval_secs must be nonzero".to_string(),
+        ]);
+    }
+
+    #[test]
+    fn rejects_out_of_range_hashrate_smoothing() {
+        for alpha in [0.0, 1.5, f64::NAN] {
+            let config = Config { hashrate_window_secs: 0, hashrate_ema_alpha: alpha, ..Config::default() };
+            let problems = problems(&config);
+            assert_eq!(problems.len(), 2);
+            assert_eq!(problems[0], "hashrate_window_secs must be nonzero");
+            assert!(problems[1].starts_with("hashrate_ema_alpha must be in (0, 1]"));
+        }
+        assert!(problems(&Config { hashrate_ema_alpha: 1.0, ..Config::default() }).is_empty());
+    }
+
+    #[test]
+    fn rejects_invalid_listen_ip() {
+        let mut config = Config::default();
+        config.tunnels.get_mut("tunnel1").unwrap().ip = "not-an-ip".to_string();
+        let problems = problems(&config);
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].contains("invalid listen IP"));
+    }
+
+    #[test]
+    fn rejects_duplicate_pool_names() {
+        let mut config = Config::default();
+        let pool = config.pools["pool1"].clone();
+        config.pools.insert("pool2".to_string(), pool);
+        let problems = problems(&config);
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0], "pool pool2: name 'Example Pool' is already used by pool pool1");
+    }
+
+    #[test]
+    fn rejects_invalid_proxy_protocol_trusted() {
+        let mut config = Config::default();
+        config.tunnels.get_mut("tunnel1").unwrap().proxy_protocol_trusted =
+            vec!["10.0.0.0/8".to_string(), "10.0.0.0/33".to_string()];
+        let problems = problems(&config);
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].starts_with("tunnel tunnel1: proxy_protocol_trusted: invalid prefix length"));
+    }
+
+    #[test]
+    fn rejects_unusable_vardiff_settings() {
+        let mut config = Config::default();
+        config.tunnels.get_mut("tunnel1").unwrap().vardiff = Some(VardiffConfig {
+            target_share_secs: 0.0,
+            min_difficulty: 1000.0,
+            max_difficulty: 100.0,
+            retarget_secs: 0,
+            variance_percent: 30.0,
+        });
+        assert_eq!(problems(&config), [
+            "tunnel tunnel1: vardiff: need 0 < min_difficulty <= max_difficulty",
+            "tunnel tunnel1: vardiff: target_share_secs must be positive",
+            "tunnel tunnel1: vardiff: retarget_secs must be nonzero",
+        ]);
+
+        let vardiff = config.tunnels.get_mut("tunnel1").unwrap().vardiff.as_mut().unwrap();
+        (vardiff.target_share_secs, vardiff.min_difficulty, vardiff.max_difficulty, vardiff.retarget_secs) =
+            (15.0, 100.0, 100.0, 60);
+        assert!(problems(&config).is_empty());
+    }
+
+    #[test]
+    fn reports_all_problems_at_once() {
+        let mut config = Config::default();
+        config.tunnels.get_mut("tunnel1").unwrap().pool = "missing".to_string();
+        config.pools.get_mut("pool1").unwrap().host = String::new();
+        assert_eq!(problems(&config).len(), 2);
+    }
+
+    #[test]
+    fn expands_set_env_vars() {
+        std::env::set_var("TUNNEL_TEST_DB_PASSWORD", "hunter2");
+        std::env::set_var("TUNNEL_TEST_API_KEY", "secret");
+        let mut config = Config::default();
+        config.database.password = "${TUNNEL_TEST_DB_PASSWORD}".to_string();
+        config.api_key = Some("key-${TUNNEL_TEST_API_KEY}-1".to_string());
+
+        config.expand_env_vars().unwrap();
+        assert_eq!(config.database.password, "hunter2");
+        assert_eq!(config.api_key.as_deref(), Some("key-secret-1"));
+    }
+
+    #[test]
+    fn fails_on_unset_env_var() {
+        std::env::remove_var("TUNNEL_TEST_UNSET");
+        let mut config = Config {
+            api_key: Some("${TUNNEL_TEST_UNSET}".to_string()),
+            ..Config::default()
+        };
+
+        let err = config.expand_env_vars().unwrap_err().to_string();
+        assert!(err.contains("api_key"));
+        assert!(err.contains("TUNNEL_TEST_UNSET is not set"));
+    }
+
+    #[test]
+    fn leaves_plain_values_alone() {
+        let mut config = Config::default();
+        config.database.password = "pa$$word".to_string();
+        config.expand_env_vars().unwrap();
+        assert_eq!(config.database.password, "pa$$word");
+    }
+
+    #[test]
+    fn default_config_round_trips_in_every_format() {
+        for path in ["config.yml", "config.yaml", "config.toml", "config.json"] {
+            let format = ConfigFormat::from_path(path).unwrap();
+            let text = format.serialize(&Config::default()).unwrap();
+            let parsed = format.parse(&text).unwrap();
+            assert_eq!(parsed.tunnels["tunnel1"].port, 3333, "{}", path);
+            assert_eq!(parsed.pools["pool1"].host, "pool.example.com", "{}", path);
         }
+        assert!(ConfigFormat::from_path("config.ini").is_err());
     }
 }
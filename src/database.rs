@@ -1,23 +1,91 @@
 use sqlx::{SqlitePool, Row};
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqliteRow, SqliteSynchronous};
 use chrono::{DateTime, Utc};
+use futures::stream::BoxStream;
 use anyhow::Result;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+use crate::config::RetentionConfig;
+use crate::logger::{log_debug, log_error, log_info, log_warning};
+
+/// Queued shares are written once this many are pending...
+const SHARE_BATCH_SIZE: usize = 500;
+/// ...or at least this often.
+const SHARE_FLUSH_INTERVAL: Duration = Duration::from_millis(500);
+/// Row counts are reused for this long so frequent scrapes don't scan the tables.
+const ROW_COUNT_CACHE_TTL: Duration = Duration::from_secs(10);
 
 pub struct Database {
     data_pool: SqlitePool,
     system_pool: SqlitePool,
+    data_path: String,
+    system_path: String,
+    share_tx: mpsc::UnboundedSender<ShareRecord>,
+    /// Taken by `spawn_share_writer`
+    share_rx: Mutex<Option<mpsc::UnboundedReceiver<ShareRecord>>>,
+    /// Last `row_counts` result and when it was taken
+    row_counts: Mutex<Option<(Instant, RowCounts)>>,
 }
 
 impl Database {
-    pub async fn new(data_path: &str, system_path: &str) -> Result<Self> {
-        let data_pool = SqlitePool::connect(data_path).await?;
-        let system_pool = SqlitePool::connect(system_path).await?;
+    pub async fn new(data_path: &str, system_path: &str, busy_timeout: Duration) -> Result<Self> {
+        let data_pool = SqlitePool::connect_with(Self::connect_options(data_path, busy_timeout)?).await?;
+        let system_pool = SqlitePool::connect_with(Self::connect_options(system_path, busy_timeout)?).await?;
+        let (share_tx, share_rx) = mpsc::unbounded_channel();
 
-        let db = Self { data_pool, system_pool };
+        let db = Self {
+            data_pool,
+            system_pool,
+            data_path: data_path.to_string(),
+            system_path: system_path.to_string(),
+            share_tx,
+            share_rx: Mutex::new(Some(share_rx)),
+            row_counts: Mutex::new(None),
+        };
         db.create_tables().await?;
         
         Ok(db)
     }
 
+    pub fn data_db_path(&self) -> &str {
+        &self.data_path
+    }
+
+    pub fn system_db_path(&self) -> &str {
+        &self.system_path
+    }
+
+    /// Rows in the `miners` and `shares` tables, cached for `ROW_COUNT_CACHE_TTL`.
+    pub async fn row_counts(&self) -> Result<RowCounts> {
+        if let Some((taken, counts)) = *self.row_counts.lock().unwrap() {
+            if taken.elapsed() < ROW_COUNT_CACHE_TTL {
+                return Ok(counts);
+            }
+        }
+
+        let miners: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM miners")
+            .fetch_one(&self.data_pool).await?;
+        let shares: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM shares")
+            .fetch_one(&self.system_pool).await?;
+        let counts = RowCounts { miners, shares };
+        *self.row_counts.lock().unwrap() = Some((Instant::now(), counts));
+
+        Ok(counts)
+    }
+
+    /// WAL lets readers proceed during writes; the busy timeout makes concurrent
+    /// writers wait for the lock instead of failing with "database is locked".
+    fn connect_options(path: &str, busy_timeout: Duration) -> Result<SqliteConnectOptions> {
+        Ok(SqliteConnectOptions::from_str(path)?
+            .create_if_missing(true)
+            .journal_mode(SqliteJournalMode::Wal)
+            .synchronous(SqliteSynchronous::Normal)
+            .busy_timeout(busy_timeout))
+    }
+
     async fn create_tables(&self) -> Result<()> {
         // Data DB tables
         sqlx::query(r#"
@@ -29,6 +97,8 @@ impl Database {
                 pool_name TEXT,
                 shares_accepted INTEGER DEFAULT 0,
                 shares_rejected INTEGER DEFAULT 0,
+                difficulty_accepted_sum REAL DEFAULT 0,
+                difficulty_rejected_sum REAL DEFAULT 0,
                 bytes_download INTEGER DEFAULT 0,
                 bytes_upload INTEGER DEFAULT 0,
                 packets_sent INTEGER DEFAULT 0,
@@ -37,9 +107,17 @@ impl Database {
                 average_hashrate REAL DEFAULT 0,
                 connected_at DATETIME DEFAULT CURRENT_TIMESTAMP,
                 last_seen DATETIME DEFAULT CURRENT_TIMESTAMP,
+                user_agent TEXT DEFAULT '',
+                handshake_secs REAL,
+                time_to_first_share_secs REAL,
                 UNIQUE(wallet, ip, miner_name)
             )
         "#).execute(&self.data_pool).await?;
+        Self::add_column_if_missing(&self.data_pool, "miners", "user_agent", "TEXT DEFAULT ''").await?;
+        Self::add_column_if_missing(&self.data_pool, "miners", "difficulty_accepted_sum", "REAL DEFAULT 0").await?;
+        Self::add_column_if_missing(&self.data_pool, "miners", "difficulty_rejected_sum", "REAL DEFAULT 0").await?;
+        Self::add_column_if_missing(&self.data_pool, "miners", "handshake_secs", "REAL").await?;
+        Self::add_column_if_missing(&self.data_pool, "miners", "time_to_first_share_secs", "REAL").await?;
 
         sqlx::query("CREATE INDEX IF NOT EXISTS idx_wallet ON miners(wallet)")
             .execute(&self.data_pool).await?;
@@ -47,11 +125,10 @@ impl Database {
             .execute(&self.data_pool).await?;
         sqlx::query("CREATE INDEX IF NOT EXISTS idx_last_seen ON miners(last_seen)")
             .execute(&self.data_pool).await?;
+        sqlx::query("CREATE INDEX IF NOT EXISTS idx_miners_pool ON miners(pool_name)")
+            .execute(&self.data_pool).await?;
 
         // System DB tables
-        sqlx::query("PRAGMA journal_mode=WAL").execute(&self.system_pool).await?;
-        sqlx::query("PRAGMA synchronous=NORMAL").execute(&self.system_pool).await?;
-        
         sqlx::query(r#"
             CREATE TABLE IF NOT EXISTS shares (
                 id INTEGER PRIMARY KEY AUTOINCREMENT,
@@ -87,37 +164,181 @@ impl Database {
         sqlx::query("CREATE INDEX IF NOT EXISTS idx_traffic_timestamp ON network_traffic(timestamp)")
             .execute(&self.system_pool).await?;
 
+        sqlx::query(r#"
+            CREATE TABLE IF NOT EXISTS hashrate_history (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                wallet TEXT NOT NULL,
+                miner_name TEXT,
+                pool_name TEXT,
+                current_hashrate REAL DEFAULT 0,
+                average_hashrate REAL DEFAULT 0,
+                timestamp DATETIME DEFAULT CURRENT_TIMESTAMP
+            )
+        "#).execute(&self.system_pool).await?;
+
+        sqlx::query("CREATE INDEX IF NOT EXISTS idx_hashrate_wallet_time ON hashrate_history(wallet, timestamp)")
+            .execute(&self.system_pool).await?;
+
+        sqlx::query(r#"
+            CREATE TABLE IF NOT EXISTS sessions (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                wallet TEXT NOT NULL,
+                miner_name TEXT,
+                ip TEXT,
+                pool_name TEXT,
+                tunnel_name TEXT,
+                connected_at DATETIME,
+                disconnected_at DATETIME,
+                duration_secs INTEGER DEFAULT 0,
+                reason TEXT
+            )
+        "#).execute(&self.system_pool).await?;
+
+        sqlx::query("CREATE INDEX IF NOT EXISTS idx_sessions_wallet ON sessions(wallet)")
+            .execute(&self.system_pool).await?;
+        sqlx::query("CREATE INDEX IF NOT EXISTS idx_sessions_disconnected ON sessions(disconnected_at)")
+            .execute(&self.system_pool).await?;
+
         Ok(())
     }
 
-    pub async fn save_share(&self, share: ShareRecord) -> Result<()> {
-        sqlx::query(r#"
-            INSERT INTO shares (wallet, miner_name, ip, pool_name, job_id, accepted, difficulty, submitted_at)
-            VALUES (?, ?, ?, ?, ?, ?, ?, ?)
-        "#)
-        .bind(&share.wallet)
-        .bind(&share.miner_name)
-        .bind(&share.ip)
-        .bind(&share.pool_name)
-        .bind(&share.job_id)
-        .bind(if share.accepted { 1 } else { 0 })
-        .bind(share.difficulty)
-        .bind(share.submitted_at.to_rfc3339())
-        .execute(&self.system_pool)
-        .await?;
-        
+    /// Queues a share for the batch writer started by `spawn_share_writer`.
+    pub fn queue_share(&self, share: ShareRecord) {
+        let _ = self.share_tx.send(share);
+    }
+
+    pub async fn save_shares_batch(&self, shares: Vec<ShareRecord>) -> Result<()> {
+        let mut tx = self.system_pool.begin().await?;
+        for share in &shares {
+            sqlx::query(r#"
+                INSERT INTO shares (wallet, miner_name, ip, pool_name, job_id, accepted, difficulty, submitted_at)
+                VALUES (?, ?, ?, ?, ?, ?, ?, ?)
+            "#)
+            .bind(&share.wallet)
+            .bind(&share.miner_name)
+            .bind(&share.ip)
+            .bind(&share.pool_name)
+            .bind(&share.job_id)
+            .bind(if share.accepted { 1 } else { 0 })
+            .bind(share.difficulty)
+            .bind(share.submitted_at.to_rfc3339())
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+
         Ok(())
     }
 
+    /// Starts the task that writes queued shares in batches. Once `shutdown` fires it saves
+    /// everything still queued and exits, so shutdown should cancel it only after the tunnels
+    /// have stopped and then await the handle.
+    pub fn spawn_share_writer(self: &Arc<Self>, shutdown: CancellationToken) -> tokio::task::JoinHandle<()> {
+        let db = Arc::clone(self);
+        let rx = self.share_rx.lock().unwrap().take();
+
+        tokio::spawn(async move {
+            let Some(mut rx) = rx else {
+                return;
+            };
+            let mut interval = tokio::time::interval(SHARE_FLUSH_INTERVAL);
+            let mut batch = Vec::with_capacity(SHARE_BATCH_SIZE);
+
+            loop {
+                let room = SHARE_BATCH_SIZE - batch.len();
+                let stopping = tokio::select! {
+                    _ = interval.tick() => false,
+                    received = rx.recv_many(&mut batch, room) => {
+                        if received > 0 && batch.len() < SHARE_BATCH_SIZE {
+                            continue;
+                        }
+                        received == 0
+                    }
+                    _ = shutdown.cancelled() => true,
+                };
+
+                if !stopping {
+                    if !batch.is_empty() {
+                        db.flush_shares(&mut batch).await;
+                    }
+                    continue;
+                }
+
+                // Drain the queue in batches of the usual size
+                loop {
+                    while batch.len() < SHARE_BATCH_SIZE {
+                        match rx.try_recv() {
+                            Ok(share) => batch.push(share),
+                            Err(_) => break,
+                        }
+                    }
+                    if batch.is_empty() {
+                        break;
+                    }
+                    db.flush_shares(&mut batch).await;
+                }
+                break;
+            }
+        })
+    }
+
+    async fn flush_shares(&self, batch: &mut Vec<ShareRecord>) {
+        let count = batch.len();
+        if let Err(e) = self.save_shares_batch(std::mem::take(batch)).await {
+            log_error(&format!("Failed to save {} shares: {}", count, e));
+        }
+    }
+
+    /// Adds a column introduced after the table was first created; a no-op once present.
+    async fn add_column_if_missing(pool: &SqlitePool, table: &str, column: &str, definition: &str) -> Result<()> {
+        let exists: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM pragma_table_info(?) WHERE name = ?")
+            .bind(table)
+            .bind(column)
+            .fetch_one(pool)
+            .await?;
+        if exists == 0 {
+            sqlx::query(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition))
+                .execute(pool)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Adds what a miner counted since its last save to its row, so it can be saved
+    /// repeatedly during a session. Sessions that never authorized have no wallet and are
+    /// not persisted, since they would all collide on the same `(wallet, ip, miner_name)` row.
     pub async fn save_miner(&self, miner: &crate::miner::MinerInfo) -> Result<()> {
+        let persisted = Arc::clone(&miner.persisted).lock_owned().await;
+        self.save_miner_snapshot(&miner.snapshot(), persisted).await
+    }
+
+    /// Saves a copy of a miner taken while `persisted`, its saved counters, was locked.
+    /// The lock is held until the write lands so concurrent saves can't add the same
+    /// delta twice.
+    pub async fn save_miner_snapshot(
+        &self,
+        miner: &crate::miner::MinerSnapshot,
+        mut persisted: tokio::sync::OwnedMutexGuard<crate::miner::MinerCounters>,
+    ) -> Result<()> {
+        if miner.wallet.is_empty() {
+            return Ok(());
+        }
+
+        let counters = miner.counters();
+        let delta = counters.since(&persisted);
+
         sqlx::query(r#"
             INSERT INTO miners (wallet, miner_name, ip, pool_name, shares_accepted, shares_rejected,
+                difficulty_accepted_sum, difficulty_rejected_sum,
                 bytes_download, bytes_upload, packets_sent, packets_received,
-                current_hashrate, average_hashrate, connected_at, last_seen)
-            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+                current_hashrate, average_hashrate, connected_at, last_seen, user_agent,
+                handshake_secs, time_to_first_share_secs)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
             ON CONFLICT(wallet, ip, miner_name) DO UPDATE SET
                 shares_accepted = shares_accepted + excluded.shares_accepted,
                 shares_rejected = shares_rejected + excluded.shares_rejected,
+                difficulty_accepted_sum = difficulty_accepted_sum + excluded.difficulty_accepted_sum,
+                difficulty_rejected_sum = difficulty_rejected_sum + excluded.difficulty_rejected_sum,
                 bytes_download = bytes_download + excluded.bytes_download,
                 bytes_upload = bytes_upload + excluded.bytes_upload,
                 packets_sent = packets_sent + excluded.packets_sent,
@@ -125,69 +346,347 @@ impl Database {
                 current_hashrate = excluded.current_hashrate,
                 average_hashrate = excluded.average_hashrate,
                 last_seen = excluded.last_seen,
-                pool_name = excluded.pool_name
+                pool_name = excluded.pool_name,
+                user_agent = excluded.user_agent,
+                handshake_secs = COALESCE(excluded.handshake_secs, handshake_secs),
+                time_to_first_share_secs = COALESCE(excluded.time_to_first_share_secs, time_to_first_share_secs)
         "#)
         .bind(&miner.wallet)
         .bind(&miner.name)
         .bind(&miner.ip)
         .bind(&miner.pool_name)
-        .bind(miner.shares_accepted.load(std::sync::atomic::Ordering::Relaxed))
-        .bind(miner.shares_rejected.load(std::sync::atomic::Ordering::Relaxed))
-        .bind(miner.bytes_download.load(std::sync::atomic::Ordering::Relaxed))
-        .bind(miner.bytes_upload.load(std::sync::atomic::Ordering::Relaxed))
-        .bind(miner.packets_sent.load(std::sync::atomic::Ordering::Relaxed))
-        .bind(miner.packets_received.load(std::sync::atomic::Ordering::Relaxed))
+        .bind(delta.shares_accepted)
+        .bind(delta.shares_rejected)
+        .bind(delta.difficulty_accepted)
+        .bind(delta.difficulty_rejected)
+        .bind(delta.bytes_download)
+        .bind(delta.bytes_upload)
+        .bind(delta.packets_sent)
+        .bind(delta.packets_received)
         .bind(miner.current_hashrate)
         .bind(miner.average_hashrate)
-        .bind(miner.connected_at.to_rfc3339())
-        .bind(miner.last_seen.to_rfc3339())
+        .bind(&miner.connected_at)
+        .bind(&miner.last_seen)
+        .bind(&miner.user_agent)
+        .bind(miner.handshake_secs)
+        .bind(miner.time_to_first_share_secs)
         .execute(&self.data_pool)
         .await?;
-        
+
+        *persisted = counters;
+        Ok(())
+    }
+
+    pub async fn save_hashrate_snapshots(&self, snapshots: &[HashrateSnapshot]) -> Result<()> {
+        let mut tx = self.system_pool.begin().await?;
+        for snapshot in snapshots {
+            sqlx::query(r#"
+                INSERT INTO hashrate_history (wallet, miner_name, pool_name, current_hashrate, average_hashrate, timestamp)
+                VALUES (?, ?, ?, ?, ?, ?)
+            "#)
+            .bind(&snapshot.wallet)
+            .bind(&snapshot.miner_name)
+            .bind(&snapshot.pool_name)
+            .bind(snapshot.current_hashrate)
+            .bind(snapshot.average_hashrate)
+            .bind(snapshot.timestamp.to_rfc3339())
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+
         Ok(())
     }
 
+    pub async fn get_hashrate_history(&self, wallet: &str, hours: u32) -> Result<Vec<HashrateSnapshot>> {
+        let since = Utc::now() - chrono::Duration::hours(hours as i64);
+        let rows = sqlx::query(r#"
+            SELECT wallet, miner_name, pool_name, current_hashrate, average_hashrate, timestamp
+            FROM hashrate_history WHERE wallet = ? AND timestamp >= ?
+            ORDER BY timestamp
+        "#)
+        .bind(wallet)
+        .bind(since.to_rfc3339())
+        .fetch_all(&self.system_pool)
+        .await?;
+
+        let mut results = Vec::new();
+        for row in rows {
+            let timestamp: String = row.get("timestamp");
+            results.push(HashrateSnapshot {
+                wallet: row.get("wallet"),
+                miner_name: row.get("miner_name"),
+                pool_name: row.get("pool_name"),
+                current_hashrate: row.get("current_hashrate"),
+                average_hashrate: row.get("average_hashrate"),
+                timestamp: DateTime::parse_from_rfc3339(&timestamp)
+                    .map(|t| t.with_timezone(&Utc))
+                    .unwrap_or_default(),
+            });
+        }
+
+        Ok(results)
+    }
+
+    pub async fn prune_hashrate_history(&self, days: u32) -> Result<u64> {
+        let cutoff = Utc::now() - chrono::Duration::days(days as i64);
+        let result = sqlx::query("DELETE FROM hashrate_history WHERE timestamp < ?")
+            .bind(cutoff.to_rfc3339())
+            .execute(&self.system_pool)
+            .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    /// Records one finished connection in the `sessions` journal.
+    pub async fn log_session(&self, session: &SessionRecord) -> Result<()> {
+        sqlx::query(r#"
+            INSERT INTO sessions (wallet, miner_name, ip, pool_name, tunnel_name,
+                connected_at, disconnected_at, duration_secs, reason)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)
+        "#)
+        .bind(&session.wallet)
+        .bind(&session.miner_name)
+        .bind(&session.ip)
+        .bind(&session.pool_name)
+        .bind(&session.tunnel_name)
+        .bind(session.connected_at.to_rfc3339())
+        .bind(session.disconnected_at.to_rfc3339())
+        .bind(session.duration_secs())
+        .bind(&session.reason)
+        .execute(&self.system_pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// `(submitted_at, difficulty)` of the shares `pool_name` accepted in the last `hours`, oldest first.
+    pub async fn get_share_intervals(&self, pool_name: &str, hours: u32) -> Result<Vec<(DateTime<Utc>, f64)>> {
+        let since = Utc::now() - chrono::Duration::hours(hours as i64);
+        let rows = sqlx::query(r#"
+            SELECT submitted_at, difficulty FROM shares
+            WHERE pool_name = ? AND accepted = 1 AND submitted_at >= ?
+            ORDER BY submitted_at
+        "#)
+        .bind(pool_name)
+        .bind(since.to_rfc3339())
+        .fetch_all(&self.system_pool)
+        .await?;
+
+        Ok(rows.iter().filter_map(|row| {
+            let submitted_at: String = row.get("submitted_at");
+            let submitted_at = DateTime::parse_from_rfc3339(&submitted_at).ok()?.with_timezone(&Utc);
+            Some((submitted_at, row.get("difficulty")))
+        }).collect())
+    }
+
+    /// Sessions that ended in the last `hours`, optionally for one wallet, newest first.
+    pub async fn get_sessions(&self, wallet: Option<&str>, hours: u32, limit: usize) -> Result<Vec<SessionRecord>> {
+        let since = Utc::now() - chrono::Duration::hours(hours as i64);
+        let rows = sqlx::query(r#"
+            SELECT wallet, miner_name, ip, pool_name, tunnel_name, connected_at, disconnected_at, reason
+            FROM sessions
+            WHERE (?1 IS NULL OR wallet = ?1) AND disconnected_at >= ?2
+            ORDER BY disconnected_at DESC
+            LIMIT ?3
+        "#)
+        .bind(wallet)
+        .bind(since.to_rfc3339())
+        .bind(limit as i64)
+        .fetch_all(&self.system_pool)
+        .await?;
+
+        let parse_time = |value: String| DateTime::parse_from_rfc3339(&value)
+            .map(|t| t.with_timezone(&Utc))
+            .unwrap_or_default();
+        Ok(rows.iter().map(|row| SessionRecord {
+            wallet: row.get("wallet"),
+            miner_name: row.get("miner_name"),
+            ip: row.get("ip"),
+            pool_name: row.get("pool_name"),
+            tunnel_name: row.get("tunnel_name"),
+            connected_at: parse_time(row.get("connected_at")),
+            disconnected_at: parse_time(row.get("disconnected_at")),
+            reason: row.get("reason"),
+        }).collect())
+    }
+
     pub async fn get_miner_by_wallet(&self, wallet: &str) -> Result<Vec<MinerRecord>> {
         let rows = sqlx::query(r#"
             SELECT wallet, miner_name, ip, pool_name, shares_accepted, shares_rejected,
+                difficulty_accepted_sum, difficulty_rejected_sum,
                 bytes_download, bytes_upload, packets_sent, packets_received,
-                current_hashrate, average_hashrate, connected_at, last_seen
+                current_hashrate, average_hashrate, connected_at, last_seen, user_agent,
+                handshake_secs, time_to_first_share_secs
             FROM miners WHERE wallet LIKE ?
         "#)
         .bind(format!("{}%", wallet))
         .fetch_all(&self.data_pool)
         .await?;
 
+        Ok(rows.iter().map(MinerRecord::from_row).collect())
+    }
+
+    /// Miners last seen on `pool_name`, most recent first.
+    pub async fn get_miners_by_pool(&self, pool_name: &str, limit: usize) -> Result<Vec<MinerRecord>> {
+        let rows = sqlx::query(r#"
+            SELECT wallet, miner_name, ip, pool_name, shares_accepted, shares_rejected,
+                difficulty_accepted_sum, difficulty_rejected_sum,
+                bytes_download, bytes_upload, packets_sent, packets_received,
+                current_hashrate, average_hashrate, connected_at, last_seen, user_agent,
+                handshake_secs, time_to_first_share_secs
+            FROM miners WHERE pool_name = ?
+            ORDER BY last_seen DESC
+            LIMIT ?
+        "#)
+        .bind(pool_name)
+        .bind(limit as i64)
+        .fetch_all(&self.data_pool)
+        .await?;
+
+        Ok(rows.iter().map(MinerRecord::from_row).collect())
+    }
+
+    /// Totals across every stored worker of `wallet`; `None` if it was never seen.
+    pub async fn get_wallet_summary(&self, wallet: &str) -> Result<Option<WalletSummary>> {
+        let row = sqlx::query(r#"
+            SELECT COUNT(*) AS row_count,
+                COUNT(DISTINCT miner_name) AS workers,
+                MIN(connected_at) AS first_seen,
+                MAX(last_seen) AS last_seen,
+                COALESCE(SUM(shares_accepted), 0) AS shares_accepted,
+                COALESCE(SUM(shares_rejected), 0) AS shares_rejected,
+                COALESCE(SUM(difficulty_accepted_sum), 0) AS difficulty_accepted_sum,
+                COALESCE(SUM(difficulty_rejected_sum), 0) AS difficulty_rejected_sum,
+                COALESCE(SUM(bytes_download), 0) AS bytes_download,
+                COALESCE(SUM(bytes_upload), 0) AS bytes_upload
+            FROM miners WHERE wallet = ?
+        "#)
+        .bind(wallet)
+        .fetch_one(&self.data_pool)
+        .await?;
+
+        let row_count: i64 = row.get("row_count");
+        if row_count == 0 {
+            return Ok(None);
+        }
+
+        Ok(Some(WalletSummary {
+            first_seen: row.get("first_seen"),
+            last_seen: row.get("last_seen"),
+            shares_accepted: row.get("shares_accepted"),
+            shares_rejected: row.get("shares_rejected"),
+            difficulty_accepted_sum: row.get("difficulty_accepted_sum"),
+            difficulty_rejected_sum: row.get("difficulty_rejected_sum"),
+            bytes_download: row.get("bytes_download"),
+            bytes_upload: row.get("bytes_upload"),
+            workers: row.get("workers"),
+        }))
+    }
+
+    /// Most recent shares for a wallet, newest first.
+    pub async fn get_shares_by_wallet(&self, wallet: &str, limit: usize) -> Result<Vec<ShareRecord>> {
+        let rows = sqlx::query(r#"
+            SELECT wallet, miner_name, ip, pool_name, job_id, accepted, difficulty, submitted_at
+            FROM shares WHERE wallet = ?
+            ORDER BY submitted_at DESC
+            LIMIT ?
+        "#)
+        .bind(wallet)
+        .bind(limit as i64)
+        .fetch_all(&self.system_pool)
+        .await?;
+
         let mut results = Vec::new();
         for row in rows {
-            results.push(MinerRecord {
+            let submitted_at: String = row.get("submitted_at");
+            let accepted: i64 = row.get("accepted");
+            results.push(ShareRecord {
                 wallet: row.get("wallet"),
                 miner_name: row.get("miner_name"),
                 ip: row.get("ip"),
                 pool_name: row.get("pool_name"),
-                shares_accepted: row.get("shares_accepted"),
-                shares_rejected: row.get("shares_rejected"),
-                bytes_download: row.get("bytes_download"),
-                bytes_upload: row.get("bytes_upload"),
-                packets_sent: row.get("packets_sent"),
-                packets_received: row.get("packets_received"),
-                current_hashrate: row.get("current_hashrate"),
-                average_hashrate: row.get("average_hashrate"),
-                connected_at: row.get("connected_at"),
-                last_seen: row.get("last_seen"),
+                job_id: row.get("job_id"),
+                accepted: accepted != 0,
+                difficulty: row.get("difficulty"),
+                submitted_at: DateTime::parse_from_rfc3339(&submitted_at)
+                    .map(|t| t.with_timezone(&Utc))
+                    .unwrap_or_default(),
             });
         }
 
         Ok(results)
     }
 
-    pub async fn cleanup_old_data(&self) -> Result<()> {
-        sqlx::query("DELETE FROM shares WHERE submitted_at < datetime('now', '-365 days')")
-            .execute(&self.system_pool).await?;
-        
-        sqlx::query("DELETE FROM network_traffic WHERE timestamp < datetime('now', '-180 days')")
-            .execute(&self.system_pool).await?;
+    /// Streams every row of the `miners` table without loading it into memory.
+    pub fn stream_miners(&self) -> BoxStream<'_, Result<SqliteRow, sqlx::Error>> {
+        sqlx::query(r#"
+            SELECT wallet, miner_name, ip, pool_name, shares_accepted, shares_rejected,
+                bytes_download, bytes_upload, packets_sent, packets_received,
+                current_hashrate, average_hashrate, connected_at, last_seen
+            FROM miners ORDER BY id
+        "#)
+        .fetch(&self.data_pool)
+    }
+
+    /// Streams shares, optionally limited to one wallet and to shares submitted after `since`.
+    pub fn stream_shares(
+        &self,
+        wallet: Option<String>,
+        since: Option<DateTime<Utc>>,
+    ) -> BoxStream<'_, Result<SqliteRow, sqlx::Error>> {
+        let since = since.map(|t| t.to_rfc3339());
+        sqlx::query(r#"
+            SELECT wallet, miner_name, ip, pool_name, job_id, accepted, difficulty, submitted_at
+            FROM shares
+            WHERE (?1 IS NULL OR wallet = ?1) AND (?2 IS NULL OR submitted_at >= ?2)
+            ORDER BY id
+        "#)
+        .bind(wallet)
+        .bind(since)
+        .fetch(&self.system_pool)
+    }
 
+    /// Deletes sessions older than `days`, returning how many were removed.
+    pub async fn prune_sessions(&self, days: u32) -> Result<u64> {
+        let cutoff = Utc::now() - chrono::Duration::days(days as i64);
+        let result = sqlx::query("DELETE FROM sessions WHERE disconnected_at < ?")
+            .bind(cutoff.to_rfc3339())
+            .execute(&self.system_pool)
+            .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    /// Deletes shares and traffic samples older than the given ages, returning how many rows each lost.
+    pub async fn cleanup_old_data(&self, shares_days: u32, traffic_days: u32) -> Result<(u64, u64)> {
+        let cutoff = Utc::now() - chrono::Duration::days(shares_days as i64);
+        let shares = sqlx::query("DELETE FROM shares WHERE submitted_at < ?")
+            .bind(cutoff.to_rfc3339())
+            .execute(&self.system_pool).await?
+            .rows_affected();
+
+        let traffic = sqlx::query("DELETE FROM network_traffic WHERE timestamp < datetime('now', ?)")
+            .bind(format!("-{} days", traffic_days))
+            .execute(&self.system_pool).await?
+            .rows_affected();
+
+        Ok((shares, traffic))
+    }
+
+    /// Copies the WAL of both databases into the main files and truncates it, so the
+    /// `-wal` files don't keep growing under sustained writes. Returns whether a checkpoint
+    /// could not finish because readers or writers were still using the WAL.
+    pub async fn checkpoint(&self) -> Result<bool> {
+        let mut busy = false;
+        for pool in [&self.system_pool, &self.data_pool] {
+            let row = sqlx::query("PRAGMA wal_checkpoint(TRUNCATE)").fetch_one(pool).await?;
+            busy |= row.get::<i64, _>(0) != 0;
+        }
+        Ok(busy)
+    }
+
+    pub async fn vacuum(&self) -> Result<()> {
         sqlx::query("VACUUM").execute(&self.system_pool).await?;
         sqlx::query("VACUUM").execute(&self.data_pool).await?;
 
@@ -195,6 +694,54 @@ impl Database {
     }
 }
 
+/// Checkpoints the WAL files every `interval_secs`; a busy checkpoint is simply retried next time.
+pub async fn run_wal_checkpoints(database: Arc<Database>, interval_secs: u64) {
+    let period = Duration::from_secs(interval_secs.max(1));
+    let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+
+    loop {
+        interval.tick().await;
+        match database.checkpoint().await {
+            Ok(false) => log_debug("WAL checkpoint completed"),
+            Ok(true) => log_warning("WAL checkpoint incomplete, the databases were busy; retrying next interval"),
+            Err(e) => log_error(&format!("WAL checkpoint failed: {}", e)),
+        }
+    }
+}
+
+/// Applies the retention windows once a day, and runs VACUUM on its own schedule when enabled.
+pub async fn run_retention(database: Arc<Database>, retention: RetentionConfig) {
+    let mut cleanup = tokio::time::interval(Duration::from_secs(24 * 60 * 60));
+    let vacuum_period = retention.vacuum_days
+        .map(|days| Duration::from_secs(days.max(1) as u64 * 24 * 60 * 60));
+    let mut vacuum = vacuum_period
+        .map(|period| tokio::time::interval_at(tokio::time::Instant::now() + period, period));
+
+    loop {
+        tokio::select! {
+            _ = cleanup.tick() => {
+                match database.cleanup_old_data(retention.shares_days, retention.traffic_days).await {
+                    Ok((shares, traffic)) => log_info(&format!(
+                        "Retention cleanup removed {} shares (>{}d) and {} traffic samples (>{}d)",
+                        shares, retention.shares_days, traffic, retention.traffic_days)),
+                    Err(e) => log_error(&format!("Retention cleanup failed: {}", e)),
+                }
+                match database.prune_sessions(retention.sessions_days).await {
+                    Ok(sessions) => log_info(&format!("Retention cleanup removed {} sessions (>{}d)",
+                        sessions, retention.sessions_days)),
+                    Err(e) => log_error(&format!("Session cleanup failed: {}", e)),
+                }
+            }
+            _ = async { vacuum.as_mut().unwrap().tick().await }, if vacuum.is_some() => {
+                match database.vacuum().await {
+                    Ok(()) => log_info("Database VACUUM completed"),
+                    Err(e) => log_error(&format!("Database VACUUM failed: {}", e)),
+                }
+            }
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct ShareRecord {
     pub wallet: String,
@@ -207,6 +754,59 @@ pub struct ShareRecord {
     pub submitted_at: DateTime<Utc>,
 }
 
+#[derive(Debug, Clone)]
+pub struct HashrateSnapshot {
+    pub wallet: String,
+    pub miner_name: String,
+    pub pool_name: String,
+    pub current_hashrate: f64,
+    pub average_hashrate: f64,
+    pub timestamp: DateTime<Utc>,
+}
+
+/// One miner connection, from accept to close.
+#[derive(Debug, Clone)]
+pub struct SessionRecord {
+    /// Empty when the miner never authorized
+    pub wallet: String,
+    pub miner_name: String,
+    pub ip: String,
+    pub pool_name: String,
+    pub tunnel_name: String,
+    pub connected_at: DateTime<Utc>,
+    pub disconnected_at: DateTime<Utc>,
+    /// A `DisconnectReason`, e.g. `client_closed` or `kicked`
+    pub reason: String,
+}
+
+impl SessionRecord {
+    pub fn duration_secs(&self) -> i64 {
+        (self.disconnected_at - self.connected_at).num_seconds()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RowCounts {
+    pub miners: i64,
+    pub shares: i64,
+}
+
+#[derive(Debug, Clone)]
+pub struct WalletSummary {
+    /// Earliest `connected_at` of any worker
+    pub first_seen: String,
+    /// Latest `last_seen` of any worker
+    pub last_seen: String,
+    pub shares_accepted: i64,
+    pub shares_rejected: i64,
+    pub difficulty_accepted_sum: f64,
+    pub difficulty_rejected_sum: f64,
+    pub bytes_download: i64,
+    pub bytes_upload: i64,
+    /// Distinct worker names
+    pub workers: i64,
+}
+
 #[derive(Debug, Clone)]
 pub struct MinerRecord {
     pub wallet: String,
@@ -215,6 +815,8 @@ pub struct MinerRecord {
     pub pool_name: String,
     pub shares_accepted: i64,
     pub shares_rejected: i64,
+    pub difficulty_accepted_sum: f64,
+    pub difficulty_rejected_sum: f64,
     pub bytes_download: i64,
     pub bytes_upload: i64,
     pub packets_sent: i64,
@@ -223,4 +825,249 @@ pub struct MinerRecord {
     pub average_hashrate: f64,
     pub connected_at: String,
     pub last_seen: String,
-}
\ No newline at end of file
+    pub user_agent: String,
+    /// Timings of the worker's latest session that got that far
+    pub handshake_secs: Option<f64>,
+    pub time_to_first_share_secs: Option<f64>,
+}
+
+impl MinerRecord {
+    fn from_row(row: &SqliteRow) -> Self {
+        Self {
+            wallet: row.get("wallet"),
+            miner_name: row.get("miner_name"),
+            ip: row.get("ip"),
+            pool_name: row.get("pool_name"),
+            shares_accepted: row.get("shares_accepted"),
+            shares_rejected: row.get("shares_rejected"),
+            difficulty_accepted_sum: row.get("difficulty_accepted_sum"),
+            difficulty_rejected_sum: row.get("difficulty_rejected_sum"),
+            bytes_download: row.get("bytes_download"),
+            bytes_upload: row.get("bytes_upload"),
+            packets_sent: row.get("packets_sent"),
+            packets_received: row.get("packets_received"),
+            current_hashrate: row.get("current_hashrate"),
+            average_hashrate: row.get("average_hashrate"),
+            connected_at: row.get("connected_at"),
+            last_seen: row.get("last_seen"),
+            user_agent: row.get::<Option<String>, _>("user_agent").unwrap_or_default(),
+            handshake_secs: row.get("handshake_secs"),
+            time_to_first_share_secs: row.get("time_to_first_share_secs"),
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::miner::MinerInfo;
+
+    async fn open_test_db(name: &str) -> (Database, Database) {
+        let dir = std::env::temp_dir().join(format!("tunnel-rust-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let data = dir.join("data.db");
+        let system = dir.join("system.db");
+
+        let (data, system) = (data.to_str().unwrap().to_string(), system.to_str().unwrap().to_string());
+        let first = Database::new(&data, &system, Duration::from_secs(5)).await.unwrap();
+        let second = Database::new(&data, &system, Duration::from_secs(5)).await.unwrap();
+        (first, second)
+    }
+
+    async fn save_miners(db: &Database, prefix: &str) {
+        for i in 0..50 {
+            let mut miner = MinerInfo::new("127.0.0.1".to_string(), "4000".to_string(), "test".to_string());
+            miner.wallet = format!("{}{}", prefix, i);
+            db.save_miner(&miner).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn share_writer_saves_the_whole_queue_on_shutdown() {
+        let (db, _) = open_test_db("share-writer").await;
+        let db = Arc::new(db);
+        let shutdown = CancellationToken::new();
+        let writer = db.spawn_share_writer(shutdown.clone());
+
+        let queued = SHARE_BATCH_SIZE * 2 + 17;
+        for i in 0..queued {
+            db.queue_share(ShareRecord {
+                wallet: "wallet".to_string(),
+                miner_name: "wallet.rig1".to_string(),
+                ip: "127.0.0.1".to_string(),
+                pool_name: "test".to_string(),
+                job_id: i.to_string(),
+                accepted: true,
+                difficulty: 1.0,
+                submitted_at: Utc::now(),
+            });
+        }
+        shutdown.cancel();
+        writer.await.unwrap();
+
+        let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM shares")
+            .fetch_one(&db.system_pool).await.unwrap();
+        assert_eq!(count, queued as i64);
+    }
+
+    #[tokio::test]
+    async fn concurrent_writers_wait_for_the_lock() {
+        let (first, second) = open_test_db("concurrent").await;
+
+        let mode: String = sqlx::query_scalar("PRAGMA journal_mode")
+            .fetch_one(&first.data_pool).await.unwrap();
+        assert_eq!(mode, "wal");
+
+        tokio::join!(save_miners(&first, "a"), save_miners(&second, "b"));
+
+        let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM miners")
+            .fetch_one(&first.data_pool).await.unwrap();
+        assert_eq!(count, 100);
+    }
+
+    #[tokio::test]
+    async fn anonymous_sessions_are_not_persisted() {
+        let (db, _) = open_test_db("anonymous").await;
+
+        for port in ["4000", "4001"] {
+            let miner = MinerInfo::new("127.0.0.1".to_string(), port.to_string(), "test".to_string());
+            miner.bytes_download.store(1024, std::sync::atomic::Ordering::Relaxed);
+            db.save_miner(&miner).await.unwrap();
+        }
+
+        let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM miners")
+            .fetch_one(&db.data_pool).await.unwrap();
+        assert_eq!(count, 0);
+    }
+
+    async fn saved_shares(db: &Database, wallet: &str) -> (i64, i64) {
+        sqlx::query_as("SELECT shares_accepted, bytes_download FROM miners WHERE wallet = ?")
+            .bind(wallet)
+            .fetch_one(&db.data_pool).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn checkpoints_save_only_new_counts() {
+        let (db, _) = open_test_db("checkpoint").await;
+        let mut miner = MinerInfo::new("127.0.0.1".to_string(), "4000".to_string(), "test".to_string());
+        miner.wallet = "wallet".to_string();
+
+        miner.shares_accepted.store(10, std::sync::atomic::Ordering::Relaxed);
+        miner.bytes_download.store(1000, std::sync::atomic::Ordering::Relaxed);
+        db.save_miner(&miner).await.unwrap();
+        db.save_miner(&miner).await.unwrap();
+        assert_eq!(saved_shares(&db, "wallet").await, (10, 1000));
+
+        miner.shares_accepted.store(15, std::sync::atomic::Ordering::Relaxed);
+        db.save_miner(&miner).await.unwrap();
+        assert_eq!(saved_shares(&db, "wallet").await, (15, 1000));
+
+        // A reset keeps what was counted but not yet saved
+        miner.shares_accepted.store(20, std::sync::atomic::Ordering::Relaxed);
+        miner.reset_stats().await;
+        miner.shares_accepted.store(3, std::sync::atomic::Ordering::Relaxed);
+        db.save_miner(&miner).await.unwrap();
+        assert_eq!(saved_shares(&db, "wallet").await, (23, 1000));
+    }
+
+    #[tokio::test]
+    async fn difficulty_sums_accumulate_across_saves() {
+        let (db, _) = open_test_db("difficulty").await;
+        let mut miner = MinerInfo::new("127.0.0.1".to_string(), "4000".to_string(), "test".to_string());
+        miner.wallet = "wallet".to_string();
+
+        miner.difficulty_accepted_sum = 4096.0;
+        db.save_miner(&miner).await.unwrap();
+        miner.difficulty_accepted_sum += 8192.0;
+        miner.difficulty_rejected_sum = 512.0;
+        db.save_miner(&miner).await.unwrap();
+
+        let summary = db.get_wallet_summary("wallet").await.unwrap().unwrap();
+        assert_eq!(summary.difficulty_accepted_sum, 12288.0);
+        assert_eq!(summary.difficulty_rejected_sum, 512.0);
+    }
+
+    #[tokio::test]
+    async fn checkpoint_truncates_the_wal() {
+        let (db, _) = open_test_db("wal-checkpoint").await;
+        let mut miner = MinerInfo::new("127.0.0.1".to_string(), "4000".to_string(), "test".to_string());
+        miner.wallet = "wallet".to_string();
+        db.save_miner(&miner).await.unwrap();
+        let wal = format!("{}-wal", db.data_db_path());
+        assert!(std::fs::metadata(&wal).unwrap().len() > 0);
+
+        assert!(!db.checkpoint().await.unwrap());
+        assert_eq!(std::fs::metadata(&wal).unwrap().len(), 0);
+    }
+
+    #[tokio::test]
+    async fn connection_timings_survive_a_session_without_them() {
+        let (db, _) = open_test_db("timings").await;
+        let mut miner = MinerInfo::new("127.0.0.1".to_string(), "4000".to_string(), "test".to_string());
+        miner.wallet = "wallet".to_string();
+        miner.authorized_at = Some(miner.connected_at + chrono::Duration::milliseconds(1500));
+        miner.first_share_at = Some(miner.connected_at + chrono::Duration::seconds(31));
+        db.save_miner(&miner).await.unwrap();
+
+        miner.authorized_at = None;
+        miner.first_share_at = None;
+        db.save_miner(&miner).await.unwrap();
+
+        let record = &db.get_miner_by_wallet("wallet").await.unwrap()[0];
+        assert_eq!(record.handshake_secs, Some(1.5));
+        assert_eq!(record.time_to_first_share_secs, Some(29.5));
+    }
+
+    #[tokio::test]
+    async fn reconnecting_miner_totals_add_up_across_sessions() {
+        let (db, _) = open_test_db("sessions").await;
+        let session = |port: &str| {
+            let mut miner = MinerInfo::new("127.0.0.1".to_string(), port.to_string(), "test".to_string());
+            miner.wallet = "wallet".to_string();
+            miner.name = "rig".to_string();
+            miner
+        };
+
+        let first = session("4000");
+        first.shares_accepted.store(60, std::sync::atomic::Ordering::Relaxed);
+        db.save_miner(&first).await.unwrap();
+        first.shares_accepted.store(100, std::sync::atomic::Ordering::Relaxed);
+        first.bytes_download.store(4096, std::sync::atomic::Ordering::Relaxed);
+        db.save_miner(&first).await.unwrap();
+
+        let second = session("4001");
+        second.shares_accepted.store(50, std::sync::atomic::Ordering::Relaxed);
+        second.bytes_download.store(1024, std::sync::atomic::Ordering::Relaxed);
+        db.save_miner(&second).await.unwrap();
+        db.save_miner(&second).await.unwrap();
+
+        let rows: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM miners")
+            .fetch_one(&db.data_pool).await.unwrap();
+        assert_eq!(rows, 1);
+        assert_eq!(saved_shares(&db, "wallet").await, (150, 5120));
+    }
+
+    #[tokio::test]
+    async fn sessions_are_journaled_per_connection() {
+        let (db, _) = open_test_db("journal").await;
+        let now = Utc::now();
+        let session = |wallet: &str, reason: &str| SessionRecord {
+            wallet: wallet.to_string(),
+            miner_name: "rig".to_string(),
+            ip: "127.0.0.1".to_string(),
+            pool_name: "pool".to_string(),
+            tunnel_name: "tunnel".to_string(),
+            connected_at: now - chrono::Duration::seconds(90),
+            disconnected_at: now,
+            reason: reason.to_string(),
+        };
+        db.log_session(&session("wallet", "client_closed")).await.unwrap();
+        db.log_session(&session("wallet", "kicked")).await.unwrap();
+        db.log_session(&session("other", "timeout")).await.unwrap();
+
+        let sessions = db.get_sessions(Some("wallet"), 1, 10).await.unwrap();
+        assert_eq!(sessions.len(), 2);
+        assert_eq!(sessions[0].duration_secs(), 90);
+        assert_eq!(db.get_sessions(None, 1, 10).await.unwrap().len(), 3);
+    }
+}
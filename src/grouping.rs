@@ -0,0 +1,103 @@
+use std::str::FromStr;
+use std::sync::RwLock;
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+
+/// Caps the memory a compiled pattern may take
+const PATTERN_SIZE_LIMIT: usize = 1 << 20;
+
+/// Patterns from `worker_groups`, in config order; replaced when the config is reloaded.
+static PATTERNS: RwLock<Vec<WorkerPattern>> = RwLock::new(Vec::new());
+
+/// Sets the patterns `group_for` tries. Miners keep the label they authorized with.
+pub fn set(patterns: &[WorkerPattern]) {
+    *PATTERNS.write().unwrap_or_else(|e| e.into_inner()) = patterns.to_vec();
+}
+
+/// The group label of the first pattern matching `worker`, the full authorize username.
+pub fn group_for(worker: &str) -> Option<String> {
+    PATTERNS.read().unwrap_or_else(|e| e.into_inner()).iter().find_map(|pattern| pattern.capture(worker))
+}
+
+/// A `worker_groups` regex such as `\.(?P<group>[^-]+)-gpu`, whose `group` capture is the
+/// label. Compiled once when the config is parsed. Worker names come from miners, but the
+/// regex crate matches in time linear in the name, so no name can make matching backtrack.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct WorkerPattern(Regex);
+
+impl FromStr for WorkerPattern {
+    type Err = anyhow::Error;
+
+    fn from_str(pattern: &str) -> Result<Self, Self::Err> {
+        let regex = RegexBuilder::new(pattern).size_limit(PATTERN_SIZE_LIMIT).build()?;
+        if !regex.capture_names().flatten().any(|name| name == "group") {
+            anyhow::bail!("'{}' needs a (?P<group>...) capture", pattern);
+        }
+        Ok(Self(regex))
+    }
+}
+
+impl TryFrom<String> for WorkerPattern {
+    type Error = anyhow::Error;
+
+    fn try_from(pattern: String) -> Result<Self, Self::Error> {
+        pattern.parse()
+    }
+}
+
+impl From<WorkerPattern> for String {
+    fn from(pattern: WorkerPattern) -> Self {
+        pattern.0.as_str().to_string()
+    }
+}
+
+impl WorkerPattern {
+    /// The non-empty `group` text of the first match in `worker`.
+    pub fn capture(&self, worker: &str) -> Option<String> {
+        let group = self.0.captures(worker)?.name("group")?.as_str();
+        (!group.is_empty()).then(|| group.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn capture(pattern: &str, worker: &str) -> Option<String> {
+        pattern.parse::<WorkerPattern>().unwrap().capture(worker)
+    }
+
+    #[test]
+    fn captures_the_group_label() {
+        assert_eq!(capture(r"\.(?P<group>[^.]+)-gpu", "wallet.rig01-gpu3"), Some("rig01".to_string()));
+        assert_eq!(capture(r"\.(?P<group>[^.]+)$", "wallet.rig01"), Some("rig01".to_string()));
+        assert_eq!(capture(r"^(?<group>[^.]+)\.", "wallet.rig01"), Some("wallet".to_string()));
+        assert_eq!(capture(r"\.(?P<group>[^.]+)-gpu", "wallet.rig01"), None);
+        assert_eq!(capture(r"\.(?P<group>[^.]*)$", "wallet."), None);
+    }
+
+    #[test]
+    fn patterns_need_a_group_capture() {
+        assert!(r"\.rig\d+".parse::<WorkerPattern>().is_err());
+        assert!(r"\.(rig\d+)".parse::<WorkerPattern>().is_err());
+        assert!(r"\.(?P<group>".parse::<WorkerPattern>().is_err());
+    }
+
+    #[test]
+    fn long_names_match_in_linear_time() {
+        // A classic catastrophic-backtracking pattern against a hostile worker name
+        let pattern: WorkerPattern = r"^(?P<group>(a+)+)$".parse().unwrap();
+        let worker = format!("{}b", "a".repeat(100_000));
+        let started = std::time::Instant::now();
+        assert_eq!(pattern.capture(&worker), None);
+        assert!(started.elapsed() < std::time::Duration::from_secs(1));
+    }
+
+    #[test]
+    fn patterns_round_trip_through_the_config() {
+        let patterns: Vec<WorkerPattern> = serde_yaml::from_str(r#"["\\.(?P<group>[^.]+)$"]"#).unwrap();
+        assert_eq!(serde_yaml::to_string(&patterns).unwrap().trim(), r#"- \.(?P<group>[^.]+)$"#);
+        assert!(serde_yaml::from_str::<Vec<WorkerPattern>>(r#"["\\.rig"]"#).is_err());
+    }
+}
@@ -0,0 +1,142 @@
+use dashmap::DashMap;
+use std::collections::VecDeque;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+const PRUNE_THRESHOLD: usize = 1024;
+
+#[derive(Debug, thiserror::Error)]
+pub enum LimitExceeded {
+    #[error("server full ({0} connections max)")]
+    ServerFull(usize),
+    #[error("too many open connections ({0} max per IP)")]
+    TooManyConnections(u32),
+    #[error("connection rate exceeded ({0} per minute max)")]
+    RateExceeded(u32),
+}
+
+#[derive(Debug, Default)]
+struct ClientState {
+    active: u32,
+    recent: VecDeque<Instant>,
+}
+
+impl ClientState {
+    fn is_idle(&self) -> bool {
+        self.active == 0 && self.recent.back().is_none_or(|t| t.elapsed() > RATE_WINDOW)
+    }
+}
+
+/// Global and per-IP connection limits shared by all tunnels. `None` limits are unlimited.
+pub struct ConnectionLimiter {
+    max_connections: Option<usize>,
+    max_conns_per_ip: Option<u32>,
+    conn_rate_per_min: Option<u32>,
+    clients: DashMap<IpAddr, ClientState>,
+    /// Connections holding a permit, across every tunnel
+    active: AtomicUsize,
+}
+
+impl ConnectionLimiter {
+    pub fn new(max_connections: Option<usize>, max_conns_per_ip: Option<u32>, conn_rate_per_min: Option<u32>) -> Self {
+        Self {
+            max_connections,
+            max_conns_per_ip,
+            conn_rate_per_min,
+            clients: DashMap::new(),
+            active: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn active_connections(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    pub fn max_connections(&self) -> Option<usize> {
+        self.max_connections
+    }
+
+    /// Registers a new connection from `ip`, or rejects it if a limit is hit.
+    /// The returned permit releases the connection slot when dropped.
+    pub fn try_acquire(self: &Arc<Self>, ip: IpAddr) -> Result<ConnectionPermit, LimitExceeded> {
+        let reserved = self.active.fetch_update(Ordering::AcqRel, Ordering::Acquire, |active| {
+            match self.max_connections {
+                Some(max) if active >= max => None,
+                _ => Some(active + 1),
+            }
+        });
+        if reserved.is_err() {
+            return Err(LimitExceeded::ServerFull(self.max_connections.unwrap_or_default()));
+        }
+
+        match self.try_acquire_ip(ip) {
+            Ok(per_ip) => Ok(ConnectionPermit { limiter: Arc::clone(self), ip, per_ip }),
+            Err(e) => {
+                self.active.fetch_sub(1, Ordering::AcqRel);
+                Err(e)
+            }
+        }
+    }
+
+    /// Applies the per-IP limits, returning whether `ip` is now tracked.
+    fn try_acquire_ip(&self, ip: IpAddr) -> Result<bool, LimitExceeded> {
+        if self.max_conns_per_ip.is_none() && self.conn_rate_per_min.is_none() {
+            return Ok(false);
+        }
+
+        if self.clients.len() > PRUNE_THRESHOLD {
+            self.clients.retain(|_, state| !state.is_idle());
+        }
+
+        let now = Instant::now();
+        let mut state = self.clients.entry(ip).or_default();
+
+        while state.recent.front().is_some_and(|t| now.duration_since(*t) > RATE_WINDOW) {
+            state.recent.pop_front();
+        }
+
+        if let Some(max) = self.max_conns_per_ip {
+            if state.active >= max {
+                return Err(LimitExceeded::TooManyConnections(max));
+            }
+        }
+
+        if let Some(rate) = self.conn_rate_per_min {
+            if state.recent.len() as u32 >= rate {
+                return Err(LimitExceeded::RateExceeded(rate));
+            }
+        }
+
+        state.active += 1;
+        state.recent.push_back(now);
+
+        Ok(true)
+    }
+
+    fn release(&self, ip: IpAddr, per_ip: bool) {
+        self.active.fetch_sub(1, Ordering::AcqRel);
+        if !per_ip {
+            return;
+        }
+        if let Some(mut state) = self.clients.get_mut(&ip) {
+            state.active = state.active.saturating_sub(1);
+        }
+        self.clients.remove_if(&ip, |_, state| state.is_idle());
+    }
+}
+
+pub struct ConnectionPermit {
+    limiter: Arc<ConnectionLimiter>,
+    ip: IpAddr,
+    /// Whether the connection also counts against the per-IP limits
+    per_ip: bool,
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        self.limiter.release(self.ip, self.per_ip);
+    }
+}
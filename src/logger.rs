@@ -1,47 +1,240 @@
-use chrono::Local;
-use colored::Colorize;
+use chrono::{Local, NaiveDate};
+use colored::{ColoredString, Colorize};
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, OnceLock};
+
+static FILE_SINK: OnceLock<Mutex<FileSink>> = OnceLock::new();
+static JSON_MODE: AtomicBool = AtomicBool::new(false);
+static QUIET: AtomicBool = AtomicBool::new(false);
+static STDERR: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy)]
+pub enum Level {
+    Info,
+    Error,
+    Warn,
+    Share,
+    Block,
+    Debug,
+}
+
+impl Level {
+    fn tag(self) -> &'static str {
+        match self {
+            Level::Info => "[INFO]",
+            Level::Error => "[ERROR]",
+            Level::Warn => "[WARN]",
+            Level::Share => "[SHARE]",
+            Level::Block => "[BLOCK]",
+            Level::Debug => "[DEBUG]",
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Level::Info => "info",
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Share => "share",
+            Level::Block => "block",
+            Level::Debug => "debug",
+        }
+    }
+
+    /// Warnings, errors and blocks still reach the console in quiet mode.
+    fn shown_when_quiet(self) -> bool {
+        matches!(self, Level::Error | Level::Warn | Level::Block)
+    }
+
+    fn colored(self) -> ColoredString {
+        match self {
+            Level::Info => self.tag().green(),
+            Level::Error => self.tag().red(),
+            Level::Warn => self.tag().yellow(),
+            Level::Share => self.tag().bright_purple(),
+            Level::Block => self.tag().bright_yellow().bold(),
+            Level::Debug => self.tag().cyan(),
+        }
+    }
+}
+
+/// Plain-text log file rotated daily to `<path>.<YYYY-MM-DD>`, keeping `retention` old files.
+struct FileSink {
+    path: PathBuf,
+    retention: usize,
+    date: NaiveDate,
+    file: File,
+}
+
+impl FileSink {
+    fn open(path: &Path) -> std::io::Result<File> {
+        OpenOptions::new().create(true).append(true).open(path)
+    }
+
+    fn write(&mut self, line: &str) {
+        let today = Local::now().date_naive();
+        if today != self.date {
+            self.rotate(today);
+        }
+        let _ = writeln!(self.file, "{}", line);
+    }
+
+    fn rotate(&mut self, today: NaiveDate) {
+        let rotated = PathBuf::from(format!("{}.{}", self.path.display(), self.date));
+        if fs::rename(&self.path, &rotated).is_ok() {
+            if let Ok(file) = Self::open(&self.path) {
+                self.file = file;
+            }
+        }
+        self.date = today;
+        self.prune();
+    }
+
+    fn prune(&self) {
+        let Some(name) = self.path.file_name().and_then(|n| n.to_str()) else {
+            return;
+        };
+        let dir = match self.path.parent() {
+            Some(p) if !p.as_os_str().is_empty() => p.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        let prefix = format!("{}.", name);
+
+        let mut rotated: Vec<PathBuf> = fs::read_dir(&dir)
+            .map(|entries| entries.filter_map(|e| e.ok()).map(|e| e.path())
+                .filter(|p| p.file_name().and_then(|n| n.to_str()).is_some_and(|n| n.starts_with(&prefix)))
+                .collect())
+            .unwrap_or_default();
+
+        // Date suffixes sort chronologically, so the oldest files come first
+        rotated.sort();
+        let excess = rotated.len().saturating_sub(self.retention);
+        for old in &rotated[..excess] {
+            let _ = fs::remove_file(old);
+        }
+    }
+}
+
+/// Mirrors every log line, without colors, to `path` in addition to stdout.
+pub fn init_file_logging(path: &str, retention: usize) -> std::io::Result<()> {
+    let path = PathBuf::from(path);
+    let file = FileSink::open(&path)?;
+    let date = file.metadata()
+        .and_then(|m| m.modified())
+        .map(|t| chrono::DateTime::<Local>::from(t).date_naive())
+        .unwrap_or_else(|_| Local::now().date_naive());
+
+    let mut sink = FileSink { path, retention, date, file };
+    let today = Local::now().date_naive();
+    if sink.date != today {
+        sink.rotate(today);
+    }
+
+    let _ = FILE_SINK.set(Mutex::new(sink));
+    Ok(())
+}
+
+/// In quiet mode only warnings, errors and blocks are logged to the console. The log file,
+/// if any, still receives everything.
+pub fn set_quiet(quiet: bool) {
+    QUIET.store(quiet, Ordering::Relaxed);
+}
+
+/// Sends console output to stderr, leaving stdout to machine-readable output.
+pub fn set_stderr(enabled: bool) {
+    STDERR.store(enabled, Ordering::Relaxed);
+}
+
+/// Prints a line to the console: stdout, or stderr after `set_stderr(true)`.
+pub fn console(line: &str) {
+    if STDERR.load(Ordering::Relaxed) {
+        eprintln!("{}", line);
+    } else {
+        println!("{}", line);
+    }
+}
+
+/// Switches all logger output to one JSON object per line.
+pub fn set_json_mode(enabled: bool) {
+    JSON_MODE.store(enabled, Ordering::Relaxed);
+}
+
+pub fn json_mode() -> bool {
+    JSON_MODE.load(Ordering::Relaxed)
+}
+
+/// Logs `message` with extra structured `fields` (a JSON object). The fields are
+/// only emitted in JSON mode; text output shows just the message.
+pub fn log_event(level: Level, message: &str, fields: serde_json::Value) {
+    let now = Local::now();
+    let to_console = !QUIET.load(Ordering::Relaxed) || level.shown_when_quiet();
+
+    let line = if json_mode() {
+        let mut event = serde_json::Map::new();
+        event.insert("level".to_string(), level.name().into());
+        event.insert("timestamp".to_string(), now.to_rfc3339().into());
+        event.insert("message".to_string(), message.into());
+        if let serde_json::Value::Object(fields) = fields {
+            event.extend(fields);
+        }
+        let line = serde_json::Value::Object(event).to_string();
+        if to_console {
+            console(&line);
+        }
+        line
+    } else {
+        let timestamp = now.format("%Y-%m-%d %H:%M:%S");
+        if to_console {
+            console(&format!("{} {} {}",
+                level.colored(),
+                timestamp.to_string().bright_black(),
+                message
+            ));
+        }
+        format!("{} {} {}", level.tag(), timestamp, message)
+    };
+
+    if let Some(sink) = FILE_SINK.get() {
+        if let Ok(mut sink) = sink.lock() {
+            sink.write(&line);
+        }
+    }
+}
 
 pub fn log_info(message: &str) {
-    let timestamp = Local::now().format("%Y-%m-%d %H:%M:%S");
-    println!("{} {} {}",
-        format!("[INFO]").green(),
-        timestamp.to_string().bright_black(),
-        message
-    );
+    log_event(Level::Info, message, serde_json::Value::Null);
 }
 
 pub fn log_error(message: &str) {
-    let timestamp = Local::now().format("%Y-%m-%d %H:%M:%S");
-    println!("{} {} {}",
-        format!("[ERROR]").red(),
-        timestamp.to_string().bright_black(),
-        message
-    );
+    log_event(Level::Error, message, serde_json::Value::Null);
 }
 
 pub fn log_warning(message: &str) {
-    let timestamp = Local::now().format("%Y-%m-%d %H:%M:%S");
-    println!("{} {} {}",
-        format!("[WARN]").yellow(),
-        timestamp.to_string().bright_black(),
-        message
-    );
+    log_event(Level::Warn, message, serde_json::Value::Null);
 }
 
 pub fn log_share(message: &str) {
-    let timestamp = Local::now().format("%Y-%m-%d %H:%M:%S");
-    println!("{} {} {}",
-        format!("[SHARE]").bright_purple(),
-        timestamp.to_string().bright_black(),
-        message
-    );
+    log_event(Level::Share, message, serde_json::Value::Null);
 }
 
 pub fn log_debug(message: &str) {
-    let timestamp = Local::now().format("%Y-%m-%d %H:%M:%S");
-    println!("{} {} {}",
-        format!("[DEBUG]").cyan(),
-        timestamp.to_string().bright_black(),
-        message
-    );
+    log_event(Level::Debug, message, serde_json::Value::Null);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quiet_mode_keeps_problems_on_the_console() {
+        let shown: Vec<&str> = [Level::Info, Level::Error, Level::Warn, Level::Share, Level::Block, Level::Debug]
+            .into_iter()
+            .filter(|level| level.shown_when_quiet())
+            .map(Level::name)
+            .collect();
+        assert_eq!(shown, ["error", "warn", "block"]);
+    }
 }
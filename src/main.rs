@@ -1,7 +1,11 @@
 use clap::Parser;
-use colored::Colorize;
+use colored::{ColoredString, Colorize};
+use std::collections::BTreeMap;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use tokio::sync::RwLock;
+use tokio_util::sync::CancellationToken;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
 
 mod config;
 mod database;
@@ -10,20 +14,54 @@ mod pool;
 mod proxy;
 mod api;
 mod metrics;
+mod limiter;
+mod banlist;
+mod vardiff;
+mod proxy_protocol;
+mod balancer;
+mod tunnel;
+mod upstream;
+mod alert;
+mod grouping;
+mod trace;
+mod throttle;
 mod logger;
 
-use config::Config;
+use config::{Config, SharedConfig};
 use database::Database;
 use miner::MinerManager;
 use pool::PoolManager;
+use tunnel::TunnelManager;
 use metrics::SystemMetrics;
+use limiter::ConnectionLimiter;
+use banlist::BanList;
+use balancer::Balancer;
 
 const VERSION: &str = "3.4";
+const SHUTDOWN_TIMEOUT_SECS: u64 = 10;
+/// First wait before restarting a failed tunnel; doubled per consecutive restart
+const TUNNEL_RESTART_BACKOFF_SECS: u64 = 1;
+const TUNNEL_RESTART_BACKOFF_MAX_SECS: u64 = 60;
+/// A tunnel that ran this long before failing starts over with a fresh restart budget
+const TUNNEL_STABLE_SECS: u64 = 300;
 
 #[derive(Parser, Debug)]
 #[command(name = "tunnel")]
 #[command(about = "Mining Pool Proxy", long_about = None)]
 struct Args {
+    /// Config file; the format follows the extension (.yml, .yaml, .toml or .json).
+    /// `-` reads YAML from stdin and an http(s):// URL is fetched
+    #[arg(long, default_value = "config.yml")]
+    config: String,
+
+    /// SQLite file for miner totals (overrides `database.data_path`)
+    #[arg(long)]
+    data_db: Option<String>,
+
+    /// SQLite file for shares and history (overrides `database.system_path`)
+    #[arg(long)]
+    system_db: Option<String>,
+
     /// Disable database logging
     #[arg(long)]
     nodata: bool,
@@ -32,6 +70,10 @@ struct Args {
     #[arg(long)]
     noapi: bool,
 
+    /// IP the API server listens on (overrides `api_bind`)
+    #[arg(long)]
+    bind: Option<String>,
+
     /// Minimal output mode (single line status)
     #[arg(long)]
     nodebug: bool,
@@ -48,6 +90,19 @@ struct Args {
     #[arg(long, default_value = "key.pem")]
     tlskey: String,
 
+    /// Emit logs as JSON lines
+    #[arg(long)]
+    json_logs: bool,
+
+    /// Log every raw Stratum line on all tunnels (rate limited)
+    #[arg(long)]
+    trace_protocol: bool,
+
+    /// Print a single JSON summary of what started to stdout instead of the status lines;
+    /// logs go to stderr
+    #[arg(long)]
+    startup_json: bool,
+
     /// Show version
     #[arg(long)]
     version: bool,
@@ -57,31 +112,64 @@ struct Args {
 async fn main() -> anyhow::Result<()> {
     let args = Args::parse();
 
-    println!("{}", format!("Tunnel v{} by mytai", VERSION).bright_cyan());
-    println!("{}", "-".repeat(60));
+    logger::set_stderr(args.startup_json);
+    let status_lines = !args.nodebug && !args.startup_json;
 
     if args.version {
+        print_banner();
         println!("Tunnel v{}", VERSION);
         return Ok(());
     }
 
+    // Load configuration
+    let mut config = Config::load_or_create(&args.config).await?;
+    let overrides = cli_overrides(&args);
+    overrides(&mut config);
+    if args.bind.is_some() {
+        // Checked here since the override skips `Config::validate`
+        config.api_addr().map_err(|e| anyhow::anyhow!("--bind: {}", e))?;
+    }
+    let shared_config = Arc::new(SharedConfig::new(config.clone(), overrides));
+
     // Initialize logger
+    let json_logs = args.json_logs || config.json_logs;
+    logger::set_json_mode(json_logs);
+    logger::set_quiet(args.nodebug);
     if !args.nodebug {
-        tracing_subscriber::fmt::init();
+        let writer = if args.startup_json {
+            BoxMakeWriter::new(std::io::stderr)
+        } else {
+            BoxMakeWriter::new(std::io::stdout)
+        };
+        if json_logs {
+            tracing_subscriber::fmt().json().with_writer(writer).init();
+        } else {
+            tracing_subscriber::fmt().with_writer(writer).init();
+        }
     }
 
-    // Load configuration
-    let config = Config::load_or_create("config.yml").await?;
+    if let Some(log_file) = &config.log_file {
+        logger::init_file_logging(log_file, config.log_retention)?;
+    }
 
-    if !args.nodebug {
-        println!("{}", format!("Loaded {} pools", config.pools.len()).green());
+    // JSON logs keep stdout to one object per line, so the banner is left out
+    if !args.startup_json && !json_logs {
+        print_banner();
+    }
+    if status_lines {
+        status(format!("Loaded {} pools", config.pools.len()).green());
+    }
+    let placeholder_pools = config.placeholder_pools();
+    if !placeholder_pools.is_empty() {
+        warn_placeholder_config(&args.config, &placeholder_pools);
     }
 
     // Initialize database
     let database = if !args.nodata {
-        let db = Database::new("./data.db", "./system.db").await?;
-        if !args.nodebug {
-            println!("{}", "Database connected (Pure Rust SQLite)".green());
+        let busy_timeout = std::time::Duration::from_millis(config.database.busy_timeout_ms);
+        let db = Database::new(&config.database.data_path, &config.database.system_path, busy_timeout).await?;
+        if status_lines {
+            status("Database connected (Pure Rust SQLite)".green());
         }
         Some(Arc::new(db))
     } else {
@@ -91,15 +179,75 @@ async fn main() -> anyhow::Result<()> {
     // Initialize managers
     let miner_manager = Arc::new(MinerManager::new());
     let pool_manager = Arc::new(PoolManager::new());
-    let system_metrics = Arc::new(RwLock::new(SystemMetrics::new().await));
+    let tunnel_manager = Arc::new(TunnelManager::new());
+    let system_metrics = Arc::new(RwLock::new(SystemMetrics::new()));
 
     // Start system metrics updater
     let metrics_clone = Arc::clone(&system_metrics);
     let miner_clone = Arc::clone(&miner_manager);
+    let public_ip_url = config.public_ip_url.clone();
+    let public_ip_refresh_secs = config.public_ip_refresh_secs;
+    let network_interfaces = config.network_interfaces.clone();
     tokio::spawn(async move {
-        metrics::update_system_metrics(metrics_clone, miner_clone).await;
+        metrics::update_system_metrics(metrics_clone, miner_clone, public_ip_url, public_ip_refresh_secs, network_interfaces).await;
+    });
+
+    // Start bandwidth rate sampler
+    tokio::spawn(miner::sample_bandwidth(Arc::clone(&miner_manager)));
+
+    // Start API snapshot refresher
+    tokio::spawn(miner::refresh_snapshots(Arc::clone(&miner_manager)));
+
+    // Start hashrate history recorder
+    if let Some(db) = &database {
+        let miner_clone = Arc::clone(&miner_manager);
+        let db_clone = Arc::clone(db);
+        let interval_secs = config.hashrate_snapshot_secs;
+        let retention_days = config.retention.hashrate_days;
+        tokio::spawn(async move {
+            miner::snapshot_hashrates(miner_clone, db_clone, interval_secs, retention_days).await;
+        });
+    }
+
+    // Start periodic miner checkpoints
+    if let Some(db) = &database {
+        tokio::spawn(miner::checkpoint_miners(Arc::clone(&miner_manager), Arc::clone(db), config.miner_checkpoint_secs));
+    }
+
+    // Start unauthorized miner reaper
+    if config.unauthorized_grace_secs > 0 {
+        tokio::spawn(miner::reap_unauthorized_miners(Arc::clone(&miner_manager), config.unauthorized_grace_secs));
+    }
+
+    // Start lost share detection
+    if config.submit_timeout_secs > 0 {
+        tokio::spawn(miner::sweep_lost_submits(Arc::clone(&miner_manager), Arc::clone(&pool_manager),
+            config.submit_timeout_secs));
+    }
+
+    // Start retention cleanup
+    if let Some(db) = &database {
+        tokio::spawn(database::run_retention(Arc::clone(db), config.retention.clone()));
+    }
+
+    // Start WAL checkpoints
+    if let Some(db) = &database {
+        if config.database.wal_checkpoint_secs > 0 {
+            tokio::spawn(database::run_wal_checkpoints(Arc::clone(db), config.database.wal_checkpoint_secs));
+        }
+    }
+
+    grouping::set(&config.worker_groups);
+    proxy::init_blocked_methods(&config.blocked_client_methods, &config.blocked_pool_methods);
+    miner::init_hashrate_settings(miner::HashrateSettings {
+        window_secs: config.hashrate_window_secs,
+        ema_alpha: config.hashrate_ema_alpha,
     });
 
+    // Start webhook alerts
+    alert::init(config.webhooks.clone(), &config.alerts);
+    tokio::spawn(alert::monitor(Arc::clone(&pool_manager), Arc::clone(&miner_manager), config.clone()));
+
     // Start pool ping monitor
     let pool_clone = Arc::clone(&pool_manager);
     let config_clone = config.clone();
@@ -107,80 +255,488 @@ async fn main() -> anyhow::Result<()> {
         pool::monitor_pool_pings(pool_clone, config_clone).await;
     });
 
+    let shutdown = CancellationToken::new();
+    let limiter = Arc::new(ConnectionLimiter::new(
+        config.max_connections, config.max_conns_per_ip, config.conn_rate_per_min));
+    let ban_list = Arc::new(BanList::new(&config.banned_ips)?);
+    let draining = Arc::new(AtomicBool::new(false));
+
+    // SIGUSR1 starts draining: tunnels refuse new miners while existing ones keep mining
+    #[cfg(unix)]
+    {
+        let draining = Arc::clone(&draining);
+        let mut usr1 = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::user_defined1())?;
+        tokio::spawn(async move {
+            while usr1.recv().await.is_some() {
+                draining.store(true, Ordering::Relaxed);
+                logger::log_info("SIGUSR1 received, draining: new miner connections will be refused");
+            }
+        });
+    }
+
+    // SIGHUP reloads the config file, like `PUT /api/config`
+    #[cfg(unix)]
+    {
+        let shared_config = Arc::clone(&shared_config);
+        let source = args.config.clone();
+        let mut hup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?;
+        tokio::spawn(async move {
+            while hup.recv().await.is_some() {
+                if source == "-" {
+                    logger::log_warning("SIGHUP ignored: the config was read from stdin");
+                    continue;
+                }
+                match Config::load(&source).await {
+                    Ok(new) => report_reload(&source, shared_config.reload(new)),
+                    Err(e) => logger::log_error(&format!("SIGHUP: keeping the running config: {:#}", e)),
+                }
+            }
+        });
+    }
+
+    // Start batched share writer
+    // Not a child of `shutdown`: it stops after the tunnels, so their last shares are queued
+    let stop_share_writer = CancellationToken::new();
+    let share_writer = database.as_ref().map(|db| db.spawn_share_writer(stop_share_writer.clone()));
+
     // Start tunnels
+    let mut server_handles = Vec::new();
+    let mut started_tunnels = BTreeMap::new();
+    let mut tunnel_binds = Vec::new();
     for (name, tunnel_config) in &config.tunnels {
-        let pool_config = config.pools.get(&tunnel_config.pool)
-            .ok_or_else(|| anyhow::anyhow!("Pool {} not found", tunnel_config.pool))?;
+        started_tunnels.insert(name.as_str(), false);
+        if config.targets_only_placeholders(&tunnel_config.pool) {
+            status_error(format!("Not starting tunnel {}: pool '{}' is still the {} placeholder",
+                name, tunnel_config.pool, config::PLACEHOLDER_POOL_HOST).red());
+            continue;
+        }
+        let balancer = Arc::new(Balancer::for_tunnel(&tunnel_config.pool, &config)?);
 
         let miner_mgr = Arc::clone(&miner_manager);
         let pool_mgr = Arc::clone(&pool_manager);
+        let tunnel_mgr = Arc::clone(&tunnel_manager);
         let db = database.clone();
         let tname = name.clone();
         let tconfig = tunnel_config.clone();
-        let pconfig = pool_config.clone();
         let tls_enabled = args.tls;
         let cert_file = args.tlscert.clone();
         let key_file = args.tlskey.clone();
-        let nodebug = args.nodebug;
+        let tunnel_limiter = Arc::clone(&limiter);
+        let tunnel_bans = Arc::clone(&ban_list);
+        let tunnel_draining = Arc::clone(&draining);
+        let tunnel_shutdown = shutdown.child_token();
+        // Only the first start reports its bind; restarts come too late for the summary
+        let (bound_tx, bound_rx) = tokio::sync::oneshot::channel();
+        let bound_tx = std::sync::Mutex::new(Some(bound_tx));
+        tunnel_binds.push((name.as_str(), bound_rx));
 
-        tokio::spawn(async move {
-            if let Err(e) = proxy::start_tunnel(
-                &tname,
-                tconfig,
-                pconfig,
-                miner_mgr,
-                pool_mgr,
-                db,
-                tls_enabled,
-                &cert_file,
-                &key_file,
-                nodebug,
-            ).await {
-                eprintln!("{}", format!("Tunnel {} error: {}", tname, e).red());
+        let start = move || {
+            let tname = tname.clone();
+            let tconfig = tconfig.clone();
+            let balancer = Arc::clone(&balancer);
+            let miner_mgr = Arc::clone(&miner_mgr);
+            let pool_mgr = Arc::clone(&pool_mgr);
+            let tunnel_mgr = Arc::clone(&tunnel_mgr);
+            let db = db.clone();
+            let cert_file = cert_file.clone();
+            let key_file = key_file.clone();
+            let tunnel_limiter = Arc::clone(&tunnel_limiter);
+            let tunnel_bans = Arc::clone(&tunnel_bans);
+            let tunnel_draining = Arc::clone(&tunnel_draining);
+            let tunnel_shutdown = tunnel_shutdown.clone();
+            let bound = bound_tx.lock().unwrap_or_else(|e| e.into_inner()).take();
+            async move {
+                proxy::start_tunnel(
+                    &tname,
+                    tconfig,
+                    balancer,
+                    miner_mgr,
+                    pool_mgr,
+                    tunnel_mgr,
+                    db,
+                    tls_enabled,
+                    &cert_file,
+                    &key_file,
+                    tunnel_limiter,
+                    tunnel_bans,
+                    tunnel_draining,
+                    tunnel_shutdown,
+                    bound,
+                ).await
             }
-        });
+        };
+        server_handles.push(tokio::spawn(supervise_tunnel(
+            name.clone(),
+            config.tunnel_max_restarts,
+            shutdown.child_token(),
+            start,
+        )));
     }
 
     // Start API server
+    let mut api_bind = None;
     if !args.noapi {
-        let api_port = config.api_port;
+        let api_config = Arc::clone(&shared_config);
         let miner_mgr = Arc::clone(&miner_manager);
         let pool_mgr = Arc::clone(&pool_manager);
+        let tunnel_mgr = Arc::clone(&tunnel_manager);
         let sys_metrics = Arc::clone(&system_metrics);
         let db = database.clone();
+        let bans = Arc::clone(&ban_list);
+        let api_limiter = Arc::clone(&limiter);
+        let api_draining = Arc::clone(&draining);
+        let api_shutdown = shutdown.child_token();
+        let (bound_tx, bound_rx) = tokio::sync::oneshot::channel();
+        api_bind = Some(bound_rx);
 
-        tokio::spawn(async move {
+        server_handles.push(tokio::spawn(async move {
             if let Err(e) = api::start_api_server(
-                api_port,
+                api_config,
                 miner_mgr,
                 pool_mgr,
+                tunnel_mgr,
                 sys_metrics,
                 db,
+                bans,
+                api_limiter,
+                api_draining,
+                api_shutdown,
+                bound_tx,
             ).await {
-                eprintln!("{}", format!("API server error: {}", e).red());
+                status_error(format!("API server error: {}", e).red());
             }
-        });
+        }));
+    }
 
-        if !args.nodebug {
-            println!("{}", format!("API server running on port {}", config.api_port).green());
+    // Report only what actually bound; a failed bind drops its sender
+    let mut active_tunnels = 0;
+    for (name, bound) in tunnel_binds {
+        if bound.await.is_ok() {
+            active_tunnels += 1;
+            started_tunnels.insert(name, true);
         }
     }
+    let api_started = match api_bind {
+        Some(bound) => bound.await.is_ok(),
+        None => false,
+    };
+    if api_started && status_lines {
+        status(format!("API server listening on {}", config.api_addr()?).green());
+    }
 
-    if !args.nodebug {
-        println!("{}", format!("Tunnel Started").green());
-        println!("{}", format!("Active tunnels: {}", config.tunnels.len()).green());
+    if args.startup_json {
+        println!("{}", startup_summary(&config, &args, &started_tunnels, api_started)?);
+    } else if !args.nodebug {
+        status("Tunnel Started".green());
+        status(format!("Active tunnels: {}", active_tunnels).green());
         if args.tls {
-            println!("{}", "TLS support enabled".green());
+            status("TLS support enabled".green());
         }
         if args.nodata {
-            println!("{}", "Database logging disabled".yellow());
+            status("Database logging disabled".yellow());
+        }
+        if !json_logs {
+            println!("{}", "-".repeat(60));
         }
-        println!("{}", "-".repeat(60));
     }
 
     // Keep running
     tokio::signal::ctrl_c().await?;
-    println!("\n{}", "Shutting down...".yellow());
+    if !json_logs {
+        logger::console("");
+    }
+    status("Shutting down...".yellow());
+
+    shutdown.cancel();
+    let timeout = tokio::time::Duration::from_secs(SHUTDOWN_TIMEOUT_SECS);
+    // Miners first, each step with its own timeout, so a listener slow to stop can't use
+    // up the time for saving them
+    if tokio::time::timeout(timeout, save_miners_on_shutdown(&miner_manager, database.as_deref()))
+        .await
+        .is_err()
+    {
+        status_error(format!("Saving miners timed out after {}s, some miner data may be lost",
+            SHUTDOWN_TIMEOUT_SECS).red());
+    }
+    if tokio::time::timeout(timeout, futures::future::join_all(server_handles)).await.is_err() {
+        status_error(format!("Tunnels and the API server did not stop within {}s", SHUTDOWN_TIMEOUT_SECS).red());
+    }
+
+    // Outside the timeout: queued shares are only lost if the writer is cut off
+    stop_share_writer.cancel();
+    if let Some(share_writer) = share_writer {
+        let _ = share_writer.await;
+    }
 
     Ok(())
 }
+
+/// Command-line flags that override config settings, applied to the loaded config and
+/// again whenever it is reloaded.
+fn cli_overrides(args: &Args) -> impl Fn(&mut Config) + Send + Sync + 'static {
+    let data_db = args.data_db.clone();
+    let system_db = args.system_db.clone();
+    let trace_protocol = args.trace_protocol;
+    let bind = args.bind.clone();
+    move |config| {
+        if let Some(path) = &data_db {
+            config.database.data_path = path.clone();
+        }
+        if let Some(path) = &system_db {
+            config.database.system_path = path.clone();
+        }
+        if trace_protocol {
+            for tunnel in config.tunnels.values_mut() {
+                tunnel.trace_protocol = true;
+            }
+        }
+        if let Some(bind) = &bind {
+            config.api_bind = bind.clone();
+        }
+    }
+}
+
+fn report_reload(source: &str, result: Result<Vec<String>, config::ReloadError>) {
+    match result {
+        Ok(pending) if pending.is_empty() => logger::log_info(&format!("Reloaded config from {}", source)),
+        Ok(pending) => logger::log_warning(&format!("Reloaded config from {}; restart to apply changes to {}",
+            source, pending.join(", "))),
+        Err(e) => logger::log_error(&format!("Config from {} not applied: {}", source, e)),
+    }
+}
+
+/// What `--startup-json` prints: the effective pools, tunnels and their bind addresses,
+/// which of the API, TLS and database are enabled, and which listeners bound.
+fn startup_summary(config: &Config, args: &Args, started_tunnels: &BTreeMap<&str, bool>, api_started: bool)
+    -> anyhow::Result<serde_json::Value> {
+    let pools: serde_json::Map<_, _> = config.pools.iter()
+        .map(|(id, pool)| (id.clone(), serde_json::json!({
+            "name": pool.name,
+            "host": pool.host,
+            "port": pool.port,
+        })))
+        .collect();
+
+    let mut tunnels = serde_json::Map::new();
+    for (name, tunnel) in &config.tunnels {
+        tunnels.insert(name.clone(), serde_json::json!({
+            "bind": tunnel.bind_addr()?.to_string(),
+            "pool": tunnel.pool,
+            "started": started_tunnels.get(name.as_str()).copied().unwrap_or(false),
+        }));
+    }
+
+    let api = if args.noapi {
+        serde_json::json!({ "enabled": false })
+    } else {
+        serde_json::json!({
+            "enabled": true,
+            "bind": config.api_addr()?.to_string(),
+            "port": config.api_port,
+            "started": api_started,
+        })
+    };
+
+    let database = if args.nodata {
+        serde_json::json!({ "enabled": false })
+    } else {
+        serde_json::json!({
+            "enabled": true,
+            "data_path": config.database.data_path,
+            "system_path": config.database.system_path,
+        })
+    };
+
+    Ok(serde_json::json!({
+        "version": VERSION,
+        "config": args.config,
+        "pools": pools,
+        "tunnels": tunnels,
+        "api": api,
+        "tls": args.tls,
+        "database": database,
+    }))
+}
+
+fn print_banner() {
+    println!("{}", format!("Tunnel v{} by mytai", VERSION).bright_cyan());
+    println!("{}", "-".repeat(60));
+}
+
+/// Prints a status line, or logs it as an info event in JSON mode so the console stays
+/// one JSON object per line.
+fn status(line: ColoredString) {
+    if logger::json_mode() {
+        logger::log_info(&line);
+    } else {
+        logger::console(&line.to_string());
+    }
+}
+
+/// Like `status`, for failures: stderr in text mode, an error event in JSON mode.
+fn status_error(line: ColoredString) {
+    if logger::json_mode() {
+        logger::log_error(&line);
+    } else {
+        eprintln!("{}", line);
+    }
+}
+
+/// Explains how to finish setting up a config that still has the generated placeholder pool.
+fn warn_placeholder_config(source: &str, pools: &[&str]) {
+    if logger::json_mode() {
+        logger::log_warning(&format!("{} still points pool(s) {} at the placeholder host {}; tunnels using \
+            only placeholder pools will not be started", source, pools.join(", "), config::PLACEHOLDER_POOL_HOST));
+        return;
+    }
+    let rule = "!".repeat(60);
+    eprintln!("{}", rule.bright_yellow());
+    eprintln!("{}", format!("{} still points pool(s) {} at the placeholder host {}",
+        source, pools.join(", "), config::PLACEHOLDER_POOL_HOST).bright_yellow().bold());
+    eprintln!("{}", "Tunnels using only placeholder pools will not be started. To finish setup:".bright_yellow());
+    eprintln!("{}", format!("  1. Edit {} and set host/port of each pool to your pool's stratum address", source).bright_yellow());
+    eprintln!("{}", "  2. Point each tunnel's ip/port at the address your miners should connect to".bright_yellow());
+    eprintln!("{}", "  3. Restart tunnel-rust".bright_yellow());
+    eprintln!("{}", rule.bright_yellow());
+}
+
+/// Runs a tunnel, restarting it with a growing backoff when it fails or panics, until
+/// `max_restarts` consecutive restarts are used up or shutdown begins.
+async fn supervise_tunnel<F, Fut>(name: String, max_restarts: u32, shutdown: CancellationToken, start: F)
+where
+    F: Fn() -> Fut,
+    Fut: std::future::Future<Output = anyhow::Result<()>> + Send + 'static,
+{
+    let mut restarts = 0;
+    loop {
+        let started = tokio::time::Instant::now();
+        let failure = match tokio::spawn(start()).await {
+            Ok(Ok(())) => return,
+            Ok(Err(e)) => e.to_string(),
+            Err(e) if e.is_panic() => "task panicked".to_string(),
+            Err(e) => e.to_string(),
+        };
+        if shutdown.is_cancelled() {
+            return;
+        }
+
+        if started.elapsed().as_secs() >= TUNNEL_STABLE_SECS {
+            restarts = 0;
+        }
+        if restarts >= max_restarts {
+            logger::log_error(&format!("Tunnel {} failed: {}; giving up after {} restarts", name, failure, restarts));
+            return;
+        }
+        restarts += 1;
+
+        let backoff = (TUNNEL_RESTART_BACKOFF_SECS << (restarts - 1).min(16)).min(TUNNEL_RESTART_BACKOFF_MAX_SECS);
+        logger::log_error(&format!("Tunnel {} failed: {}; restarting in {}s ({}/{})",
+            name, failure, backoff, restarts, max_restarts));
+        tokio::select! {
+            _ = tokio::time::sleep(tokio::time::Duration::from_secs(backoff)) => {}
+            _ = shutdown.cancelled() => return,
+        }
+    }
+}
+
+/// Persists all miners still in memory.
+async fn save_miners_on_shutdown(miner_manager: &MinerManager, database: Option<&Database>) {
+    let miners = miner_manager.drain_miners();
+    let Some(db) = database else {
+        return;
+    };
+
+    let mut saved = 0;
+    for miner_arc in &miners {
+        let miner = miner_arc.read().await;
+        match db.save_miner(&miner).await {
+            Ok(()) => saved += 1,
+            Err(e) => status_error(format!("Failed to save miner {}: {}", miner.name, e).red()),
+        }
+    }
+
+    status(format!("Saved {}/{} miners to database", saved, miners.len()).green());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use tokio::time::{Duration, Instant};
+
+    /// Supervises a tunnel whose `n`th start (from 1) runs `attempt(n)`, returning how many
+    /// starts there were and how long supervision took.
+    async fn supervise<Fut>(max_restarts: u32, shutdown: CancellationToken, attempt: fn(u32) -> Fut) -> (u32, Duration)
+    where
+        Fut: std::future::Future<Output = anyhow::Result<()>> + Send + 'static,
+    {
+        let starts = Arc::new(AtomicU32::new(0));
+        let counted = Arc::clone(&starts);
+        let began = Instant::now();
+        supervise_tunnel("test".to_string(), max_restarts, shutdown, move || {
+            attempt(counted.fetch_add(1, Ordering::SeqCst) + 1)
+        }).await;
+        (starts.load(Ordering::SeqCst), began.elapsed())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failed_tunnels_restart_with_growing_backoff_until_the_limit() {
+        let (starts, elapsed) = supervise(3, CancellationToken::new(), |_| async {
+            anyhow::bail!("address in use")
+        }).await;
+
+        assert_eq!(starts, 4);
+        assert_eq!(elapsed, Duration::from_secs(1 + 2 + 4));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn backoff_is_capped() {
+        let (starts, elapsed) = supervise(8, CancellationToken::new(), |_| async {
+            anyhow::bail!("address in use")
+        }).await;
+
+        assert_eq!(starts, 9);
+        assert_eq!(elapsed, Duration::from_secs(1 + 2 + 4 + 8 + 16 + 32 + 60 + 60));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn panicked_tunnels_restart_and_clean_exits_end_supervision() {
+        let (starts, elapsed) = supervise(3, CancellationToken::new(), |n| async move {
+            assert!(n > 1, "listener task blew up");
+            Ok(())
+        }).await;
+
+        assert_eq!(starts, 2);
+        assert_eq!(elapsed, Duration::from_secs(1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_stable_run_resets_the_restart_count() {
+        // Without the reset the second failure would use up the only restart
+        let (starts, _) = supervise(1, CancellationToken::new(), |n| async move {
+            if n == 2 {
+                tokio::time::sleep(Duration::from_secs(TUNNEL_STABLE_SECS)).await;
+            }
+            anyhow::bail!("connection reset")
+        }).await;
+
+        assert_eq!(starts, 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn shutdown_cuts_the_backoff_short() {
+        let shutdown = CancellationToken::new();
+        let cancel = shutdown.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            cancel.cancel();
+        });
+
+        let (starts, elapsed) = supervise(3, shutdown, |_| async {
+            anyhow::bail!("address in use")
+        }).await;
+
+        assert_eq!(starts, 1);
+        assert_eq!(elapsed, Duration::from_millis(500));
+    }
+}
@@ -1,4 +1,4 @@
-use sysinfo::{System, SystemExt, DiskExt, CpuExt};
+use sysinfo::{System, SystemExt, DiskExt, CpuExt, NetworkExt, Networks, NetworksExt, Process, ProcessExt};
 use std::sync::Arc;
 use tokio::sync::RwLock;
 use crate::miner::MinerManager;
@@ -8,10 +8,21 @@ pub struct SystemMetrics {
     pub cpu_model: String,
     pub cpu_cores: usize,
     pub cpu_usage: f32,
+    pub cpu_per_core: Vec<f32>,
     pub ram_total: u64,
     pub ram_used: u64,
     pub disk_total: u64,
     pub disk_used: u64,
+    /// CPU used by this process, where 100% is one full core
+    pub process_cpu_percent: f32,
+    pub process_memory_bytes: u64,
+    pub process_threads: usize,
+    /// Interfaces counted in the host network totals below
+    pub net_interfaces: Vec<String>,
+    pub net_rx_bytes: u64,
+    pub net_tx_bytes: u64,
+    pub net_rx_rate: f64,
+    pub net_tx_rate: f64,
     pub os: String,
     pub public_ip: String,
     pub uptime: std::time::Duration,
@@ -19,7 +30,7 @@ pub struct SystemMetrics {
 }
 
 impl SystemMetrics {
-    pub async fn new() -> Self {
+    pub fn new() -> Self {
         let mut sys = System::new_all();
         sys.refresh_all();
 
@@ -37,32 +48,39 @@ impl SystemMetrics {
             sys.os_version().unwrap_or_else(|| "Unknown".to_string())
         );
 
-        let public_ip = Self::get_public_ip().await.unwrap_or_else(|| "Unknown".to_string());
-
         Self {
             cpu_model,
             cpu_cores,
             cpu_usage: 0.0,
+            cpu_per_core: vec![0.0; cpu_cores],
             ram_total,
             ram_used,
             disk_total,
             disk_used,
+            process_cpu_percent: 0.0,
+            process_memory_bytes: 0,
+            process_threads: 0,
+            net_interfaces: Vec::new(),
+            net_rx_bytes: 0,
+            net_tx_bytes: 0,
+            net_rx_rate: 0.0,
+            net_tx_rate: 0.0,
             os,
-            public_ip,
+            public_ip: "Unknown".to_string(),
             uptime: std::time::Duration::from_secs(0),
             active_miners: 0,
         }
     }
 
-    async fn get_public_ip() -> Option<String> {
-        match reqwest::get("https://api.ipify.org?format=text").await {
-            Ok(resp) => resp.text().await.ok(),
-            Err(_) => None,
-        }
+    async fn get_public_ip(url: &str) -> Option<String> {
+        let text = reqwest::get(url).await.ok()?.text().await.ok()?;
+        let ip = text.trim();
+        ip.parse::<std::net::IpAddr>().ok().map(|_| ip.to_string())
     }
 
     pub fn update(&mut self, sys: &System, active_miners: usize, start_time: std::time::Instant) {
         self.cpu_usage = sys.global_cpu_info().cpu_usage();
+        self.cpu_per_core = sys.cpus().iter().map(|cpu| cpu.cpu_usage()).collect();
         self.ram_used = sys.used_memory();
 
         self.disk_used = sys.disks().iter()
@@ -72,24 +90,163 @@ impl SystemMetrics {
         self.active_miners = active_miners;
         self.uptime = start_time.elapsed();
     }
+
+    pub fn update_process(&mut self, process: &Process) {
+        self.process_cpu_percent = process.cpu_usage();
+        self.process_memory_bytes = process.memory();
+        self.process_threads = thread_count(process);
+    }
+
+    /// Sums host NIC traffic, limited to `filter` when it isn't empty. `elapsed` is the
+    /// time since the previous network refresh and turns its deltas into rates.
+    pub fn update_network(&mut self, networks: &Networks, filter: &[String], elapsed: std::time::Duration) {
+        let mut interfaces = Vec::new();
+        let (mut rx_total, mut tx_total, mut rx_delta, mut tx_delta) = (0, 0, 0, 0);
+
+        for (name, data) in networks.iter() {
+            if !filter.is_empty() && !filter.iter().any(|f| f == name) {
+                continue;
+            }
+            interfaces.push(name.clone());
+            rx_total += data.total_received();
+            tx_total += data.total_transmitted();
+            rx_delta += data.received();
+            tx_delta += data.transmitted();
+        }
+        interfaces.sort();
+
+        let secs = elapsed.as_secs_f64();
+        if secs > 0.0 {
+            self.net_rx_rate = rx_delta as f64 / secs;
+            self.net_tx_rate = tx_delta as f64 / secs;
+        }
+        self.net_interfaces = interfaces;
+        self.net_rx_bytes = rx_total;
+        self.net_tx_bytes = tx_total;
+    }
+}
+
+/// `tasks` lists every thread except the main one.
+#[cfg(target_os = "linux")]
+fn thread_count(process: &Process) -> usize {
+    process.tasks.len() + 1
+}
+
+#[cfg(not(target_os = "linux"))]
+fn thread_count(_process: &Process) -> usize {
+    0
 }
 
 pub async fn update_system_metrics(
     metrics: Arc<RwLock<SystemMetrics>>,
     miner_manager: Arc<MinerManager>,
+    public_ip_url: String,
+    public_ip_refresh_secs: u64,
+    network_interfaces: Vec<String>,
 ) {
-    let mut sys = System::new_all();
+    let ip_metrics = Arc::clone(&metrics);
+    tokio::spawn(async move {
+        refresh_public_ip(ip_metrics, public_ip_url, public_ip_refresh_secs).await;
+    });
+
+    let mut sys = System::new();
+    sys.refresh_disks_list();
+    sys.refresh_networks_list();
+    let mut last_network_refresh = std::time::Instant::now();
+    // Refreshing a process also re-samples the global CPU times, which would shrink the
+    // system-wide CPU window to nothing, so the process gets its own `System`
+    let mut process_sys = System::new();
+    let pid = sysinfo::get_current_pid().ok();
     let start_time = std::time::Instant::now();
+    // CPU usage is a delta between refreshes, so this interval is also the CPU sampling window
     let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(5));
 
     loop {
         interval.tick().await;
 
-        sys.refresh_all();
+        refresh_host(&mut sys);
+        // Also picks up interfaces added since the last tick, e.g. new docker bridges
+        sys.refresh_networks_list();
+        let network_elapsed = last_network_refresh.elapsed();
+        last_network_refresh = std::time::Instant::now();
 
         let active_miners = miner_manager.active_count();
 
         let mut m = metrics.write().await;
         m.update(&sys, active_miners, start_time);
+        m.update_network(sys.networks(), &network_interfaces, network_elapsed);
+        if let Some(pid) = pid {
+            if process_sys.refresh_process(pid) {
+                if let Some(process) = process_sys.process(pid) {
+                    m.update_process(process);
+                }
+            }
+        }
+    }
+}
+
+/// Refreshes only what `SystemMetrics::update` reads. `refresh_all` also walks every
+/// process, network interface and user on each tick, and the process scan alone grows
+/// with the number of processes on the host: about 2 ms against 40 µs per tick on a
+/// small container (`refresh_cost` below measures it).
+fn refresh_host(sys: &mut System) {
+    sys.refresh_cpu();
+    sys.refresh_memory();
+    sys.refresh_disks();
+}
+
+/// Looks up the public IP on its own interval, keeping the last good value when a lookup fails.
+async fn refresh_public_ip(metrics: Arc<RwLock<SystemMetrics>>, url: String, refresh_secs: u64) {
+    let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(refresh_secs.max(1)));
+
+    loop {
+        interval.tick().await;
+
+        if let Some(ip) = SystemMetrics::get_public_ip(&url).await {
+            metrics.write().await.public_ip = ip;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refresh_host_fills_what_update_reads() {
+        let mut sys = System::new();
+        sys.refresh_disks_list();
+        refresh_host(&mut sys);
+
+        let mut metrics = SystemMetrics::new();
+        metrics.update(&sys, 3, std::time::Instant::now());
+        assert_eq!(metrics.cpu_per_core.len(), sys.cpus().len());
+        assert!(metrics.ram_used > 0);
+        assert!(metrics.disk_used <= metrics.disk_total);
+        assert_eq!(metrics.active_miners, 3);
     }
-}
\ No newline at end of file
+
+    /// Average time of one metrics tick with `refresh_all` versus `refresh_host`.
+    /// Run with `cargo test --release -- --ignored --nocapture refresh_cost`.
+    #[test]
+    #[ignore]
+    fn refresh_cost() {
+        const TICKS: u32 = 50;
+        let mut sys = System::new_all();
+        let start = std::time::Instant::now();
+        for _ in 0..TICKS {
+            sys.refresh_all();
+        }
+        let all = start.elapsed() / TICKS;
+
+        let mut sys = System::new();
+        sys.refresh_disks_list();
+        let start = std::time::Instant::now();
+        for _ in 0..TICKS {
+            refresh_host(&mut sys);
+        }
+        let targeted = start.elapsed() / TICKS;
+
+        println!("refresh_all: {:?} per tick, refresh_host: {:?} per tick", all, targeted);
+    }
+}
@@ -1,7 +1,55 @@
 use chrono::{DateTime, Utc};
 use dashmap::DashMap;
-use std::sync::Arc;
-use std::sync::atomic::AtomicI64;
+use serde::{Serialize, Serializer};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, OnceLock};
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use tokio_util::sync::CancellationToken;
+use crate::database::{Database, HashrateSnapshot};
+
+/// Jobs older than this many notifications are treated as expired even without clean_jobs.
+const MAX_ACTIVE_JOBS: usize = 16;
+/// Submits awaiting a pool answer beyond this many are forgotten, oldest first.
+const MAX_PENDING_SUBMITS: usize = 64;
+/// Handshake requests remembered per miner for replaying to a new upstream.
+const MAX_HANDSHAKE_REQUESTS: usize = 8;
+/// Shares remembered per miner to recognise resubmits.
+const MAX_RECENT_SHARES: usize = 256;
+/// Window over which reconnects are counted.
+const RECONNECT_WINDOW_SECS: i64 = 3600;
+/// Wallets idle for a whole window are pruned once this many are tracked.
+const MAX_TRACKED_WALLETS: usize = 10_000;
+/// How often per-miner bandwidth rates are recomputed.
+const BANDWIDTH_SAMPLE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+/// How often the snapshots served to the API are rebuilt.
+const SNAPSHOT_REFRESH_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+/// A miner counts as throttled for this long after its traffic was last delayed.
+const THROTTLE_REPORT_SECS: i64 = 5;
+
+/// Hashrate smoothing from the config, shared by every miner.
+static HASHRATE_SETTINGS: OnceLock<HashrateSettings> = OnceLock::new();
+
+#[derive(Debug, Clone, Copy)]
+pub struct HashrateSettings {
+    /// Shares older than this are left out of `current_hashrate`
+    pub window_secs: u64,
+    /// Weight of each new `current_hashrate` in the `average_hashrate` EMA
+    pub ema_alpha: f64,
+}
+
+impl Default for HashrateSettings {
+    fn default() -> Self {
+        Self { window_secs: 600, ema_alpha: 0.1 }
+    }
+}
+
+/// Applies `hashrate_window_secs` and `hashrate_ema_alpha`; the defaults hold until called.
+pub fn init_hashrate_settings(settings: HashrateSettings) {
+    let _ = HASHRATE_SETTINGS.set(settings);
+}
+
+/// Miner snapshots keyed by connection key, as served to the API.
+pub type MinerSnapshots = Arc<Vec<(String, MinerSnapshot)>>;
 
 #[derive(Debug)]
 pub struct MinerInfo {
@@ -10,20 +58,192 @@ pub struct MinerInfo {
     pub ip: String,
     pub port: String,
     pub pool_name: String,
+    /// Tunnel the miner connected through
+    pub tunnel_name: String,
+    /// Mining software, from the first `mining.subscribe` parameter
+    pub user_agent: String,
+    /// Label from the first `worker_groups` pattern matching the worker name
+    pub group: Option<String>,
+    /// Job ID of the last submitted share
     pub job_id: String,
+    /// Jobs from `mining.notify` that a share may still be submitted against, oldest first
+    pub active_jobs: VecDeque<String>,
+    /// `mining.notify` jobs received this session
+    pub jobs_received: i64,
     pub shares_accepted: AtomicI64,
     pub shares_rejected: AtomicI64,
+    pub shares_stale: AtomicI64,
+    /// Shares submitted again with the same job, extranonce2, ntime and nonce
+    pub shares_duplicate: AtomicI64,
+    /// Hashes of recently submitted shares, oldest first
+    recent_shares: VecDeque<u64>,
+    /// Summed pool difficulty of accepted shares, the basis for proportional reward estimates
+    pub difficulty_accepted_sum: f64,
+    /// Summed pool difficulty of rejected shares
+    pub difficulty_rejected_sum: f64,
+    /// Rejected shares by reason classified from the pool's error
+    pub reject_reasons: HashMap<String, i64>,
+    /// `id`s of `mining.submit` requests the pool has not answered yet, with when they were sent
+    pub pending_submits: VecDeque<(serde_json::Value, std::time::Instant)>,
+    /// Pending submits pushed out by `MAX_PENDING_SUBMITS` newer ones since the last sweep
+    pub evicted_submits: usize,
+    /// Submits the pool never answered within `submit_timeout_secs`, or before too many
+    /// newer ones were pending
+    pub shares_lost: AtomicI64,
     pub last_seen: DateTime<Utc>,
     pub connected_at: DateTime<Utc>,
+    /// When the pool first accepted the miner's `mining.authorize`
+    pub authorized_at: Option<DateTime<Utc>>,
+    /// When the pool accepted the miner's first share
+    pub first_share_at: Option<DateTime<Utc>>,
     pub bytes_download: AtomicI64,
     pub bytes_upload: AtomicI64,
     pub packets_sent: AtomicI64,
     pub packets_received: AtomicI64,
+    /// Bytes per second from the pool over the last sampling interval, as `f64` bits so
+    /// the sampler only needs a read lock
+    download_rate: AtomicU64,
+    /// Bytes per second from the miner over the last sampling interval, as `f64` bits
+    upload_rate: AtomicU64,
+    /// Last time `max_bytes_per_sec` delayed a line in either direction
+    pub throttled_at: Option<DateTime<Utc>>,
+    /// When the rates were last computed and the byte counters at that time
+    bandwidth_sample: std::sync::Mutex<(std::time::Instant, i64, i64)>,
     pub last_share_time: DateTime<Utc>,
     pub share_times: Vec<DateTime<Utc>>,
     pub current_hashrate: f64,
     pub average_hashrate: f64,
+    /// Difficulty last set by the pool
+    pub difficulty: f64,
+    /// Difficulty the miner asked for with `mining.suggest_difficulty`
+    pub suggested_difficulty: Option<f64>,
+    /// `id` of the miner's pending `mining.subscribe`, used to recognise the pool's reply
+    pub subscribe_request_id: Option<serde_json::Value>,
+    /// `id` of the miner's pending `mining.authorize`
+    pub authorize_request_id: Option<serde_json::Value>,
+    /// Whether the pool accepted the miner's last `mining.authorize`
+    pub authorized: bool,
+    /// The pool has answered the miner's `mining.subscribe`
+    pub subscribed: bool,
+    /// The pool has sent `mining.set_difficulty` this session
+    pub pool_difficulty_received: bool,
+    pub subscription_id: String,
+    pub extranonce1: String,
+    pub extranonce2_size: u32,
+    /// The miner sent `mining.extranonce.subscribe`, so it accepts `mining.set_extranonce`
+    pub extranonce_subscribed: bool,
+    /// The miner's subscribe/authorize requests, replayed when following a `client.reconnect`
+    pub handshake: Vec<serde_json::Value>,
+    /// Difficulty chosen by vardiff, if it has taken over from the pool's
+    pub proxy_difficulty: Option<f64>,
+    /// The pool's `difficulty_multiplier`, applied to the pool's difficulty in hashrate estimates
+    pub difficulty_multiplier: f64,
+    /// Counters already written to the database; each save adds only what came since.
+    /// Shared so a save can hold it after letting go of the miner's lock.
+    pub persisted: Arc<tokio::sync::Mutex<MinerCounters>>,
+}
+
+/// The cumulative counters `Database::save_miner` persists.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct MinerCounters {
+    pub shares_accepted: i64,
+    pub shares_rejected: i64,
+    pub difficulty_accepted: f64,
+    pub difficulty_rejected: f64,
+    pub bytes_download: i64,
+    pub bytes_upload: i64,
+    pub packets_sent: i64,
+    pub packets_received: i64,
+}
+
+impl MinerCounters {
+    /// What was counted after `earlier`.
+    pub fn since(&self, earlier: &MinerCounters) -> MinerCounters {
+        MinerCounters {
+            shares_accepted: self.shares_accepted - earlier.shares_accepted,
+            shares_rejected: self.shares_rejected - earlier.shares_rejected,
+            difficulty_accepted: self.difficulty_accepted - earlier.difficulty_accepted,
+            difficulty_rejected: self.difficulty_rejected - earlier.difficulty_rejected,
+            bytes_download: self.bytes_download - earlier.bytes_download,
+            bytes_upload: self.bytes_upload - earlier.bytes_upload,
+            packets_sent: self.packets_sent - earlier.packets_sent,
+            packets_received: self.packets_received - earlier.packets_received,
+        }
+    }
+}
+
+/// Point-in-time copy of a `MinerInfo` as reported by the API and Prometheus.
+#[derive(Debug, Clone, Serialize)]
+pub struct MinerSnapshot {
+    pub wallet: String,
+    #[serde(rename = "miner_name")]
+    pub name: String,
+    pub ip: String,
+    pub port: String,
+    pub pool_name: String,
+    pub tunnel_name: String,
+    pub user_agent: String,
+    pub group: Option<String>,
+    pub authorized: bool,
+    pub shares_accepted: i64,
+    pub shares_rejected: i64,
+    pub shares_stale: i64,
+    pub shares_duplicate: i64,
+    pub shares_lost: i64,
+    pub difficulty_accepted_sum: f64,
+    pub difficulty_rejected_sum: f64,
+    pub efficiency_percent: f64,
+    pub reject_reasons: HashMap<String, i64>,
+    pub bytes_download: i64,
+    pub bytes_upload: i64,
+    pub packets_sent: i64,
+    pub packets_received: i64,
+    /// Bytes per second over the last few seconds
+    pub download_rate: f64,
+    pub upload_rate: f64,
+    /// `max_bytes_per_sec` delayed traffic within the last few seconds
+    pub throttled: bool,
+    /// H/s; serialized human-readable, e.g. "1.25 MH/s"
+    #[serde(serialize_with = "serialize_hashrate")]
+    pub current_hashrate: f64,
+    #[serde(serialize_with = "serialize_hashrate")]
+    pub average_hashrate: f64,
     pub difficulty: f64,
+    pub suggested_difficulty: Option<f64>,
+    pub proxy_difficulty: Option<f64>,
+    pub jobs_received: i64,
+    pub extranonce1: String,
+    pub extranonce2_size: u32,
+    pub extranonce_subscribed: bool,
+    pub uptime_seconds: i64,
+    pub connected_at: String,
+    pub authorized_at: Option<String>,
+    pub first_share_at: Option<String>,
+    /// Seconds from accepting the connection to authorization
+    pub handshake_secs: Option<f64>,
+    /// Seconds from authorization to the first accepted share
+    pub time_to_first_share_secs: Option<f64>,
+    pub last_seen: String,
+}
+
+impl MinerSnapshot {
+    /// The cumulative counters `Database::save_miner_snapshot` persists.
+    pub fn counters(&self) -> MinerCounters {
+        MinerCounters {
+            shares_accepted: self.shares_accepted,
+            shares_rejected: self.shares_rejected,
+            difficulty_accepted: self.difficulty_accepted_sum,
+            difficulty_rejected: self.difficulty_rejected_sum,
+            bytes_download: self.bytes_download,
+            bytes_upload: self.bytes_upload,
+            packets_sent: self.packets_sent,
+            packets_received: self.packets_received,
+        }
+    }
+}
+
+pub fn serialize_hashrate<S: Serializer>(hashrate: &f64, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&MinerInfo::format_hashrate(*hashrate))
 }
 
 impl MinerInfo {
@@ -34,26 +254,312 @@ impl MinerInfo {
             ip,
             port,
             pool_name,
+            tunnel_name: String::new(),
+            user_agent: String::new(),
+            group: None,
             job_id: String::new(),
+            active_jobs: VecDeque::new(),
+            jobs_received: 0,
             shares_accepted: AtomicI64::new(0),
             shares_rejected: AtomicI64::new(0),
+            shares_stale: AtomicI64::new(0),
+            shares_duplicate: AtomicI64::new(0),
+            recent_shares: VecDeque::new(),
+            difficulty_accepted_sum: 0.0,
+            difficulty_rejected_sum: 0.0,
+            reject_reasons: HashMap::new(),
+            pending_submits: VecDeque::new(),
+            evicted_submits: 0,
+            shares_lost: AtomicI64::new(0),
             last_seen: Utc::now(),
             connected_at: Utc::now(),
+            authorized_at: None,
+            first_share_at: None,
             bytes_download: AtomicI64::new(0),
             bytes_upload: AtomicI64::new(0),
             packets_sent: AtomicI64::new(0),
             packets_received: AtomicI64::new(0),
+            download_rate: AtomicU64::new(0),
+            upload_rate: AtomicU64::new(0),
+            throttled_at: None,
+            bandwidth_sample: std::sync::Mutex::new((std::time::Instant::now(), 0, 0)),
             last_share_time: Utc::now(),
             share_times: Vec::new(),
             current_hashrate: 0.0,
             average_hashrate: 0.0,
             difficulty: 1.0,
+            suggested_difficulty: None,
+            subscribe_request_id: None,
+            authorize_request_id: None,
+            authorized: false,
+            subscribed: false,
+            pool_difficulty_received: false,
+            subscription_id: String::new(),
+            extranonce1: String::new(),
+            extranonce2_size: 0,
+            extranonce_subscribed: false,
+            handshake: Vec::new(),
+            proxy_difficulty: None,
+            difficulty_multiplier: 1.0,
+            persisted: Arc::new(tokio::sync::Mutex::new(MinerCounters::default())),
+        }
+    }
+
+    pub fn counters(&self) -> MinerCounters {
+        use std::sync::atomic::Ordering;
+
+        MinerCounters {
+            shares_accepted: self.shares_accepted.load(Ordering::Relaxed),
+            shares_rejected: self.shares_rejected.load(Ordering::Relaxed),
+            difficulty_accepted: self.difficulty_accepted_sum,
+            difficulty_rejected: self.difficulty_rejected_sum,
+            bytes_download: self.bytes_download.load(Ordering::Relaxed),
+            bytes_upload: self.bytes_upload.load(Ordering::Relaxed),
+            packets_sent: self.packets_sent.load(Ordering::Relaxed),
+            packets_received: self.packets_received.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Records a job from `mining.notify`; `clean_jobs` invalidates all previous jobs.
+    pub fn record_job(&mut self, job_id: &str, clean_jobs: bool) {
+        self.jobs_received += 1;
+        if clean_jobs {
+            self.active_jobs.clear();
         }
+        self.active_jobs.retain(|j| j != job_id);
+        self.active_jobs.push_back(job_id.to_string());
+        while self.active_jobs.len() > MAX_ACTIVE_JOBS {
+            self.active_jobs.pop_front();
+        }
+    }
+
+    /// A share is stale when its job is no longer active. Before the first notify
+    /// nothing is known, so no share is considered stale.
+    pub fn is_stale_job(&self, job_id: &str) -> bool {
+        !self.active_jobs.is_empty() && !self.active_jobs.iter().any(|j| j == job_id)
+    }
+
+    pub fn record_handshake(&mut self, msg: &serde_json::Value) {
+        if self.handshake.len() < MAX_HANDSHAKE_REQUESTS {
+            self.handshake.push(msg.clone());
+        }
+    }
+
+    pub fn record_submit(&mut self, id: &serde_json::Value) {
+        self.pending_submits.push_back((id.clone(), std::time::Instant::now()));
+        while self.pending_submits.len() > MAX_PENDING_SUBMITS {
+            // Unanswered while this many newer ones went out, so it won't be answered
+            self.pending_submits.pop_front();
+            self.evicted_submits += 1;
+        }
+    }
+
+    /// Identifies a share by every `mining.submit` param after the worker name.
+    fn share_key(params: &[serde_json::Value]) -> u64 {
+        use std::hash::{Hash, Hasher};
+
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        for param in params.iter().skip(1) {
+            param.to_string().hash(&mut hasher);
+        }
+        hasher.finish()
+    }
+
+    /// Whether a share with these `mining.submit` params was already submitted.
+    pub fn is_duplicate_share(&self, params: &[serde_json::Value]) -> bool {
+        self.recent_shares.contains(&Self::share_key(params))
+    }
+
+    /// Remembers a submitted share, returning whether it was a resubmit.
+    pub fn remember_share(&mut self, params: &[serde_json::Value]) -> bool {
+        let key = Self::share_key(params);
+        if self.recent_shares.contains(&key) {
+            return true;
+        }
+        self.recent_shares.push_back(key);
+        while self.recent_shares.len() > MAX_RECENT_SHARES {
+            self.recent_shares.pop_front();
+        }
+        false
+    }
+
+    /// Returns whether `id` answers a pending submit, forgetting it if so.
+    pub fn take_submit(&mut self, id: &serde_json::Value) -> bool {
+        match self.pending_submits.iter().position(|(pending, _)| pending == id) {
+            Some(index) => {
+                self.pending_submits.remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Whether `expire_submits` has anything to count, checked without the write lock.
+    pub fn has_lost_submits(&self, timeout: std::time::Duration) -> bool {
+        self.evicted_submits > 0 || self.pending_submits.front().is_some_and(|(_, sent)| sent.elapsed() >= timeout)
+    }
+
+    /// Forgets submits the pool hasn't answered within `timeout` and counts them, with those
+    /// evicted since the last call, as lost, returning how many. A reply arriving later is
+    /// no longer counted as a share.
+    pub fn expire_submits(&mut self, timeout: std::time::Duration) -> usize {
+        let mut lost = std::mem::take(&mut self.evicted_submits);
+        while self.pending_submits.front().is_some_and(|(_, sent)| sent.elapsed() >= timeout) {
+            self.pending_submits.pop_front();
+            lost += 1;
+        }
+        self.shares_lost.fetch_add(lost as i64, std::sync::atomic::Ordering::Relaxed);
+        lost
+    }
+
+    /// Zeroes the session counters and hashrate without touching the connection or
+    /// persisted history. Callers hold the write lock, which the proxy tasks also take
+    /// before updating these counters, so no update is lost halfway through a reset.
+    pub async fn reset_stats(&mut self) {
+        use std::sync::atomic::Ordering;
+
+        // Rebase what was persisted onto the zeroed counters, so progress not yet
+        // saved still reaches the database. Waits for a save in flight to land first.
+        let persisted = Arc::clone(&self.persisted);
+        let mut persisted = persisted.lock().await;
+        let unsaved = self.counters().since(&persisted);
+        *persisted = MinerCounters::default().since(&unsaved);
+
+        self.shares_accepted.store(0, Ordering::Relaxed);
+        self.shares_rejected.store(0, Ordering::Relaxed);
+        self.shares_stale.store(0, Ordering::Relaxed);
+        self.shares_duplicate.store(0, Ordering::Relaxed);
+        self.shares_lost.store(0, Ordering::Relaxed);
+        self.difficulty_accepted_sum = 0.0;
+        self.difficulty_rejected_sum = 0.0;
+        self.bytes_download.store(0, Ordering::Relaxed);
+        self.bytes_upload.store(0, Ordering::Relaxed);
+        self.packets_sent.store(0, Ordering::Relaxed);
+        self.packets_received.store(0, Ordering::Relaxed);
+        self.reject_reasons.clear();
+        self.share_times.clear();
+        self.current_hashrate = 0.0;
+        self.average_hashrate = 0.0;
+        self.download_rate.store(0.0f64.to_bits(), Ordering::Relaxed);
+        self.upload_rate.store(0.0f64.to_bits(), Ordering::Relaxed);
+    }
+
+    /// Recomputes the download and upload rates from the byte counters.
+    pub fn sample_bandwidth(&self) {
+        let now = std::time::Instant::now();
+        let download = self.bytes_download.load(Ordering::Relaxed);
+        let upload = self.bytes_upload.load(Ordering::Relaxed);
+        let mut sample = self.bandwidth_sample.lock().unwrap_or_else(|e| e.into_inner());
+        let (last_time, last_download, last_upload) = *sample;
+        let elapsed = now.duration_since(last_time).as_secs_f64();
+
+        if elapsed > 0.0 {
+            // Counters only go backwards after a stats reset; report 0 for that interval
+            let download_rate = (download - last_download).max(0) as f64 / elapsed;
+            let upload_rate = (upload - last_upload).max(0) as f64 / elapsed;
+            self.download_rate.store(download_rate.to_bits(), Ordering::Relaxed);
+            self.upload_rate.store(upload_rate.to_bits(), Ordering::Relaxed);
+        }
+        *sample = (now, download, upload);
+    }
+
+    pub fn download_rate(&self) -> f64 {
+        f64::from_bits(self.download_rate.load(Ordering::Relaxed))
+    }
+
+    pub fn upload_rate(&self) -> f64 {
+        f64::from_bits(self.upload_rate.load(Ordering::Relaxed))
+    }
+
+    /// Accepted shares as a percentage of all answered shares; 0 before any answer.
+    pub fn efficiency_percent(&self) -> f64 {
+        let accepted = self.shares_accepted.load(std::sync::atomic::Ordering::Relaxed);
+        let rejected = self.shares_rejected.load(std::sync::atomic::Ordering::Relaxed);
+        let total = accepted + rejected;
+        if total == 0 {
+            return 0.0;
+        }
+        accepted as f64 / total as f64 * 100.0
+    }
+
+    pub fn snapshot(&self) -> MinerSnapshot {
+        use std::sync::atomic::Ordering;
+
+        MinerSnapshot {
+            wallet: self.wallet.clone(),
+            name: self.name.clone(),
+            ip: self.ip.clone(),
+            port: self.port.clone(),
+            pool_name: self.pool_name.clone(),
+            tunnel_name: self.tunnel_name.clone(),
+            user_agent: self.user_agent.clone(),
+            group: self.group.clone(),
+            authorized: self.authorized,
+            shares_accepted: self.shares_accepted.load(Ordering::Relaxed),
+            shares_rejected: self.shares_rejected.load(Ordering::Relaxed),
+            shares_stale: self.shares_stale.load(Ordering::Relaxed),
+            shares_duplicate: self.shares_duplicate.load(Ordering::Relaxed),
+            shares_lost: self.shares_lost.load(Ordering::Relaxed),
+            difficulty_accepted_sum: self.difficulty_accepted_sum,
+            difficulty_rejected_sum: self.difficulty_rejected_sum,
+            efficiency_percent: self.efficiency_percent(),
+            reject_reasons: self.reject_reasons.clone(),
+            bytes_download: self.bytes_download.load(Ordering::Relaxed),
+            bytes_upload: self.bytes_upload.load(Ordering::Relaxed),
+            packets_sent: self.packets_sent.load(Ordering::Relaxed),
+            packets_received: self.packets_received.load(Ordering::Relaxed),
+            download_rate: self.download_rate(),
+            upload_rate: self.upload_rate(),
+            throttled: self.is_throttled(),
+            current_hashrate: self.current_hashrate,
+            average_hashrate: self.average_hashrate,
+            difficulty: self.difficulty,
+            suggested_difficulty: self.suggested_difficulty,
+            proxy_difficulty: self.proxy_difficulty,
+            jobs_received: self.jobs_received,
+            extranonce1: self.extranonce1.clone(),
+            extranonce2_size: self.extranonce2_size,
+            extranonce_subscribed: self.extranonce_subscribed,
+            uptime_seconds: (Utc::now() - self.connected_at).num_seconds(),
+            connected_at: self.connected_at.to_rfc3339(),
+            authorized_at: self.authorized_at.map(|t| t.to_rfc3339()),
+            first_share_at: self.first_share_at.map(|t| t.to_rfc3339()),
+            handshake_secs: self.handshake_secs(),
+            time_to_first_share_secs: self.time_to_first_share_secs(),
+            last_seen: self.last_seen.to_rfc3339(),
+        }
+    }
+
+    /// Whether `max_bytes_per_sec` delayed this miner's traffic within the last few seconds.
+    pub fn is_throttled(&self) -> bool {
+        self.throttled_at.is_some_and(|t| (Utc::now() - t).num_seconds() < THROTTLE_REPORT_SECS)
+    }
+
+    /// Seconds from accepting the connection to the pool authorizing the miner.
+    pub fn handshake_secs(&self) -> Option<f64> {
+        self.authorized_at.map(|t| (t - self.connected_at).num_milliseconds() as f64 / 1000.0)
+    }
+
+    /// Seconds from authorization to the first accepted share.
+    pub fn time_to_first_share_secs(&self) -> Option<f64> {
+        let authorized_at = self.authorized_at?;
+        self.first_share_at.map(|t| (t - authorized_at).num_milliseconds() as f64 / 1000.0)
+    }
+
+    /// The pool has answered `mining.authorize` and refused it.
+    pub fn authorization_refused(&self) -> bool {
+        !self.authorized && self.authorize_request_id.is_none() && !self.wallet.is_empty()
+    }
+
+    /// The difficulty the miner is actually working at.
+    pub fn effective_difficulty(&self) -> f64 {
+        self.proxy_difficulty.unwrap_or(self.difficulty)
     }
 
     pub fn calculate_hashrate(&mut self) {
+        let settings = HASHRATE_SETTINGS.get().copied().unwrap_or_default();
         let now = Utc::now();
-        let cutoff = now - chrono::Duration::minutes(10);
+        let cutoff = now - chrono::Duration::seconds(settings.window_secs as i64);
         
         self.share_times.retain(|&t| t > cutoff);
         
@@ -67,16 +573,37 @@ impl MinerInfo {
         
         if total_time > 0.0 {
             let shares_per_second = self.share_times.len() as f64 / total_time;
-            self.current_hashrate = shares_per_second * self.difficulty;
+            // Only the pool's own difficulty is in its units; a tunnel's
+            // `initial_difficulty` or vardiff value is taken as-is
+            let difficulty = self.proxy_difficulty.unwrap_or(self.difficulty * self.difficulty_multiplier);
+            self.current_hashrate = shares_per_second * difficulty;
         }
 
         if self.average_hashrate == 0.0 {
             self.average_hashrate = self.current_hashrate;
         } else {
-            self.average_hashrate = (self.average_hashrate * 0.9) + (self.current_hashrate * 0.1);
+            self.average_hashrate = self.average_hashrate * (1.0 - settings.ema_alpha)
+                + self.current_hashrate * settings.ema_alpha;
         }
     }
 
+    /// Reads a hashrate such as `500000`, `10T`, `10TH` or `1.5 GH/s` back into H/s.
+    pub fn parse_hashrate(text: &str) -> Option<f64> {
+        let text = text.trim();
+        let text = text.strip_suffix("/s").unwrap_or(text);
+        let text = text.strip_suffix(['H', 'h']).unwrap_or(text).trim_end();
+        let (number, scale) = match text.chars().last()?.to_ascii_uppercase() {
+            'K' => (&text[..text.len() - 1], 1e3),
+            'M' => (&text[..text.len() - 1], 1e6),
+            'G' => (&text[..text.len() - 1], 1e9),
+            'T' => (&text[..text.len() - 1], 1e12),
+            'P' => (&text[..text.len() - 1], 1e15),
+            _ => (text, 1.0),
+        };
+        let value: f64 = number.trim().parse().ok()?;
+        (value.is_finite() && value >= 0.0).then_some(value * scale)
+    }
+
     pub fn format_hashrate(hashrate: f64) -> String {
         if hashrate == 0.0 {
             return "0 H/s".to_string();
@@ -101,19 +628,122 @@ impl MinerInfo {
     }
 }
 
+/// Sessions of one wallet, kept across connections so flapping miners show up.
+#[derive(Debug, Default)]
+struct SessionHistory {
+    total_sessions: u64,
+    /// Start of each session within the reconnect window, oldest first
+    recent: VecDeque<DateTime<Utc>>,
+}
+
+impl SessionHistory {
+    fn prune(&mut self, now: DateTime<Utc>) {
+        let cutoff = now - chrono::Duration::seconds(RECONNECT_WINDOW_SECS);
+        while self.recent.front().is_some_and(|&start| start <= cutoff) {
+            self.recent.pop_front();
+        }
+    }
+
+    /// Sessions started in the last hour, not counting the wallet's very first one.
+    fn reconnects_last_hour(&self) -> u64 {
+        let cutoff = Utc::now() - chrono::Duration::seconds(RECONNECT_WINDOW_SECS);
+        let recent = self.recent.iter().filter(|&&start| start > cutoff).count() as u64;
+        if recent == self.total_sessions {
+            recent.saturating_sub(1)
+        } else {
+            recent
+        }
+    }
+}
+
 pub struct MinerManager {
     miners: Arc<DashMap<String, Arc<tokio::sync::RwLock<MinerInfo>>>>,
+    /// Cancelled to make the connection serving a miner hang up
+    disconnects: Arc<DashMap<String, CancellationToken>>,
+    /// Session history by wallet; outlives the `miners` entries
+    sessions: Arc<DashMap<String, SessionHistory>>,
+    /// Connections closed for never sending `mining.authorize`
+    reaped_total: AtomicU64,
+    /// Copy of every miner for the API, so polling never waits on the locks the proxy updates
+    snapshots: std::sync::RwLock<MinerSnapshots>,
 }
 
 impl MinerManager {
     pub fn new() -> Self {
         Self {
             miners: Arc::new(DashMap::new()),
+            disconnects: Arc::new(DashMap::new()),
+            sessions: Arc::new(DashMap::new()),
+            reaped_total: AtomicU64::new(0),
+            snapshots: std::sync::RwLock::new(Arc::new(Vec::new())),
         }
     }
 
-    pub fn add_miner(&self, key: String, miner: MinerInfo) {
+    /// Counts a new session for `wallet`, called once per connection when it authorizes.
+    pub fn record_session(&self, wallet: &str) {
+        let now = Utc::now();
+        if self.sessions.len() >= MAX_TRACKED_WALLETS {
+            self.sessions.retain(|_, history| {
+                history.prune(now);
+                !history.recent.is_empty()
+            });
+        }
+
+        let mut history = self.sessions.entry(wallet.to_string()).or_default();
+        history.prune(now);
+        history.total_sessions += 1;
+        history.recent.push_back(now);
+    }
+
+    /// Returns `(total sessions, reconnects in the last hour)` for `wallet`.
+    pub fn session_stats(&self, wallet: &str) -> Option<(u64, u64)> {
+        self.sessions.get(wallet)
+            .map(|history| (history.total_sessions, history.reconnects_last_hour()))
+    }
+
+    /// Tracks a miner, returning the token its connection should watch for a forced disconnect.
+    pub fn add_miner(&self, key: String, miner: MinerInfo) -> CancellationToken {
+        let disconnect = CancellationToken::new();
+        self.disconnects.insert(key.clone(), disconnect.clone());
         self.miners.insert(key, Arc::new(tokio::sync::RwLock::new(miner)));
+        disconnect
+    }
+
+    /// Asks the connection serving `key` to close; false if no such miner is connected.
+    pub fn disconnect_miner(&self, key: &str) -> bool {
+        match self.disconnects.get(key) {
+            Some(disconnect) => {
+                disconnect.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Disconnects miners still without a wallet `grace` after connecting, returning how many.
+    pub async fn reap_unauthorized(&self, grace: chrono::Duration) -> usize {
+        let cutoff = Utc::now() - grace;
+        let mut reaped = 0;
+        for (key, miner_arc) in self.get_all_entries() {
+            // The proxy applies an authorize under the write lock, so this sees it or delays it
+            // until the miner is cancelled; the read lock leaves other readers running
+            let miner = miner_arc.read().await;
+            if !miner.wallet.is_empty() || miner.connected_at > cutoff {
+                continue;
+            }
+            if let Some(disconnect) = self.disconnects.get(&key) {
+                if !disconnect.is_cancelled() {
+                    disconnect.cancel();
+                    reaped += 1;
+                }
+            }
+        }
+        self.reaped_total.fetch_add(reaped as u64, Ordering::Relaxed);
+        reaped
+    }
+
+    pub fn reaped_total(&self) -> u64 {
+        self.reaped_total.load(Ordering::Relaxed)
     }
 
     pub fn get_miner(&self, key: &str) -> Option<Arc<tokio::sync::RwLock<MinerInfo>>> {
@@ -121,9 +751,16 @@ impl MinerManager {
     }
 
     pub fn remove_miner(&self, key: &str) -> Option<Arc<tokio::sync::RwLock<MinerInfo>>> {
+        self.disconnects.remove(key);
         self.miners.remove(key).map(|(_, m)| m)
     }
 
+    /// Removes every tracked miner, returning them so they can be persisted on shutdown.
+    pub fn drain_miners(&self) -> Vec<Arc<tokio::sync::RwLock<MinerInfo>>> {
+        let keys: Vec<String> = self.miners.iter().map(|entry| entry.key().clone()).collect();
+        keys.iter().filter_map(|key| self.remove_miner(key)).collect()
+    }
+
     pub fn active_count(&self) -> usize {
         self.miners.len()
     }
@@ -131,4 +768,374 @@ impl MinerManager {
     pub async fn get_all_miners(&self) -> Vec<Arc<tokio::sync::RwLock<MinerInfo>>> {
         self.miners.iter().map(|entry| Arc::clone(entry.value())).collect()
     }
-}
\ No newline at end of file
+
+    /// Rebuilds the cached snapshots without waiting on any miner: one the proxy is
+    /// updating right now keeps its previous snapshot until the next refresh.
+    pub fn refresh_snapshots(&self) {
+        let previous = self.cached_snapshots();
+        let previous: HashMap<&str, &MinerSnapshot> = previous.iter()
+            .map(|(key, snapshot)| (key.as_str(), snapshot))
+            .collect();
+
+        let mut snapshots = Vec::with_capacity(self.miners.len());
+        for entry in self.miners.iter() {
+            let snapshot = match entry.value().try_read() {
+                Ok(miner) => miner.snapshot(),
+                Err(_) => match previous.get(entry.key().as_str()) {
+                    Some(snapshot) => (*snapshot).clone(),
+                    None => continue,
+                },
+            };
+            snapshots.push((entry.key().clone(), snapshot));
+        }
+
+        *self.snapshots.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(snapshots);
+    }
+
+    /// Every miner as of the last `refresh_snapshots`, at most `SNAPSHOT_REFRESH_INTERVAL` old.
+    pub fn cached_snapshots(&self) -> MinerSnapshots {
+        Arc::clone(&self.snapshots.read().unwrap_or_else(|e| e.into_inner()))
+    }
+
+    /// Like `get_all_miners`, paired with each miner's connection key.
+    pub fn get_all_entries(&self) -> Vec<(String, Arc<tokio::sync::RwLock<MinerInfo>>)> {
+        self.miners.iter().map(|entry| (entry.key().clone(), Arc::clone(entry.value()))).collect()
+    }
+}
+
+/// Periodically records every authorized miner's hashrate and drops history past the retention window.
+pub async fn snapshot_hashrates(
+    manager: Arc<MinerManager>,
+    database: Arc<Database>,
+    interval_secs: u64,
+    retention_days: u32,
+) {
+    let period = tokio::time::Duration::from_secs(interval_secs.max(1));
+    let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+
+    loop {
+        interval.tick().await;
+
+        let now = Utc::now();
+        let mut snapshots = Vec::new();
+        for miner_arc in manager.get_all_miners().await {
+            let miner = miner_arc.read().await;
+            if miner.wallet.is_empty() {
+                continue;
+            }
+            snapshots.push(HashrateSnapshot {
+                wallet: miner.wallet.clone(),
+                miner_name: miner.name.clone(),
+                pool_name: miner.pool_name.clone(),
+                current_hashrate: miner.current_hashrate,
+                average_hashrate: miner.average_hashrate,
+                timestamp: now,
+            });
+        }
+
+        if !snapshots.is_empty() {
+            let _ = database.save_hashrate_snapshots(&snapshots).await;
+        }
+        let _ = database.prune_hashrate_history(retention_days).await;
+    }
+}
+
+/// Periodically saves every active miner, so a crash loses at most one interval of stats.
+pub async fn checkpoint_miners(manager: Arc<MinerManager>, database: Arc<Database>, interval_secs: u64) {
+    let period = tokio::time::Duration::from_secs(interval_secs.max(1));
+    let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+
+    loop {
+        interval.tick().await;
+
+        for miner_arc in manager.get_all_miners().await {
+            // Only the copy is saved, so the proxy isn't kept waiting on the database
+            let (snapshot, persisted) = {
+                let miner = miner_arc.read().await;
+                // Locked before the miner is let go, so a reset waits for this save
+                let persisted = Arc::clone(&miner.persisted).lock_owned().await;
+                (miner.snapshot(), persisted)
+            };
+            if let Err(e) = database.save_miner_snapshot(&snapshot, persisted).await {
+                crate::logger::log_error(&format!("Failed to checkpoint miner {}: {}", snapshot.name, e));
+            }
+        }
+    }
+}
+
+/// Periodically disconnects miners that never authorized within `grace_secs` of connecting.
+pub async fn reap_unauthorized_miners(manager: Arc<MinerManager>, grace_secs: u64) {
+    let grace = chrono::Duration::seconds(grace_secs as i64);
+    let mut interval = tokio::time::interval(tokio::time::Duration::from_secs((grace_secs / 2).clamp(1, 30)));
+
+    loop {
+        interval.tick().await;
+
+        let reaped = manager.reap_unauthorized(grace).await;
+        if reaped > 0 {
+            crate::logger::log_info(&format!("Disconnected {} miner(s) that never authorized within {}s",
+                reaped, grace_secs));
+        }
+    }
+}
+
+/// Periodically counts submits the pool left unanswered for `timeout_secs` as lost, per
+/// miner and per pool, which shows pools that silently drop shares.
+pub async fn sweep_lost_submits(manager: Arc<MinerManager>, pool_manager: Arc<crate::pool::PoolManager>, timeout_secs: u64) {
+    let timeout = std::time::Duration::from_secs(timeout_secs);
+    let mut interval = tokio::time::interval(tokio::time::Duration::from_secs((timeout_secs / 3).clamp(1, 10)));
+
+    loop {
+        interval.tick().await;
+
+        for miner_arc in manager.get_all_miners().await {
+            // Most miners have nothing to expire; only those are locked for writing
+            if !miner_arc.read().await.has_lost_submits(timeout) {
+                continue;
+            }
+            let mut miner = miner_arc.write().await;
+            let lost = miner.expire_submits(timeout);
+            if lost == 0 {
+                continue;
+            }
+            crate::logger::log_warning(&format!("{} share(s) from {} ({}:{}) got no answer from {} within {}s",
+                lost, miner.name, miner.ip, miner.port, miner.pool_name, timeout_secs));
+            let pool_name = miner.pool_name.clone();
+            drop(miner);
+            pool_manager.get_or_create(&pool_name).write().await.shares_lost += lost as i64;
+        }
+    }
+}
+
+/// Keeps the snapshots served to the API current.
+pub async fn refresh_snapshots(manager: Arc<MinerManager>) {
+    let mut interval = tokio::time::interval(SNAPSHOT_REFRESH_INTERVAL);
+
+    loop {
+        interval.tick().await;
+        manager.refresh_snapshots();
+    }
+}
+
+/// Keeps every miner's bandwidth rates current. Only takes read locks, so it never waits
+/// behind or blocks the proxy.
+pub async fn sample_bandwidth(manager: Arc<MinerManager>) {
+    let mut interval = tokio::time::interval(BANDWIDTH_SAMPLE_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        for miner_arc in manager.get_all_miners().await {
+            miner_arc.read().await.sample_bandwidth();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bandwidth_is_sampled_through_a_shared_reference() {
+        let miner = MinerInfo::new("10.0.0.1".to_string(), "1000".to_string(), "pool".to_string());
+        *miner.bandwidth_sample.lock().unwrap() = (std::time::Instant::now() - std::time::Duration::from_secs(2), 0, 0);
+        miner.bytes_download.store(4000, Ordering::Relaxed);
+        miner.bytes_upload.store(400, Ordering::Relaxed);
+
+        miner.sample_bandwidth();
+        assert!((1000.0..=2000.0).contains(&miner.download_rate()), "{}", miner.download_rate());
+        assert!((100.0..=200.0).contains(&miner.upload_rate()), "{}", miner.upload_rate());
+    }
+
+    #[test]
+    fn multiplier_scales_only_the_pool_difficulty() {
+        let mut miner = MinerInfo::new("10.0.0.1".to_string(), "1000".to_string(), "pool".to_string());
+        let now = Utc::now();
+        miner.share_times = vec![now - chrono::Duration::seconds(10), now];
+        miner.difficulty = 1000.0;
+        miner.difficulty_multiplier = 4.0;
+
+        // Two shares over ten seconds
+        miner.calculate_hashrate();
+        assert_eq!(miner.current_hashrate, 0.2 * 4000.0);
+
+        miner.proxy_difficulty = Some(2000.0);
+        miner.calculate_hashrate();
+        assert_eq!(miner.current_hashrate, 0.2 * 2000.0);
+    }
+
+    #[test]
+    fn disconnect_miner_cancels_its_connection() {
+        let manager = MinerManager::new();
+        let token = manager.add_miner("10.0.0.1:1000".to_string(),
+            MinerInfo::new("10.0.0.1".to_string(), "1000".to_string(), "pool".to_string()));
+        assert!(!manager.disconnect_miner("10.0.0.1:1001"));
+        assert!(!token.is_cancelled());
+
+        assert!(manager.disconnect_miner("10.0.0.1:1000"));
+        assert!(token.is_cancelled());
+
+        manager.remove_miner("10.0.0.1:1000");
+        assert!(!manager.disconnect_miner("10.0.0.1:1000"));
+    }
+
+    #[test]
+    fn unanswered_submits_expire_as_lost() {
+        let mut miner = MinerInfo::new("10.0.0.1".to_string(), "1000".to_string(), "pool".to_string());
+        miner.record_submit(&serde_json::json!(1));
+        miner.record_submit(&serde_json::json!(2));
+        let sent = std::time::Instant::now() - std::time::Duration::from_secs(60);
+        miner.pending_submits[0].1 = sent;
+
+        assert!(miner.has_lost_submits(std::time::Duration::from_secs(30)));
+        assert_eq!(miner.expire_submits(std::time::Duration::from_secs(30)), 1);
+        assert!(!miner.has_lost_submits(std::time::Duration::from_secs(30)));
+        assert_eq!(miner.shares_lost.load(Ordering::Relaxed), 1);
+        assert!(!miner.take_submit(&serde_json::json!(1)));
+        assert!(miner.take_submit(&serde_json::json!(2)));
+    }
+
+    #[test]
+    fn submits_pushed_out_of_the_queue_count_as_lost() {
+        let mut miner = MinerInfo::new("10.0.0.1".to_string(), "1000".to_string(), "pool".to_string());
+        for id in 0..MAX_PENDING_SUBMITS + 2 {
+            miner.record_submit(&serde_json::json!(id));
+        }
+        assert_eq!(miner.pending_submits.len(), MAX_PENDING_SUBMITS);
+
+        let timeout = std::time::Duration::from_secs(30);
+        assert!(miner.has_lost_submits(timeout));
+        assert_eq!(miner.expire_submits(timeout), 2);
+        assert_eq!(miner.shares_lost.load(Ordering::Relaxed), 2);
+        assert!(!miner.has_lost_submits(timeout));
+    }
+
+    #[tokio::test]
+    async fn lost_submits_reach_the_pool_metrics() {
+        let manager = Arc::new(MinerManager::new());
+        let pool_manager = Arc::new(crate::pool::PoolManager::new());
+        let mut miner = MinerInfo::new("10.0.0.1".to_string(), "1000".to_string(), "pool".to_string());
+        for id in 0..MAX_PENDING_SUBMITS + 1 {
+            miner.record_submit(&serde_json::json!(id));
+        }
+        manager.add_miner("10.0.0.1:1000".to_string(), miner);
+        manager.add_miner("10.0.0.2:1000".to_string(),
+            MinerInfo::new("10.0.0.2".to_string(), "1000".to_string(), "pool".to_string()));
+
+        let sweeper = tokio::spawn(sweep_lost_submits(manager, Arc::clone(&pool_manager), 30));
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        sweeper.abort();
+        assert_eq!(pool_manager.get("pool").unwrap().read().await.shares_lost, 1);
+    }
+
+    #[tokio::test]
+    async fn reaper_only_disconnects_stale_unauthorized_miners() {
+        let manager = MinerManager::new();
+        let grace = chrono::Duration::seconds(60);
+        let stale = Utc::now() - chrono::Duration::seconds(120);
+
+        let mut idle = MinerInfo::new("10.0.0.1".to_string(), "1000".to_string(), "pool".to_string());
+        idle.connected_at = stale;
+        let idle_token = manager.add_miner("idle".to_string(), idle);
+
+        let mut authorized = MinerInfo::new("10.0.0.2".to_string(), "1000".to_string(), "pool".to_string());
+        authorized.connected_at = stale;
+        authorized.wallet = "wallet".to_string();
+        let authorized_token = manager.add_miner("authorized".to_string(), authorized);
+
+        let fresh = MinerInfo::new("10.0.0.3".to_string(), "1000".to_string(), "pool".to_string());
+        let fresh_token = manager.add_miner("fresh".to_string(), fresh);
+
+        assert_eq!(manager.reap_unauthorized(grace).await, 1);
+        assert!(idle_token.is_cancelled());
+        assert!(!authorized_token.is_cancelled());
+        assert!(!fresh_token.is_cancelled());
+
+        // Already asked to close, so not counted again
+        assert_eq!(manager.reap_unauthorized(grace).await, 0);
+        assert_eq!(manager.reaped_total(), 1);
+    }
+
+    #[tokio::test]
+    async fn reaper_reads_alongside_other_readers() {
+        let manager = MinerManager::new();
+        let mut idle = MinerInfo::new("10.0.0.1".to_string(), "1000".to_string(), "pool".to_string());
+        idle.connected_at = Utc::now() - chrono::Duration::seconds(120);
+        let idle_token = manager.add_miner("idle".to_string(), idle);
+
+        // An API request reading the miner doesn't hold the reaper up
+        let miner_arc = manager.get_miner("idle").unwrap();
+        let _reading = miner_arc.read().await;
+        let reaped = tokio::time::timeout(std::time::Duration::from_secs(1),
+            manager.reap_unauthorized(chrono::Duration::seconds(60))).await;
+        assert_eq!(reaped.unwrap(), 1);
+        assert!(idle_token.is_cancelled());
+    }
+
+    #[tokio::test]
+    async fn snapshot_refresh_skips_miners_being_written() {
+        let manager = MinerManager::new();
+        manager.add_miner("m".to_string(), MinerInfo::new("10.0.0.1".to_string(), "1000".to_string(), "pool".to_string()));
+        manager.refresh_snapshots();
+
+        let miner = manager.get_miner("m").unwrap();
+        let guard = miner.write().await;
+        guard.shares_accepted.store(5, Ordering::Relaxed);
+
+        // Returns at once with the last good copy instead of waiting for the writer
+        manager.refresh_snapshots();
+        let snapshots = manager.cached_snapshots();
+        assert_eq!(snapshots.len(), 1);
+        assert_eq!(snapshots[0].1.shares_accepted, 0);
+
+        drop(guard);
+        manager.refresh_snapshots();
+        assert_eq!(manager.cached_snapshots()[0].1.shares_accepted, 5);
+    }
+
+    /// Worst-case time the proxy waits for a miner's write lock while the API polls
+    /// 1000 miners in a loop, reading them directly or through the cached snapshots.
+    /// Run with `cargo test --release -- --ignored --nocapture api_polling_contention`.
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    #[ignore]
+    async fn api_polling_contention() {
+        for cached in [false, true] {
+            let manager = Arc::new(MinerManager::new());
+            for i in 0..1000 {
+                manager.add_miner(i.to_string(), MinerInfo::new("10.0.0.1".to_string(), i.to_string(), "pool".to_string()));
+            }
+            manager.refresh_snapshots();
+
+            let stop = Arc::new(std::sync::atomic::AtomicBool::new(false));
+            let poller = {
+                let (manager, stop) = (Arc::clone(&manager), Arc::clone(&stop));
+                tokio::spawn(async move {
+                    while !stop.load(Ordering::Relaxed) {
+                        if cached {
+                            manager.refresh_snapshots();
+                            let _ = manager.cached_snapshots().len();
+                        } else {
+                            for miner in manager.get_all_miners().await {
+                                let _ = miner.read().await.snapshot();
+                            }
+                        }
+                        tokio::task::yield_now().await;
+                    }
+                })
+            };
+
+            let miner = manager.get_miner("0").unwrap();
+            let mut worst = std::time::Duration::ZERO;
+            for _ in 0..10_000 {
+                let start = std::time::Instant::now();
+                miner.write().await.bytes_download.fetch_add(1, Ordering::Relaxed);
+                worst = worst.max(start.elapsed());
+                tokio::task::yield_now().await;
+            }
+            stop.store(true, Ordering::Relaxed);
+            poller.await.unwrap();
+
+            println!("{}: worst write lock wait {:?}", if cached { "cached snapshots" } else { "direct reads" }, worst);
+        }
+    }
+}
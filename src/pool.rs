@@ -1,8 +1,88 @@
 use dashmap::DashMap;
+use std::collections::VecDeque;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicI64, Ordering};
 use chrono::{DateTime, Utc};
+use serde::Serialize;
 use crate::config::{Config, PoolConfig};
 
+/// A pool with no successful ping for this long is reported unhealthy.
+const HEALTH_TIMEOUT_SECS: i64 = 90;
+/// Job IDs remembered per pool, for debugging and to count each job once
+const RECENT_JOB_IDS: usize = 16;
+/// Upper bounds of the share accept time histogram buckets
+pub const ACCEPT_TIME_BUCKETS_MS: [f64; 7] = [10.0, 50.0, 100.0, 250.0, 500.0, 1000.0, 2500.0];
+
+/// Cumulative histogram in the Prometheus sense: each bucket counts every
+/// observation at or below its bound.
+#[derive(Debug, Clone, Default)]
+pub struct Histogram {
+    pub buckets: [u64; ACCEPT_TIME_BUCKETS_MS.len()],
+    pub sum: f64,
+    pub count: u64,
+}
+
+impl Histogram {
+    pub fn observe(&mut self, value: f64) {
+        for (bucket, bound) in self.buckets.iter_mut().zip(ACCEPT_TIME_BUCKETS_MS) {
+            if value <= bound {
+                *bucket += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+/// Share arrival statistics over a window of accepted shares, for judging luck and variance.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ShareVariance {
+    pub shares: usize,
+    /// Mean and standard deviation of the time between consecutive shares
+    pub mean_interval_secs: f64,
+    pub stddev_interval_secs: f64,
+    /// Standard deviation over mean of the intervals; around 1 for steady random arrivals,
+    /// higher when shares come in bursts
+    pub interval_cv: f64,
+    /// Summed share difficulty over the network difficulty; `None` without `network_difficulty`
+    pub expected_blocks: Option<f64>,
+    /// Shares that met the network difficulty
+    pub blocks: Option<usize>,
+    /// Blocks found against expected, in percent; above 100 is lucky
+    pub luck_percent: Option<f64>,
+}
+
+impl ShareVariance {
+    /// `shares` are the `(submitted_at, difficulty)` of accepted shares, oldest first.
+    pub fn from_shares(shares: &[(DateTime<Utc>, f64)], network_difficulty: Option<f64>) -> Self {
+        let intervals: Vec<f64> = shares.windows(2)
+            .map(|pair| (pair[1].0 - pair[0].0).num_milliseconds() as f64 / 1000.0)
+            .collect();
+
+        let mut variance = ShareVariance { shares: shares.len(), ..Default::default() };
+        if !intervals.is_empty() {
+            let count = intervals.len() as f64;
+            variance.mean_interval_secs = intervals.iter().sum::<f64>() / count;
+            variance.stddev_interval_secs = (intervals.iter()
+                .map(|i| (i - variance.mean_interval_secs).powi(2))
+                .sum::<f64>() / count).sqrt();
+            if variance.mean_interval_secs > 0.0 {
+                variance.interval_cv = variance.stddev_interval_secs / variance.mean_interval_secs;
+            }
+        }
+
+        if let Some(network) = network_difficulty.filter(|d| *d > 0.0) {
+            let expected = shares.iter().map(|(_, difficulty)| difficulty).sum::<f64>() / network;
+            let blocks = shares.iter().filter(|(_, difficulty)| *difficulty >= network).count();
+            variance.expected_blocks = Some(expected);
+            variance.blocks = Some(blocks);
+            variance.luck_percent = (expected > 0.0).then(|| blocks as f64 / expected * 100.0);
+        }
+
+        variance
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct PoolMetrics {
     pub name: String,
@@ -11,9 +91,23 @@ pub struct PoolMetrics {
     pub ping_samples: Vec<f64>,
     pub avg_accept_time: f64,
     pub accept_times: Vec<f64>,
+    /// Every accept time since startup, unlike the rolling `accept_times`
+    pub accept_time_histogram: Histogram,
     pub shares_accepted: i64,
     pub shares_rejected: i64,
+    /// Submits the pool never answered within `submit_timeout_secs`
+    pub shares_lost: i64,
+    /// Accepted shares that met the pool's configured network difficulty
+    pub blocks_found: i64,
+    /// Distinct jobs announced by `mining.notify`, and how many of them had clean_jobs set
+    pub jobs_total: i64,
+    pub clean_jobs_total: i64,
+    pub recent_job_ids: VecDeque<String>,
+    /// When each job of the last minute was first seen
+    job_times: VecDeque<DateTime<Utc>>,
     pub last_ping_time: DateTime<Utc>,
+    /// Miners currently routed to this pool; shared with the `PoolConnection` guards
+    pub active_connections: Arc<AtomicI64>,
 }
 
 impl PoolMetrics {
@@ -25,9 +119,17 @@ impl PoolMetrics {
             ping_samples: Vec::new(),
             avg_accept_time: 0.0,
             accept_times: Vec::new(),
+            accept_time_histogram: Histogram::default(),
             shares_accepted: 0,
             shares_rejected: 0,
+            shares_lost: 0,
+            blocks_found: 0,
+            jobs_total: 0,
+            clean_jobs_total: 0,
+            recent_job_ids: VecDeque::new(),
+            job_times: VecDeque::new(),
             last_ping_time: Utc::now(),
+            active_connections: Arc::new(AtomicI64::new(0)),
         }
     }
 
@@ -42,31 +144,135 @@ impl PoolMetrics {
     }
 
     pub fn add_accept_time(&mut self, time: f64) {
+        self.accept_time_histogram.observe(time);
         self.accept_times.push(time);
         if self.accept_times.len() > 100 {
             self.accept_times.remove(0);
         }
         self.avg_accept_time = self.accept_times.iter().sum::<f64>() / self.accept_times.len() as f64;
     }
+
+    /// Nearest-rank percentile (0-100) over the retained ping samples.
+    pub fn ping_percentile(&self, percentile: f64) -> f64 {
+        if self.ping_samples.is_empty() {
+            return 0.0;
+        }
+
+        let mut sorted = self.ping_samples.clone();
+        sorted.sort_by(|a, b| a.total_cmp(b));
+        let rank = ((percentile / 100.0) * sorted.len() as f64).ceil() as usize;
+        sorted[rank.clamp(1, sorted.len()) - 1]
+    }
+
+    /// Counts a job from `mining.notify`. Every miner on the pool relays the same job,
+    /// so a job ID already seen recently isn't counted again.
+    pub fn record_job(&mut self, job_id: &str, clean_jobs: bool) {
+        if self.recent_job_ids.iter().any(|j| j == job_id) {
+            return;
+        }
+        self.recent_job_ids.push_back(job_id.to_string());
+        if self.recent_job_ids.len() > RECENT_JOB_IDS {
+            self.recent_job_ids.pop_front();
+        }
+
+        self.jobs_total += 1;
+        if clean_jobs {
+            self.clean_jobs_total += 1;
+        }
+
+        let now = Utc::now();
+        self.job_times.push_back(now);
+        while self.job_times.front().is_some_and(|t| (now - *t).num_seconds() >= 60) {
+            self.job_times.pop_front();
+        }
+    }
+
+    /// Distinct jobs announced over the last minute.
+    pub fn jobs_per_minute(&self) -> usize {
+        let now = Utc::now();
+        self.job_times.iter().filter(|t| (now - **t).num_seconds() < 60).count()
+    }
+
+    pub fn acceptance_rate(&self) -> f64 {
+        let total = self.shares_accepted + self.shares_rejected;
+        if total == 0 {
+            return 0.0;
+        }
+        self.shares_accepted as f64 / total as f64 * 100.0
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        !self.ping_samples.is_empty()
+            && (Utc::now() - self.last_ping_time).num_seconds() < HEALTH_TIMEOUT_SECS
+    }
+
+    /// Time of the last successful ping, if any probe has succeeded yet.
+    pub fn last_successful_ping(&self) -> Option<DateTime<Utc>> {
+        (!self.ping_samples.is_empty()).then_some(self.last_ping_time)
+    }
+
+    /// Whether a ping succeeded within the last two probe intervals, so one lost probe is tolerated.
+    pub fn is_up(&self, ping_interval_secs: u64) -> bool {
+        self.last_successful_ping()
+            .is_some_and(|t| (Utc::now() - t).num_seconds() < 2 * ping_interval_secs.max(1) as i64)
+    }
 }
 
 pub struct PoolManager {
     pools: Arc<DashMap<String, Arc<tokio::sync::RwLock<PoolMetrics>>>>,
+    /// Same counters as `PoolMetrics::active_connections`, reachable without the metrics lock
+    connections: Arc<DashMap<String, Arc<AtomicI64>>>,
+}
+
+/// Counts one miner against a pool for as long as it is held.
+pub struct PoolConnection {
+    count: Arc<AtomicI64>,
+}
+
+impl Drop for PoolConnection {
+    fn drop(&mut self) {
+        self.count.fetch_sub(1, Ordering::Relaxed);
+    }
 }
 
 impl PoolManager {
     pub fn new() -> Self {
         Self {
             pools: Arc::new(DashMap::new()),
+            connections: Arc::new(DashMap::new()),
         }
     }
 
+    fn connection_counter(&self, name: &str) -> Arc<AtomicI64> {
+        Arc::clone(self.connections.entry(name.to_string()).or_default().value())
+    }
+
+    /// Counts a miner against `name` until the returned guard is dropped.
+    pub fn track_connection(&self, name: &str) -> PoolConnection {
+        self.get_or_create(name);
+        let count = self.connection_counter(name);
+        count.fetch_add(1, Ordering::Relaxed);
+        PoolConnection { count }
+    }
+
+    pub fn active_connections(&self, name: &str) -> i64 {
+        self.connections.get(name).map_or(0, |count| count.load(Ordering::Relaxed))
+    }
+
     pub fn get_or_create(&self, name: &str) -> Arc<tokio::sync::RwLock<PoolMetrics>> {
         self.pools.entry(name.to_string())
-            .or_insert_with(|| Arc::new(tokio::sync::RwLock::new(PoolMetrics::new(name.to_string()))))
+            .or_insert_with(|| {
+                let mut metrics = PoolMetrics::new(name.to_string());
+                metrics.active_connections = self.connection_counter(name);
+                Arc::new(tokio::sync::RwLock::new(metrics))
+            })
             .clone()
     }
 
+    pub fn get(&self, name: &str) -> Option<Arc<tokio::sync::RwLock<PoolMetrics>>> {
+        self.pools.get(name).map(|entry| Arc::clone(entry.value()))
+    }
+
     pub async fn get_all_pools(&self) -> Vec<Arc<tokio::sync::RwLock<PoolMetrics>>> {
         self.pools.iter().map(|entry| Arc::clone(entry.value())).collect()
     }
@@ -79,33 +285,144 @@ impl Default for PoolManager {
 }
 
 pub async fn monitor_pool_pings(manager: Arc<PoolManager>, config: Config) {
-    let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(30));
+    let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(config.ping_interval_secs.max(1)));
 
     loop {
         interval.tick().await;
 
-        for (pool_name, pool_config) in &config.pools {
+        for pool_config in config.pools.values() {
             let mgr = Arc::clone(&manager);
-            let name = pool_name.clone();
             let cfg = pool_config.clone();
 
             tokio::spawn(async move {
-                measure_pool_ping(mgr, &name, &cfg).await;
+                measure_pool_ping(mgr, &cfg).await;
             });
         }
     }
 }
 
-async fn measure_pool_ping(manager: Arc<PoolManager>, name: &str, config: &PoolConfig) {
+async fn measure_pool_ping(manager: Arc<PoolManager>, config: &PoolConfig) {
     let start = std::time::Instant::now();
     let addr = format!("{}:{}", config.host, config.port);
 
-    if tokio::time::timeout(
-        tokio::time::Duration::from_secs(5),
+    // Only a connection made in time counts; a refused one fails fast but isn't up
+    if let Ok(Ok(_)) = tokio::time::timeout(
+        config.ping_timeout(),
         tokio::net::TcpStream::connect(&addr)
-    ).await.is_ok() {
+    ).await {
         let ping_ms = start.elapsed().as_secs_f64() * 1000.0;
-        let metrics = manager.get_or_create(name);
+        let metrics = manager.get_or_create(&config.name);
         metrics.write().await.add_ping_sample(ping_ms);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn jobs_relayed_to_several_miners_count_once() {
+        let mut metrics = PoolMetrics::new("test".to_string());
+        for _ in 0..3 {
+            metrics.record_job("a", true);
+        }
+        metrics.record_job("b", false);
+
+        assert_eq!(metrics.jobs_total, 2);
+        assert_eq!(metrics.clean_jobs_total, 1);
+        assert_eq!(metrics.jobs_per_minute(), 2);
+        assert_eq!(metrics.recent_job_ids, ["a", "b"]);
+    }
+
+    #[test]
+    fn pool_is_up_only_after_a_recent_ping() {
+        let mut metrics = PoolMetrics::new("test".to_string());
+        assert!(!metrics.is_up(30));
+        assert_eq!(metrics.last_successful_ping(), None);
+
+        metrics.add_ping_sample(20.0);
+        assert!(metrics.is_up(30));
+
+        metrics.last_ping_time = Utc::now() - chrono::Duration::seconds(61);
+        assert!(!metrics.is_up(30));
+        assert!(metrics.is_up(31));
+    }
+
+    #[tokio::test]
+    async fn only_accepted_connections_count_as_pings() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut config = crate::config::Config::default().pools["pool1"].clone();
+        config.host = "127.0.0.1".to_string();
+        config.port = listener.local_addr().unwrap().port();
+        let manager = Arc::new(PoolManager::new());
+
+        measure_pool_ping(Arc::clone(&manager), &config).await;
+        assert_eq!(manager.get(&config.name).unwrap().read().await.ping_samples.len(), 1);
+
+        // Nothing listens on the port any more, so the connect is refused
+        drop(listener);
+        measure_pool_ping(Arc::clone(&manager), &config).await;
+        assert_eq!(manager.get(&config.name).unwrap().read().await.ping_samples.len(), 1);
+    }
+
+    #[test]
+    fn ping_percentiles_use_nearest_rank() {
+        let mut metrics = PoolMetrics::new("test".to_string());
+        assert_eq!(metrics.ping_percentile(50.0), 0.0);
+
+        // Shuffled 1..=100 so the result doesn't depend on insertion order
+        for i in 0..100 {
+            metrics.add_ping_sample(((i * 37) % 100 + 1) as f64);
+        }
+        assert_eq!(metrics.ping_percentile(50.0), 50.0);
+        assert_eq!(metrics.ping_percentile(95.0), 95.0);
+        assert_eq!(metrics.ping_percentile(99.0), 99.0);
+        assert_eq!(metrics.ping_percentile(100.0), 100.0);
+    }
+
+    #[test]
+    fn ping_spike_shows_in_tail_not_median() {
+        let mut metrics = PoolMetrics::new("test".to_string());
+        for _ in 0..98 {
+            metrics.add_ping_sample(20.0);
+        }
+        metrics.add_ping_sample(900.0);
+        metrics.add_ping_sample(1000.0);
+
+        assert_eq!(metrics.ping_percentile(50.0), 20.0);
+        assert_eq!(metrics.ping_percentile(95.0), 20.0);
+        assert_eq!(metrics.ping_percentile(99.0), 900.0);
+        assert!(metrics.average_ping < 50.0);
+    }
+
+    #[test]
+    fn share_variance_reports_intervals_and_luck() {
+        let start = Utc::now();
+        let at = |secs: i64, difficulty: f64| (start + chrono::Duration::seconds(secs), difficulty);
+        let shares = [at(0, 100.0), at(10, 100.0), at(20, 1000.0), at(50, 100.0)];
+
+        let variance = ShareVariance::from_shares(&shares, Some(650.0));
+        assert_eq!(variance.shares, 4);
+        assert_eq!(variance.mean_interval_secs, 50.0 / 3.0);
+        assert!((variance.stddev_interval_secs - 9.428).abs() < 0.001);
+        assert_eq!(variance.expected_blocks, Some(2.0));
+        assert_eq!(variance.blocks, Some(1));
+        assert_eq!(variance.luck_percent, Some(50.0));
+
+        let without_network = ShareVariance::from_shares(&shares[..1], None);
+        assert_eq!(without_network, ShareVariance { shares: 1, ..Default::default() });
+    }
+
+    #[test]
+    fn accept_time_histogram_is_cumulative() {
+        let mut metrics = PoolMetrics::new("test".to_string());
+        for time in [5.0, 10.0, 75.0, 400.0, 3000.0] {
+            metrics.add_accept_time(time);
+        }
+
+        let histogram = &metrics.accept_time_histogram;
+        assert_eq!(histogram.buckets, [2, 2, 3, 3, 4, 4, 4]);
+        assert_eq!(histogram.count, 5);
+        assert_eq!(histogram.sum, 3490.0);
+    }
+}
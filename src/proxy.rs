@@ -1,113 +1,447 @@
-use tokio::net::{TcpListener, TcpStream};
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
-use std::sync::Arc;
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use std::net::SocketAddr;
+use std::sync::{Arc, OnceLock};
+use std::sync::atomic::AtomicBool;
+use std::time::Duration;
 use anyhow::Result;
-use colored::Colorize;
-use crate::{config::*, miner::*, pool::*, database::*};
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+use crate::{config::*, miner::*, pool::*, database::*, limiter::*, balancer::Balancer, banlist::{BanList, IpRange}};
+use crate::tunnel::{TunnelManager, TunnelMetrics};
+use crate::trace::{Direction, ProtocolTracer};
+use crate::throttle::Throttle;
+use crate::alert::AlertEvent;
+use crate::logger::{log_debug, log_error, log_event, log_info, log_share, log_warning, Level};
 
+/// Longest `wait` honored from a pool's `client.reconnect` before reconnecting upstream.
+const MAX_RECONNECT_WAIT: Duration = Duration::from_secs(60);
+/// First wait after a failed accept; it doubles with each failure in a row up to `ACCEPT_RETRY_MAX`.
+const ACCEPT_RETRY_BASE: Duration = Duration::from_millis(50);
+const ACCEPT_RETRY_MAX: Duration = Duration::from_secs(1);
+/// How long a refused miner gets to take the error reply before the socket is closed.
+const REFUSAL_WRITE_TIMEOUT: Duration = Duration::from_secs(2);
+/// Stratum error code for anything without a dedicated one.
+const STRATUM_OTHER_ERROR: i64 = 20;
+
+/// Stratum methods from `blocked_client_methods` and `blocked_pool_methods`.
+static BLOCKED_METHODS: OnceLock<(Vec<String>, Vec<String>)> = OnceLock::new();
+
+/// Starts dropping the configured methods; until called nothing is blocked.
+pub fn init_blocked_methods(client: &[String], pool: &[String]) {
+    let _ = BLOCKED_METHODS.set((client.to_vec(), pool.to_vec()));
+}
+
+/// Why a miner connection ended, as recorded in the `sessions` journal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// The miner closed its connection
+    ClientClosed,
+    /// The pool closed its connection
+    PoolClosed,
+    /// Reading from the miner or the pool failed
+    ReadError,
+    /// Writing to the miner or the pool failed
+    WriteError,
+    /// The miner sent nothing for `idle_timeout_secs`
+    Timeout,
+    /// Either side sent an oversized or non-UTF-8 frame
+    ProtocolError,
+    /// Disconnected through the API, or for not authorizing within `unauthorized_grace_secs`
+    Kicked,
+    /// The pool refused `mining.authorize` and `disconnect_unauthorized` is set
+    Unauthorized,
+}
+
+impl DisconnectReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DisconnectReason::ClientClosed => "client_closed",
+            DisconnectReason::PoolClosed => "pool_closed",
+            DisconnectReason::ReadError => "read_error",
+            DisconnectReason::WriteError => "write_error",
+            DisconnectReason::Timeout => "timeout",
+            DisconnectReason::ProtocolError => "protocol_error",
+            DisconnectReason::Kicked => "kicked",
+            DisconnectReason::Unauthorized => "unauthorized",
+        }
+    }
+
+    /// Classifies a `read_frame` error: socket failures versus bad frames.
+    fn from_read_error(e: &anyhow::Error) -> Self {
+        if e.downcast_ref::<std::io::Error>().is_some() {
+            DisconnectReason::ReadError
+        } else {
+            DisconnectReason::ProtocolError
+        }
+    }
+}
+
+/// Where a pool's `client.reconnect` asks us to go: `[host, port, wait]`, each optional.
+#[derive(Debug, PartialEq)]
+struct ReconnectTarget {
+    host: String,
+    port: u16,
+    wait: Duration,
+}
+
+impl ReconnectTarget {
+    fn from_request(msg: &serde_json::Value, pool_config: &PoolConfig) -> Self {
+        let params = msg.get("params").and_then(|p| p.as_array()).map(Vec::as_slice).unwrap_or_default();
+        let host = params.first().and_then(|h| h.as_str()).filter(|h| !h.is_empty())
+            .unwrap_or(&pool_config.host);
+        let port = params.get(1).and_then(|p| {
+            p.as_u64().or_else(|| p.as_str().and_then(|s| s.parse().ok()))
+        }).and_then(|p| u16::try_from(p).ok()).unwrap_or(pool_config.port);
+        let wait = params.get(2).and_then(|w| w.as_u64()).unwrap_or(0);
+
+        Self {
+            host: host.to_string(),
+            port,
+            wait: Duration::from_secs(wait).min(MAX_RECONNECT_WAIT),
+        }
+    }
+
+    /// Only the pool's own host or one listed in `reconnect_allowed_hosts` (as `host` or
+    /// `host:port`), so a pool can't point the proxy at an arbitrary address.
+    fn is_allowed(&self, pool_config: &PoolConfig, allowed_hosts: &[String]) -> bool {
+        let host_port = format!("{}:{}", self.host, self.port);
+        self.host.eq_ignore_ascii_case(&pool_config.host)
+            || allowed_hosts.iter().any(|allowed| allowed.eq_ignore_ascii_case(&self.host)
+                || allowed.eq_ignore_ascii_case(&host_port))
+    }
+}
+
+type UpstreamWriter = tokio::io::WriteHalf<Box<dyn crate::upstream::Upstream>>;
+type UpstreamReader = tokio::io::ReadHalf<Box<dyn crate::upstream::Upstream>>;
+
+#[allow(clippy::too_many_arguments)]
 pub async fn start_tunnel(
     name: &str,
     tunnel_config: TunnelConfig,
-    pool_config: PoolConfig,
+    balancer: Arc<Balancer>,
     miner_manager: Arc<MinerManager>,
     pool_manager: Arc<PoolManager>,
+    tunnel_manager: Arc<TunnelManager>,
     database: Option<Arc<Database>>,
     _tls_enabled: bool,
     _cert_file: &str,
     _key_file: &str,
-    nodebug: bool,
+    limiter: Arc<ConnectionLimiter>,
+    ban_list: Arc<BanList>,
+    draining: Arc<AtomicBool>,
+    shutdown: CancellationToken,
+    bound: Option<tokio::sync::oneshot::Sender<()>>,
 ) -> Result<()> {
-    let addr = format!("{}:{}", tunnel_config.ip, tunnel_config.port);
-    let listener = TcpListener::bind(&addr).await?;
-
-    if !nodebug {
-        println!("{}", format!("Tunnel {} listening on {} -> {}:{} ({})",
-            name, addr, pool_config.host, pool_config.port, pool_config.name).bright_blue());
+    let addr = tunnel_config.bind_addr()?;
+    let listener = bind_listener(addr, tunnel_config.listen_backlog)?;
+    if let Some(bound) = bound {
+        let _ = bound.send(());
     }
 
+    log_info(&format!("Tunnel {} listening on {} -> {}", name, addr, balancer.describe()));
+
+    let tunnel_metrics = tunnel_manager.get_or_create(name);
+    // Checked by `Config::validate`
+    let proxy_trusted: Vec<IpRange> = tunnel_config.proxy_protocol_trusted.iter()
+        .filter_map(|range| range.parse().ok())
+        .collect();
+
+    let mut accept_failures = 0u32;
     loop {
-        let (client_conn, client_addr) = listener.accept().await?;
-        
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = shutdown.cancelled() => break,
+        };
+        let (client_conn, client_addr) = match accepted {
+            Ok(accepted) => {
+                accept_failures = 0;
+                accepted
+            }
+            Err(e) => {
+                // Mostly transient, e.g. EMFILE while out of file descriptors; the pending
+                // connection stays queued, so back off instead of spinning on it
+                accept_failures += 1;
+                let backoff = (ACCEPT_RETRY_BASE * 2u32.pow(accept_failures.min(5) - 1)).min(ACCEPT_RETRY_MAX);
+                log_error(&format!("Tunnel {} failed to accept a connection ({} in a row): {}; retrying in {}ms",
+                    name, accept_failures, e, backoff.as_millis()));
+                tokio::select! {
+                    _ = tokio::time::sleep(backoff) => continue,
+                    _ = shutdown.cancelled() => break,
+                }
+            }
+        };
+
+        // Refuse newcomers while draining; existing connections keep running
+        if draining.load(std::sync::atomic::Ordering::Relaxed) {
+            log_debug(&format!("Tunnel {} draining, refused {}", name, client_addr));
+            continue;
+        }
+
+        // Behind a load balancer the real address is only known after reading the PROXY header
+        let proxy_protocol = tunnel_config.proxy_protocol
+            && (proxy_trusted.is_empty() || proxy_trusted.iter().any(|range| range.contains(client_addr.ip())));
+        let permit = if proxy_protocol {
+            None
+        } else {
+            match admit_connection(&ban_list, &limiter, client_addr) {
+                Ok(permit) => Some(permit),
+                Err(refused) => {
+                    if tunnel_config.refuse_with_stratum_error {
+                        tokio::spawn(close_refused(client_conn, refused));
+                    }
+                    continue;
+                }
+            }
+        };
+
         let miner_mgr = Arc::clone(&miner_manager);
         let pool_mgr = Arc::clone(&pool_manager);
         let db = database.clone();
-        let pool_cfg = pool_config.clone();
-        let tunnel_name = name.to_string();
+        let (pool_cfg, pool_connection) = balancer.pick(&pool_manager);
+        let tunnel_cfg = tunnel_config.clone();
+        let tunnel = Arc::clone(&tunnel_metrics);
+        let tunnel_bans = Arc::clone(&ban_list);
+        let tunnel_limiter = Arc::clone(&limiter);
 
         tokio::spawn(async move {
+            let mut client_conn = client_conn;
+            let mut client_addr = client_addr;
+            let mut permit = permit;
+
+            if proxy_protocol {
+                match crate::proxy_protocol::read_header(&mut client_conn).await {
+                    Ok(Some(real_addr)) => client_addr = real_addr,
+                    Ok(None) => {}
+                    Err(e) => log_warning(&format!("No valid PROXY header from {}, treating as direct: {}",
+                        client_addr, e)),
+                }
+                match admit_connection(&tunnel_bans, &tunnel_limiter, client_addr) {
+                    Ok(admitted) => permit = Some(admitted),
+                    Err(refused) => {
+                        if tunnel_cfg.refuse_with_stratum_error {
+                            close_refused(client_conn, refused).await;
+                        }
+                        return;
+                    }
+                }
+            }
+
+            let _permit = permit;
             if let Err(e) = handle_connection(
                 client_conn,
-                client_addr.to_string(),
-                &tunnel_name,
+                client_addr,
+                tunnel,
+                tunnel_cfg,
                 pool_cfg,
+                pool_connection,
                 miner_mgr,
                 pool_mgr,
                 db,
-                nodebug,
             ).await {
-                if !nodebug {
-                    eprintln!("{}", format!("Connection error: {}", e).red());
-                }
+                log_error(&format!("Connection error: {}", e));
             }
         });
     }
+
+    log_info(&format!("Tunnel {} stopped accepting connections", name));
+
+    Ok(())
 }
 
+/// Binds a listening socket with an explicit accept backlog.
+fn bind_listener(addr: SocketAddr, backlog: u32) -> Result<TcpListener> {
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    // As `TcpListener::bind` does, so a restart can rebind while old connections linger
+    #[cfg(unix)]
+    socket.set_reuseaddr(true)?;
+    socket.bind(addr)?;
+    Ok(socket.listen(backlog)?)
+}
+
+/// Why `admit_connection` turned a connection away.
+enum Refused {
+    Banned,
+    Limited(LimitExceeded),
+}
+
+/// Applies the ban list and connection limits, returning a permit if the connection may proceed.
+fn admit_connection(
+    ban_list: &BanList,
+    limiter: &Arc<ConnectionLimiter>,
+    client_addr: SocketAddr,
+) -> Result<ConnectionPermit, Refused> {
+    if ban_list.check(client_addr.ip()) {
+        log_warning(&format!("Rejected banned connection from {}", client_addr));
+        return Err(Refused::Banned);
+    }
+
+    limiter.try_acquire(client_addr.ip()).map_err(|e| {
+        log_warning(&format!("Dropped connection from {}: {}", client_addr, e));
+        Refused::Limited(e)
+    })
+}
+
+/// Tells a miner turned away by a limit why, as an unsolicited Stratum error, then closes.
+/// Banned addresses are closed without a reply.
+async fn close_refused(mut conn: TcpStream, refused: Refused) {
+    let Refused::Limited(limit) = refused else {
+        return;
+    };
+    let reply = serde_json::json!({
+        "id": null,
+        "result": null,
+        "error": [STRATUM_OTHER_ERROR, limit.to_string(), null],
+    });
+    let line = format!("{}\n", reply);
+    let _ = tokio::time::timeout(REFUSAL_WRITE_TIMEOUT, conn.write_all(line.as_bytes())).await;
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn handle_connection(
     client_conn: TcpStream,
-    client_addr: String,
-    _tunnel_name: &str,
+    client_addr: SocketAddr,
+    tunnel: Arc<TunnelMetrics>,
+    tunnel_config: TunnelConfig,
     pool_config: PoolConfig,
+    _pool_connection: PoolConnection,
     miner_manager: Arc<MinerManager>,
     pool_manager: Arc<PoolManager>,
     database: Option<Arc<Database>>,
-    nodebug: bool,
 ) -> Result<()> {
-    let (client_ip, client_port) = client_addr.split_once(':').unwrap_or(("unknown", "0"));
-    
-    if !nodebug {
-        println!("{}", format!("New connection from {}", client_addr).bright_cyan());
-    }
+    let client_ip = client_addr.ip().to_string();
+    let client_port = client_addr.port().to_string();
+
+    log_info(&format!("New connection from {}", client_addr));
+    client_conn.set_nodelay(tunnel_config.tcp_nodelay)?;
+
+    let pool_conn = connect_upstream(&pool_config.host, pool_config.port, &pool_config, &tunnel_config).await
+        .map_err(|e| anyhow::anyhow!("{}, closing {}", e, client_addr))?;
+    let _tunnel_connection = tunnel.track_connection();
+
+    let miner_key = client_addr.to_string();
+    let mut miner = MinerInfo::new(client_ip.clone(), client_port, pool_config.name.clone());
+    miner.difficulty_multiplier = pool_config.difficulty_multiplier;
+    miner.tunnel_name = tunnel.name.clone();
+    let disconnect = miner_manager.add_miner(miner_key.clone(), miner);
+    crate::alert::notify(AlertEvent::MinerConnected, &miner_key,
+        &format!("Miner connected from {} to {}", client_addr, pool_config.name),
+        serde_json::json!({ "ip": client_ip, "pool": pool_config.name }));
 
-    let pool_addr = format!("{}:{}", pool_config.host, pool_config.port);
-    let pool_conn = TcpStream::connect(&pool_addr).await?;
+    let tracer = tunnel_config.trace_protocol.then(|| {
+        Arc::new(ProtocolTracer::new(&miner_key, &tunnel.name, tunnel_config.trace_dir.as_deref(),
+            Arc::clone(&tunnel.trace_budget)))
+    });
 
-    let miner_key = format!("{}:{}", client_ip, client_port);
-    let miner = MinerInfo::new(client_ip.to_string(), client_port.to_string(), pool_config.name.clone());
-    miner_manager.add_miner(miner_key.clone(), miner);
+    let (client_reader, mut client_writer) = client_conn.into_split();
+    let (pool_reader, pool_writer) = tokio::io::split(pool_conn);
 
-    let (client_reader, client_writer) = client_conn.into_split();
-    let (pool_reader, pool_writer) = pool_conn.into_split();
+    // All writes to the miner go through this channel so the proxy can inject its own messages
+    let (client_tx, mut client_rx) = mpsc::unbounded_channel::<String>();
+    let tracer_write = tracer.clone();
+    let client_write = tokio::spawn(async move {
+        while let Some(line) = client_rx.recv().await {
+            if let Some(tracer) = &tracer_write {
+                tracer.trace(Direction::PoolToMiner, &line);
+            }
+            if client_writer.write_all(line.as_bytes()).await.is_err() {
+                return DisconnectReason::WriteError;
+            }
+        }
+        DisconnectReason::ClientClosed
+    });
 
-    let mut client_buf = BufReader::new(client_reader);
-    let mut pool_buf = BufReader::new(pool_reader);
+    let mut client_buf = BufReader::with_capacity(tunnel_config.read_buffer_bytes, client_reader);
+    let mut pool_buf = BufReader::with_capacity(tunnel_config.read_buffer_bytes, pool_reader);
 
     let miner_mgr_c2p = Arc::clone(&miner_manager);
     let miner_key_c2p = miner_key.clone();
     let pool_cfg_c2p = pool_config.clone();
-    let mut pool_writer_c2p = pool_writer;
+    let tunnel_c2p = Arc::clone(&tunnel);
+    let client_tx_c2p = client_tx.clone();
+    let drop_duplicate_shares = tunnel_config.drop_duplicate_shares;
+    // Shared with the pool side, which swaps in a new connection on `client.reconnect`
+    let pool_writer = Arc::new(tokio::sync::Mutex::new(pool_writer));
+    let pool_writer_c2p = Arc::clone(&pool_writer);
+
+    let max_line_bytes = tunnel_config.max_line_bytes;
+    let mut upload_throttle = tunnel_config.max_bytes_per_sec.map(Throttle::new);
+    let mut download_throttle = tunnel_config.max_bytes_per_sec.map(Throttle::new);
+    let idle_timeout = match tunnel_config.idle_timeout_secs {
+        0 => None,
+        secs => Some(Duration::from_secs(secs)),
+    };
 
     // Client to Pool
     let c2p = tokio::spawn(async move {
-        let mut line = String::new();
+        let mut buf = Vec::new();
         loop {
-            line.clear();
-            match client_buf.read_line(&mut line).await {
-                Ok(0) | Err(_) => break,
-                Ok(n) => {
-                    if pool_writer_c2p.write_all(line.as_bytes()).await.is_err() {
-                        break;
+            let read = read_frame(&mut client_buf, &mut buf, max_line_bytes);
+            let frame = match idle_timeout {
+                Some(timeout) => match tokio::time::timeout(timeout, read).await {
+                    Ok(frame) => frame,
+                    Err(_) => {
+                        log_warning(&format!("Miner {} idle for {}s, disconnecting",
+                            client_addr, timeout.as_secs()));
+                        return DisconnectReason::Timeout;
                     }
-                    
-                    if let Some(miner) = miner_mgr_c2p.get_miner(&miner_key_c2p) {
-                        let m = miner.write().await;
-                        m.bytes_upload.fetch_add(n as i64, std::sync::atomic::Ordering::Relaxed);
-                        m.packets_sent.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                },
+                None => read.await,
+            };
+            let line = match frame {
+                Ok(Some(line)) => line,
+                Ok(None) => return DisconnectReason::ClientClosed,
+                Err(e) => {
+                    log_error(&format!("Closing connection from {}: {}", client_addr, e));
+                    return DisconnectReason::from_read_error(&e);
+                }
+            };
+            if let Some(tracer) = &tracer {
+                tracer.trace(Direction::MinerToPool, &line);
+            }
+            let blocked = BLOCKED_METHODS.get().map(|(client, _)| client.as_slice()).unwrap_or_default();
+            let line = match strip_blocked_methods(&line, blocked) {
+                None => line,
+                Some((methods, rest)) => {
+                    log_warning(&format!("Dropped blocked {} from miner {}", methods.join(", "), client_addr));
+                    match rest {
+                        Some(rest) => rest,
+                        None => continue,
+                    }
+                }
+            };
+            if drop_duplicate_shares {
+                if let Some(id) = duplicate_submit(&line, &miner_key_c2p, &miner_mgr_c2p).await {
+                    // Answer as the pool would, so the miner isn't left waiting for a reply
+                    let reply = serde_json::json!({ "id": id, "result": null, "error": [22, "Duplicate share", null] });
+                    if client_tx_c2p.send(format!("{}\n", reply)).is_err() {
+                        return DisconnectReason::WriteError;
                     }
+                    continue;
+                }
+            }
+            let throttled = match upload_throttle.as_mut() {
+                Some(throttle) => throttle.take(line.len()).await,
+                None => false,
+            };
+            if pool_writer_c2p.lock().await.write_all(line.as_bytes()).await.is_err() {
+                return DisconnectReason::WriteError;
+            }
+            tunnel_c2p.bytes_upload.fetch_add(line.len() as i64, std::sync::atomic::Ordering::Relaxed);
 
-                    parse_client_message(&line, &miner_key_c2p, &miner_mgr_c2p, &pool_cfg_c2p, nodebug).await;
+            if let Some(miner) = miner_mgr_c2p.get_miner(&miner_key_c2p) {
+                let mut m = miner.write().await;
+                if throttled {
+                    mark_throttled(&mut m, "upload");
                 }
+                m.bytes_upload.fetch_add(line.len() as i64, std::sync::atomic::Ordering::Relaxed);
+                m.packets_sent.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
             }
+
+            parse_client_message(&line, &miner_key_c2p, &miner_mgr_c2p, &pool_cfg_c2p).await;
         }
     });
 
@@ -116,224 +450,1217 @@ async fn handle_connection(
     let pool_mgr_p2c = Arc::clone(&pool_manager);
     let pool_cfg_p2c = pool_config.clone();
     let db_p2c = database.clone();
-    let mut client_writer_p2c = client_writer;
+    let client_tx_p2c = client_tx.clone();
+    let pool_name_p2c = pool_config.name.clone();
+    let tunnel_p2c = Arc::clone(&tunnel);
+    let disconnect_unauthorized = tunnel_config.disconnect_unauthorized;
+    let mut initial_difficulty = tunnel_config.initial_difficulty;
+    let tunnel_cfg_p2c = tunnel_config.clone();
 
     // Pool to Client
     let p2c = tokio::spawn(async move {
-        let mut line = String::new();
+        let mut buf = Vec::new();
+        // Ids of handshake requests replayed to a new upstream, whose replies the miner must not see
+        let mut replayed: Vec<serde_json::Value> = Vec::new();
         loop {
-            line.clear();
-            match pool_buf.read_line(&mut line).await {
-                Ok(0) | Err(_) => break,
-                Ok(n) => {
-                    if client_writer_p2c.write_all(line.as_bytes()).await.is_err() {
-                        break;
+            let line = match read_frame(&mut pool_buf, &mut buf, max_line_bytes).await {
+                Ok(Some(line)) => line,
+                Ok(None) => return DisconnectReason::PoolClosed,
+                Err(e) => {
+                    log_error(&format!("Closing upstream {} for {}: {}", pool_name_p2c, client_addr, e));
+                    return DisconnectReason::from_read_error(&e);
+                }
+            };
+            // Holding back the pool's lines lets TCP push back on the pool as well
+            let throttled = match download_throttle.as_mut() {
+                Some(throttle) => throttle.take(line.len()).await,
+                None => false,
+            };
+            tunnel_p2c.bytes_download.fetch_add(line.len() as i64, std::sync::atomic::Ordering::Relaxed);
+            if let Some(miner) = miner_mgr_p2c.get_miner(&miner_key_p2c) {
+                let mut m = miner.write().await;
+                if throttled {
+                    mark_throttled(&mut m, "download");
+                }
+                m.bytes_download.fetch_add(line.len() as i64, std::sync::atomic::Ordering::Relaxed);
+                m.packets_received.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            }
+
+            let blocked = BLOCKED_METHODS.get().map(|(_, pool)| pool.as_slice()).unwrap_or_default();
+            let line = match strip_blocked_methods(&line, blocked) {
+                None => line,
+                Some((methods, rest)) => {
+                    log_warning(&format!("Dropped blocked {} from pool {} for {}",
+                        methods.join(", "), pool_name_p2c, client_addr));
+                    match rest {
+                        Some(rest) => rest,
+                        None => continue,
+                    }
+                }
+            };
+
+            if !replayed.is_empty() {
+                if let Some(reason) = swallow_replayed_reply(&line, &mut replayed, &miner_key_p2c, &miner_mgr_p2c,
+                    &client_tx_p2c, &pool_name_p2c).await
+                {
+                    match reason {
+                        Some(reason) => return reason,
+                        None => continue,
+                    }
+                }
+            }
+
+            let line = match take_reconnect(&line).filter(|_| !tunnel_cfg_p2c.reconnect_passthrough) {
+                None => line,
+                Some((request, rest)) => {
+                    let target = ReconnectTarget::from_request(&request, &pool_cfg_p2c);
+                    if !target.is_allowed(&pool_cfg_p2c, &tunnel_cfg_p2c.reconnect_allowed_hosts) {
+                        log_warning(&format!("Ignored client.reconnect from {} to {}:{} for {}: not the pool's host \
+                            or in reconnect_allowed_hosts", pool_name_p2c, target.host, target.port, client_addr));
+                    } else {
+                        let handshake = match miner_mgr_p2c.get_miner(&miner_key_p2c) {
+                            Some(miner) => miner.read().await.handshake.clone(),
+                            None => Vec::new(),
+                        };
+                        match reconnect_upstream(&target, &pool_cfg_p2c, &tunnel_cfg_p2c, &pool_writer, &handshake).await {
+                            Ok(reader) => {
+                                pool_buf = BufReader::with_capacity(tunnel_cfg_p2c.read_buffer_bytes, reader);
+                                replayed = handshake.iter().filter_map(|msg| msg.get("id").cloned()).collect();
+                                log_info(&format!("Followed client.reconnect from {} to {}:{} for {}",
+                                    pool_name_p2c, target.host, target.port, client_addr));
+                            }
+                            Err(e) => {
+                                log_error(&format!("Could not follow client.reconnect from {} for {}: {}",
+                                    pool_name_p2c, client_addr, e));
+                                return DisconnectReason::PoolClosed;
+                            }
+                        }
+                    }
+                    match rest {
+                        Some(rest) => rest,
+                        None => continue,
                     }
-                    
-                    if let Some(miner) = miner_mgr_p2c.get_miner(&miner_key_p2c) {
-                        let m = miner.write().await;
-                        m.bytes_download.fetch_add(n as i64, std::sync::atomic::Ordering::Relaxed);
-                        m.packets_received.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                }
+            };
+
+            let rewritten = parse_pool_message(&line, &miner_key_p2c, &miner_mgr_p2c, &pool_mgr_p2c,
+                &tunnel_p2c, &pool_cfg_p2c, &db_p2c).await;
+
+            // The writer task only stops early when a write to the miner failed
+            if client_tx_p2c.send(rewritten.unwrap_or(line)).is_err() {
+                return DisconnectReason::WriteError;
+            }
+
+            // Sent once the subscribe reply is through, unless the pool got there first
+            if let Some(difficulty) = initial_difficulty {
+                if let Some(miner) = miner_mgr_p2c.get_miner(&miner_key_p2c) {
+                    let mut miner = miner.write().await;
+                    if miner.pool_difficulty_received {
+                        initial_difficulty = None;
+                    } else if miner.subscribed {
+                        let message = crate::vardiff::set_difficulty_message(&serde_json::Value::Null, difficulty);
+                        if client_tx_p2c.send(message).is_err() {
+                            return DisconnectReason::WriteError;
+                        }
+                        miner.difficulty = difficulty;
+                        log_debug(&format!("Sent initial difficulty {:.2} to {} ahead of {}",
+                            difficulty, client_addr, pool_name_p2c));
+                        initial_difficulty = None;
                     }
+                }
+            }
 
-                    parse_pool_message(&line, &miner_key_p2c, &miner_mgr_p2c, &pool_mgr_p2c, 
-                        &pool_cfg_p2c, &db_p2c, nodebug).await;
+            if disconnect_unauthorized {
+                if let Some(miner) = miner_mgr_p2c.get_miner(&miner_key_p2c) {
+                    if miner.read().await.authorization_refused() {
+                        log_info(&format!("Disconnecting {}: authorization refused by {}",
+                            client_addr, pool_name_p2c));
+                        return DisconnectReason::Unauthorized;
+                    }
                 }
             }
         }
     });
 
-    tokio::select! {
-        _ = c2p => {},
-        _ = p2c => {},
+    let vardiff_task = tunnel_config.vardiff.clone().map(|cfg| {
+        tokio::spawn(crate::vardiff::run(
+            Arc::clone(&miner_manager),
+            miner_key.clone(),
+            cfg,
+            client_tx.clone(),
+        ))
+    });
+    drop(client_tx);
+
+    let c2p_abort = c2p.abort_handle();
+    let p2c_abort = p2c.abort_handle();
+    let reason = tokio::select! {
+        reason = c2p => reason.unwrap_or(DisconnectReason::ClientClosed),
+        reason = p2c => reason.unwrap_or(DisconnectReason::PoolClosed),
+        reason = client_write => reason.unwrap_or(DisconnectReason::WriteError),
+        _ = disconnect.cancelled() => {
+            log_info(&format!("Disconnecting {} on request", client_addr));
+            DisconnectReason::Kicked
+        }
+    };
+    c2p_abort.abort();
+    p2c_abort.abort();
+    if let Some(task) = vardiff_task {
+        task.abort();
     }
 
     if let Some(miner_arc) = miner_manager.remove_miner(&miner_key) {
+        let miner = miner_arc.read().await;
+        crate::alert::notify(AlertEvent::MinerDisconnected, &miner_key,
+            &format!("Miner {} disconnected from {}", miner.name, pool_config.name),
+            serde_json::json!({
+                "wallet": miner.wallet,
+                "miner": miner.name,
+                "ip": miner.ip,
+                "pool": pool_config.name,
+            }));
         if let Some(db) = database {
-            let miner = miner_arc.read().await;
-            let _ = db.save_miner(&*miner).await;
+            let _ = db.save_miner(&miner).await;
+            let session = SessionRecord {
+                wallet: miner.wallet.clone(),
+                miner_name: miner.name.clone(),
+                ip: miner.ip.clone(),
+                pool_name: pool_config.name.clone(),
+                tunnel_name: tunnel.name.clone(),
+                connected_at: miner.connected_at,
+                disconnected_at: chrono::Utc::now(),
+                reason: reason.as_str().to_string(),
+            };
+            if let Err(e) = db.log_session(&session).await {
+                log_error(&format!("Failed to record session for {}: {}", client_addr, e));
+            }
         }
     }
 
-    if !nodebug {
-        println!("{}", format!("Connection closed for {}", client_addr).yellow());
-    }
+    tunnel.record_disconnect(reason.as_str());
+    log_info(&format!("Connection closed for {} ({})", client_addr, reason.as_str()));
 
     Ok(())
 }
 
+/// Connects to the pool within `connect_timeout_secs`.
+async fn connect_upstream(
+    host: &str,
+    port: u16,
+    pool_config: &PoolConfig,
+    tunnel_config: &TunnelConfig,
+) -> Result<Box<dyn crate::upstream::Upstream>> {
+    let connect_timeout = Duration::from_secs(tunnel_config.connect_timeout_secs);
+    let connect = crate::upstream::connect(host, port, pool_config, tunnel_config.tcp_nodelay);
+    match tokio::time::timeout(connect_timeout, connect).await {
+        Ok(conn) => conn,
+        Err(_) => anyhow::bail!("pool {} ({}:{}) did not accept a connection within {}s",
+            pool_config.name, host, port, tunnel_config.connect_timeout_secs),
+    }
+}
+
+/// Follows a pool's `client.reconnect` without involving the miner: connects to `target`,
+/// swaps it in for the miner's writes and replays the miner's handshake there. Returns the
+/// new connection's read half.
+async fn reconnect_upstream(
+    target: &ReconnectTarget,
+    pool_config: &PoolConfig,
+    tunnel_config: &TunnelConfig,
+    pool_writer: &tokio::sync::Mutex<UpstreamWriter>,
+    handshake: &[serde_json::Value],
+) -> Result<UpstreamReader> {
+    tokio::time::sleep(target.wait).await;
+    let conn = connect_upstream(&target.host, target.port, pool_config, tunnel_config).await?;
+    let (reader, writer) = tokio::io::split(conn);
+
+    // Held until the handshake is out, so no submit reaches the new pool ahead of it
+    let mut pool_writer = pool_writer.lock().await;
+    *pool_writer = writer;
+    for msg in handshake {
+        pool_writer.write_all(format!("{}\n", msg).as_bytes()).await?;
+    }
+    Ok(reader)
+}
+
+/// Splits a pool's `client.reconnect` out of a frame. Returns `None` when there is none;
+/// otherwise the (last) request and what is left of the frame, if anything.
+fn take_reconnect(line: &str) -> Option<(serde_json::Value, Option<String>)> {
+    if !line.contains("client.reconnect") {
+        return None;
+    }
+    let value: serde_json::Value = serde_json::from_str(line.trim()).ok()?;
+    let (requests, kept): (Vec<&serde_json::Value>, Vec<&serde_json::Value>) = stratum_messages(&value)
+        .iter()
+        .partition(|msg| msg.get("method").and_then(|m| m.as_str()) == Some("client.reconnect"));
+    let request = (*requests.last()?).clone();
+    let rest = (!kept.is_empty()).then(|| format!("{}\n", serde_json::Value::Array(kept.into_iter().cloned().collect())));
+    Some((request, rest))
+}
+
+/// Drops the new pool's reply to a replayed handshake request, which the miner already had
+/// answered by the old one. A changed extranonce is passed on with `mining.set_extranonce`,
+/// or ends the connection if the miner can't take it. Returns `None` for other lines,
+/// `Some(None)` for a swallowed reply and `Some(Some(reason))` to disconnect.
+async fn swallow_replayed_reply(
+    line: &str,
+    replayed: &mut Vec<serde_json::Value>,
+    miner_key: &str,
+    miner_manager: &Arc<MinerManager>,
+    client_tx: &mpsc::UnboundedSender<String>,
+    pool_name: &str,
+) -> Option<Option<DisconnectReason>> {
+    let msg: serde_json::Value = serde_json::from_str(line.trim()).ok()?;
+    let id = msg.get("id").filter(|id| !id.is_null())?;
+    let index = replayed.iter().position(|pending| pending == id)?;
+    replayed.remove(index);
+
+    let Some(result) = msg.get("result").and_then(|r| r.as_array()) else {
+        return Some(None);
+    };
+    let extranonce1 = result.get(1).and_then(|e| e.as_str());
+    let extranonce2_size = result.get(2).and_then(|s| s.as_u64());
+    let (Some(extranonce1), Some(extranonce2_size)) = (extranonce1, extranonce2_size) else {
+        return Some(None);
+    };
+
+    let miner_arc = miner_manager.get_miner(miner_key)?;
+    let mut miner = miner_arc.write().await;
+    if miner.extranonce1 == extranonce1 && miner.extranonce2_size as u64 == extranonce2_size {
+        return Some(None);
+    }
+    if !miner.extranonce_subscribed {
+        log_warning(&format!("{} assigned {} ({}:{}) a new extranonce after reconnecting, which the miner \
+            can't be told about; disconnecting it", pool_name, miner.name, miner.ip, miner.port));
+        return Some(Some(DisconnectReason::PoolClosed));
+    }
+
+    let update = serde_json::json!({
+        "id": null,
+        "method": "mining.set_extranonce",
+        "params": [extranonce1, extranonce2_size],
+    });
+    if client_tx.send(format!("{}\n", update)).is_err() {
+        return Some(Some(DisconnectReason::WriteError));
+    }
+    miner.extranonce1 = extranonce1.to_string();
+    miner.extranonce2_size = extranonce2_size as u32;
+    Some(None)
+}
+
+/// The `id` of `line` if it is a single `mining.submit` of a share the miner already
+/// submitted, counting it as a duplicate. Submits inside a batch are always relayed.
+async fn duplicate_submit(line: &str, miner_key: &str, miner_manager: &Arc<MinerManager>) -> Option<serde_json::Value> {
+    let msg = serde_json::from_str::<serde_json::Value>(line).ok()?;
+    if msg.get("method").and_then(|m| m.as_str()) != Some("mining.submit") {
+        return None;
+    }
+    let params = msg.get("params")?.as_array()?;
+    let miner_arc = miner_manager.get_miner(miner_key)?;
+    let miner = miner_arc.read().await;
+    if !miner.is_duplicate_share(params) {
+        return None;
+    }
+    miner.shares_duplicate.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    log_warning(&format!("Dropped duplicate share from {} ({}:{})", miner.name, miner.ip, miner.port));
+    Some(msg.get("id").cloned().unwrap_or(serde_json::Value::Null))
+}
+
+/// Notes that `max_bytes_per_sec` delayed a line, warning when a miner starts being throttled.
+fn mark_throttled(miner: &mut MinerInfo, direction: &str) {
+    if !miner.is_throttled() {
+        log_warning(&format!("Throttling {} from {}:{} ({}) to max_bytes_per_sec",
+            direction, miner.ip, miner.port, miner.name));
+    }
+    miner.throttled_at = Some(chrono::Utc::now());
+}
+
+/// Reads one newline-terminated line of at most `max_len` bytes. Returns `None` at EOF;
+/// a trailing partial line is still returned. Oversized or non-UTF-8 lines are errors.
+async fn read_frame<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    buf: &mut Vec<u8>,
+    max_len: usize,
+) -> Result<Option<String>> {
+    buf.clear();
+    let n = reader.take(max_len as u64).read_until(b'\n', buf).await?;
+    if n == 0 {
+        return Ok(None);
+    }
+    if n >= max_len && buf.last() != Some(&b'\n') {
+        anyhow::bail!("line exceeds {} bytes", max_len);
+    }
+    match std::str::from_utf8(buf) {
+        Ok(line) => Ok(Some(line.to_string())),
+        Err(e) => anyhow::bail!("line is not valid UTF-8 ({})", e),
+    }
+}
+
 async fn parse_client_message(
     message: &str,
     miner_key: &str,
     miner_manager: &Arc<MinerManager>,
     pool_config: &PoolConfig,
-    nodebug: bool,
 ) {
-    if let Ok(msg) = serde_json::from_str::<serde_json::Value>(message) {
+    if let Ok(parsed) = serde_json::from_str::<serde_json::Value>(message) {
         if let Some(miner_arc) = miner_manager.get_miner(miner_key) {
             let mut miner = miner_arc.write().await;
-            
-            if let Some(method) = msg.get("method").and_then(|m| m.as_str()) {
-                match method {
-                    "mining.authorize" => {
-                        if let Some(params) = msg.get("params").and_then(|p| p.as_array()) {
-                            if let Some(username) = params.get(0).and_then(|u| u.as_str()) {
-                                let parts: Vec<&str> = username.split('.').collect();
-                                miner.wallet = parts[0].to_string();
-                                miner.name = username.to_string();
-                                
-                                if !nodebug {
-                                    println!("{}", format!("Miner {} ({}:{}) authorized on {} -> {}",
-                                        username, miner.ip, miner.port, pool_config.name, pool_config.name).green());
-                                }
-                            }
-                        }
+            let was_authorized = !miner.wallet.is_empty();
+
+            for msg in stratum_messages(&parsed) {
+                handle_client_message(msg, &mut miner, pool_config);
+            }
+
+            if !was_authorized && !miner.wallet.is_empty() {
+                miner_manager.record_session(&miner.wallet);
+            }
+
+            miner.last_seen = chrono::Utc::now();
+        }
+    }
+}
+
+/// Removes messages whose parsed `method` is in `blocked` from a frame. Returns `None` when
+/// nothing matched; otherwise the removed methods and what is left of the frame, if anything.
+fn strip_blocked_methods(line: &str, blocked: &[String]) -> Option<(Vec<String>, Option<String>)> {
+    if blocked.is_empty() {
+        return None;
+    }
+    let value: serde_json::Value = serde_json::from_str(line.trim()).ok()?;
+    let is_blocked = |msg: &serde_json::Value| msg.get("method")
+        .and_then(|m| m.as_str())
+        .is_some_and(|method| blocked.iter().any(|b| b == method));
+
+    let (removed, kept): (Vec<&serde_json::Value>, Vec<&serde_json::Value>) =
+        stratum_messages(&value).iter().partition(|msg| is_blocked(msg));
+    if removed.is_empty() {
+        return None;
+    }
+
+    let methods = removed.iter()
+        .filter_map(|msg| msg.get("method").and_then(|m| m.as_str()).map(str::to_string))
+        .collect();
+    let rest = (!kept.is_empty()).then(|| format!("{}\n", serde_json::Value::Array(kept.into_iter().cloned().collect())));
+    Some((methods, rest))
+}
+
+/// A JSON-RPC batch is a top-level array of calls; anything else is a single message.
+fn stratum_messages(value: &serde_json::Value) -> &[serde_json::Value] {
+    match value {
+        serde_json::Value::Array(batch) => batch,
+        single => std::slice::from_ref(single),
+    }
+}
+
+fn handle_client_message(msg: &serde_json::Value, miner: &mut MinerInfo, pool_config: &PoolConfig) {
+    if let Some(method) = msg.get("method").and_then(|m| m.as_str()) {
+        match method {
+            "mining.subscribe" => {
+                miner.subscribe_request_id = msg.get("id").cloned();
+                miner.record_handshake(msg);
+                let user_agent = msg.get("params")
+                    .and_then(|p| p.as_array())
+                    .and_then(|p| p.first())
+                    .and_then(|u| u.as_str());
+                if let Some(user_agent) = user_agent {
+                    miner.user_agent = user_agent.to_string();
+                }
+
+                log_info(&format!("Miner {}:{} subscribing to {} (agent: {})",
+                    miner.ip, miner.port, pool_config.name, user_agent.unwrap_or("unknown")));
+            }
+            "mining.extranonce.subscribe" => {
+                miner.extranonce_subscribed = true;
+                miner.record_handshake(msg);
+                log_info(&format!("Miner {}:{} subscribed to extranonce updates on {}",
+                    miner.ip, miner.port, pool_config.name));
+            }
+            "mining.authorize" => {
+                miner.authorize_request_id = msg.get("id").cloned();
+                miner.record_handshake(msg);
+                if let Some(params) = msg.get("params").and_then(|p| p.as_array()) {
+                    if let Some(username) = params.first().and_then(|u| u.as_str()) {
+                        let parts: Vec<&str> = username.split('.').collect();
+                        miner.wallet = parts[0].to_string();
+                        miner.name = username.to_string();
+                        miner.group = crate::grouping::group_for(username);
+
+                        log_info(&format!("Miner {} ({}:{}) authorizing on {}",
+                            username, miner.ip, miner.port, pool_config.name));
                     }
-                    "mining.submit" => {
-                        if let Some(params) = msg.get("params").and_then(|p| p.as_array()) {
-                            if let Some(job_id) = params.get(1).and_then(|j| j.as_str()) {
-                                miner.job_id = job_id.to_string();
-                            }
-                        }
-                        miner.last_share_time = chrono::Utc::now();
-                        miner.share_times.push(chrono::Utc::now());
-                        
-                        if !nodebug {
-                            println!("{}", format!("Share submitted: {} ({}:{}) job={} pool={}",
-                                miner.name, miner.ip, miner.port, miner.job_id, pool_config.name).bright_purple());
+                }
+            }
+            "mining.submit" => {
+                if let Some(id) = msg.get("id") {
+                    miner.record_submit(id);
+                }
+                if let Some(params) = msg.get("params").and_then(|p| p.as_array()) {
+                    if miner.remember_share(params) {
+                        miner.shares_duplicate.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                        log_warning(&format!("Duplicate share: {} ({}:{}) job={} pool={}",
+                            miner.name, miner.ip, miner.port,
+                            params.get(1).and_then(|j| j.as_str()).unwrap_or_default(), pool_config.name));
+                    }
+                    if let Some(job_id) = params.get(1).and_then(|j| j.as_str()) {
+                        miner.job_id = job_id.to_string();
+                        if miner.is_stale_job(job_id) {
+                            miner.shares_stale.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                            log_warning(&format!("Stale share: {} ({}:{}) job={} pool={}",
+                                miner.name, miner.ip, miner.port, job_id, pool_config.name));
                         }
                     }
-                    _ => {}
                 }
+                miner.last_share_time = chrono::Utc::now();
+                miner.share_times.push(chrono::Utc::now());
+
+                log_share(&format!("Share submitted: {} ({}:{}) job={} pool={}",
+                    miner.name, miner.ip, miner.port, miner.job_id, pool_config.name));
             }
-            
-            miner.last_seen = chrono::Utc::now();
+            "mining.suggest_difficulty" => {
+                let suggested = msg.get("params")
+                    .and_then(|p| p.as_array())
+                    .and_then(|p| p.first())
+                    .and_then(|d| d.as_f64());
+                if let Some(suggested) = suggested {
+                    miner.suggested_difficulty = Some(suggested);
+                    log_info(&format!("Miner {} ({}:{}) suggests difficulty {} to {} (current {})",
+                        miner.name, miner.ip, miner.port, suggested, pool_config.name, miner.difficulty));
+                }
+            }
+            _ => {}
         }
     }
 }
 
+/// Updates miner, pool and tunnel state from a pool message. Returns a replacement line
+/// when the message must be rewritten before it reaches the miner.
+#[allow(clippy::too_many_arguments)]
 async fn parse_pool_message(
     message: &str,
     miner_key: &str,
     miner_manager: &Arc<MinerManager>,
     pool_manager: &Arc<PoolManager>,
+    tunnel: &TunnelMetrics,
     pool_config: &PoolConfig,
     database: &Option<Arc<Database>>,
-    nodebug: bool,
-) {
-    if let Ok(msg) = serde_json::from_str::<serde_json::Value>(message) {
+) -> Option<String> {
+    let mut rewritten = None;
+
+    if let Ok(parsed) = serde_json::from_str::<serde_json::Value>(message) {
         if let Some(miner_arc) = miner_manager.get_miner(miner_key) {
             let mut miner = miner_arc.write().await;
-            
-            if let Some(error) = msg.get("error") {
-                if !error.is_null() {
-                    if !nodebug {
-                        println!("{}", format!("Error from pool {}: {:?}", pool_config.name, error).red());
+
+            let mut changed = false;
+            let mut messages = Vec::new();
+            for msg in stratum_messages(&parsed) {
+                let replacement = handle_pool_message(msg, &mut miner, pool_manager, tunnel, pool_config,
+                    database).await;
+                changed |= replacement.is_some();
+                messages.push(replacement.unwrap_or_else(|| msg.clone()));
+            }
+
+            if changed {
+                rewritten = match parsed {
+                    serde_json::Value::Array(_) => Some(serde_json::Value::Array(messages)),
+                    _ => messages.pop(),
+                };
+            }
+
+            miner.last_seen = chrono::Utc::now();
+        }
+    }
+
+    rewritten.map(|msg| format!("{}\n", msg))
+}
+
+fn report_block_candidate(miner: &MinerInfo, pool_config: &PoolConfig) {
+    let network = pool_config.network_difficulty.unwrap_or_default();
+    let message = format!("★ POSSIBLE BLOCK by {} ({}) on {}: share difficulty {} >= network {}",
+        miner.name, miner.wallet, pool_config.name, miner.difficulty, network);
+    let fields = serde_json::json!({
+        "wallet": miner.wallet,
+        "miner": miner.name,
+        "ip": miner.ip,
+        "pool": pool_config.name,
+        "job_id": miner.job_id,
+        "difficulty": miner.difficulty,
+        "network_difficulty": network,
+    });
+
+    let mut event = fields.clone();
+    event["event"] = "block_candidate".into();
+    log_event(Level::Block, &message, event);
+    crate::alert::notify(AlertEvent::BlockCandidate, &format!("{}:{}", pool_config.name, miner.job_id),
+        &message, fields);
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn handle_pool_message(
+    msg: &serde_json::Value,
+    miner: &mut MinerInfo,
+    pool_manager: &Arc<PoolManager>,
+    tunnel: &TunnelMetrics,
+    pool_config: &PoolConfig,
+    database: &Option<Arc<Database>>,
+) -> Option<serde_json::Value> {
+    let mut rewritten = None;
+
+    if let Some(error) = msg.get("error") {
+        if !error.is_null() {
+            log_error(&format!("Error from pool {}: {}", pool_config.name, error));
+        }
+    }
+
+    if let Some(method) = msg.get("method").and_then(|m| m.as_str()) {
+        match method {
+            "mining.notify" => {
+                if let Some(params) = msg.get("params").and_then(|p| p.as_array()) {
+                    if let Some(job_id) = params.first().and_then(|j| j.as_str()) {
+                        let clean_jobs = params.get(8).and_then(|c| c.as_bool()).unwrap_or(false);
+                        miner.record_job(job_id, clean_jobs);
+                        pool_manager.get_or_create(&pool_config.name).write().await.record_job(job_id, clean_jobs);
+                        log_debug(&format!("New job {} for miner {} from pool {}",
+                            job_id, miner.name, pool_config.name));
                     }
                 }
             }
-
-            if let Some(method) = msg.get("method").and_then(|m| m.as_str()) {
-                match method {
-                    "mining.notify" => {
-                        if let Some(params) = msg.get("params").and_then(|p| p.as_array()) {
-                            if let Some(job_id) = params.get(0).and_then(|j| j.as_str()) {
-                                miner.job_id = job_id.to_string();
-                                if !nodebug {
-                                    println!("{}", format!("New job {} for miner {} from pool {}",
-                                        job_id, miner.name, pool_config.name).bright_blue());
-                                }
+            "mining.set_difficulty" => {
+                if let Some(params) = msg.get("params").and_then(|p| p.as_array()) {
+                    if let Some(diff) = params.first().and_then(|d| d.as_f64()) {
+                        miner.difficulty = diff;
+                        miner.pool_difficulty_received = true;
+                        if let Some(proxy_diff) = miner.proxy_difficulty {
+                            // Vardiff never goes below the pool, or the pool would reject our shares
+                            let effective = proxy_diff.max(diff);
+                            miner.proxy_difficulty = Some(effective);
+                            if effective != diff {
+                                let id = msg.get("id").cloned().unwrap_or(serde_json::Value::Null);
+                                rewritten = Some(crate::vardiff::set_difficulty_request(&id, effective));
                             }
                         }
+                        log_debug(&format!("Difficulty set to {:.2} for miner {} (effective {:.2})",
+                            diff, miner.name, miner.effective_difficulty()));
                     }
-                    "mining.set_difficulty" => {
-                        if let Some(params) = msg.get("params").and_then(|p| p.as_array()) {
-                            if let Some(diff) = params.get(0).and_then(|d| d.as_f64()) {
-                                miner.difficulty = diff;
-                                if !nodebug {
-                                    println!("{}", format!("Difficulty set to {:.2} for miner {}", diff, miner.name).bright_blue());
-}
-}
+                }
+            }
+            "mining.set_extranonce" => {
+                if let Some(params) = msg.get("params").and_then(|p| p.as_array()) {
+                    if let Some(extranonce1) = params.first().and_then(|e| e.as_str()) {
+                        miner.extranonce1 = extranonce1.to_string();
+                    }
+                    if let Some(size) = params.get(1).and_then(|s| s.as_u64()) {
+                        miner.extranonce2_size = size as u32;
+                    }
+                    log_debug(&format!("Extranonce changed for miner {} on {} (extranonce1={} extranonce2_size={})",
+                        miner.name, pool_config.name, miner.extranonce1, miner.extranonce2_size));
+                    if !miner.extranonce_subscribed {
+                        log_warning(&format!("{} sent mining.set_extranonce to {} ({}:{}), which never subscribed to \
+                            extranonce updates and may ignore it", pool_config.name, miner.name, miner.ip, miner.port));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if let Some(id) = msg.get("id").filter(|id| !id.is_null()) {
+        if miner.subscribe_request_id.as_ref() == Some(id) {
+            if let Some(result) = msg.get("result").and_then(|r| r.as_array()) {
+                handle_subscribe_result(miner, result, pool_config);
+                miner.subscribed = true;
+            }
+            miner.subscribe_request_id = None;
+        }
+        if miner.authorize_request_id.as_ref() == Some(id) {
+            handle_authorize_result(miner, msg, pool_config);
+            miner.authorize_request_id = None;
+        }
+    }
+
+    if let Some(id) = msg.get("id") {
+        // Subscribe, authorize and other calls also answer with a boolean result, so
+        // only replies to a recorded `mining.submit` count as shares
+        let is_submit = miner.take_submit(id);
+        let error = msg.get("error").filter(|e| !e.is_null());
+        let accepted = match msg.get("result").and_then(|r| r.as_bool()) {
+            _ if !is_submit => None,
+            Some(accepted) => Some(accepted),
+            // Many pools answer a rejected submit with `"result": null` and an error
+            None if error.is_some() => Some(false),
+            None => None,
+        };
+
+        if let Some(accepted) = accepted {
+            let submit_time = (chrono::Utc::now() - miner.last_share_time).num_milliseconds() as f64;
+            // What the share was worked at: the proxy's difficulty when vardiff or
+            // `initial_difficulty` set one, else the pool's
+            let share_difficulty = miner.effective_difficulty();
+
+            if accepted {
+                miner.shares_accepted.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                tunnel.shares_accepted.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                miner.difficulty_accepted_sum += share_difficulty;
+                miner.first_share_at.get_or_insert_with(chrono::Utc::now);
+                miner.calculate_hashrate();
+
+                // The share only proves work at its own difficulty, so this is a heuristic
+                let block_candidate = pool_config.network_difficulty
+                    .is_some_and(|network| share_difficulty >= network);
+
+                let pool_metrics = pool_manager.get_or_create(&pool_config.name);
+                {
+                    let mut pm = pool_metrics.write().await;
+                    pm.shares_accepted += 1;
+                    pm.add_accept_time(submit_time);
+                    if block_candidate {
+                        pm.blocks_found += 1;
+                    }
+                }
+
+                if block_candidate {
+                    report_block_candidate(miner, pool_config);
+                }
+
+                if let Some(db) = database {
+                    let share = ShareRecord {
+                        wallet: miner.wallet.clone(),
+                        miner_name: miner.name.clone(),
+                        ip: miner.ip.clone(),
+                        pool_name: pool_config.name.clone(),
+                        job_id: miner.job_id.clone(),
+                        accepted: true,
+                        difficulty: share_difficulty,
+                        submitted_at: chrono::Utc::now(),
+                    };
+                    db.queue_share(share);
+                }
+
+                log_event(Level::Share, &format!("✓ ACCEPTED: {} ({}:{}) pool={} ({:.0}ms) [curr={} avg={}]",
+                    miner.name, miner.ip, miner.port, pool_config.name, submit_time,
+                    MinerInfo::format_hashrate(miner.current_hashrate),
+                    MinerInfo::format_hashrate(miner.average_hashrate)),
+                    serde_json::json!({
+                        "event": "share_result",
+                        "result": "accepted",
+                        "wallet": miner.wallet,
+                        "miner": miner.name,
+                        "ip": miner.ip,
+                        "pool": pool_config.name,
+                        "job_id": miner.job_id,
+                        "difficulty": share_difficulty,
+                        "latency_ms": submit_time,
+                        "hashrate": miner.current_hashrate,
+                    }));
+            } else {
+                miner.shares_rejected.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                tunnel.shares_rejected.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                miner.difficulty_rejected_sum += share_difficulty;
+                let reason = error.map(reject_reason).unwrap_or("other");
+                *miner.reject_reasons.entry(reason.to_string()).or_insert(0) += 1;
+
+                let pool_metrics = pool_manager.get_or_create(&pool_config.name);
+                {
+                    let mut pm = pool_metrics.write().await;
+                    pm.shares_rejected += 1;
+                }
+
+                if let Some(db) = database {
+                    let share = ShareRecord {
+                        wallet: miner.wallet.clone(),
+                        miner_name: miner.name.clone(),
+                        ip: miner.ip.clone(),
+                        pool_name: pool_config.name.clone(),
+                        job_id: miner.job_id.clone(),
+                        accepted: false,
+                        difficulty: share_difficulty,
+                        submitted_at: chrono::Utc::now(),
+                    };
+                    db.queue_share(share);
+                }
+
+                log_event(Level::Share, &format!("✗ REJECTED: {} ({}:{}) pool={} reason={}",
+                    miner.name, miner.ip, miner.port, pool_config.name, reason),
+                    serde_json::json!({
+                        "event": "share_result",
+                        "result": "rejected",
+                        "wallet": miner.wallet,
+                        "miner": miner.name,
+                        "ip": miner.ip,
+                        "pool": pool_config.name,
+                        "job_id": miner.job_id,
+                        "difficulty": share_difficulty,
+                        "reason": reason,
+                    }));
+            }
+        }
+    }
+
+    rewritten
 }
+
+/// Buckets a pool's `error` (`[code, message, ...]` or `{code, message}`) into a reject reason.
+fn reject_reason(error: &serde_json::Value) -> &'static str {
+    let (code, message) = match error {
+        serde_json::Value::Array(parts) => (parts.first(), parts.get(1)),
+        serde_json::Value::Object(fields) => (fields.get("code"), fields.get("message")),
+        other => (None, Some(other)),
+    };
+    let message = message.and_then(|m| m.as_str()).unwrap_or_default().to_lowercase();
+
+    if message.contains("low difficulty") || message.contains("low diff") {
+        "low_difficulty"
+    } else if message.contains("job not found") || message.contains("stale") {
+        "job_not_found"
+    } else if message.contains("duplicate") {
+        "duplicate"
+    } else if message.contains("unauthorized") || message.contains("not authorized") {
+        "unauthorized"
+    } else {
+        // Fall back to the conventional Stratum error codes
+        match code.and_then(|c| c.as_i64()) {
+            Some(21) => "job_not_found",
+            Some(22) => "duplicate",
+            Some(23) => "low_difficulty",
+            Some(24) => "unauthorized",
+            _ => "other",
+        }
+    }
 }
-_ => {}
+
+/// Pools refuse a worker with `"result": false` or an error; anything else counts as success.
+fn handle_authorize_result(miner: &mut MinerInfo, msg: &serde_json::Value, pool_config: &PoolConfig) {
+    let error = msg.get("error").filter(|e| !e.is_null());
+    let refused = msg.get("result").and_then(|r| r.as_bool()) == Some(false);
+    miner.authorized = error.is_none() && !refused;
+
+    if miner.authorized {
+        miner.authorized_at.get_or_insert_with(chrono::Utc::now);
+        log_info(&format!("Miner {} ({}:{}) authorized on {}",
+            miner.name, miner.ip, miner.port, pool_config.name));
+    } else {
+        log_warning(&format!("Pool {} refused authorization for {} ({}:{}): {}",
+            pool_config.name, miner.name, miner.ip, miner.port,
+            error.map_or_else(|| "result false".to_string(), |e| e.to_string())));
+    }
 }
+
+/// Parses a `mining.subscribe` result: `[[subscriptions...], extranonce1, extranonce2_size]`.
+fn handle_subscribe_result(
+    miner: &mut MinerInfo,
+    result: &[serde_json::Value],
+    pool_config: &PoolConfig,
+) {
+    if let Some(subscriptions) = result.first().and_then(|s| s.as_array()) {
+        // Either a single [method, id] pair or a list of them
+        let first = match subscriptions.first() {
+            Some(serde_json::Value::Array(pair)) => pair.get(1),
+            _ => subscriptions.get(1),
+        };
+        if let Some(sub_id) = first.and_then(|id| id.as_str()) {
+            miner.subscription_id = sub_id.to_string();
+        }
+    }
+    if let Some(extranonce1) = result.get(1).and_then(|e| e.as_str()) {
+        miner.extranonce1 = extranonce1.to_string();
+    }
+    if let Some(size) = result.get(2).and_then(|s| s.as_u64()) {
+        miner.extranonce2_size = size as u32;
+    }
+
+    log_debug(&format!("Miner {}:{} subscribed on {} (extranonce1={} extranonce2_size={})",
+        miner.ip, miner.port, pool_config.name, miner.extranonce1, miner.extranonce2_size));
 }
-        if msg.get("id").is_some() {
-            if let Some(result) = msg.get("result") {
-                if let Some(accepted) = result.as_bool() {
-                    let submit_time = (chrono::Utc::now() - miner.last_share_time).num_milliseconds() as f64;
-
-                    if accepted {
-                        miner.shares_accepted.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-                        miner.calculate_hashrate();
-
-                        let pool_metrics = pool_manager.get_or_create(&pool_config.name);
-                        {
-                            let mut pm = pool_metrics.write().await;
-                            pm.shares_accepted += 1;
-                            pm.add_accept_time(submit_time);
-                        }
 
-                        if let Some(db) = database {
-                            let db_clone = Arc::clone(db);
-                            let share = ShareRecord {
-                                wallet: miner.wallet.clone(),
-                                miner_name: miner.name.clone(),
-                                ip: miner.ip.clone(),
-                                pool_name: pool_config.name.clone(),
-                                job_id: miner.job_id.clone(),
-                                accepted: true,
-                                difficulty: miner.difficulty,
-                                submitted_at: chrono::Utc::now(),
-                            };
-                            tokio::spawn(async move {
-                                let _ = db_clone.save_share(share).await;
-                            });
-                        }
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-                        if !nodebug {
-                            println!("{}", format!("✓ ACCEPTED: {} ({}:{}) pool={} ({:.0}ms) [curr={} avg={}]",
-                                miner.name, miner.ip, miner.port, pool_config.name, submit_time,
-                                MinerInfo::format_hashrate(miner.current_hashrate),
-                                MinerInfo::format_hashrate(miner.average_hashrate)).green());
-                        }
-                    } else {
-                        miner.shares_rejected.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    fn test_pool_config() -> PoolConfig {
+        PoolConfig {
+            host: "127.0.0.1".to_string(),
+            port: 3333,
+            name: "test".to_string(),
+            network_difficulty: None,
+            difficulty_multiplier: 1.0,
+            ping_timeout_secs: None,
+            tls: false,
+            tls_sni: None,
+            tls_insecure: false,
+        }
+    }
 
-                        let pool_metrics = pool_manager.get_or_create(&pool_config.name);
-                        {
-                            let mut pm = pool_metrics.write().await;
-                            pm.shares_rejected += 1;
-                        }
+    fn test_tunnel() -> Arc<TunnelMetrics> {
+        Arc::new(TunnelMetrics::new("test".to_string()))
+    }
 
-                        if let Some(db) = database {
-                            let db_clone = Arc::clone(db);
-                            let share = ShareRecord {
-                                wallet: miner.wallet.clone(),
-                                miner_name: miner.name.clone(),
-                                ip: miner.ip.clone(),
-                                pool_name: pool_config.name.clone(),
-                                job_id: miner.job_id.clone(),
-                                accepted: false,
-                                difficulty: miner.difficulty,
-                                submitted_at: chrono::Utc::now(),
-                            };
-                            tokio::spawn(async move {
-                                let _ = db_clone.save_share(share).await;
-                            });
-                        }
+    async fn read_json_line<R: AsyncBufRead + Unpin>(reader: &mut R) -> serde_json::Value {
+        let mut line = String::new();
+        tokio::time::timeout(Duration::from_secs(5), reader.read_line(&mut line)).await
+            .expect("no line within 5s").unwrap();
+        serde_json::from_str(&line).unwrap()
+    }
 
-                        if !nodebug {
-                            println!("{}", format!("✗ REJECTED: {} ({}:{}) pool={}",
-                                miner.name, miner.ip, miner.port, pool_config.name).red());
-                        }
-                    }
-                }
-            }
+    fn test_tunnel_config(port: u16) -> TunnelConfig {
+        TunnelConfig {
+            ip: "127.0.0.1".to_string(),
+            port,
+            pool: "test".to_string(),
+            vardiff: None,
+            initial_difficulty: None,
+            reconnect_passthrough: false,
+            reconnect_allowed_hosts: Vec::new(),
+            proxy_protocol: false,
+            proxy_protocol_trusted: Vec::new(),
+            max_line_bytes: 64 * 1024,
+            read_buffer_bytes: 8 * 1024,
+            tcp_nodelay: true,
+            max_bytes_per_sec: None,
+            idle_timeout_secs: 600,
+            connect_timeout_secs: 10,
+            listen_backlog: 128,
+            disconnect_unauthorized: false,
+            refuse_with_stratum_error: false,
+            drop_duplicate_shares: false,
+            trace_protocol: false,
+            trace_dir: None,
+        }
+    }
+
+    #[test]
+    fn blocked_methods_are_dropped_by_exact_name() {
+        let blocked = vec!["client.reconnect".to_string(), "mining.set_goal".to_string()];
+
+        let single = r#"{"id":null,"method":"client.reconnect","params":["evil.example",3333,0]}"#;
+        assert_eq!(strip_blocked_methods(single, &blocked), Some((vec!["client.reconnect".to_string()], None)));
+
+        // Only the `method` field counts, not the text elsewhere in the line
+        let mentions = r#"{"id":1,"method":"mining.notify","params":["client.reconnect"]}"#;
+        assert_eq!(strip_blocked_methods(mentions, &blocked), None);
+        assert_eq!(strip_blocked_methods(r#"{"id":1,"method":"client.reconnect.x"}"#, &blocked), None);
+
+        let batch = r#"[{"id":null,"method":"mining.set_goal","params":[]},{"id":2,"result":true,"error":null}]"#;
+        let (methods, rest) = strip_blocked_methods(batch, &blocked).unwrap();
+        assert_eq!(methods, ["mining.set_goal"]);
+        assert_eq!(rest.as_deref(), Some("[{\"error\":null,\"id\":2,\"result\":true}]\n"));
+    }
+
+    #[tokio::test]
+    async fn set_extranonce_updates_miner() {
+        let miner_manager = Arc::new(MinerManager::new());
+        let pool_manager = Arc::new(PoolManager::new());
+        let pool_config = test_pool_config();
+        miner_manager.add_miner("m".to_string(),
+            MinerInfo::new("127.0.0.1".to_string(), "4000".to_string(), pool_config.name.clone()));
+
+        let frame = r#"{"id":null,"method":"mining.set_extranonce","params":["b41c2f00",4]}"#;
+        parse_pool_message(frame, "m", &miner_manager, &pool_manager, &test_tunnel(), &pool_config, &None).await;
+
+        let miner = miner_manager.get_miner("m").unwrap();
+        let miner = miner.read().await;
+        assert_eq!(miner.extranonce1, "b41c2f00");
+        assert_eq!(miner.extranonce2_size, 4);
+    }
+
+    #[tokio::test]
+    async fn batch_submit_updates_counters() {
+        let miner_manager = Arc::new(MinerManager::new());
+        let pool_config = test_pool_config();
+        miner_manager.add_miner("m".to_string(),
+            MinerInfo::new("127.0.0.1".to_string(), "4000".to_string(), pool_config.name.clone()));
+
+        let batch = r#"[{"id":2,"method":"mining.authorize","params":["wallet.rig1","x"]},{"id":3,"method":"mining.submit","params":["wallet.rig1","job1","00000000","5f5e1000","a1b2c3d4"]}]"#;
+        parse_client_message(batch, "m", &miner_manager, &pool_config).await;
+
+        let miner = miner_manager.get_miner("m").unwrap();
+        let miner = miner.read().await;
+        assert_eq!(miner.wallet, "wallet");
+        assert_eq!(miner.job_id, "job1");
+        assert_eq!(miner.share_times.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn resubmitted_share_counts_as_duplicate() {
+        let miner_manager = Arc::new(MinerManager::new());
+        let pool_config = test_pool_config();
+        miner_manager.add_miner("m".to_string(),
+            MinerInfo::new("127.0.0.1".to_string(), "4000".to_string(), pool_config.name.clone()));
+
+        let submit = |id: u32, nonce: &str| format!(
+            r#"{{"id":{},"method":"mining.submit","params":["wallet.rig1","job1","00000000","5f5e1000","{}"]}}"#, id, nonce);
+        parse_client_message(&submit(3, "a1b2c3d4"), "m", &miner_manager, &pool_config).await;
+        parse_client_message(&submit(4, "a1b2c3d5"), "m", &miner_manager, &pool_config).await;
+        assert!(duplicate_submit(&submit(5, "a1b2c3d4"), "m", &miner_manager).await.is_some_and(|id| id == 5));
+        parse_client_message(&submit(5, "a1b2c3d4"), "m", &miner_manager, &pool_config).await;
+
+        let miner = miner_manager.get_miner("m").unwrap();
+        let miner = miner.read().await;
+        assert_eq!(miner.shares_duplicate.load(std::sync::atomic::Ordering::Relaxed), 2);
+        assert_eq!(miner.share_times.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn pool_difficulty_is_a_floor_for_vardiff() {
+        let miner_manager = Arc::new(MinerManager::new());
+        let pool_manager = Arc::new(PoolManager::new());
+        let pool_config = test_pool_config();
+        let mut miner = MinerInfo::new("127.0.0.1".to_string(), "4000".to_string(), pool_config.name.clone());
+        miner.proxy_difficulty = Some(4000.0);
+        miner_manager.add_miner("m".to_string(), miner);
+        let tunnel = test_tunnel();
+
+        // Below the proxy difficulty the miner keeps working at its own
+        let frame = r#"{"id":null,"method":"mining.set_difficulty","params":[1000]}"#;
+        let rewritten = parse_pool_message(frame, "m", &miner_manager, &pool_manager, &tunnel, &pool_config, &None).await;
+        assert!(rewritten.unwrap().contains("[4000.0]"));
+
+        // Above it, the pool's difficulty wins and is relayed untouched
+        let frame = r#"{"id":null,"method":"mining.set_difficulty","params":[8000]}"#;
+        let rewritten = parse_pool_message(frame, "m", &miner_manager, &pool_manager, &tunnel, &pool_config, &None).await;
+        assert_eq!(rewritten, None);
+        let miner = miner_manager.get_miner("m").unwrap();
+        assert_eq!(miner.read().await.effective_difficulty(), 8000.0);
+    }
+
+    #[tokio::test]
+    async fn only_submit_replies_count_as_shares() {
+        let miner_manager = Arc::new(MinerManager::new());
+        let pool_manager = Arc::new(PoolManager::new());
+        let pool_config = test_pool_config();
+        miner_manager.add_miner("m".to_string(),
+            MinerInfo::new("127.0.0.1".to_string(), "4000".to_string(), pool_config.name.clone()));
+
+        let requests = r#"[{"id":2,"method":"mining.authorize","params":["wallet.rig1","x"]},{"id":3,"method":"mining.submit","params":["wallet.rig1","job1","00000000","5f5e1000","a1b2c3d4"]},{"id":4,"method":"mining.extranonce.subscribe","params":[]}]"#;
+        parse_client_message(requests, "m", &miner_manager, &pool_config).await;
+        let tunnel = test_tunnel();
+        for reply in [r#"{"id":2,"result":true,"error":null}"#, r#"{"id":3,"result":true,"error":null}"#,
+            r#"{"id":4,"result":true,"error":null}"#] {
+            parse_pool_message(reply, "m", &miner_manager, &pool_manager, &tunnel, &pool_config, &None).await;
+        }
+
+        let miner = miner_manager.get_miner("m").unwrap();
+        let miner = miner.read().await;
+        assert!(miner.authorized);
+        assert!(miner.extranonce_subscribed);
+        assert!(miner.time_to_first_share_secs().is_some_and(|secs| secs >= 0.0));
+        assert_eq!(miner.shares_accepted.load(std::sync::atomic::Ordering::Relaxed), 1);
+        assert_eq!(miner.shares_rejected.load(std::sync::atomic::Ordering::Relaxed), 0);
+        assert_eq!(miner.difficulty_accepted_sum, miner.difficulty);
+        assert_eq!(tunnel.shares_accepted.load(std::sync::atomic::Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn shares_count_at_the_difficulty_they_were_worked_at() {
+        let miner_manager = Arc::new(MinerManager::new());
+        let pool_manager = Arc::new(PoolManager::new());
+        let pool_config = test_pool_config();
+        let mut miner = MinerInfo::new("127.0.0.1".to_string(), "4000".to_string(), pool_config.name.clone());
+        miner.difficulty = 1000.0;
+        miner.proxy_difficulty = Some(4000.0);
+        miner_manager.add_miner("m".to_string(), miner);
+
+        let submits = r#"[{"id":3,"method":"mining.submit","params":["wallet.rig1","job1","00000000","5f5e1000","a1b2c3d4"]},{"id":4,"method":"mining.submit","params":["wallet.rig1","job1","00000000","5f5e1000","a1b2c3d5"]}]"#;
+        parse_client_message(submits, "m", &miner_manager, &pool_config).await;
+        let tunnel = test_tunnel();
+        for reply in [r#"{"id":3,"result":true,"error":null}"#, r#"{"id":4,"result":null,"error":[23,"Low difficulty share",null]}"#] {
+            parse_pool_message(reply, "m", &miner_manager, &pool_manager, &tunnel, &pool_config, &None).await;
+        }
+
+        let miner = miner_manager.get_miner("m").unwrap();
+        let miner = miner.read().await;
+        assert_eq!(miner.difficulty_accepted_sum, 4000.0);
+        assert_eq!(miner.difficulty_rejected_sum, 4000.0);
+    }
+
+    #[tokio::test]
+    async fn listener_binds_with_backlog() {
+        let listener = bind_listener("127.0.0.1:0".parse().unwrap(), 16).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (connected, accepted) = tokio::join!(TcpStream::connect(addr), listener.accept());
+        assert_eq!(connected.unwrap().local_addr().unwrap(), accepted.unwrap().1);
+    }
+
+    #[tokio::test]
+    async fn oversized_line_closes_connection() {
+        let pool = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let tunnel = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let pool_config = PoolConfig {
+            port: pool.local_addr().unwrap().port(),
+            ..test_pool_config()
+        };
+        let tunnel_config = test_tunnel_config(tunnel.local_addr().unwrap().port());
+
+        let mut client = TcpStream::connect(tunnel.local_addr().unwrap()).await.unwrap();
+        let (client_conn, client_addr) = tunnel.accept().await.unwrap();
+        let miner_manager = Arc::new(MinerManager::new());
+        let pool_manager = Arc::new(PoolManager::new());
+        let tunnel = test_tunnel();
+        let connection = tokio::spawn(handle_connection(client_conn, client_addr, Arc::clone(&tunnel), tunnel_config,
+            pool_config, pool_manager.track_connection("test"), Arc::clone(&miner_manager),
+            Arc::clone(&pool_manager), None));
+        let _upstream = pool.accept().await.unwrap();
+
+        // The proxy may hang up mid-write, so a failed write is expected
+        let _ = client.write_all(&vec![b'a'; 1024 * 1024]).await;
+
+        let mut byte = [0u8; 1];
+        let read = tokio::time::timeout(Duration::from_secs(5), client.read(&mut byte)).await
+            .expect("connection was not closed");
+        assert!(matches!(read, Ok(0) | Err(_)));
+
+        connection.await.unwrap().unwrap();
+        assert!(miner_manager.get_miner(&client_addr.to_string()).is_none());
+        assert_eq!(pool_manager.active_connections("test"), 0);
+        assert_eq!(tunnel.active_connections.load(std::sync::atomic::Ordering::Relaxed), 0);
+        assert_eq!(tunnel.disconnect_counts().get("protocol_error"), Some(&1));
+    }
+
+    #[tokio::test]
+    async fn initial_difficulty_follows_subscribe_reply() {
+        let pool = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let tunnel = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let pool_config = PoolConfig {
+            port: pool.local_addr().unwrap().port(),
+            ..test_pool_config()
+        };
+        let tunnel_config = TunnelConfig {
+            initial_difficulty: Some(512.0),
+            ..test_tunnel_config(tunnel.local_addr().unwrap().port())
+        };
+
+        let client = TcpStream::connect(tunnel.local_addr().unwrap()).await.unwrap();
+        let (client_conn, client_addr) = tunnel.accept().await.unwrap();
+        let miner_manager = Arc::new(MinerManager::new());
+        let pool_manager = Arc::new(PoolManager::new());
+        tokio::spawn(handle_connection(client_conn, client_addr, test_tunnel(), tunnel_config,
+            pool_config, pool_manager.track_connection("test"), Arc::clone(&miner_manager), pool_manager, None));
+        let (upstream, _) = pool.accept().await.unwrap();
+        let (upstream_reader, mut upstream_writer) = upstream.into_split();
+        let mut upstream_reader = BufReader::new(upstream_reader);
+        let (client_reader, mut client_writer) = client.into_split();
+        let mut client_reader = BufReader::new(client_reader);
+
+        let mut line = String::new();
+        client_writer.write_all(b"{\"id\":1,\"method\":\"mining.subscribe\",\"params\":[]}\n").await.unwrap();
+        upstream_reader.read_line(&mut line).await.unwrap();
+        upstream_writer.write_all(b"{\"id\":1,\"result\":[[],\"08000002\",4],\"error\":null}\n").await.unwrap();
+        upstream_writer.write_all(b"{\"id\":null,\"method\":\"mining.set_difficulty\",\"params\":[2048]}\n").await.unwrap();
+
+        assert_eq!(read_json_line(&mut client_reader).await["id"], 1);
+        let injected = read_json_line(&mut client_reader).await;
+        assert_eq!(injected["method"], "mining.set_difficulty");
+        assert_eq!(injected["params"][0], 512.0);
+        assert_eq!(read_json_line(&mut client_reader).await["params"][0], 2048);
+
+        let miner = miner_manager.get_miner(&client_addr.to_string()).unwrap();
+        assert_eq!(miner.read().await.difficulty, 2048.0);
+    }
+
+    #[test]
+    fn reconnect_target_must_be_allowed() {
+        let pool_config = test_pool_config();
+        let request = serde_json::json!({"id": null, "method": "client.reconnect", "params": ["10.0.0.9", 4444, 600]});
+        let target = ReconnectTarget::from_request(&request, &pool_config);
+        assert_eq!(target, ReconnectTarget {
+            host: "10.0.0.9".to_string(),
+            port: 4444,
+            wait: MAX_RECONNECT_WAIT,
+        });
+        assert!(!target.is_allowed(&pool_config, &[]));
+        assert!(!target.is_allowed(&pool_config, &["10.0.0.9:3333".to_string()]));
+        assert!(target.is_allowed(&pool_config, &["10.0.0.9:4444".to_string()]));
+        assert!(target.is_allowed(&pool_config, &["10.0.0.9".to_string()]));
+
+        let same_host = serde_json::json!({"id": null, "method": "client.reconnect", "params": []});
+        assert!(ReconnectTarget::from_request(&same_host, &pool_config).is_allowed(&pool_config, &[]));
+    }
+
+    #[tokio::test]
+    async fn client_reconnect_is_followed_upstream() {
+        let pool = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let moved_pool = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let tunnel = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let pool_config = PoolConfig {
+            port: pool.local_addr().unwrap().port(),
+            ..test_pool_config()
+        };
+        let tunnel_config = test_tunnel_config(tunnel.local_addr().unwrap().port());
+
+        let client = TcpStream::connect(tunnel.local_addr().unwrap()).await.unwrap();
+        let (client_conn, client_addr) = tunnel.accept().await.unwrap();
+        let pool_manager = Arc::new(PoolManager::new());
+        tokio::spawn(handle_connection(client_conn, client_addr, test_tunnel(), tunnel_config,
+            pool_config, pool_manager.track_connection("test"), Arc::new(MinerManager::new()), pool_manager, None));
+        let (upstream, _) = pool.accept().await.unwrap();
+        let (upstream_reader, mut upstream_writer) = upstream.into_split();
+        let mut upstream_reader = BufReader::new(upstream_reader);
+        let (client_reader, mut client_writer) = client.into_split();
+        let mut client_reader = BufReader::new(client_reader);
+
+        client_writer.write_all(b"{\"id\":1,\"method\":\"mining.subscribe\",\"params\":[]}\n").await.unwrap();
+        assert_eq!(read_json_line(&mut upstream_reader).await["method"], "mining.subscribe");
+        upstream_writer.write_all(b"{\"id\":1,\"result\":[[],\"08000002\",4],\"error\":null}\n").await.unwrap();
+        assert_eq!(read_json_line(&mut client_reader).await["id"], 1);
+
+        let reconnect = serde_json::json!({
+            "id": null,
+            "method": "client.reconnect",
+            "params": ["127.0.0.1", moved_pool.local_addr().unwrap().port(), 0],
+        });
+        upstream_writer.write_all(format!("{}\n", reconnect).as_bytes()).await.unwrap();
+
+        // The new pool gets the miner's subscribe again and its reply stays with the proxy
+        let (moved, _) = moved_pool.accept().await.unwrap();
+        let (moved_reader, mut moved_writer) = moved.into_split();
+        let mut moved_reader = BufReader::new(moved_reader);
+        assert_eq!(read_json_line(&mut moved_reader).await["method"], "mining.subscribe");
+        moved_writer.write_all(b"{\"id\":1,\"result\":[[],\"08000002\",4],\"error\":null}\n").await.unwrap();
+        moved_writer.write_all(b"{\"id\":null,\"method\":\"mining.set_difficulty\",\"params\":[64]}\n").await.unwrap();
+        assert_eq!(read_json_line(&mut client_reader).await["method"], "mining.set_difficulty");
+
+        client_writer.write_all(b"{\"id\":2,\"method\":\"mining.authorize\",\"params\":[\"wallet.rig1\",\"x\"]}\n").await.unwrap();
+        assert_eq!(read_json_line(&mut moved_reader).await["method"], "mining.authorize");
+    }
+
+    #[tokio::test]
+    async fn kicked_miner_is_disconnected() {
+        let pool = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let tunnel = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let pool_config = PoolConfig {
+            port: pool.local_addr().unwrap().port(),
+            ..test_pool_config()
+        };
+        let tunnel_config = test_tunnel_config(tunnel.local_addr().unwrap().port());
+
+        let mut client = TcpStream::connect(tunnel.local_addr().unwrap()).await.unwrap();
+        let (client_conn, client_addr) = tunnel.accept().await.unwrap();
+        let miner_manager = Arc::new(MinerManager::new());
+        let tunnel = test_tunnel();
+        let pool_manager = Arc::new(PoolManager::new());
+        let connection = tokio::spawn(handle_connection(client_conn, client_addr, Arc::clone(&tunnel), tunnel_config,
+            pool_config, pool_manager.track_connection("test"), Arc::clone(&miner_manager), pool_manager, None));
+        let _upstream = pool.accept().await.unwrap();
+
+        while miner_manager.get_miner(&client_addr.to_string()).is_none() {
+            tokio::task::yield_now().await;
         }
+        assert!(miner_manager.disconnect_miner(&client_addr.to_string()));
+
+        let mut byte = [0u8; 1];
+        let read = tokio::time::timeout(Duration::from_secs(5), client.read(&mut byte)).await
+            .expect("connection was not closed");
+        assert!(matches!(read, Ok(0) | Err(_)));
+        connection.await.unwrap().unwrap();
+        assert_eq!(tunnel.disconnect_counts().get("kicked"), Some(&1));
+    }
+
+    #[tokio::test]
+    async fn limited_miners_are_told_why() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (conn, _) = listener.accept().await.unwrap();
+        close_refused(conn, Refused::Limited(LimitExceeded::ServerFull(500))).await;
 
-        miner.last_seen = chrono::Utc::now();
+        let reply = read_json_line(&mut BufReader::new(client)).await;
+        assert_eq!(reply["id"], serde_json::Value::Null);
+        assert_eq!(reply["error"], serde_json::json!([20, "server full (500 connections max)", null]));
     }
 }
-}
\ No newline at end of file
@@ -0,0 +1,195 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use anyhow::{bail, Result};
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpStream;
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+const V1_MAX_LEN: usize = 107;
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+const V2_HEADER_LEN: usize = 16;
+const HEADER_TIMEOUT: tokio::time::Duration = tokio::time::Duration::from_secs(5);
+
+/// Consumes a PROXY protocol v1 or v2 header from the front of `stream` and returns
+/// the original client address. Returns `Ok(None)` when no header is present, leaving
+/// the stream untouched, or when the header carries no address (LOCAL / UNKNOWN).
+pub async fn read_header(stream: &mut TcpStream) -> Result<Option<SocketAddr>> {
+    read_header_within(stream, HEADER_TIMEOUT).await
+}
+
+/// A header cut short stays in the socket buffer for `peek` to see again, so it only
+/// fails once `timeout` runs out.
+async fn read_header_within(stream: &mut TcpStream, timeout: tokio::time::Duration) -> Result<Option<SocketAddr>> {
+    tokio::time::timeout(timeout, read_header_inner(stream)).await?
+}
+
+async fn read_header_inner(stream: &mut TcpStream) -> Result<Option<SocketAddr>> {
+    let mut buf = [0u8; 232];
+
+    let n = peek_at_least(stream, &mut buf, V1_PREFIX.len()).await?;
+    if buf[..n].starts_with(V1_PREFIX) {
+        let n = loop {
+            let n = stream.peek(&mut buf[..V1_MAX_LEN]).await?;
+            if let Some(end) = buf[..n].windows(2).position(|w| w == b"\r\n") {
+                break end + 2;
+            }
+            if n >= V1_MAX_LEN {
+                bail!("PROXY v1 header too long");
+            }
+            tokio::time::sleep(tokio::time::Duration::from_millis(5)).await;
+        };
+        let mut header = vec![0u8; n];
+        stream.read_exact(&mut header).await?;
+        return parse_v1(&header);
+    }
+
+    let n = peek_at_least(stream, &mut buf, V2_HEADER_LEN).await?;
+    if n >= V2_HEADER_LEN && buf.starts_with(V2_SIGNATURE) {
+        let len = u16::from_be_bytes([buf[14], buf[15]]) as usize;
+        let mut header = vec![0u8; V2_HEADER_LEN + len];
+        stream.read_exact(&mut header).await?;
+        return parse_v2(&header);
+    }
+
+    Ok(None)
+}
+
+/// Peeks until at least `min` bytes are buffered, or the data can no longer be a header.
+async fn peek_at_least(stream: &TcpStream, buf: &mut [u8], min: usize) -> Result<usize> {
+    loop {
+        let n = stream.peek(buf).await?;
+        if n == 0 {
+            bail!("connection closed before PROXY header");
+        }
+        let could_be_header = V1_PREFIX.starts_with(&buf[..n.min(V1_PREFIX.len())])
+            || V2_SIGNATURE.starts_with(&buf[..n.min(V2_SIGNATURE.len())]);
+        if n >= min || !could_be_header {
+            return Ok(n);
+        }
+        tokio::time::sleep(tokio::time::Duration::from_millis(5)).await;
+    }
+}
+
+/// `PROXY TCP4 <src> <dst> <sport> <dport>\r\n` or `PROXY UNKNOWN ...\r\n`
+fn parse_v1(header: &[u8]) -> Result<Option<SocketAddr>> {
+    let line = std::str::from_utf8(header)?.trim_end();
+    let parts: Vec<&str> = line.split(' ').collect();
+
+    match parts.get(1) {
+        Some(&"UNKNOWN") => Ok(None),
+        Some(&"TCP4") | Some(&"TCP6") if parts.len() == 6 => {
+            let ip: IpAddr = parts[2].parse()?;
+            let port: u16 = parts[4].parse()?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => bail!("malformed PROXY v1 header: {}", line),
+    }
+}
+
+fn parse_v2(header: &[u8]) -> Result<Option<SocketAddr>> {
+    let version_command = header[12];
+    if version_command >> 4 != 2 {
+        bail!("unsupported PROXY v2 version {}", version_command >> 4);
+    }
+    // LOCAL connections (e.g. load balancer health checks) carry no client address
+    if version_command & 0x0f == 0 {
+        return Ok(None);
+    }
+
+    let addr = &header[V2_HEADER_LEN..];
+    match header[13] {
+        // TCP over IPv4: src(4) dst(4) sport(2) dport(2)
+        0x11 if addr.len() >= 12 => {
+            let ip = Ipv4Addr::new(addr[0], addr[1], addr[2], addr[3]);
+            let port = u16::from_be_bytes([addr[8], addr[9]]);
+            Ok(Some(SocketAddr::new(IpAddr::V4(ip), port)))
+        }
+        // TCP over IPv6: src(16) dst(16) sport(2) dport(2)
+        0x21 if addr.len() >= 36 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&addr[..16]);
+            let port = u16::from_be_bytes([addr[32], addr[33]]);
+            Ok(Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), port)))
+        }
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+
+    /// Sends `bytes` from a client and returns what `read_header` made of them on the
+    /// accepted side, plus whatever the server can read afterwards.
+    async fn read_from(bytes: &[u8]) -> (Result<Option<SocketAddr>>, Vec<u8>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = tokio::net::TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+
+        client.write_all(bytes).await.unwrap();
+        client.shutdown().await.unwrap();
+        let header = read_header_within(&mut server, tokio::time::Duration::from_millis(200)).await;
+        let mut rest = Vec::new();
+        let _ = server.read_to_end(&mut rest).await;
+        (header, rest)
+    }
+
+    fn v2_header(command: u8, family: u8, addr: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.push(0x20 | command);
+        header.push(family);
+        header.extend_from_slice(&(addr.len() as u16).to_be_bytes());
+        header.extend_from_slice(addr);
+        header
+    }
+
+    #[tokio::test]
+    async fn v1_header_is_consumed() {
+        let (addr, rest) = read_from(b"PROXY TCP4 203.0.113.7 10.0.0.1 51234 3333\r\n{\"id\":1}\n").await;
+        assert_eq!(addr.unwrap(), Some("203.0.113.7:51234".parse().unwrap()));
+        assert_eq!(rest, b"{\"id\":1}\n");
+
+        let (addr, _) = read_from(b"PROXY TCP6 2001:db8::7 2001:db8::1 51234 3333\r\n").await;
+        assert_eq!(addr.unwrap(), Some("[2001:db8::7]:51234".parse().unwrap()));
+
+        let (addr, _) = read_from(b"PROXY UNKNOWN\r\n").await;
+        assert_eq!(addr.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn v2_header_is_consumed() {
+        let addr = [203, 0, 113, 7, 10, 0, 0, 1, 0xc8, 0x22, 0x0d, 0x05];
+        let mut bytes = v2_header(0x1, 0x11, &addr);
+        bytes.extend_from_slice(b"{\"id\":1}\n");
+        let (addr, rest) = read_from(&bytes).await;
+        assert_eq!(addr.unwrap(), Some("203.0.113.7:51234".parse().unwrap()));
+        assert_eq!(rest, b"{\"id\":1}\n");
+
+        // LOCAL, as sent by load balancer health checks
+        let (addr, _) = read_from(&v2_header(0x0, 0x00, &[])).await;
+        assert_eq!(addr.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn no_header_leaves_stream_untouched() {
+        let (addr, rest) = read_from(b"{\"id\":1,\"method\":\"mining.subscribe\"}\n").await;
+        assert_eq!(addr.unwrap(), None);
+        assert_eq!(rest, b"{\"id\":1,\"method\":\"mining.subscribe\"}\n");
+    }
+
+    #[tokio::test]
+    async fn malformed_or_truncated_headers_fail() {
+        assert!(read_from(b"PROXY TCP4 not-an-ip 10.0.0.1 51234 3333\r\n").await.0.is_err());
+        assert!(read_from(b"PROXY TCP4 203.0.113.7\r\n").await.0.is_err());
+        assert!(read_from(&[b"PROXY TCP4 ".as_slice(), &[b'1'; 200], b"\r\n"].concat()).await.0.is_err());
+        // Closed before the line ends or before the v2 address block arrives
+        assert!(read_from(b"PROXY TCP4 203.0.113.7 10.0.0.1").await.0.is_err());
+        assert!(read_from(&v2_header(0x1, 0x11, &[203, 0, 113, 7])[..V2_HEADER_LEN + 2]).await.0.is_err());
+        assert!(read_from(&V2_SIGNATURE[..6]).await.0.is_err());
+
+        let mut wrong_version = v2_header(0x1, 0x11, &[0; 12]);
+        wrong_version[12] = 0x11;
+        assert!(read_from(&wrong_version).await.0.is_err());
+    }
+}
@@ -0,0 +1,52 @@
+use std::time::{Duration, Instant};
+
+/// Token bucket capping one direction of a connection at `rate` bytes per second,
+/// with bursts of up to one second's worth. A line larger than the bucket still goes
+/// through, and the debt is paid by waiting.
+#[derive(Debug)]
+pub struct Throttle {
+    rate: f64,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl Throttle {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        let rate = bytes_per_sec.max(1) as f64;
+        Self {
+            rate,
+            tokens: rate,
+            refilled_at: Instant::now(),
+        }
+    }
+
+    /// Waits until `bytes` fit within the rate. Returns whether it had to wait.
+    pub async fn take(&mut self, bytes: usize) -> bool {
+        let now = Instant::now();
+        let refill = now.duration_since(self.refilled_at).as_secs_f64() * self.rate;
+        self.tokens = (self.tokens + refill).min(self.rate);
+        self.refilled_at = now;
+
+        self.tokens -= bytes as f64;
+        if self.tokens >= 0.0 {
+            return false;
+        }
+        tokio::time::sleep(Duration::from_secs_f64(-self.tokens / self.rate)).await;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn waits_once_the_burst_is_spent() {
+        let mut throttle = Throttle::new(1000);
+        assert!(!throttle.take(1000).await);
+
+        let start = Instant::now();
+        assert!(throttle.take(100).await);
+        assert!(start.elapsed() >= Duration::from_millis(90));
+    }
+}
@@ -0,0 +1,261 @@
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+use crate::logger::{log_debug, log_warning};
+
+/// Lines traced per connection in each `TRACE_WINDOW`; the rest are counted and skipped.
+const TRACE_LINES_PER_WINDOW: u32 = 50;
+const TRACE_WINDOW: Duration = Duration::from_secs(1);
+/// Longer lines are cut to this many bytes.
+const TRACE_MAX_LINE_BYTES: usize = 1024;
+/// A per-miner trace file stops growing at this size.
+const TRACE_FILE_MAX_BYTES: u64 = 16 * 1024 * 1024;
+/// All trace files of one tunnel together stop growing at this size.
+pub const TRACE_TUNNEL_MAX_BYTES: u64 = 256 * 1024 * 1024;
+/// Lines waiting for a file writer; while it is this far behind, new lines skip the file.
+const TRACE_QUEUE_LINES: usize = 1024;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Direction {
+    MinerToPool,
+    PoolToMiner,
+}
+
+impl Direction {
+    fn arrow(self) -> &'static str {
+        match self {
+            Direction::MinerToPool => "miner->pool",
+            Direction::PoolToMiner => "pool->miner",
+        }
+    }
+}
+
+/// Trace-file bytes one tunnel may still write, shared by its connections.
+#[derive(Debug)]
+pub struct TraceBudget {
+    remaining: AtomicU64,
+}
+
+impl TraceBudget {
+    pub fn new(bytes: u64) -> Self {
+        Self { remaining: AtomicU64::new(bytes) }
+    }
+
+    /// Takes `bytes` from the budget, or nothing if fewer remain.
+    fn take(&self, bytes: u64) -> bool {
+        self.remaining.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| left.checked_sub(bytes)).is_ok()
+    }
+
+    fn give_back(&self, bytes: u64) {
+        self.remaining.fetch_add(bytes, Ordering::Relaxed);
+    }
+}
+
+/// Logs the raw Stratum lines of one connection for protocol debugging.
+pub struct ProtocolTracer {
+    miner_key: String,
+    file: Option<TraceFile>,
+    state: Mutex<TraceState>,
+}
+
+/// Lines for a trace file, written by `write_trace_file` so tracing never waits on the disk.
+struct TraceFile {
+    lines: mpsc::Sender<String>,
+    budget: Arc<TraceBudget>,
+}
+
+struct TraceState {
+    file_bytes: u64,
+    /// The file is full or the tunnel's budget is spent; lines only go to the log
+    file_closed: bool,
+    window_start: Instant,
+    window_lines: u32,
+    suppressed: u64,
+}
+
+impl ProtocolTracer {
+    /// With `dir` set, lines also go to `<dir>/<tunnel>-<miner>.log`, as long as the tunnel's
+    /// `budget` lasts.
+    pub fn new(miner_key: &str, tunnel_name: &str, dir: Option<&str>, budget: Arc<TraceBudget>) -> Self {
+        let file = dir.map(|dir| {
+            let name = format!("{}-{}.log", tunnel_name, miner_key)
+                .replace(|c: char| !c.is_ascii_alphanumeric() && c != '.' && c != '-', "_");
+            let (lines, queued) = mpsc::channel(TRACE_QUEUE_LINES);
+            tokio::spawn(write_trace_file(Path::new(dir).join(name), queued));
+            TraceFile { lines, budget }
+        });
+
+        Self {
+            miner_key: miner_key.to_string(),
+            file,
+            state: Mutex::new(TraceState {
+                file_bytes: 0,
+                file_closed: false,
+                window_start: Instant::now(),
+                window_lines: 0,
+                suppressed: 0,
+            }),
+        }
+    }
+
+    pub fn trace(&self, direction: Direction, line: &str) {
+        let Ok(mut state) = self.state.lock() else {
+            return;
+        };
+
+        if state.window_start.elapsed() >= TRACE_WINDOW {
+            if state.suppressed > 0 {
+                let note = format!("{} lines not traced (over {} per second)",
+                    state.suppressed, TRACE_LINES_PER_WINDOW);
+                self.emit(&mut state, "trace", &note);
+            }
+            state.window_start = Instant::now();
+            state.window_lines = 0;
+            state.suppressed = 0;
+        }
+        if state.window_lines >= TRACE_LINES_PER_WINDOW {
+            state.suppressed += 1;
+            return;
+        }
+        state.window_lines += 1;
+
+        let line = line.trim_end();
+        if line.len() > TRACE_MAX_LINE_BYTES {
+            let mut end = TRACE_MAX_LINE_BYTES;
+            while !line.is_char_boundary(end) {
+                end -= 1;
+            }
+            let cut = format!("{}... ({} bytes)", &line[..end], line.len());
+            self.emit(&mut state, direction.arrow(), &cut);
+        } else {
+            self.emit(&mut state, direction.arrow(), line);
+        }
+    }
+
+    fn emit(&self, state: &mut TraceState, label: &str, text: &str) {
+        let message = format!("[{} {}] {}", label, self.miner_key, text);
+        log_debug(&message);
+
+        let Some(file) = &self.file else {
+            return;
+        };
+        if state.file_closed {
+            return;
+        }
+        let entry = format!("{} {}\n", chrono::Local::now().format("%Y-%m-%d %H:%M:%S%.3f"), message);
+        let bytes = entry.len() as u64;
+
+        // The closing notes aren't counted, so the limits can be overrun by one short line
+        if state.file_bytes + bytes > TRACE_FILE_MAX_BYTES {
+            state.file_closed = true;
+            let _ = file.lines.try_send(format!("trace file reached {} bytes, no longer written\n",
+                TRACE_FILE_MAX_BYTES));
+            return;
+        }
+        if !file.budget.take(bytes) {
+            state.file_closed = true;
+            let _ = file.lines.try_send(format!("tunnel trace files reached {} bytes, no longer written\n",
+                TRACE_TUNNEL_MAX_BYTES));
+            return;
+        }
+        match file.lines.try_send(entry) {
+            Ok(()) => state.file_bytes += bytes,
+            // The writer is behind or gave up; the line is still in the log
+            Err(_) => file.budget.give_back(bytes),
+        }
+    }
+}
+
+/// Appends queued lines to `path` until the tracer is dropped.
+async fn write_trace_file(path: PathBuf, mut lines: mpsc::Receiver<String>) {
+    let opened = match path.parent() {
+        Some(dir) => tokio::fs::create_dir_all(dir).await,
+        None => Ok(()),
+    };
+    let opened = match opened {
+        Ok(()) => tokio::fs::OpenOptions::new().create(true).append(true).open(&path).await,
+        Err(e) => Err(e),
+    };
+    let mut file = match opened {
+        Ok(file) => file,
+        Err(e) => {
+            log_warning(&format!("Cannot open protocol trace file {}: {}", path.display(), e));
+            return;
+        }
+    };
+
+    while let Some(line) = lines.recv().await {
+        if let Err(e) = file.write_all(line.as_bytes()).await {
+            log_warning(&format!("Cannot write protocol trace file {}: {}", path.display(), e));
+            return;
+        }
+    }
+    let _ = file.flush().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trace_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("tunnel-rust-trace-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    /// Waits for the writer of a dropped tracer to finish `path`, ending with `last`.
+    async fn written(path: &Path, last: &str) -> String {
+        for _ in 0..100 {
+            if let Ok(content) = tokio::fs::read_to_string(path).await {
+                if content.ends_with(last) {
+                    return content;
+                }
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("{} never ended with {:?}", path.display(), last);
+    }
+
+    #[tokio::test]
+    async fn trace_files_share_the_tunnel_budget() {
+        let dir = trace_dir("budget");
+        let budget = Arc::new(TraceBudget::new(250));
+        let first = ProtocolTracer::new("1.2.3.4:1000", "t", dir.to_str(), Arc::clone(&budget));
+        let second = ProtocolTracer::new("1.2.3.4:2000", "t", dir.to_str(), Arc::clone(&budget));
+
+        for _ in 0..4 {
+            first.trace(Direction::MinerToPool, r#"{"id":1,"method":"mining.subscribe","params":[]}"#);
+        }
+        second.trace(Direction::PoolToMiner, r#"{"id":1,"result":true,"error":null}"#);
+        drop((first, second));
+
+        // Each entry is about 100 bytes, so the first file takes two before the budget runs out
+        let notice = format!("tunnel trace files reached {} bytes, no longer written\n", TRACE_TUNNEL_MAX_BYTES);
+        let first = written(&dir.join("t-1.2.3.4_1000.log"), &notice).await;
+        assert_eq!(first.matches("mining.subscribe").count(), 2);
+        let second = written(&dir.join("t-1.2.3.4_2000.log"), &notice).await;
+        assert!(!second.contains("result"));
+        assert!((first.len() - notice.len()) as u64 <= 250);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn trace_files_stop_at_their_own_cap() {
+        let dir = trace_dir("cap");
+        let tracer = ProtocolTracer::new("miner", "t", dir.to_str(), Arc::new(TraceBudget::new(u64::MAX)));
+        tracer.state.lock().unwrap().file_bytes = TRACE_FILE_MAX_BYTES - 10;
+
+        tracer.trace(Direction::MinerToPool, "too long for what is left");
+        tracer.trace(Direction::MinerToPool, "x");
+        drop(tracer);
+
+        let notice = format!("trace file reached {} bytes, no longer written\n", TRACE_FILE_MAX_BYTES);
+        assert_eq!(written(&dir.join("t-miner.log"), &notice).await, notice);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
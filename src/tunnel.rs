@@ -0,0 +1,89 @@
+use dashmap::DashMap;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicI64, Ordering};
+use crate::trace::{TraceBudget, TRACE_TUNNEL_MAX_BYTES};
+
+/// Totals for one listening tunnel, across every miner it has served.
+#[derive(Debug)]
+pub struct TunnelMetrics {
+    pub name: String,
+    pub active_connections: AtomicI64,
+    pub connections_total: AtomicI64,
+    pub shares_accepted: AtomicI64,
+    pub shares_rejected: AtomicI64,
+    pub bytes_download: AtomicI64,
+    pub bytes_upload: AtomicI64,
+    /// Closed connections by `DisconnectReason`
+    disconnects: Mutex<BTreeMap<&'static str, i64>>,
+    /// What this tunnel's protocol trace files may still take, kept across restarts
+    pub trace_budget: Arc<TraceBudget>,
+}
+
+impl TunnelMetrics {
+    pub fn new(name: String) -> Self {
+        Self {
+            name,
+            active_connections: AtomicI64::new(0),
+            connections_total: AtomicI64::new(0),
+            shares_accepted: AtomicI64::new(0),
+            shares_rejected: AtomicI64::new(0),
+            bytes_download: AtomicI64::new(0),
+            bytes_upload: AtomicI64::new(0),
+            disconnects: Mutex::new(BTreeMap::new()),
+            trace_budget: Arc::new(TraceBudget::new(TRACE_TUNNEL_MAX_BYTES)),
+        }
+    }
+
+    pub fn record_disconnect(&self, reason: &'static str) {
+        let mut disconnects = self.disconnects.lock().unwrap_or_else(|e| e.into_inner());
+        *disconnects.entry(reason).or_insert(0) += 1;
+    }
+
+    /// Closed connections per reason, by reason name.
+    pub fn disconnect_counts(&self) -> BTreeMap<&'static str, i64> {
+        self.disconnects.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Counts a miner connection until the returned guard is dropped.
+    pub fn track_connection(self: &Arc<Self>) -> TunnelConnection {
+        self.connections_total.fetch_add(1, Ordering::Relaxed);
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+        TunnelConnection { tunnel: Arc::clone(self) }
+    }
+}
+
+pub struct TunnelConnection {
+    tunnel: Arc<TunnelMetrics>,
+}
+
+impl Drop for TunnelConnection {
+    fn drop(&mut self) {
+        self.tunnel.active_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub struct TunnelManager {
+    tunnels: Arc<DashMap<String, Arc<TunnelMetrics>>>,
+}
+
+impl TunnelManager {
+    pub fn new() -> Self {
+        Self {
+            tunnels: Arc::new(DashMap::new()),
+        }
+    }
+
+    pub fn get_or_create(&self, name: &str) -> Arc<TunnelMetrics> {
+        self.tunnels.entry(name.to_string())
+            .or_insert_with(|| Arc::new(TunnelMetrics::new(name.to_string())))
+            .clone()
+    }
+
+    /// Every tunnel, sorted by name.
+    pub fn get_all_tunnels(&self) -> Vec<Arc<TunnelMetrics>> {
+        let mut tunnels: Vec<_> = self.tunnels.iter().map(|entry| Arc::clone(entry.value())).collect();
+        tunnels.sort_by(|a, b| a.name.cmp(&b.name));
+        tunnels
+    }
+}
@@ -0,0 +1,145 @@
+use std::sync::{Arc, OnceLock};
+use std::time::SystemTime;
+use anyhow::{anyhow, Result};
+use rustls::client::{ServerCertVerified, ServerCertVerifier};
+use rustls::{Certificate, ClientConfig, RootCertStore, ServerName};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+use crate::config::PoolConfig;
+
+/// A connection to a pool, either plain TCP or TLS.
+pub trait Upstream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Upstream for T {}
+
+/// Accepts any certificate, for pools with `tls_insecure` set.
+struct AcceptAnyCertificate;
+
+impl ServerCertVerifier for AcceptAnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+/// The client config for verified or `tls_insecure` pools, built once per kind.
+fn tls_config(insecure: bool) -> Arc<ClientConfig> {
+    static VERIFIED: OnceLock<Arc<ClientConfig>> = OnceLock::new();
+    static INSECURE: OnceLock<Arc<ClientConfig>> = OnceLock::new();
+
+    let builder = || {
+        let mut roots = RootCertStore::empty();
+        roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|anchor| {
+            rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(
+                anchor.subject,
+                anchor.spki,
+                anchor.name_constraints,
+            )
+        }));
+        ClientConfig::builder().with_safe_defaults().with_root_certificates(roots).with_no_client_auth()
+    };
+
+    if insecure {
+        INSECURE.get_or_init(|| {
+            let mut config = builder();
+            config.dangerous().set_certificate_verifier(Arc::new(AcceptAnyCertificate));
+            Arc::new(config)
+        }).clone()
+    } else {
+        VERIFIED.get_or_init(|| Arc::new(builder())).clone()
+    }
+}
+
+/// Connects to `host:port`, wrapping the stream in TLS when `pool.tls` is set.
+pub async fn connect(host: &str, port: u16, pool: &PoolConfig, nodelay: bool) -> Result<Box<dyn Upstream>> {
+    let tcp = TcpStream::connect((host, port)).await?;
+    tcp.set_nodelay(nodelay)?;
+    if !pool.tls {
+        return Ok(Box::new(tcp));
+    }
+
+    let server_name = pool.tls_sni.as_deref().unwrap_or(host);
+    let server_name = ServerName::try_from(server_name)
+        .map_err(|_| anyhow!("pool {}: '{}' is not a valid TLS server name", pool.name, server_name))?;
+
+    match TlsConnector::from(tls_config(pool.tls_insecure)).connect(server_name, tcp).await {
+        Ok(stream) => Ok(Box::new(stream)),
+        Err(e) if pool.tls_insecure => Err(anyhow!("TLS handshake with pool {} ({}:{}) failed: {}",
+            pool.name, host, port, e)),
+        Err(e) => Err(anyhow!("TLS handshake with pool {} ({}:{}) failed: {} \
+            (check tls_sni, or set tls_insecure if the pool uses a self-signed certificate)",
+            pool.name, host, port, e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio_rustls::TlsAcceptor;
+
+    /// A TLS echo server with a self-signed certificate for `localhost`.
+    async fn self_signed_pool() -> u16 {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let config = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![Certificate(cert.serialize_der().unwrap())],
+                rustls::PrivateKey(cert.serialize_private_key_der()),
+            )
+            .unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        tokio::spawn(async move {
+            while let Ok((tcp, _)) = listener.accept().await {
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    let Ok(mut stream) = acceptor.accept(tcp).await else { return };
+                    let mut buf = [0u8; 64];
+                    if let Ok(n) = stream.read(&mut buf).await {
+                        let _ = stream.write_all(&buf[..n]).await;
+                        let _ = stream.flush().await;
+                    }
+                });
+            }
+        });
+        port
+    }
+
+    fn tls_pool(insecure: bool) -> PoolConfig {
+        PoolConfig {
+            tls: true,
+            tls_sni: Some("localhost".to_string()),
+            tls_insecure: insecure,
+            ..crate::config::Config::default().pools["pool1"].clone()
+        }
+    }
+
+    #[tokio::test]
+    async fn connects_over_tls() {
+        let port = self_signed_pool().await;
+
+        // A self-signed certificate isn't in the bundled roots, so it's refused...
+        let err = connect("127.0.0.1", port, &tls_pool(false), true).await.err().unwrap();
+        assert!(err.to_string().contains("set tls_insecure"), "{}", err);
+
+        // ...unless the pool is marked insecure
+        let mut stream = connect("127.0.0.1", port, &tls_pool(true), true).await.unwrap();
+        stream.write_all(b"ping\n").await.unwrap();
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping\n");
+    }
+}
@@ -0,0 +1,142 @@
+use chrono::Utc;
+use std::sync::Arc;
+use tokio::sync::mpsc::UnboundedSender;
+use crate::config::VardiffConfig;
+use crate::logger::log_debug;
+use crate::miner::{MinerInfo, MinerManager};
+
+/// Largest factor a single retarget may move the difficulty by, in either direction.
+const MAX_STEP: f64 = 4.0;
+
+pub fn set_difficulty_request(id: &serde_json::Value, difficulty: f64) -> serde_json::Value {
+    serde_json::json!({
+        "id": id,
+        "method": "mining.set_difficulty",
+        "params": [difficulty],
+    })
+}
+
+pub fn set_difficulty_message(id: &serde_json::Value, difficulty: f64) -> String {
+    format!("{}\n", set_difficulty_request(id, difficulty))
+}
+
+/// Computes the next proxy difficulty from the shares seen in the last retarget window,
+/// up or down within `min_difficulty..=max_difficulty` but never below the pool's own
+/// difficulty, since the pool would reject easier shares. Returns `None` when the current
+/// difficulty is within the configured variance.
+pub fn retarget(miner: &MinerInfo, config: &VardiffConfig) -> Option<f64> {
+    let current = miner.effective_difficulty();
+    // Nothing to scale from, e.g. before the pool set a difficulty or after a bogus one
+    if !(current > 0.0 && current.is_finite()) {
+        return None;
+    }
+    let window = config.retarget_secs.max(1) as f64;
+    let cutoff = Utc::now() - chrono::Duration::seconds(config.retarget_secs as i64);
+    let shares = miner.share_times.iter().filter(|t| **t > cutoff).count() as f64;
+
+    let wanted = if shares == 0.0 {
+        current / MAX_STEP
+    } else {
+        current * shares * config.target_share_secs / window
+    };
+
+    let next = wanted
+        .clamp(current / MAX_STEP, current * MAX_STEP)
+        .clamp(config.min_difficulty, config.max_difficulty)
+        .max(miner.difficulty);
+
+    if ((next - current) / current).abs() * 100.0 < config.variance_percent {
+        return None;
+    }
+
+    Some(next)
+}
+
+/// Periodically retargets one miner and pushes `mining.set_difficulty` to its client.
+/// Exits once the miner is removed or the client writer closes.
+pub async fn run(
+    miner_manager: Arc<MinerManager>,
+    miner_key: String,
+    config: VardiffConfig,
+    client_tx: UnboundedSender<String>,
+) {
+    let period = tokio::time::Duration::from_secs(config.retarget_secs.max(1));
+    let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+
+    loop {
+        interval.tick().await;
+
+        let Some(miner_arc) = miner_manager.get_miner(&miner_key) else {
+            break;
+        };
+        let mut miner = miner_arc.write().await;
+        if miner.wallet.is_empty() {
+            continue;
+        }
+
+        if let Some(next) = retarget(&miner, &config) {
+            if client_tx.send(set_difficulty_message(&serde_json::Value::Null, next)).is_err() {
+                break;
+            }
+            log_debug(&format!("Vardiff: {} difficulty {:.2} -> {:.2}",
+                miner.name, miner.effective_difficulty(), next));
+            miner.proxy_difficulty = Some(next);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> VardiffConfig {
+        VardiffConfig {
+            target_share_secs: 10.0,
+            min_difficulty: 100.0,
+            max_difficulty: 100_000.0,
+            retarget_secs: 60,
+            variance_percent: 10.0,
+        }
+    }
+
+    fn miner_with_shares(difficulty: f64, shares: usize) -> MinerInfo {
+        let mut miner = MinerInfo::new("10.0.0.1".to_string(), "1000".to_string(), "pool".to_string());
+        miner.difficulty = difficulty;
+        miner.share_times.extend((0..shares).map(|_| Utc::now()));
+        miner
+    }
+
+    #[test]
+    fn slow_miner_is_never_retargeted_below_the_pool() {
+        // One share a minute against a 10s target would want 250
+        let mut miner = miner_with_shares(1000.0, 1);
+        miner.proxy_difficulty = Some(4000.0);
+        assert_eq!(retarget(&miner, &config()), Some(1000.0));
+
+        // Already at the pool's difficulty, there is nowhere lower to go
+        miner.proxy_difficulty = Some(1000.0);
+        assert_eq!(retarget(&miner, &config()), None);
+
+        // Nor below the configured floor
+        let mut miner = miner_with_shares(50.0, 0);
+        miner.proxy_difficulty = Some(200.0);
+        assert_eq!(retarget(&miner, &config()), Some(100.0));
+    }
+
+    #[test]
+    fn unusable_difficulties_are_left_alone() {
+        for difficulty in [0.0, -1000.0, f64::NAN, f64::INFINITY] {
+            let miner = miner_with_shares(difficulty, 6);
+            assert_eq!(retarget(&miner, &config()), None);
+        }
+    }
+
+    #[test]
+    fn fast_miner_is_retargeted_up_within_the_step() {
+        let miner = miner_with_shares(1000.0, 60);
+        assert_eq!(retarget(&miner, &config()), Some(4000.0));
+
+        let miner = miner_with_shares(1000.0, 6);
+        assert_eq!(retarget(&miner, &config()), None);
+    }
+}